@@ -0,0 +1,420 @@
+use crate::zone::{Node, Zone};
+
+/// Longest chain of CNAMEs followed within a zone before giving up on it.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// The zones served by this server, by origin.
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    zones: Vec<Zone>,
+}
+
+impl Catalog {
+    pub fn new() -> Self {
+        Catalog::default()
+    }
+
+    /// Serves `zone`, returning the zone previously served at the same origin, if any.
+    pub fn insert(&mut self, zone: Zone) -> Option<Zone> {
+        match self.zones.iter_mut().find(|z| z.origin() == zone.origin()) {
+            Some(existing) => Some(std::mem::replace(existing, zone)),
+            None => {
+                self.zones.push(zone);
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, origin: &dns_codec::Name) -> Option<Zone> {
+        let index = self.zones.iter().position(|zone| zone.origin() == origin)?;
+        Some(self.zones.remove(index))
+    }
+
+    pub fn zone(&self, origin: &dns_codec::Name) -> Option<&Zone> {
+        self.zones.iter().find(|zone| zone.origin() == origin)
+    }
+
+    /// The most specific zone `name` lies within.
+    pub fn find(&self, name: &dns_codec::Name) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .filter(|zone| name.is_subdomain_of(zone.origin()))
+            .max_by_key(|zone| zone.origin().labels().count())
+    }
+
+    /// The responses making up a zone transfer (AXFR or IXFR) of the zone at the origin asked for.
+    /// IXFR requests carry the SOA of the client's version as `client_soa`.
+    ///
+    /// Requests for zones not served here are REFUSED, and IXFR requests without a SOA are a FORMERR.
+    pub fn transfer(
+        &self,
+        query: &dns_codec::Message,
+        client_soa: Option<&dns_codec::Record>,
+    ) -> Vec<dns_codec::Message> {
+        let event = format!("0x{:04x}", query.header.id);
+        let Some(question) = query.question() else {
+            return vec![error(query, dns_codec::Rcode::FORMERR)];
+        };
+
+        let Some(zone) = self.zone(&question.name) else {
+            log::info!(target: &event, "authority: refusing transfer of {}, not served here", question.name);
+            return vec![error(query, dns_codec::Rcode::REFUSED)];
+        };
+        let records = match (question.kind, client_soa) {
+            (dns_codec::QType::AXFR, _) => crate::transfer::axfr_records(zone),
+            (dns_codec::QType::IXFR, Some(soa)) if soa.kind == dns_codec::Type::SOA => {
+                crate::transfer::ixfr_records(zone, crate::zone::soa_serial(soa))
+            }
+            _ => return vec![error(query, dns_codec::Rcode::FORMERR)],
+        };
+        log::info!(target: &event, "authority: transferring {} at {} ({:?})", zone.origin(), zone.serial(), question.kind);
+
+        crate::transfer::messages(query, records)
+    }
+
+    /// Applies `update` to the zone it names if its prerequisites hold (RFC 2136),
+    /// returning the response code to answer with; NOTAUTH if that zone is not served here.
+    pub fn update(&mut self, update: &dns_codec::Update) -> dns_codec::Rcode {
+        let origin = &update.zone.name;
+        match self.zones.iter_mut().find(|zone| zone.origin() == origin) {
+            Some(zone) => crate::update::apply(zone, update),
+            None => {
+                let event = format!("0x{:04x}", update.header.id);
+                log::info!(target: &event, "authority: refusing update of {origin}, not served here");
+                dns_codec::Rcode::NOTAUTH
+            }
+        }
+    }
+
+    /// The response to `query`, answered from the zone it falls within.
+    ///
+    /// Queries for names outside of every zone are REFUSED, as are opcodes other than QUERY (NOTIMP).
+    /// Zone transfers need a stream, see [`Catalog::transfer`]: AXFR is a FORMERR here,
+    /// and IXFR is answered with the current SOA alone, telling the client to retry over TCP.
+    /// Queries asking other than exactly one question are a FORMERR.
+    pub fn answer(&self, query: &dns_codec::Message) -> dns_codec::Message {
+        let mut response = query
+            .response()
+            .build()
+            .expect("the questions of a query fit the response");
+
+        let event = format!("0x{:04x}", query.header.id);
+        let rcode = match query.questions.as_slice() {
+            _ if query.header.opcode() != Some(dns_codec::Opcode::QUERY) => {
+                dns_codec::Rcode::NOTIMP
+            }
+            [question] if question.kind == dns_codec::QType::AXFR => dns_codec::Rcode::FORMERR,
+            [question] if question.kind == dns_codec::QType::IXFR => {
+                match self.zone(&question.name) {
+                    Some(zone) => {
+                        response.header.set_authoritative(true);
+                        response.answers.push(zone.soa().clone());
+                        dns_codec::Rcode::NOERROR
+                    }
+                    None => dns_codec::Rcode::REFUSED,
+                }
+            }
+            [question] => match self.find(&question.name) {
+                Some(zone) if question.class == dns_codec::QClass::IN => {
+                    log::debug!(target: &event, "authority: {} {:?} in zone {}", question.name, question.kind, zone.origin());
+                    resolve(zone, question, &mut response)
+                }
+                _ => {
+                    log::info!(target: &event, "authority: refusing {}, not served here", question.name);
+                    dns_codec::Rcode::REFUSED
+                }
+            },
+            _ => dns_codec::Rcode::FORMERR,
+        };
+        response.header.set_rcode(rcode);
+        if let Err(e) = response.set_counts() {
+            log::warn!(target: &event, "authority: answer does not fit a message: {e}");
+            return error(query, dns_codec::Rcode::SERVFAIL);
+        }
+
+        response
+    }
+}
+
+/// Fills the sections of `response` from `zone`, following the algorithm of RFC 1034, section 4.3.2.
+fn resolve(
+    zone: &Zone,
+    question: &dns_codec::Question,
+    response: &mut dns_codec::Message,
+) -> dns_codec::Rcode {
+    let mut name = question.name.clone();
+
+    for _ in 0..MAX_CNAME_CHAIN {
+        if let Some(cut) = zone.delegation(&name) {
+            // Data below a zone cut is not ours to answer; point at the child zone's nameservers instead
+            if response.answers.is_empty() {
+                refer(zone, &cut, response);
+            } else {
+                response.header.set_authoritative(true);
+            }
+            return dns_codec::Rcode::NOERROR;
+        }
+        response.header.set_authoritative(true);
+
+        let records: Vec<dns_codec::Record> = match zone.node(&name) {
+            Node::Exists(records) => records.to_vec(),
+            // Synthesized records are owned by the name asked for (RFC 4592, section 4.3)
+            Node::Wildcard(records) => records
+                .iter()
+                .map(|record| dns_codec::Record {
+                    name: name.clone(),
+                    ..record.clone()
+                })
+                .collect(),
+            Node::Missing => {
+                negative(zone, response);
+                return dns_codec::Rcode::NXDOMAIN;
+            }
+        };
+
+        let matching: Vec<_> = records
+            .iter()
+            .filter(|record| {
+                question.kind == dns_codec::QType::STAR || record.kind == question.kind
+            })
+            .cloned()
+            .collect();
+        if !matching.is_empty() {
+            response.answers.extend(matching);
+            return dns_codec::Rcode::NOERROR;
+        }
+
+        let cname = records
+            .iter()
+            .find(|record| record.kind == dns_codec::Type::CNAME);
+        let target = match cname.map(|cname| &cname.rdata) {
+            Some(dns_codec::RData::Name(target)) => target.clone(),
+            _ => {
+                negative(zone, response);
+                return dns_codec::Rcode::NOERROR;
+            }
+        };
+        response.answers.push(cname.unwrap().clone());
+
+        // Targets in other zones are left for the client to resolve
+        if !target.is_subdomain_of(zone.origin()) {
+            return dns_codec::Rcode::NOERROR;
+        }
+        name = target;
+    }
+
+    log::info!("authority: CNAME chain from {} is too long", question.name);
+    dns_codec::Rcode::SERVFAIL
+}
+
+/// A referral to the nameservers of the child zone at `cut`, with glue for those within this zone.
+fn refer(zone: &Zone, cut: &dns_codec::Name, response: &mut dns_codec::Message) {
+    let nameservers: Vec<_> = zone.rrset(cut, dns_codec::Type::NS).cloned().collect();
+
+    for nameserver in &nameservers {
+        let dns_codec::RData::Name(target) = &nameserver.rdata else {
+            continue;
+        };
+        if !target.is_subdomain_of(zone.origin()) {
+            continue;
+        }
+        let glue = zone
+            .rrset(target, dns_codec::Type::A)
+            .chain(zone.rrset(target, dns_codec::Type::AAAA));
+        response.additionals.extend(glue.cloned());
+    }
+    response.authorities.extend(nameservers);
+}
+
+/// Adds the SOA record that negative answers carry, with the TTL negative caching should use (RFC 2308, section 3).
+fn negative(zone: &Zone, response: &mut dns_codec::Message) {
+    let mut soa = zone.soa().clone();
+    if let dns_codec::RData::Soa(data) = &soa.rdata {
+        soa.ttl = dns_codec::Ttl::from_secs(soa.ttl.as_secs().min(data.minimum));
+    }
+    response.authorities.push(soa);
+}
+
+/// A response to `query` that carries nothing but `rcode`.
+pub(crate) fn error(query: &dns_codec::Message, rcode: dns_codec::Rcode) -> dns_codec::Message {
+    query
+        .response()
+        .rcode(rcode)
+        .build()
+        .expect("the questions of a query fit the response")
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn name(name: &str) -> dns_codec::Name {
+        name.as_bytes().to_vec().try_into().unwrap()
+    }
+
+    fn record(owner: &str, kind: dns_codec::Type, rdata: dns_codec::RData) -> dns_codec::Record {
+        dns_codec::Record {
+            name: name(owner),
+            kind,
+            class: dns_codec::Class::IN,
+            ttl: dns_codec::Ttl::from_secs(3600),
+            length: 0,
+            rdata,
+        }
+    }
+
+    fn a(owner: &str, address: [u8; 4]) -> dns_codec::Record {
+        record(
+            owner,
+            dns_codec::Type::A,
+            dns_codec::RData::Ipv4(Ipv4Addr::from(address)),
+        )
+    }
+
+    fn pointer(owner: &str, kind: dns_codec::Type, target: &str) -> dns_codec::Record {
+        record(owner, kind, dns_codec::RData::Name(name(target)))
+    }
+
+    fn query(qname: &str, kind: dns_codec::QType) -> dns_codec::Message {
+        dns_codec::Message::query(qname, kind)
+            .id(0xbeef)
+            .build()
+            .unwrap()
+    }
+
+    pub(crate) fn catalog() -> super::Catalog {
+        let soa = record(
+            "example.com",
+            dns_codec::Type::SOA,
+            dns_codec::RData::Soa(dns_codec::Soa {
+                mname: name("ns1.example.com"),
+                rname: name("hostmaster.example.com"),
+                serial: 1,
+                refresh: 7200,
+                retry: 900,
+                expire: 1209600,
+                minimum: 300,
+            }),
+        );
+        let records = vec![
+            soa,
+            pointer("example.com", dns_codec::Type::NS, "ns1.example.com"),
+            a("ns1.example.com", [192, 0, 2, 53]),
+            a("www.example.com", [192, 0, 2, 80]),
+            pointer(
+                "alias.example.com",
+                dns_codec::Type::CNAME,
+                "www.example.com",
+            ),
+            pointer(
+                "elsewhere.example.com",
+                dns_codec::Type::CNAME,
+                "www.example.org",
+            ),
+            a("*.wild.example.com", [192, 0, 2, 42]),
+            a("fixed.wild.example.com", [192, 0, 2, 43]),
+            a("host.deep.example.com", [192, 0, 2, 7]),
+            // Delegation of sub.example.com, with in-zone glue
+            pointer("sub.example.com", dns_codec::Type::NS, "ns.sub.example.com"),
+            pointer("sub.example.com", dns_codec::Type::NS, "ns.example.net"),
+            a("ns.sub.example.com", [192, 0, 2, 99]),
+            record(
+                "ns.sub.example.com",
+                dns_codec::Type::AAAA,
+                dns_codec::RData::Ipv6(Ipv6Addr::LOCALHOST),
+            ),
+        ];
+
+        let mut catalog = super::Catalog::new();
+        catalog.insert(crate::Zone::new(name("example.com"), records).unwrap());
+        catalog
+    }
+
+    #[test]
+    fn authoritative_answers() {
+        let catalog = catalog();
+
+        let response = catalog.answer(&query("WWW.example.com", dns_codec::QType::A));
+        assert!(response.header.is_response() && response.header.is_authoritative());
+        assert_eq!(response.header.id, 0xbeef);
+        assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::NOERROR));
+        assert_eq!(response.answers, [a("www.example.com", [192, 0, 2, 80])]);
+        assert_eq!(response.header.ancount, 1);
+
+        // CNAMEs are followed within the zone, but not beyond it
+        let response = catalog.answer(&query("alias.example.com", dns_codec::QType::A));
+        let kinds: Vec<_> = response.answers.iter().map(|r| r.kind).collect();
+        assert_eq!(kinds, [dns_codec::Type::CNAME, dns_codec::Type::A]);
+        let response = catalog.answer(&query("elsewhere.example.com", dns_codec::QType::A));
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::NOERROR));
+
+        // Wildcards synthesize records owned by the name asked for
+        let response = catalog.answer(&query("anything.wild.example.com", dns_codec::QType::A));
+        assert_eq!(
+            response.answers,
+            [a("anything.wild.example.com", [192, 0, 2, 42])]
+        );
+    }
+
+    #[test]
+    fn negative_answers() {
+        let catalog = catalog();
+        let soa_ttl = |response: &dns_codec::Message| {
+            let [soa] = response.authorities.as_slice() else {
+                panic!("expected a single SOA, got {:?}", response.authorities);
+            };
+            assert_eq!(soa.kind, dns_codec::Type::SOA);
+            soa.ttl.as_secs()
+        };
+
+        let response = catalog.answer(&query("missing.example.com", dns_codec::QType::A));
+        assert!(response.header.is_authoritative());
+        assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::NXDOMAIN));
+        assert_eq!(soa_ttl(&response), 300);
+
+        // NODATA, including at empty non-terminals
+        for qname in ["www.example.com", "deep.example.com"] {
+            let response = catalog.answer(&query(qname, dns_codec::QType::MX));
+            assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::NOERROR));
+            assert!(response.answers.is_empty());
+            assert_eq!(soa_ttl(&response), 300);
+        }
+
+        // Existing names are not matched by the wildcard, nor are names beneath them
+        let response = catalog.answer(&query("fixed.wild.example.com", dns_codec::QType::A));
+        assert_eq!(
+            response.answers,
+            [a("fixed.wild.example.com", [192, 0, 2, 43])]
+        );
+        let response = catalog.answer(&query("x.fixed.wild.example.com", dns_codec::QType::A));
+        assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::NXDOMAIN));
+    }
+
+    #[test]
+    fn referrals() {
+        let catalog = catalog();
+
+        let response = catalog.answer(&query("www.sub.example.com", dns_codec::QType::A));
+        assert!(!response.header.is_authoritative());
+        assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::NOERROR));
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities.len(), 2);
+        assert!(response
+            .authorities
+            .iter()
+            .all(|r| r.kind == dns_codec::Type::NS));
+        let glue: Vec<_> = response.additionals.iter().map(|r| r.kind).collect();
+        assert_eq!(glue, [dns_codec::Type::A, dns_codec::Type::AAAA]);
+    }
+
+    #[test]
+    fn refuses_other_zones() {
+        let catalog = catalog();
+
+        let response = catalog.answer(&query("www.example.org", dns_codec::QType::A));
+        assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::REFUSED));
+        assert!(!response.header.is_authoritative());
+        assert!(response.answers.is_empty() && response.authorities.is_empty());
+    }
+}
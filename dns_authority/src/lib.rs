@@ -0,0 +1,15 @@
+//! Authoritative nameserver, answering from zones held in memory.
+//!
+//! A [`Catalog`] holds the [`Zone`]s served and turns queries into responses on its own;
+//! [`server::Server`] puts it on the network, and [`secondary::Secondary`] keeps copies of other servers' zones up to date.
+
+mod catalog;
+pub mod notify;
+pub mod secondary;
+pub mod server;
+pub mod transfer;
+pub mod update;
+mod zone;
+
+pub use catalog::Catalog;
+pub use zone::{Diff, Zone};
@@ -0,0 +1,130 @@
+//! NOTIFY (RFC 1996): a primary tells its secondaries that a zone has changed,
+//! so that they refresh it right away rather than when their refresh timer runs out.
+
+use std::{io, net::SocketAddr, time::Duration};
+
+use tokio::net::UdpSocket;
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder as _, Encoder as _},
+};
+
+/// Transmissions of a NOTIFY before giving up on a secondary.
+const ATTEMPTS: u32 = 5;
+
+/// Time waited for the first response; doubled with every retransmission.
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A NOTIFY received from `source` for the zone at `zone`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notify {
+    pub zone: dns_codec::Name,
+    pub source: SocketAddr,
+    /// Serial of the new version, if the primary included its SOA.
+    pub serial: Option<u32>,
+}
+
+impl Notify {
+    /// Reads a NOTIFY from `message`, which must ask about the SOA of a single zone.
+    pub(crate) fn parse(message: &dns_codec::Message, source: SocketAddr) -> Option<Self> {
+        let [question] = message.questions.as_slice() else {
+            return None;
+        };
+        if question.kind != dns_codec::QType::SOA {
+            return None;
+        }
+
+        let serial = message
+            .answers
+            .iter()
+            .find(|record| record.kind == dns_codec::Type::SOA && record.name == question.name)
+            .map(crate::zone::soa_serial);
+        Some(Notify {
+            zone: question.name.clone(),
+            source,
+            serial,
+        })
+    }
+}
+
+/// Tells `secondary` that the zone of `soa` changed to the version of `soa`,
+/// retransmitting until the secondary acknowledges it.
+///
+/// Fails with [`io::ErrorKind::TimedOut`] if the secondary never answers,
+/// and with [`io::ErrorKind::Other`] if it rejects the NOTIFY.
+pub async fn notify(secondary: SocketAddr, soa: &dns_codec::Record) -> io::Result<()> {
+    let id = dns_codec::Header::random_id();
+    let mut header = dns_codec::Header {
+        id,
+        flags: 0,
+        qdcount: 1,
+        ancount: 1,
+        ncount: 0,
+        arcount: 0,
+    };
+    header.set_opcode(dns_codec::Opcode::NOTIFY);
+    header.set_authoritative(true);
+    let request = dns_codec::Message {
+        header,
+        questions: vec![dns_codec::Question {
+            name: soa.name.clone(),
+            kind: dns_codec::QType::SOA,
+            class: dns_codec::QClass::IN,
+        }],
+        answers: vec![soa.clone()],
+        authorities: Vec::new(),
+        additionals: Vec::new(),
+        edns: None,
+    };
+    let mut datagram = BytesMut::new();
+    dns_codec::MessageCodec.encode(request, &mut datagram)?;
+
+    let local: SocketAddr = match secondary {
+        SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(secondary).await?;
+
+    let event = format!("0x{id:04x}");
+    let mut timeout = INITIAL_TIMEOUT;
+    let mut buffer = vec![0; 512];
+    for _ in 0..ATTEMPTS {
+        log::info!(target: &event, "notify: {} changed, telling {secondary}", soa.name);
+        socket.send(&datagram).await?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buffer)).await {
+            let length = received?;
+            let response =
+                match dns_codec::MessageCodec.decode(&mut BytesMut::from(&buffer[..length])) {
+                    Ok(Some(response)) => response,
+                    _ => continue,
+                };
+            if response.header.id != id
+                || !response.header.is_response()
+                || response.header.opcode() != Some(dns_codec::Opcode::NOTIFY)
+            {
+                log::debug!(target: &event, "notify: ignoring unrelated message from {secondary}");
+                continue;
+            }
+
+            return match response.header.rcode() {
+                Some(dns_codec::Rcode::NOERROR) => Ok(()),
+                rcode => Err(io::Error::other(format!(
+                    "{secondary} rejected the NOTIFY for {} with {rcode:?}",
+                    soa.name
+                ))),
+            };
+        }
+        timeout *= 2;
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!(
+            "{secondary} did not acknowledge the NOTIFY for {}",
+            soa.name
+        ),
+    ))
+}
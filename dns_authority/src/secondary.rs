@@ -0,0 +1,374 @@
+//! Secondary zones, kept in sync with their primary by zone transfers.
+//!
+//! A zone is refreshed on the timers of its SOA record (RFC 1034, section 4.3.5)
+//! and whenever the primary sends a NOTIFY (RFC 1996).
+
+use std::{
+    future,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc;
+
+use crate::{notify::Notify, Catalog, Zone};
+
+/// Interval between attempts to load a zone for the first time, before its SOA tells the actual one.
+const INITIAL_RETRY: Duration = Duration::from_secs(60);
+
+/// Time allowed for a single refresh, including the transfer.
+const REFRESH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// When a secondary zone is due to be refreshed, and when it expires without a refresh,
+/// driven by explicit points in time.
+#[derive(Debug, Clone)]
+pub struct Timers {
+    retry: Duration,
+    /// When to check the primary for a newer version next.
+    next: Instant,
+    /// When to stop serving the zone, unless refreshed before; `None` while there is nothing to serve.
+    expires: Option<Instant>,
+}
+
+impl Timers {
+    /// Timers of a zone not loaded yet, which is due right away.
+    pub fn new(now: Instant) -> Self {
+        Timers {
+            retry: INITIAL_RETRY,
+            next: now,
+            expires: None,
+        }
+    }
+
+    /// The next point in time at which something is due.
+    pub fn poll_timeout(&self) -> Instant {
+        match self.expires {
+            Some(expires) => self.next.min(expires),
+            None => self.next,
+        }
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.next <= now
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// The primary was reached and the zone is at the version of `soa`.
+    pub fn refreshed(&mut self, soa: &dns_codec::Soa, now: Instant) {
+        let secs = |secs: u32| Duration::from_secs(secs.into());
+        self.retry = secs(soa.retry);
+        self.next = now + secs(soa.refresh);
+        self.expires = Some(now + secs(soa.expire));
+    }
+
+    /// The primary could not be reached, or its transfer failed.
+    pub fn failed(&mut self, now: Instant) {
+        self.next = now + self.retry;
+    }
+
+    /// The primary announced a change.
+    pub fn notified(&mut self, now: Instant) {
+        self.next = now;
+    }
+
+    /// The zone is no longer served; it is only retried from here on.
+    pub fn expired(&mut self) {
+        self.expires = None;
+    }
+}
+
+/// A zone served from copies of a primary's.
+struct SecondaryZone {
+    origin: dns_codec::Name,
+    primary: SocketAddr,
+    /// Key to sign transfers from the primary with, if it requires them signed.
+    key: Option<dns_codec::tsig::Key>,
+    timers: Timers,
+}
+
+/// Keeps the secondary zones of a shared [`Catalog`] up to date.
+pub struct Secondary {
+    catalog: Arc<RwLock<Catalog>>,
+    zones: Vec<SecondaryZone>,
+    sender: mpsc::UnboundedSender<Notify>,
+    notifications: mpsc::UnboundedReceiver<Notify>,
+}
+
+impl Secondary {
+    pub fn new(catalog: Arc<RwLock<Catalog>>) -> Self {
+        let (sender, notifications) = mpsc::unbounded_channel();
+        Secondary {
+            catalog,
+            zones: Vec::new(),
+            sender,
+            notifications,
+        }
+    }
+
+    /// Serves the zone at `origin` as a copy of `primary`'s, starting with a full transfer.
+    /// Transfers are signed with `key`, if given.
+    pub fn add_zone(
+        &mut self,
+        origin: dns_codec::Name,
+        primary: SocketAddr,
+        key: Option<dns_codec::tsig::Key>,
+    ) {
+        self.zones.push(SecondaryZone {
+            origin,
+            primary,
+            key,
+            timers: Timers::new(Instant::now()),
+        });
+    }
+
+    /// Where to pass on NOTIFY messages, see [`crate::server::Server::with_notifications`].
+    pub fn notifications(&self) -> mpsc::UnboundedSender<Notify> {
+        self.sender.clone()
+    }
+
+    /// Refreshes zones as their timers run out or their primary notifies a change, forever.
+    pub async fn run(mut self) {
+        loop {
+            for index in 0..self.zones.len() {
+                let now = Instant::now();
+                if self.zones[index].timers.is_expired(now) {
+                    self.expire(index);
+                }
+                if self.zones[index].timers.is_due(now) {
+                    self.refresh(index).await;
+                }
+            }
+
+            let deadline = self
+                .zones
+                .iter()
+                .map(|zone| zone.timers.poll_timeout())
+                .min();
+            let sleep = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                    None => future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = sleep => (),
+                Some(notify) = self.notifications.recv() => self.notified(notify),
+            }
+        }
+    }
+
+    fn notified(&mut self, notify: Notify) {
+        let Some(zone) = self
+            .zones
+            .iter_mut()
+            .find(|zone| zone.origin == notify.zone)
+        else {
+            log::info!(
+                "secondary: ignoring NOTIFY for {}, not a secondary zone",
+                notify.zone
+            );
+            return;
+        };
+        // Only the primary may trigger a transfer (RFC 1996, section 3.10)
+        if notify.source.ip() != zone.primary.ip() {
+            log::warn!(
+                "secondary: ignoring NOTIFY for {} from {}, which is not its primary",
+                notify.zone,
+                notify.source
+            );
+            return;
+        }
+
+        let current = self
+            .catalog
+            .read()
+            .unwrap()
+            .zone(&zone.origin)
+            .map(Zone::serial);
+        match (current, notify.serial) {
+            (Some(current), Some(serial)) if !crate::transfer::serial_newer(serial, current) => {
+                log::debug!("secondary: {} is up to date at {current}", zone.origin);
+            }
+            _ => zone.timers.notified(Instant::now()),
+        }
+    }
+
+    /// Brings the zone at `index` up to date with its primary: incrementally if it is served already,
+    /// and with a full transfer otherwise. The transfer is skipped if the primary has no newer version.
+    async fn refresh(&mut self, index: usize) {
+        let zone = &mut self.zones[index];
+        let current: Option<Vec<_>> = self
+            .catalog
+            .read()
+            .unwrap()
+            .zone(&zone.origin)
+            .map(|zone| zone.records().cloned().collect());
+
+        let transfer = async {
+            match &current {
+                Some(records) => {
+                    crate::transfer::ixfr(zone.primary, records, zone.key.as_ref()).await
+                }
+                None => {
+                    crate::transfer::axfr(zone.primary, &zone.origin, zone.key.as_ref()).await
+                }
+            }
+        };
+        let result = match tokio::time::timeout(REFRESH_TIMEOUT, transfer).await {
+            Ok(result) => result.and_then(|records| Zone::new(zone.origin.clone(), records)),
+            Err(elapsed) => Err(elapsed.into()),
+        };
+
+        let now = Instant::now();
+        let updated = match result {
+            Ok(updated) => updated,
+            Err(e) => {
+                log::warn!(
+                    "secondary: failed to refresh {} from {}: {e}",
+                    zone.origin,
+                    zone.primary
+                );
+                zone.timers.failed(now);
+                return;
+            }
+        };
+
+        if let dns_codec::RData::Soa(soa) = &updated.soa().rdata {
+            zone.timers.refreshed(soa, now);
+        }
+        let serial = updated.serial();
+        let mut catalog = self.catalog.write().unwrap();
+        if catalog.zone(&zone.origin).map(Zone::serial) != Some(serial) {
+            log::info!("secondary: serving {} at {serial}", zone.origin);
+            catalog.insert(updated);
+        }
+    }
+
+    /// Stops serving the zone at `index`, whose primary has not been reached for too long.
+    fn expire(&mut self, index: usize) {
+        let zone = &mut self.zones[index];
+        log::warn!("secondary: {} expired, no longer serving it", zone.origin);
+        self.catalog.write().unwrap().remove(&zone.origin);
+        zone.timers.expired();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, RwLock},
+        time::{Duration, Instant},
+    };
+
+    use tokio::net::{TcpListener, UdpSocket};
+
+    fn soa(refresh: u32, retry: u32, expire: u32) -> dns_codec::Soa {
+        dns_codec::Soa {
+            mname: b"ns1.example.com".to_vec().try_into().unwrap(),
+            rname: b"hostmaster.example.com".to_vec().try_into().unwrap(),
+            serial: 1,
+            refresh,
+            retry,
+            expire,
+            minimum: 300,
+        }
+    }
+
+    #[test]
+    fn timers() {
+        let start = Instant::now();
+        let mut timers = super::Timers::new(start);
+        assert!(timers.is_due(start));
+        assert!(!timers.is_expired(start + Duration::from_secs(1_000_000)));
+
+        timers.refreshed(&soa(3600, 600, 86400), start);
+        assert!(!timers.is_due(start + Duration::from_secs(3599)));
+        assert!(timers.is_due(start + Duration::from_secs(3600)));
+        assert_eq!(timers.poll_timeout(), start + Duration::from_secs(3600));
+
+        // Failures are retried on the shorter interval, until the zone expires
+        let failure = start + Duration::from_secs(3600);
+        timers.failed(failure);
+        assert_eq!(timers.poll_timeout(), failure + Duration::from_secs(600));
+        assert!(!timers.is_expired(start + Duration::from_secs(86399)));
+        assert!(timers.is_expired(start + Duration::from_secs(86400)));
+
+        timers.expired();
+        assert!(!timers.is_expired(start + Duration::from_secs(86400)));
+
+        let notified = start + Duration::from_secs(10);
+        timers.notified(notified);
+        assert!(timers.is_due(notified));
+    }
+
+    /// Serves `catalog` over UDP and TCP on the same loopback port.
+    async fn serve(server: crate::server::Server) -> SocketAddr {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = tcp.local_addr().unwrap();
+        let udp = UdpSocket::bind(address).await.unwrap();
+        tokio::spawn(server.clone().serve_udp(udp));
+        tokio::spawn(server.serve_tcp(tcp));
+        address
+    }
+
+    /// Waits for the secondary's copy of `origin` to reach `serial`.
+    async fn wait_for(catalog: &RwLock<crate::Catalog>, origin: &dns_codec::Name, serial: u32) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while catalog
+                .read()
+                .unwrap()
+                .zone(origin)
+                .map(crate::Zone::serial)
+                != Some(serial)
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("secondary did not catch up");
+    }
+
+    #[tokio::test]
+    async fn notified_refresh() {
+        let origin: dns_codec::Name = b"example.com".to_vec().try_into().unwrap();
+        let primary_catalog = Arc::new(RwLock::new(crate::catalog::test::catalog()));
+        let primary = serve(crate::server::Server::new(primary_catalog.clone())).await;
+
+        let catalog = Arc::new(RwLock::new(crate::Catalog::new()));
+        let mut secondary = super::Secondary::new(catalog.clone());
+        secondary.add_zone(origin.clone(), primary, None);
+        let server = crate::server::Server::new(catalog.clone())
+            .with_notifications(secondary.notifications());
+        let address = serve(server).await;
+        tokio::spawn(secondary.run());
+
+        // Loaded right away
+        let serial = primary_catalog
+            .read()
+            .unwrap()
+            .zone(&origin)
+            .unwrap()
+            .serial();
+        wait_for(&catalog, &origin, serial).await;
+
+        let soa = {
+            let mut primary_catalog = primary_catalog.write().unwrap();
+            let mut zone = primary_catalog.zone(&origin).unwrap().clone();
+            zone.update(&[], vec![]).unwrap();
+            let soa = zone.soa().clone();
+            primary_catalog.insert(zone);
+            soa
+        };
+        crate::notify::notify(address, &soa).await.unwrap();
+        wait_for(&catalog, &origin, crate::zone::soa_serial(&soa)).await;
+
+        // Servers that are not a secondary refuse
+        let error = crate::notify::notify(primary, &soa).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::Other);
+    }
+}
@@ -0,0 +1,482 @@
+//! Serves a [`Catalog`] to clients over UDP and TCP.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use dns_codec::{sig0, tkey, tsig};
+use futures::{SinkExt as _, StreamExt as _};
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc,
+};
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{BytesCodec, Decoder as _, Encoder as _, FramedRead, FramedWrite},
+};
+
+use crate::{notify::Notify, Catalog};
+
+/// Largest response sent over UDP; longer ones are truncated so that the client retries over TCP (RFC 1035, section 4.2.1).
+const MAX_UDP_RESPONSE: usize = 512;
+
+/// Largest datagram accepted from a client.
+const MAX_DATAGRAM: usize = 65535;
+
+/// Answers queries from the zones of a shared [`Catalog`], which may be changed while serving.
+///
+/// Cloning is cheap; all clones share the same catalog.
+#[derive(Clone)]
+pub struct Server {
+    catalog: Arc<RwLock<Catalog>>,
+    /// Where NOTIFY messages are passed on to; they are REFUSED without one.
+    notifications: Option<mpsc::UnboundedSender<Notify>>,
+    /// Clients allowed to send UPDATE messages; those of everyone else are REFUSED.
+    updaters: Vec<IpAddr>,
+    /// Keys that requests may be signed with (TSIG, RFC 8945); TKEY requests may add and delete them.
+    keyring: Option<Arc<RwLock<tsig::Keyring>>>,
+    /// Public keys that requests may be signed with (SIG(0), RFC 2931).
+    sig0_keys: Vec<sig0::PublicKey>,
+    /// Zones that UPDATE messages signed with a key may change, by key name; other signed ones are REFUSED.
+    update_keys: HashMap<dns_codec::Name, Vec<dns_codec::Name>>,
+}
+
+/// How a request was signed, and thereby how to sign the responses to it.
+enum Signature {
+    Unsigned,
+    Valid(tsig::Session),
+    Rejected(tsig::Rejection),
+    /// Signed with SIG(0) by the known key of this name; responses are not signed.
+    PublicKey(dns_codec::Name),
+    /// Signed with SIG(0), but not by any known key.
+    Forged,
+}
+
+impl Signature {
+    /// Signs the encoded `responses` like the request they answer.
+    fn sign(self, mut responses: Vec<BytesMut>) -> io::Result<Vec<BytesMut>> {
+        let now = SystemTime::now();
+        match self {
+            Signature::Unsigned | Signature::PublicKey(_) | Signature::Forged => (),
+            Signature::Valid(mut session) => {
+                for response in &mut responses {
+                    session.sign(response, now)?;
+                }
+            }
+            Signature::Rejected(rejection) => {
+                if let Some(response) = responses.first_mut() {
+                    rejection.sign(response, now)?;
+                }
+            }
+        }
+        Ok(responses)
+    }
+
+    /// Name of the key a request was validly signed with.
+    fn signer(&self) -> Option<&dns_codec::Name> {
+        match self {
+            Signature::Valid(session) => Some(&session.key().name),
+            Signature::PublicKey(name) => Some(name),
+            Signature::Unsigned | Signature::Rejected(_) | Signature::Forged => None,
+        }
+    }
+}
+
+impl Server {
+    pub fn new(catalog: Arc<RwLock<Catalog>>) -> Self {
+        Server {
+            catalog,
+            notifications: None,
+            updaters: Vec::new(),
+            keyring: None,
+            sig0_keys: Vec::new(),
+            update_keys: HashMap::new(),
+        }
+    }
+
+    /// Passes the NOTIFY messages received on to `notifications`, usually those of a [`crate::secondary::Secondary`].
+    pub fn with_notifications(mut self, notifications: mpsc::UnboundedSender<Notify>) -> Self {
+        self.notifications = Some(notifications);
+        self
+    }
+
+    /// Authenticates requests signed with the keys of `keyring`, and signs the responses to them.
+    ///
+    /// Zone transfers are only served if signed. Signed dynamic updates are accepted from any client,
+    /// but only for the zones granted to their key with [`Server::allow_key_updates`].
+    pub fn with_keyring(mut self, keyring: tsig::Keyring) -> Self {
+        self.keyring = Some(Arc::new(RwLock::new(keyring)));
+        self
+    }
+
+    /// Authenticates requests signed with SIG(0) by the private counterparts of `keys`,
+    /// granting them the same as requests signed with TSIG.
+    pub fn with_sig0_keys(mut self, keys: impl IntoIterator<Item = sig0::PublicKey>) -> Self {
+        self.sig0_keys.extend(keys);
+        self
+    }
+
+    /// Accepts dynamic updates (RFC 2136) from `clients`.
+    pub fn allow_updates(mut self, clients: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.updaters.extend(clients);
+        self
+    }
+
+    /// Accepts dynamic updates of `zones` signed with the TSIG or SIG(0) key named `key`, from any client.
+    pub fn allow_key_updates(
+        mut self,
+        key: dns_codec::Name,
+        zones: impl IntoIterator<Item = dns_codec::Name>,
+    ) -> Self {
+        self.update_keys.entry(key).or_default().extend(zones);
+        self
+    }
+
+    pub fn catalog(&self) -> &Arc<RwLock<Catalog>> {
+        &self.catalog
+    }
+
+    /// Binds `address` over both UDP and TCP, and serves clients until either listener fails.
+    pub async fn serve(self, address: SocketAddr) -> io::Result<()> {
+        let udp = UdpSocket::bind(address).await?;
+        let tcp = TcpListener::bind(address).await?;
+        log::info!("serving on {address}");
+
+        tokio::try_join!(self.clone().serve_udp(udp), self.serve_tcp(tcp))?;
+        Ok(())
+    }
+
+    /// Answers every datagram arriving on `socket`.
+    pub async fn serve_udp(self, socket: UdpSocket) -> io::Result<()> {
+        let mut datagram = vec![0; MAX_DATAGRAM];
+
+        loop {
+            let (length, client) = socket.recv_from(&mut datagram).await?;
+            let request = &datagram[..length];
+            // Decoded as full messages, since NOTIFY and UPDATE messages carry records beyond the question
+            let message = dns_codec::MessageCodec.decode(&mut BytesMut::from(request));
+            let Some((message, question)) = message.ok().flatten().and_then(as_request) else {
+                log::debug!("udp: dropping malformed query from {client}");
+                continue;
+            };
+
+            let signature = self.authenticate(request, message.header.id, &question);
+            let result = self
+                .respond(message, &question, client, &signature, false)
+                .into_iter()
+                .map(encode_datagram)
+                .collect::<io::Result<Vec<_>>>()
+                .and_then(|responses| signature.sign(responses));
+            let result = match result {
+                Ok(responses) => match responses.first() {
+                    Some(response) => socket.send_to(response, client).await.map(drop),
+                    None => Ok(()),
+                },
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                log::warn!("udp: failed to answer {client}: {e}");
+            }
+        }
+    }
+
+    /// Accepts connections on `listener`, each served in its own task.
+    pub async fn serve_tcp(self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, client) = listener.accept().await?;
+
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_connection(stream).await {
+                    log::debug!("tcp: connection to {client} failed: {e}");
+                }
+            });
+        }
+    }
+
+    /// Answers the queries arriving on `stream` one after the other, until the client closes it.
+    /// Zone transfers are only served here, as their responses may span several messages.
+    async fn serve_connection(&self, stream: TcpStream) -> io::Result<()> {
+        let client = stream.peer_addr()?;
+        let (read, write) = stream.into_split();
+        // Kept as bytes until authenticated, as signatures cover messages exactly as they were sent
+        let mut requests = FramedRead::new(read, dns_codec::StreamCodec(BytesCodec::new()));
+        let mut responses = FramedWrite::new(write, dns_codec::StreamCodec(BytesCodec::new()));
+
+        while let Some(request) = requests.next().await {
+            let request = request?;
+            let Some(message) = dns_codec::MessageCodec.decode(&mut request.clone())? else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Message is shorter than its contents require",
+                ));
+            };
+            let Some((message, question)) = as_request(message) else {
+                continue;
+            };
+
+            let signature = self.authenticate(&request, message.header.id, &question);
+            let encoded = self
+                .respond(message, &question, client, &signature, true)
+                .into_iter()
+                .map(|response| {
+                    let mut encoded = BytesMut::new();
+                    dns_codec::MessageCodec.encode(response, &mut encoded)?;
+                    Ok(encoded)
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            for response in signature.sign(encoded)? {
+                responses.feed(response).await?;
+            }
+            futures::SinkExt::<BytesMut>::flush(&mut responses).await?;
+        }
+        Ok(())
+    }
+
+    /// Checks the TSIG or SIG(0) record `request` may end with against the keys known, if any.
+    fn authenticate(&self, request: &[u8], id: u16, question: &dns_codec::Question) -> Signature {
+        let event = format!("0x{id:04x}");
+        let now = SystemTime::now();
+        if let Some(keyring) = &self.keyring {
+            match tsig::verify_request(request, &keyring.read().unwrap(), now) {
+                Ok(Some(session)) => return Signature::Valid(session),
+                Ok(None) => (),
+                Err(rejection) => {
+                    log::info!(target: &event, "authority: rejecting signature of {} ({:?})", question.name, rejection.error);
+                    return Signature::Rejected(rejection);
+                }
+            }
+        }
+        if self.sig0_keys.is_empty() {
+            return Signature::Unsigned;
+        }
+        match sig0::verify(request, &self.sig0_keys, now) {
+            Ok(Some(key)) => {
+                log::debug!(target: &event, "authority: {} is signed by {}", question.name, key.name);
+                Signature::PublicKey(key.name.clone())
+            }
+            Ok(None) => Signature::Unsigned,
+            Err(e) => {
+                log::info!(target: &event, "authority: rejecting SIG(0) of {}: {e}", question.name);
+                Signature::Forged
+            }
+        }
+    }
+
+    /// The responses to the request in `message`, asking `question`, from `client`;
+    /// zone transfers are only served on `stream` transports.
+    fn respond(
+        &self,
+        message: dns_codec::Message,
+        question: &dns_codec::Question,
+        client: SocketAddr,
+        signature: &Signature,
+        stream: bool,
+    ) -> Vec<dns_codec::Message> {
+        if let Signature::Rejected(_) | Signature::Forged = signature {
+            return vec![crate::catalog::error(&message, dns_codec::Rcode::NOTAUTH)];
+        }
+        let signer = signature.signer();
+
+        match message.header.opcode() {
+            Some(dns_codec::Opcode::NOTIFY) => vec![self.notified(&message, client)],
+            Some(dns_codec::Opcode::UPDATE) => {
+                vec![self.updated(message, question, client, signer)]
+            }
+            _ if question.kind == dns_codec::QType::TKEY => {
+                vec![self.negotiated(&message, question, signature)]
+            }
+            _ if stream
+                && matches!(
+                    question.kind,
+                    dns_codec::QType::AXFR | dns_codec::QType::IXFR
+                ) =>
+            {
+                if (self.keyring.is_some() || !self.sig0_keys.is_empty()) && signer.is_none() {
+                    let event = format!("0x{:04x}", message.header.id);
+                    log::info!(target: &event, "authority: refusing unsigned transfer of {} to {client}", question.name);
+                    return vec![crate::catalog::error(&message, dns_codec::Rcode::REFUSED)];
+                }
+                self.catalog
+                    .read()
+                    .unwrap()
+                    .transfer(&message, message.authorities.first())
+            }
+            _ => vec![self.answer(&message)],
+        }
+    }
+
+    fn answer(&self, query: &dns_codec::Message) -> dns_codec::Message {
+        self.catalog.read().unwrap().answer(query)
+    }
+
+    /// Passes the NOTIFY in `message` on and acknowledges it (RFC 1996, section 4.7).
+    fn notified(&self, message: &dns_codec::Message, client: SocketAddr) -> dns_codec::Message {
+        let event = format!("0x{:04x}", message.header.id);
+        let rcode = match (Notify::parse(message, client), &self.notifications) {
+            (None, _) => dns_codec::Rcode::FORMERR,
+            (Some(notify), Some(notifications)) => {
+                log::info!(target: &event, "authority: {client} notified a change of {}", notify.zone);
+                match notifications.send(notify) {
+                    Ok(()) => dns_codec::Rcode::NOERROR,
+                    Err(_) => dns_codec::Rcode::SERVFAIL,
+                }
+            }
+            (Some(notify), None) => {
+                log::info!(target: &event, "authority: refusing NOTIFY of {} from {client}, not a secondary", notify.zone);
+                dns_codec::Rcode::REFUSED
+            }
+        };
+        crate::catalog::error(message, rcode)
+    }
+
+    /// Processes the TKEY request in `message`, which may assign a new TSIG key or delete the one it is signed with.
+    fn negotiated(
+        &self,
+        message: &dns_codec::Message,
+        question: &dns_codec::Question,
+        signature: &Signature,
+    ) -> dns_codec::Message {
+        let Some(keyring) = &self.keyring else {
+            return crate::catalog::error(message, dns_codec::Rcode::REFUSED);
+        };
+        let session = match signature {
+            Signature::Valid(session) => Some(session),
+            _ => None,
+        };
+        let event = format!("0x{:04x}", message.header.id);
+        log::info!(target: &event, "authority: TKEY request for {}", question.name);
+        tkey::respond(
+            message,
+            session,
+            &mut keyring.write().unwrap(),
+            SystemTime::now(),
+        )
+    }
+
+    /// Applies the UPDATE in `message` if it is signed by the key named `signer` and that key may change the zone,
+    /// or `client` may change zones here.
+    fn updated(
+        &self,
+        message: dns_codec::Message,
+        question: &dns_codec::Question,
+        client: SocketAddr,
+        signer: Option<&dns_codec::Name>,
+    ) -> dns_codec::Message {
+        let event = format!("0x{:04x}", message.header.id);
+        let granted = signer.is_some_and(|key| {
+            self.update_keys
+                .get(key)
+                .is_some_and(|zones| zones.contains(&question.name))
+        });
+        let rcode = if !granted && !self.updaters.contains(&client.ip()) {
+            match signer {
+                Some(key) => {
+                    log::info!(target: &event, "authority: refusing update of {} signed by {key}", question.name)
+                }
+                None => {
+                    log::info!(target: &event, "authority: refusing update of {} from {client}", question.name)
+                }
+            }
+            dns_codec::Rcode::REFUSED
+        } else {
+            match dns_codec::Update::try_from(message.clone()) {
+                Ok(update) => self.catalog.write().unwrap().update(&update),
+                Err(e) => {
+                    log::info!(target: &event, "authority: malformed update from {client}: {e}");
+                    dns_codec::Rcode::FORMERR
+                }
+            }
+        };
+        crate::catalog::error(&message, rcode)
+    }
+}
+
+/// The question of the request in `message`, unless it is a response or asks no question.
+fn as_request(message: dns_codec::Message) -> Option<(dns_codec::Message, dns_codec::Question)> {
+    if message.header.is_response() {
+        return None;
+    }
+    let question = message.question()?.clone();
+    Some((message, question))
+}
+
+/// Encodes `response` for UDP, dropping its records and setting TC if it does not fit.
+/// Encoding derives the header counts from what is left.
+fn encode_datagram(mut response: dns_codec::Message) -> io::Result<BytesMut> {
+    let mut datagram = BytesMut::new();
+    dns_codec::MessageCodec.encode(response.clone(), &mut datagram)?;
+    if datagram.len() <= MAX_UDP_RESPONSE {
+        return Ok(datagram);
+    }
+
+    response.answers.clear();
+    response.authorities.clear();
+    response.additionals.clear();
+    response.header.set_truncated(true);
+
+    datagram.clear();
+    dns_codec::MessageCodec.encode(response, &mut datagram)?;
+    Ok(datagram)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, RwLock},
+        time::Instant,
+    };
+
+    use tokio::net::UdpSocket;
+    use tokio_util::{
+        bytes::BytesMut,
+        codec::{Decoder as _, Encoder as _},
+    };
+
+    /// Resolves against a local stand-in instead of a public nameserver.
+    #[tokio::test]
+    async fn local_stand_in() {
+        let catalog = Arc::new(RwLock::new(crate::catalog::test::catalog()));
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(super::Server::new(catalog).serve_udp(socket));
+
+        let mut sans_io = dns_sans_io::DnsSansIo::new();
+        sans_io.enqueue_query(&[address], dns_codec::QType::A, b"www.example.com".to_vec());
+        let transmit = sans_io.poll_query(Instant::now()).unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut datagram = BytesMut::new();
+        dns_codec::MessageCodec
+            .encode(transmit.query, &mut datagram)
+            .unwrap();
+        client.send_to(&datagram, transmit.target).await.unwrap();
+
+        let mut buffer = vec![0; 512];
+        let (length, source) = client.recv_from(&mut buffer).await.unwrap();
+        let response = dns_codec::MessageCodec
+            .decode(&mut BytesMut::from(&buffer[..length]))
+            .unwrap()
+            .unwrap();
+        assert!(response.header.is_authoritative());
+
+        let responses = sans_io
+            .handle_response(Instant::now(), source, response)
+            .unwrap();
+        let [dns_sans_io::Response {
+            outcome: dns_sans_io::Outcome::Resolved(records),
+            ..
+        }] = responses.as_slice()
+        else {
+            panic!("expected an answer, got {responses:?}");
+        };
+        assert_eq!(
+            records[0].rdata,
+            dns_codec::RData::Ipv4([192, 0, 2, 80].into())
+        );
+    }
+}
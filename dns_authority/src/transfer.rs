@@ -0,0 +1,519 @@
+//! Zone transfers over TCP: full (AXFR, RFC 5936) and incremental (IXFR, RFC 1995).
+//!
+//! The server side turns a [`Zone`] into the messages of a transfer;
+//! [`axfr`] and [`ixfr`] pull a zone from a primary, optionally signed with TSIG (RFC 8945).
+
+use std::{collections::VecDeque, io, net::SocketAddr, time::SystemTime};
+
+use dns_codec::tsig;
+use futures::{SinkExt as _, StreamExt as _};
+use tokio::net::{tcp::OwnedReadHalf, TcpStream};
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{BytesCodec, Decoder as _, Encoder as _, FramedRead, FramedWrite},
+};
+
+use crate::{
+    zone::{same, soa_serial},
+    Zone,
+};
+
+/// Records per message of an outgoing transfer, keeping messages well below the 64 KiB a stream message may hold.
+const RECORDS_PER_MESSAGE: usize = 100;
+
+/// Whether serial `a` is newer than `b`, in serial number arithmetic (RFC 1982).
+pub fn serial_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
+
+/// Records of a full transfer of `zone`: its SOA, every other record, and the SOA again.
+pub(crate) fn axfr_records(zone: &Zone) -> Vec<dns_codec::Record> {
+    let soa = zone.soa().clone();
+    let others = zone
+        .records()
+        .filter(|record| record.kind != dns_codec::Type::SOA)
+        .cloned();
+
+    std::iter::once(soa.clone())
+        .chain(others)
+        .chain(std::iter::once(soa))
+        .collect()
+}
+
+/// Records of an incremental transfer from version `serial` of `zone` (RFC 1995, section 4).
+///
+/// Consists of the current SOA alone if `serial` is up to date,
+/// and falls back to a full transfer if the journal does not reach back to `serial`.
+pub(crate) fn ixfr_records(zone: &Zone, serial: u32) -> Vec<dns_codec::Record> {
+    let soa = zone.soa().clone();
+    if !serial_newer(zone.serial(), serial) {
+        return vec![soa];
+    }
+    let Some(diffs) = zone.journal_since(serial) else {
+        log::debug!(
+            "transfer: journal of {} does not reach back to {serial}",
+            zone.origin()
+        );
+        return axfr_records(zone);
+    };
+
+    let mut records = vec![soa.clone()];
+    for diff in diffs {
+        records.push(diff.old_soa.clone());
+        records.extend(diff.removed.iter().cloned());
+        records.push(diff.new_soa.clone());
+        records.extend(diff.added.iter().cloned());
+    }
+    records.push(soa);
+    records
+}
+
+/// Splits `records` over as many responses to `query` as needed; only the first repeats the question.
+pub(crate) fn messages(
+    query: &dns_codec::Message,
+    records: Vec<dns_codec::Record>,
+) -> Vec<dns_codec::Message> {
+    let mut messages = Vec::new();
+    let mut records = records.into_iter().peekable();
+    while records.peek().is_some() {
+        let mut message = query
+            .response()
+            .authoritative(true)
+            .build()
+            .expect("the questions of a query fit the response");
+        if !messages.is_empty() {
+            message.questions.clear();
+        }
+        message.answers = records.by_ref().take(RECORDS_PER_MESSAGE).collect();
+        message
+            .set_counts()
+            .expect("RECORDS_PER_MESSAGE records fit a message");
+        messages.push(message);
+    }
+    messages
+}
+
+/// Pulls every record of the zone at `origin` from `server`, starting with its SOA.
+/// With a `key`, the request is signed and so must be every response.
+///
+/// Fails with [`io::ErrorKind::InvalidData`] if the transfer does not start with the zone's SOA,
+/// ends with a different version than it started with, or carries records from outside of the zone,
+/// and with [`io::ErrorKind::PermissionDenied`] if a response fails verification.
+pub async fn axfr(
+    server: SocketAddr,
+    origin: &dns_codec::Name,
+    key: Option<&tsig::Key>,
+) -> io::Result<Vec<dns_codec::Record>> {
+    let mut transfer = Transfer::start(server, origin, dns_codec::QType::AXFR, None, key).await?;
+    let soa = transfer.next_soa(origin).await?;
+    transfer.rest_of_axfr(origin, vec![soa]).await
+}
+
+/// Brings `current`, the records of a zone including its SOA, up to date with `server`.
+///
+/// Only the changes since the version of `current` are transferred, if `server` still has them.
+/// `current` is returned as is if `server` does not have a newer version.
+/// Signed with `key` and failing like [`axfr`].
+pub async fn ixfr(
+    server: SocketAddr,
+    current: &[dns_codec::Record],
+    key: Option<&tsig::Key>,
+) -> io::Result<Vec<dns_codec::Record>> {
+    let Some(soa) = current.iter().find(|r| r.kind == dns_codec::Type::SOA) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Zone to be updated has no SOA record",
+        ));
+    };
+    let origin = soa.name.clone();
+    let mut version = soa_serial(soa);
+
+    let mut transfer = Transfer::start(
+        server,
+        &origin,
+        dns_codec::QType::IXFR,
+        Some(soa.clone()),
+        key,
+    )
+    .await?;
+    let newest = transfer.next_soa(&origin).await?;
+    let newest_serial = soa_serial(&newest);
+    if !serial_newer(newest_serial, version) {
+        log::debug!("transfer: {origin} is up to date at {version} (server has {newest_serial})");
+        return transfer.check_end(current.to_vec(), &newest, newest_serial);
+    }
+
+    // A full transfer is sent instead if the server lacks the changes since our version
+    let second = transfer.next().await?;
+    if second.kind != dns_codec::Type::SOA || soa_serial(&second) != version {
+        let mut records = vec![newest];
+        if second.kind == dns_codec::Type::SOA {
+            return transfer.check_end(records, &second, newest_serial);
+        }
+        records.push(second);
+        return transfer.rest_of_axfr(&origin, records).await;
+    }
+
+    let mut records = current.to_vec();
+    let mut old_soa = second;
+    loop {
+        if soa_serial(&old_soa) != version {
+            return Err(invalid(format!(
+                "Incremental transfer of {origin} skips from {version} to {}",
+                soa_serial(&old_soa)
+            )));
+        }
+
+        let new_soa = loop {
+            let record = transfer.next().await?;
+            if record.kind == dns_codec::Type::SOA {
+                break record;
+            }
+            records.retain(|r| !same(r, &record));
+        };
+        version = soa_serial(&new_soa);
+        records.retain(|r| r.kind != dns_codec::Type::SOA);
+        records.insert(0, new_soa);
+
+        old_soa = loop {
+            let record = transfer.next().await?;
+            if record.kind == dns_codec::Type::SOA {
+                break record;
+            }
+            if !record.name.is_subdomain_of(&origin) {
+                return Err(invalid(format!(
+                    "{} lies outside of zone {origin}",
+                    record.name
+                )));
+            }
+            if !records.iter().any(|r| same(r, &record)) {
+                records.push(record);
+            }
+        };
+        if version == newest_serial {
+            return transfer.check_end(records, &old_soa, newest_serial);
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// An outgoing transfer request, and the records of the responses to it.
+struct Transfer {
+    id: u16,
+    server: SocketAddr,
+    /// Responses as they were received, since signatures cover their exact bytes.
+    responses: FramedRead<OwnedReadHalf, dns_codec::StreamCodec<BytesCodec>>,
+    pending: VecDeque<dns_codec::Record>,
+    session: Option<tsig::Session>,
+}
+
+impl Transfer {
+    /// Asks `server` for a transfer of `kind`, carrying `soa` in the authority section for IXFR,
+    /// and signed with `key` if given.
+    async fn start(
+        server: SocketAddr,
+        origin: &dns_codec::Name,
+        kind: dns_codec::QType,
+        soa: Option<dns_codec::Record>,
+        key: Option<&tsig::Key>,
+    ) -> io::Result<Self> {
+        let id = dns_codec::Header::random_id();
+        let authorities: Vec<_> = soa.into_iter().collect();
+        let request = dns_codec::Message {
+            header: dns_codec::Header {
+                id,
+                flags: 0,
+                qdcount: 1,
+                ancount: 0,
+                ncount: authorities.len() as u16,
+                arcount: 0,
+            },
+            questions: vec![dns_codec::Question {
+                name: origin.clone(),
+                kind,
+                class: dns_codec::QClass::IN,
+            }],
+            answers: Vec::new(),
+            authorities,
+            additionals: Vec::new(),
+            edns: None,
+        };
+
+        let event = format!("0x{id:04x}");
+        log::info!(target: &event, "transfer: requesting {kind:?} of {origin} from {server}");
+
+        let mut encoded = BytesMut::new();
+        dns_codec::MessageCodec.encode(request, &mut encoded)?;
+        let mut session = key.cloned().map(tsig::Session::new);
+        if let Some(session) = &mut session {
+            session.sign(&mut encoded, SystemTime::now())?;
+        }
+
+        let (read, write) = TcpStream::connect(server).await?.into_split();
+        let mut requests = FramedWrite::new(write, dns_codec::StreamCodec(BytesCodec::new()));
+        requests.send(encoded).await?;
+
+        Ok(Transfer {
+            id,
+            server,
+            responses: FramedRead::new(read, dns_codec::StreamCodec(BytesCodec::new())),
+            pending: VecDeque::new(),
+            session,
+        })
+    }
+
+    /// The next record of the transfer, reading further messages as needed.
+    async fn next(&mut self) -> io::Result<dns_codec::Record> {
+        while self.pending.is_empty() {
+            let Some(response) = self.responses.next().await else {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("{} closed the connection during the transfer", self.server),
+                ));
+            };
+            let response = response?;
+            let Some(message) = dns_codec::MessageCodec.decode(&mut response.clone())? else {
+                return Err(invalid(format!(
+                    "{} sent a message shorter than its contents require",
+                    self.server
+                )));
+            };
+
+            if message.header.id != self.id || !message.header.is_response() {
+                return Err(invalid(format!(
+                    "{} sent a message that is not part of the transfer",
+                    self.server
+                )));
+            }
+            if let Some(session) = &mut self.session {
+                session.verify(&response, SystemTime::now())?;
+            }
+            match message.header.rcode() {
+                Some(dns_codec::Rcode::NOERROR) => (),
+                rcode => {
+                    return Err(io::Error::other(format!(
+                        "{} rejected the transfer with {rcode:?}",
+                        self.server
+                    )))
+                }
+            }
+            self.pending.extend(message.answers);
+        }
+        Ok(self.pending.pop_front().unwrap())
+    }
+
+    /// The SOA record of `origin` every transfer starts with.
+    async fn next_soa(&mut self, origin: &dns_codec::Name) -> io::Result<dns_codec::Record> {
+        let soa = self.next().await?;
+        if soa.kind != dns_codec::Type::SOA || soa.name != *origin {
+            return Err(invalid(format!(
+                "Transfer of {origin} starts with {} {:?} instead of its SOA",
+                soa.name, soa.kind
+            )));
+        }
+        Ok(soa)
+    }
+
+    /// Accepts `records` if the SOA `last` closing the transfer is of the version it started with,
+    /// and, for signed transfers, the message it came in was signed.
+    fn check_end(
+        &self,
+        records: Vec<dns_codec::Record>,
+        last: &dns_codec::Record,
+        serial: u32,
+    ) -> io::Result<Vec<dns_codec::Record>> {
+        if self.session.as_ref().is_some_and(|s| !s.is_complete()) {
+            return Err(tsig::TsigError::BADSIG.into());
+        }
+        match soa_serial(last) {
+            last if last == serial => Ok(records),
+            last => Err(invalid(format!(
+                "Transfer started at version {serial} but ended at {last}"
+            ))),
+        }
+    }
+
+    /// Reads records until the closing SOA of a full transfer, appending them to `records`.
+    async fn rest_of_axfr(
+        &mut self,
+        origin: &dns_codec::Name,
+        mut records: Vec<dns_codec::Record>,
+    ) -> io::Result<Vec<dns_codec::Record>> {
+        let serial = soa_serial(&records[0]);
+        loop {
+            let record = self.next().await?;
+            if record.kind == dns_codec::Type::SOA {
+                return self.check_end(records, &record, serial);
+            }
+            if !record.name.is_subdomain_of(origin) {
+                return Err(invalid(format!(
+                    "{} lies outside of zone {origin}",
+                    record.name
+                )));
+            }
+            records.push(record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, RwLock},
+    };
+
+    use tokio::net::TcpListener;
+
+    fn name(name: &str) -> dns_codec::Name {
+        name.as_bytes().to_vec().try_into().unwrap()
+    }
+
+    fn a(owner: &str, address: [u8; 4]) -> dns_codec::Record {
+        dns_codec::Record {
+            name: name(owner),
+            kind: dns_codec::Type::A,
+            class: dns_codec::Class::IN,
+            ttl: dns_codec::Ttl::from_secs(3600),
+            length: 0,
+            rdata: dns_codec::RData::Ipv4(address.into()),
+        }
+    }
+
+    /// Whether `records` hold the same data as `zone`, in any order.
+    fn matches(records: &[dns_codec::Record], zone: &crate::Zone) -> bool {
+        records.len() == zone.records().count()
+            && zone
+                .records()
+                .all(|z| records.iter().any(|r| crate::zone::same(r, z)))
+    }
+
+    async fn primary(catalog: Arc<RwLock<crate::Catalog>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(crate::server::Server::new(catalog).serve_tcp(listener));
+        address
+    }
+
+    #[tokio::test]
+    async fn full_and_incremental() {
+        let origin = name("example.com");
+        let mut catalog = crate::catalog::test::catalog();
+        let mut zone = catalog.zone(&origin).unwrap().clone();
+        // Enough records to span several messages
+        let hosts: Vec<_> = (0..250u8)
+            .map(|i| a(&format!("host{i}.example.com"), [198, 51, 100, i]))
+            .collect();
+        zone.update(&[], hosts).unwrap();
+        catalog.insert(zone);
+
+        let catalog = Arc::new(RwLock::new(catalog));
+        let server = primary(catalog.clone()).await;
+
+        let transferred = super::axfr(server, &origin, None).await.unwrap();
+        assert_eq!(transferred[0].kind, dns_codec::Type::SOA);
+        assert!(matches(
+            &transferred,
+            catalog.read().unwrap().zone(&origin).unwrap()
+        ));
+
+        // Up to date already
+        let unchanged = super::ixfr(server, &transferred, None).await.unwrap();
+        assert_eq!(unchanged, transferred);
+
+        // Two changes on the primary are fetched incrementally
+        {
+            let mut catalog = catalog.write().unwrap();
+            let mut zone = catalog.zone(&origin).unwrap().clone();
+            zone.update(
+                &[a("www.example.com", [192, 0, 2, 80])],
+                vec![a("www.example.com", [192, 0, 2, 81])],
+            )
+            .unwrap();
+            zone.update(&[a("host0.example.com", [198, 51, 100, 0])], vec![])
+                .unwrap();
+            catalog.insert(zone);
+        }
+        let updated = super::ixfr(server, &transferred, None).await.unwrap();
+        let catalog = catalog.read().unwrap().clone();
+        let zone = catalog.zone(&origin).unwrap();
+        assert!(matches(&updated, zone));
+        assert_eq!(crate::zone::soa_serial(&updated[0]), zone.serial());
+
+        // Versions older than the journal get a full transfer instead
+        let mut ancient = transferred.clone();
+        if let dns_codec::RData::Soa(soa) = &mut ancient[0].rdata {
+            soa.serial = 0;
+        }
+        let catalog = Arc::new(RwLock::new(catalog));
+        let server = primary(catalog.clone()).await;
+        let updated = super::ixfr(server, &ancient, None).await.unwrap();
+        assert!(matches(
+            &updated,
+            catalog.read().unwrap().zone(&origin).unwrap()
+        ));
+
+        // Zones not served are refused
+        let error = super::axfr(server, &name("example.org"), None).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::Other);
+    }
+
+    #[tokio::test]
+    async fn signed() {
+        let origin = name("example.com");
+        let key = |secret: &[u8]| {
+            dns_codec::tsig::Key::new(
+                name("transfer.example.com"),
+                dns_codec::tsig::Algorithm::HmacSha512,
+                secret.to_vec(),
+            )
+        };
+        let mut catalog = crate::catalog::test::catalog();
+        let mut zone = catalog.zone(&origin).unwrap().clone();
+        let hosts: Vec<_> = (0..250u8)
+            .map(|i| a(&format!("host{i}.example.com"), [198, 51, 100, i]))
+            .collect();
+        zone.update(&[], hosts).unwrap();
+        catalog.insert(zone);
+
+        let mut keyring = dns_codec::tsig::Keyring::new();
+        keyring.insert(key(b"secret"));
+        let catalog = Arc::new(RwLock::new(catalog));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        let primary = crate::server::Server::new(catalog.clone()).with_keyring(keyring);
+        tokio::spawn(primary.serve_tcp(listener));
+
+        // Every message of the stream is signed
+        let transferred = super::axfr(server, &origin, Some(&key(b"secret")))
+            .await
+            .unwrap();
+        assert!(matches(
+            &transferred,
+            catalog.read().unwrap().zone(&origin).unwrap()
+        ));
+        let unchanged = super::ixfr(server, &transferred, Some(&key(b"secret")))
+            .await
+            .unwrap();
+        assert_eq!(unchanged, transferred);
+
+        // Unsigned transfers are refused, wrongly signed ones rejected
+        let error = super::axfr(server, &origin, None).await.unwrap_err();
+        assert!(error.to_string().contains("REFUSED"), "{error}");
+        let error = super::axfr(server, &origin, Some(&key(b"guess")))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn serial_arithmetic() {
+        assert!(super::serial_newer(2, 1));
+        assert!(super::serial_newer(0, u32::MAX));
+        assert!(!super::serial_newer(1, 1));
+        assert!(!super::serial_newer(1, 2));
+    }
+}
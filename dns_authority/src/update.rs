@@ -0,0 +1,511 @@
+//! Dynamic updates (RFC 2136): checking their prerequisites against a zone and applying them,
+//! and sending them to a primary.
+
+use std::{io, net::SocketAddr, time::SystemTime};
+
+use dns_codec::{sig0, tsig, Change, Prerequisite, Rcode, Record, Type, Update};
+use futures::{SinkExt as _, StreamExt as _};
+use tokio::net::TcpStream;
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{BytesCodec, Decoder as _, Encoder as _, Framed},
+};
+
+use crate::{zone::same, Zone};
+
+/// Applies `update` to `zone` if all of its prerequisites hold, returning the outcome to respond with.
+///
+/// Changes are applied in order and all at once, bumping the serial a single time.
+/// As RFC 2136, section 3.4.2 asks, changes to the SOA record are ignored,
+/// as are deletions of the NS records at the origin, and additions that would put a CNAME next to other data.
+pub(crate) fn apply(zone: &mut Zone, update: &Update) -> Rcode {
+    let event = format!("0x{:04x}", update.header.id);
+    let origin = zone.origin().clone();
+
+    let names = update
+        .prerequisites
+        .iter()
+        .map(prerequisite_name)
+        .chain(update.updates.iter().map(change_name));
+    if let Some(name) = names
+        .into_iter()
+        .find(|name| !name.is_subdomain_of(&origin))
+    {
+        log::info!(target: &event, "update: {name} lies outside of zone {origin}");
+        return Rcode::NOTZONE;
+    }
+
+    let mut records: Vec<Record> = zone.records().cloned().collect();
+    if let Err(rcode) = check(&records, &update.prerequisites) {
+        log::info!(target: &event, "update: prerequisites on {origin} do not hold ({rcode:?})");
+        return rcode;
+    }
+
+    let current = records.clone();
+    for change in &update.updates {
+        change_records(&mut records, &origin, change);
+    }
+
+    let removed: Vec<_> = current
+        .iter()
+        .filter(|record| !records.iter().any(|r| same(r, record)))
+        .cloned()
+        .collect();
+    let added: Vec<_> = records
+        .into_iter()
+        .filter(|record| !current.iter().any(|r| same(r, record)))
+        .collect();
+    if removed.is_empty() && added.is_empty() {
+        log::debug!(target: &event, "update: nothing changes in {origin}");
+        return Rcode::NOERROR;
+    }
+
+    match zone.update(&removed, added) {
+        Ok(diff) => {
+            log::info!(
+                target: &event,
+                "update: {origin} is at {} after removing {} and adding {} records",
+                crate::zone::soa_serial(&diff.new_soa),
+                diff.removed.len(),
+                diff.added.len()
+            );
+            Rcode::NOERROR
+        }
+        Err(e) => {
+            log::warn!(target: &event, "update: failed to change {origin}: {e}");
+            Rcode::SERVFAIL
+        }
+    }
+}
+
+fn prerequisite_name(prerequisite: &Prerequisite) -> &dns_codec::Name {
+    match prerequisite {
+        Prerequisite::NameInUse(name)
+        | Prerequisite::NameNotInUse(name)
+        | Prerequisite::RrsetExists(name, _)
+        | Prerequisite::RrsetDoesNotExist(name, _) => name,
+        Prerequisite::Rrset(record) => &record.name,
+    }
+}
+
+fn change_name(change: &Change) -> &dns_codec::Name {
+    match change {
+        Change::Add(record) | Change::Delete(record) => &record.name,
+        Change::DeleteRrset(name, _) | Change::DeleteName(name) => name,
+    }
+}
+
+/// Checks `prerequisites` against the `records` of a zone (RFC 2136, section 3.2.5).
+fn check(records: &[Record], prerequisites: &[Prerequisite]) -> Result<(), Rcode> {
+    fn rrset<'a>(
+        records: &'a [Record],
+        name: &'a dns_codec::Name,
+        kind: Type,
+    ) -> impl Iterator<Item = &'a Record> {
+        records
+            .iter()
+            .filter(move |r| r.name == *name && r.kind == kind)
+    }
+    let owns = |name: &dns_codec::Name| records.iter().any(|r| r.name == *name);
+
+    for prerequisite in prerequisites {
+        match prerequisite {
+            Prerequisite::NameInUse(name) if !owns(name) => return Err(Rcode::NXDOMAIN),
+            Prerequisite::NameNotInUse(name) if owns(name) => return Err(Rcode::YXDOMAIN),
+            Prerequisite::RrsetExists(name, kind)
+                if rrset(records, name, *kind).next().is_none() =>
+            {
+                return Err(Rcode::NXRRSET)
+            }
+            Prerequisite::RrsetDoesNotExist(name, kind)
+                if rrset(records, name, *kind).next().is_some() =>
+            {
+                return Err(Rcode::YXRRSET)
+            }
+            Prerequisite::Rrset(record) => {
+                // The whole RRset must match the records given for it, no more and no less
+                let expected: Vec<_> = prerequisites
+                    .iter()
+                    .filter_map(|p| match p {
+                        Prerequisite::Rrset(r)
+                            if r.name == record.name && r.kind == record.kind =>
+                        {
+                            Some(r)
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                let actual: Vec<_> = rrset(records, &record.name, record.kind).collect();
+                let equal = actual.iter().all(|a| expected.iter().any(|e| same(a, e)))
+                    && expected.iter().all(|e| actual.iter().any(|a| same(a, e)));
+                if !equal {
+                    return Err(Rcode::NXRRSET);
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Applies `change` to `records`, the working copy of the zone at `origin`.
+fn change_records(records: &mut Vec<Record>, origin: &dns_codec::Name, change: &Change) {
+    // The SOA is maintained by the zone, and the origin keeps its nameservers
+    let protected = |record: &Record| {
+        record.kind == Type::SOA || (record.kind == Type::NS && record.name == *origin)
+    };
+
+    match change {
+        Change::Add(record) => {
+            let at_name = || records.iter().filter(|r| r.name == record.name);
+            let has_cname = at_name().any(|r| r.kind == Type::CNAME);
+            let has_other = at_name().any(|r| r.kind != Type::CNAME);
+            let conflicts = match record.kind {
+                Type::CNAME => has_other,
+                _ => has_cname,
+            };
+            if record.kind == Type::SOA || conflicts {
+                return;
+            }
+            if record.kind == Type::CNAME {
+                // A name holds at most one CNAME, which is replaced
+                records.retain(|r| !(r.name == record.name && r.kind == Type::CNAME));
+            }
+            if !records.iter().any(|r| same(r, record)) {
+                records.push(record.clone());
+            }
+        }
+        Change::Delete(record) => {
+            let last_nameserver = record.kind == Type::NS
+                && record.name == *origin
+                && records
+                    .iter()
+                    .filter(|r| r.kind == Type::NS && r.name == *origin)
+                    .count()
+                    <= 1;
+            if record.kind != Type::SOA && !last_nameserver {
+                records.retain(|r| !same(r, record));
+            }
+        }
+        Change::DeleteRrset(name, kind) => {
+            records.retain(|r| !(r.name == *name && r.kind == *kind) || protected(r));
+        }
+        Change::DeleteName(name) => {
+            records.retain(|r| r.name != *name || protected(r));
+        }
+    }
+}
+
+/// How to sign an outgoing update.
+#[derive(Debug, Clone, Copy)]
+pub enum Signer<'k> {
+    /// With a secret shared with the server, verifying its response as well.
+    Tsig(&'k tsig::Key),
+    /// With a private key whose public counterpart the server knows.
+    Sig0(&'k sig0::SigningKey),
+}
+
+/// Sends `update` to the primary at `server` over TCP, signed by `signer` if given, and waits for the outcome.
+///
+/// Fails with [`io::ErrorKind::Other`] if the server does not apply the update,
+/// e.g. because one of its prerequisites does not hold,
+/// and with [`io::ErrorKind::PermissionDenied`] if a TSIG-signed update gets a response failing verification.
+pub async fn send(server: SocketAddr, update: Update, signer: Option<Signer<'_>>) -> io::Result<()> {
+    let id = update.header.id;
+    let zone = update.zone.name.clone();
+    let event = format!("0x{id:04x}");
+    log::info!(target: &event, "update: sending changes of {zone} to {server}");
+
+    let mut request = BytesMut::new();
+    dns_codec::MessageCodec.encode(update.into(), &mut request)?;
+    let mut session = None;
+    match signer {
+        Some(Signer::Tsig(key)) => {
+            let session = session.insert(tsig::Session::new(key.clone()));
+            session.sign(&mut request, SystemTime::now())?;
+        }
+        Some(Signer::Sig0(key)) => key.sign(&mut request, SystemTime::now())?,
+        None => (),
+    }
+
+    let stream = TcpStream::connect(server).await?;
+    let mut framed = Framed::new(stream, dns_codec::StreamCodec(BytesCodec::new()));
+    framed.send(request).await?;
+
+    while let Some(frame) = framed.next().await {
+        let frame = frame?;
+        let response = match dns_codec::MessageCodec.decode(&mut frame.clone()) {
+            Ok(Some(response)) => response,
+            _ => continue,
+        };
+        if response.header.id != id || !response.header.is_response() {
+            log::debug!(target: &event, "update: ignoring unrelated message from {server}");
+            continue;
+        }
+        if let Some(session) = &mut session {
+            session.verify(&frame, SystemTime::now())?;
+        }
+        return match response.header.rcode() {
+            Some(Rcode::NOERROR) => Ok(()),
+            rcode => Err(io::Error::other(format!(
+                "{server} rejected the update of {zone} with {rcode:?}"
+            ))),
+        };
+    }
+    Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("{server} closed the connection before responding"),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, RwLock},
+    };
+
+    use dns_codec::{Prerequisite, Rcode, Type, Update};
+    use tokio::net::TcpListener;
+
+    fn name(name: &str) -> dns_codec::Name {
+        name.as_bytes().to_vec().try_into().unwrap()
+    }
+
+    fn a(owner: &str, address: [u8; 4]) -> dns_codec::Record {
+        dns_codec::Record {
+            name: name(owner),
+            kind: Type::A,
+            class: dns_codec::Class::IN,
+            ttl: dns_codec::Ttl::from_secs(3600),
+            length: 0,
+            rdata: dns_codec::RData::Ipv4(address.into()),
+        }
+    }
+
+    fn zone() -> crate::Zone {
+        crate::catalog::test::catalog()
+            .zone(&name("example.com"))
+            .unwrap()
+            .clone()
+    }
+
+    fn addresses(zone: &crate::Zone, owner: &str) -> Vec<dns_codec::RData> {
+        zone.rrset(&name(owner), Type::A)
+            .map(|record| record.rdata.clone())
+            .collect()
+    }
+
+    #[test]
+    fn prerequisites() {
+        let mut zone = zone();
+        let serial = zone.serial();
+        let update = |prerequisite| {
+            Update::builder(1, name("example.com"))
+                .require(prerequisite)
+                .add_record(a("dhcp.example.com", [192, 0, 2, 100]))
+                .build()
+        };
+
+        let failing = [
+            (
+                Prerequisite::NameInUse(name("dhcp.example.com")),
+                Rcode::NXDOMAIN,
+            ),
+            (
+                Prerequisite::NameNotInUse(name("www.example.com")),
+                Rcode::YXDOMAIN,
+            ),
+            (
+                Prerequisite::RrsetExists(name("www.example.com"), Type::MX),
+                Rcode::NXRRSET,
+            ),
+            (
+                Prerequisite::RrsetDoesNotExist(name("www.example.com"), Type::A),
+                Rcode::YXRRSET,
+            ),
+            (
+                Prerequisite::Rrset(a("www.example.com", [192, 0, 2, 81])),
+                Rcode::NXRRSET,
+            ),
+            (
+                Prerequisite::NameInUse(name("www.example.org")),
+                Rcode::NOTZONE,
+            ),
+        ];
+        for (prerequisite, rcode) in failing {
+            assert_eq!(super::apply(&mut zone, &update(prerequisite)), rcode);
+        }
+        assert_eq!(zone.serial(), serial);
+        assert!(addresses(&zone, "dhcp.example.com").is_empty());
+
+        let holding = Prerequisite::Rrset(a("www.example.com", [192, 0, 2, 80]));
+        assert_eq!(super::apply(&mut zone, &update(holding)), Rcode::NOERROR);
+        assert_eq!(zone.serial(), serial + 1);
+        assert_eq!(addresses(&zone, "dhcp.example.com").len(), 1);
+    }
+
+    #[test]
+    fn changes() {
+        let mut zone = zone();
+        let update = Update::builder(1, name("example.com"))
+            // Replacing an address, in order
+            .delete_rrset(name("www.example.com"), Type::A)
+            .add_record(a("www.example.com", [192, 0, 2, 81]))
+            .delete_name(name("host.deep.example.com"))
+            // Ignored: the origin keeps its SOA and nameservers, and CNAMEs stand alone
+            .delete_name(name("example.com"))
+            .add_record(a("alias.example.com", [192, 0, 2, 1]))
+            .build();
+        assert_eq!(super::apply(&mut zone, &update), Rcode::NOERROR);
+
+        assert_eq!(
+            addresses(&zone, "www.example.com"),
+            [dns_codec::RData::Ipv4([192, 0, 2, 81].into())]
+        );
+        assert!(addresses(&zone, "host.deep.example.com").is_empty());
+        assert!(addresses(&zone, "alias.example.com").is_empty());
+        assert_eq!(zone.rrset(&name("example.com"), Type::NS).count(), 1);
+
+        // Changes that change nothing leave the serial alone
+        let serial = zone.serial();
+        let update = Update::builder(2, name("example.com"))
+            .add_record(a("www.example.com", [192, 0, 2, 81]))
+            .delete_record(a("missing.example.com", [192, 0, 2, 1]))
+            .build();
+        assert_eq!(super::apply(&mut zone, &update), Rcode::NOERROR);
+        assert_eq!(zone.serial(), serial);
+    }
+
+    async fn primary(server: crate::server::Server) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(server.serve_tcp(listener));
+        address
+    }
+
+    #[tokio::test]
+    async fn over_the_network() {
+        let catalog = Arc::new(RwLock::new(crate::catalog::test::catalog()));
+        let update = || {
+            Update::builder(dns_codec::Header::random_id(), name("example.com"))
+                .require(Prerequisite::NameNotInUse(name("laptop.example.com")))
+                .add_record(a("laptop.example.com", [192, 0, 2, 200]))
+                .build()
+        };
+
+        // Updates are refused unless allowed
+        let server = primary(crate::server::Server::new(catalog.clone())).await;
+        let error = super::send(server, update(), None).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::Other);
+
+        let server = crate::server::Server::new(catalog.clone())
+            .allow_updates([std::net::Ipv4Addr::LOCALHOST.into()]);
+        let server = primary(server).await;
+        super::send(server, update(), None).await.unwrap();
+        let zone = catalog
+            .read()
+            .unwrap()
+            .zone(&name("example.com"))
+            .unwrap()
+            .clone();
+        assert_eq!(addresses(&zone, "laptop.example.com").len(), 1);
+
+        // The name is taken now
+        let error = super::send(server, update(), None).await.unwrap_err();
+        assert!(error.to_string().contains("YXDOMAIN"), "{error}");
+
+        // Zones not served here
+        let update = Update::builder(1, name("example.org")).build();
+        let error = super::send(server, update, None).await.unwrap_err();
+        assert!(error.to_string().contains("NOTAUTH"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn signed() {
+        let key = |secret: &[u8]| {
+            dns_codec::tsig::Key::new(
+                name("update.example.com"),
+                dns_codec::tsig::Algorithm::HmacSha256,
+                secret.to_vec(),
+            )
+        };
+        let other = dns_codec::tsig::Key::new(
+            name("other.example.com"),
+            dns_codec::tsig::Algorithm::HmacSha256,
+            b"other".to_vec(),
+        );
+        let mut keyring = dns_codec::tsig::Keyring::new();
+        keyring.insert(key(b"secret"));
+        keyring.insert(other.clone());
+        let catalog = Arc::new(RwLock::new(crate::catalog::test::catalog()));
+        let server = crate::server::Server::new(catalog.clone())
+            .with_keyring(keyring)
+            .allow_key_updates(name("update.example.com"), [name("example.com")])
+            .allow_key_updates(name("other.example.com"), [name("example.org")]);
+        let server = primary(server).await;
+        let update = Update::builder(dns_codec::Header::random_id(), name("example.com"))
+            .add_record(a("laptop.example.com", [192, 0, 2, 200]))
+            .build();
+
+        // Signed updates are accepted from anywhere, unsigned ones only from allowed clients
+        let error = super::send(server, update.clone(), None).await.unwrap_err();
+        assert!(error.to_string().contains("REFUSED"), "{error}");
+        let error = super::send(server, update.clone(), Some(super::Signer::Tsig(&key(b"guess"))))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+
+        // A known key only changes the zones granted to it
+        let error = super::send(server, update.clone(), Some(super::Signer::Tsig(&other)))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("REFUSED"), "{error}");
+
+        super::send(server, update, Some(super::Signer::Tsig(&key(b"secret")))).await.unwrap();
+        let zone = catalog
+            .read()
+            .unwrap()
+            .zone(&name("example.com"))
+            .unwrap()
+            .clone();
+        assert_eq!(addresses(&zone, "laptop.example.com").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn signed_with_public_key() {
+        let key = |name: &str| {
+            let algorithm = dns_codec::sig0::Algorithm::Ed25519;
+            dns_codec::sig0::SigningKey::generate(self::name(name), algorithm).unwrap()
+        };
+        let partner = key("partner.example.com");
+        let stranger = key("partner.example.com");
+
+        let catalog = Arc::new(RwLock::new(crate::catalog::test::catalog()));
+        let server = crate::server::Server::new(catalog.clone())
+            .with_sig0_keys([partner.public_key().clone()])
+            .allow_key_updates(name("partner.example.com"), [name("example.com")]);
+        let server = primary(server).await;
+        let update = Update::builder(dns_codec::Header::random_id(), name("example.com"))
+            .add_record(a("partner.example.com", [192, 0, 2, 201]))
+            .build();
+
+        let error = super::send(server, update.clone(), None).await.unwrap_err();
+        assert!(error.to_string().contains("REFUSED"), "{error}");
+        let signer = super::Signer::Sig0(&stranger);
+        let error = super::send(server, update.clone(), Some(signer))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("NOTAUTH"), "{error}");
+
+        let signer = super::Signer::Sig0(&partner);
+        super::send(server, update, Some(signer)).await.unwrap();
+        let zone = catalog
+            .read()
+            .unwrap()
+            .zone(&name("example.com"))
+            .unwrap()
+            .clone();
+        assert_eq!(addresses(&zone, "partner.example.com").len(), 1);
+    }
+}
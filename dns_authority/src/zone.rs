@@ -0,0 +1,263 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+};
+
+/// Number of changes kept in a zone's journal; older ones are served by falling back to a full transfer.
+const JOURNAL_LENGTH: usize = 64;
+
+/// The records of a zone, i.e. everything at and below its origin that is served by this server.
+///
+/// Records below a delegation point are kept only as glue for the delegation's nameservers.
+#[derive(Debug, Clone)]
+pub struct Zone {
+    origin: dns_codec::Name,
+    records: HashMap<dns_codec::Name, Vec<dns_codec::Record>>,
+    /// Every name that exists in the zone: owners of records and the empty non-terminals above them.
+    nodes: HashSet<dns_codec::Name>,
+    /// Most recent changes, oldest first.
+    journal: Vec<Diff>,
+}
+
+/// A change from one version of a zone to the next, as kept in its journal for IXFR (RFC 1995).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diff {
+    pub old_soa: dns_codec::Record,
+    pub removed: Vec<dns_codec::Record>,
+    pub new_soa: dns_codec::Record,
+    pub added: Vec<dns_codec::Record>,
+}
+
+/// What a zone holds at a name, see [`Zone::node`].
+#[derive(Debug)]
+pub(crate) enum Node<'a> {
+    /// The name exists; an empty non-terminal has no records.
+    Exists(&'a [dns_codec::Record]),
+    /// The name does not exist, but is matched by the wildcard whose records are given (RFC 4592).
+    Wildcard(&'a [dns_codec::Record]),
+    Missing,
+}
+
+impl Zone {
+    /// A zone rooted at `origin`, holding `records`.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] unless there is exactly one SOA record,
+    /// at the origin, and every record lies within the zone.
+    pub fn new(origin: dns_codec::Name, records: Vec<dns_codec::Record>) -> io::Result<Self> {
+        let invalid = |message: String| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+
+        let mut zone = Zone {
+            origin,
+            records: HashMap::new(),
+            nodes: HashSet::new(),
+            journal: Vec::new(),
+        };
+        for record in records {
+            if !record.name.is_subdomain_of(&zone.origin) {
+                return invalid(format!(
+                    "{} lies outside of zone {}",
+                    record.name, zone.origin
+                ));
+            }
+            zone.insert(record);
+        }
+
+        let soas = zone.records().filter(|r| r.kind == dns_codec::Type::SOA);
+        match soas.map(|soa| &soa.name).collect::<Vec<_>>().as_slice() {
+            [owner] if **owner == zone.origin => Ok(zone),
+            [] => invalid(format!("Zone {} has no SOA record", zone.origin)),
+            _ => invalid(format!(
+                "Zone {} needs exactly one SOA record, at its origin",
+                zone.origin
+            )),
+        }
+    }
+
+    pub fn origin(&self) -> &dns_codec::Name {
+        &self.origin
+    }
+
+    /// The SOA record at the origin.
+    pub fn soa(&self) -> &dns_codec::Record {
+        self.rrset(&self.origin, dns_codec::Type::SOA)
+            .next()
+            .expect("zones always have a SOA record")
+    }
+
+    /// Version of the zone, from its SOA record.
+    pub fn serial(&self) -> u32 {
+        soa_serial(self.soa())
+    }
+
+    /// Every record of the zone, in no particular order.
+    pub fn records(&self) -> impl Iterator<Item = &dns_codec::Record> {
+        self.records.values().flatten()
+    }
+
+    /// Changes that lead from version `serial` to the current one, if the journal reaches back that far.
+    pub fn journal_since(&self, serial: u32) -> Option<&[Diff]> {
+        let start = self
+            .journal
+            .iter()
+            .position(|diff| soa_serial(&diff.old_soa) == serial)?;
+        Some(&self.journal[start..])
+    }
+
+    /// Removes `removed` and adds `added`, bumping the serial and keeping the change in the journal.
+    /// Records are compared ignoring their TTL; removing absent or adding present records has no effect.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if any of the records is a SOA or lies outside of the zone.
+    pub fn update(
+        &mut self,
+        removed: &[dns_codec::Record],
+        added: Vec<dns_codec::Record>,
+    ) -> io::Result<&Diff> {
+        for record in removed.iter().chain(&added) {
+            if record.kind == dns_codec::Type::SOA || !record.name.is_subdomain_of(&self.origin) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} {:?} cannot be changed in zone {}",
+                        record.name, record.kind, self.origin
+                    ),
+                ));
+            }
+        }
+
+        let old_soa = self.soa().clone();
+        let mut new_soa = old_soa.clone();
+        if let dns_codec::RData::Soa(soa) = &mut new_soa.rdata {
+            soa.serial = soa.serial.wrapping_add(1);
+        }
+
+        let mut diff = Diff {
+            old_soa: old_soa.clone(),
+            removed: Vec::new(),
+            new_soa: new_soa.clone(),
+            added: Vec::new(),
+        };
+        for record in removed {
+            let Some(rrset) = self.records.get_mut(&record.name) else {
+                continue;
+            };
+            if let Some(index) = rrset.iter().position(|r| same(r, record)) {
+                diff.removed.push(rrset.remove(index));
+            }
+        }
+        self.records.retain(|_, rrset| !rrset.is_empty());
+        for record in added {
+            if self.insert(record.clone()) {
+                diff.added.push(record);
+            }
+        }
+        self.replace_soa(new_soa);
+        self.rebuild_nodes();
+
+        if self.journal.len() == JOURNAL_LENGTH {
+            self.journal.remove(0);
+        }
+        self.journal.push(diff);
+        Ok(self.journal.last().unwrap())
+    }
+
+    fn replace_soa(&mut self, soa: dns_codec::Record) {
+        let rrset = self.records.get_mut(&self.origin).unwrap();
+        rrset.retain(|record| record.kind != dns_codec::Type::SOA);
+        rrset.push(soa);
+    }
+
+    fn rebuild_nodes(&mut self) {
+        self.nodes.clear();
+        let owners: Vec<_> = self.records.keys().cloned().collect();
+        for owner in owners {
+            self.insert_node(owner);
+        }
+    }
+
+    /// Adds `record` unless an identical one is present, returning whether it was added.
+    fn insert(&mut self, record: dns_codec::Record) -> bool {
+        self.insert_node(record.name.clone());
+
+        let rrset = self.records.entry(record.name.clone()).or_default();
+        if rrset.iter().any(|r| same(r, &record)) {
+            return false;
+        }
+        rrset.push(record);
+        true
+    }
+
+    fn insert_node(&mut self, name: dns_codec::Name) {
+        let mut node = Some(name);
+        while let Some(name) = node {
+            if !name.is_subdomain_of(&self.origin) || !self.nodes.insert(name.clone()) {
+                break;
+            }
+            node = name.parent();
+        }
+    }
+
+    /// Records of type `kind` owned by `name`.
+    pub(crate) fn rrset<'a>(
+        &'a self,
+        name: &dns_codec::Name,
+        kind: dns_codec::Type,
+    ) -> impl Iterator<Item = &'a dns_codec::Record> {
+        self.records
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(move |record| record.kind == kind)
+    }
+
+    /// The topmost delegation point between the origin (exclusive) and `name` (inclusive), if any.
+    pub(crate) fn delegation(&self, name: &dns_codec::Name) -> Option<dns_codec::Name> {
+        let mut ancestors = Vec::new();
+        let mut node = Some(name.clone());
+        while let Some(ancestor) = node.filter(|ancestor| *ancestor != self.origin) {
+            node = ancestor.parent();
+            ancestors.push(ancestor);
+        }
+
+        ancestors
+            .into_iter()
+            .rev()
+            .find(|ancestor| self.rrset(ancestor, dns_codec::Type::NS).next().is_some())
+    }
+
+    /// The records at `name`, or at the wildcard matching it if `name` does not exist.
+    pub(crate) fn node(&self, name: &dns_codec::Name) -> Node<'_> {
+        if self.nodes.contains(name) {
+            let records = self
+                .records
+                .get(name)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            return Node::Exists(records);
+        }
+
+        // A wildcard only matches below the closest existing ancestor
+        let mut encloser = name.parent();
+        while let Some(candidate) = encloser {
+            if self.nodes.contains(&candidate) {
+                return match self.records.get(&candidate.child(b"*")) {
+                    Some(records) => Node::Wildcard(records),
+                    None => Node::Missing,
+                };
+            }
+            encloser = candidate.parent();
+        }
+        Node::Missing
+    }
+}
+
+/// Whether two records hold the same data, regardless of their TTL.
+pub(crate) fn same(a: &dns_codec::Record, b: &dns_codec::Record) -> bool {
+    a.name == b.name && a.kind == b.kind && a.class == b.class && a.rdata == b.rdata
+}
+
+pub(crate) fn soa_serial(record: &dns_codec::Record) -> u32 {
+    match &record.rdata {
+        dns_codec::RData::Soa(soa) => soa.serial,
+        _ => 0,
+    }
+}
@@ -8,26 +8,16 @@ use tokio_util::udp::UdpFramed;
 async fn main() {
     let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 53)).await.unwrap();
 
-    let mut sink = UdpFramed::new(&socket, dns_codec::QueryCodec);
-    let mut stream = UdpFramed::new(&socket, dns_codec::ResponseCodec);
-
-    let query = dns_codec::Query {
-        header: dns_codec::Header {
-            id: 0x8298,
-            flags: 0,
-            qdcount: 1,
-            ancount: 0,
-            ncount: 0,
-            arcount: 0,
-        },
-        question: dns_codec::Question {
-            name: b"google.com".to_vec().try_into().unwrap(),
-            kind: dns_codec::QType::A,
-            class: dns_codec::QClass::IN,
-        },
-    };
-
-    let _ = sink
+    let mut sink = UdpFramed::new(&socket, dns_codec::MessageCodec);
+    let mut stream = UdpFramed::new(&socket, dns_codec::MessageCodec);
+
+    let query = dns_codec::Message::query("google.com", dns_codec::QType::A)
+        .id(0x8298)
+        .recursion_desired(true)
+        .build()
+        .unwrap();
+
+    sink
         .send((query, "8.8.8.8:53".parse().unwrap()))
         .await
         .unwrap();
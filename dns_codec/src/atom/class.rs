@@ -1,6 +1,7 @@
 use std::io;
 
-use byteorder::{NetworkEndian, ReadBytesExt};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt as _};
+use bytes::BufMut as _;
 use num_enum::TryFromPrimitive;
 
 use super::rtri;
@@ -20,10 +21,16 @@ pub enum Class {
 
     /// Hesiod
     HS = 4,
+
+    /// No class; marks records that must not exist or are to be deleted in UPDATE messages (RFC 2136)
+    NONE = 254,
+
+    /// Any class; marks whole RRsets or names in UPDATE messages (RFC 2136)
+    STAR = 255,
 }
 
 impl Class {
-    pub(crate) fn decode<'a>(src: &mut io::Cursor<&'a [u8]>) -> Result<Option<Self>, io::Error> {
+    pub(crate) fn decode(src: &mut io::Cursor<&[u8]>) -> Result<Option<Self>, io::Error> {
         let decoded = rtri!(src.read_u16::<NetworkEndian>());
         let class = decoded
             .try_into()
@@ -31,4 +38,10 @@ impl Class {
 
         Ok(Some(class))
     }
+
+    pub(crate) fn encode(self, dst: &mut tokio_util::bytes::BytesMut) -> Result<(), io::Error> {
+        let mut writer = dst.writer();
+        writer.write_u16::<NetworkEndian>(self as u16)?;
+        Ok(())
+    }
 }
@@ -13,7 +13,7 @@ pub struct Header {
     /// to match up replies to outstanding queries.
     pub id: u16,
 
-    ///
+    /// Packed QR, OPCODE, AA, TC, RD, RA, Z and RCODE fields.
     pub flags: u16,
 
     /// Number of entries in the question section.
@@ -44,7 +44,96 @@ impl std::fmt::Debug for Header {
 }
 
 impl Header {
-    pub(crate) fn decode<'a>(src: &mut io::Cursor<&'a [u8]>) -> Result<Option<Self>, io::Error> {
+    const QR_MASK: u16 = 0x8000;
+    const OPCODE_MASK: u16 = 0x7800;
+    const AA_MASK: u16 = 0x0400;
+    const TC_MASK: u16 = 0x0200;
+    const RD_MASK: u16 = 0x0100;
+    const RA_MASK: u16 = 0x0080;
+    const RCODE_MASK: u16 = 0x000f;
+
+    /// Whether the QR bit is set, i.e. this message is a response rather than a query.
+    pub fn is_response(&self) -> bool {
+        self.flags & Self::QR_MASK != 0
+    }
+
+    pub fn set_response(&mut self, response: bool) {
+        self.set(Self::QR_MASK, response);
+    }
+
+    /// Kind of query in this message, if it is one of the known values.
+    pub fn opcode(&self) -> Option<super::Opcode> {
+        let opcode = ((self.flags & Self::OPCODE_MASK) >> 11) as u8;
+        opcode.try_into().ok()
+    }
+
+    pub fn set_opcode(&mut self, opcode: super::Opcode) {
+        self.flags = (self.flags & !Self::OPCODE_MASK) | (u16::from(opcode as u8) << 11);
+    }
+
+    /// Whether the AA bit is set, i.e. the responding nameserver is an authority for the name in question.
+    pub fn is_authoritative(&self) -> bool {
+        self.flags & Self::AA_MASK != 0
+    }
+
+    pub fn set_authoritative(&mut self, authoritative: bool) {
+        self.set(Self::AA_MASK, authoritative);
+    }
+
+    /// Whether the TC bit is set, i.e. this message was truncated to fit the transport.
+    pub fn is_truncated(&self) -> bool {
+        self.flags & Self::TC_MASK != 0
+    }
+
+    pub fn set_truncated(&mut self, truncated: bool) {
+        self.set(Self::TC_MASK, truncated);
+    }
+
+    /// Whether the RD bit is set, i.e. the nameserver is asked to pursue the query recursively.
+    pub fn recursion_desired(&self) -> bool {
+        self.flags & Self::RD_MASK != 0
+    }
+
+    pub fn set_recursion_desired(&mut self, desired: bool) {
+        self.set(Self::RD_MASK, desired);
+    }
+
+    /// Whether the RA bit is set, i.e. the responding nameserver supports recursive queries.
+    pub fn recursion_available(&self) -> bool {
+        self.flags & Self::RA_MASK != 0
+    }
+
+    pub fn set_recursion_available(&mut self, available: bool) {
+        self.set(Self::RA_MASK, available);
+    }
+
+    /// Response code of this message, if it is one of the known values.
+    pub fn rcode(&self) -> Option<super::Rcode> {
+        let rcode = (self.flags & Self::RCODE_MASK) as u8;
+        rcode.try_into().ok()
+    }
+
+    pub fn set_rcode(&mut self, rcode: super::Rcode) {
+        self.flags = (self.flags & !Self::RCODE_MASK) | u16::from(rcode as u8);
+    }
+
+    fn set(&mut self, mask: u16, value: bool) {
+        if value {
+            self.flags |= mask;
+        } else {
+            self.flags &= !mask;
+        }
+    }
+
+    /// Draws a query identifier from a cryptographically secure random number generator,
+    /// making responses harder to forge for off-path attackers.
+    #[cfg(feature = "rand")]
+    pub fn random_id() -> u16 {
+        use rand::Rng as _;
+        rand::thread_rng().gen()
+    }
+
+    pub(crate) fn decode(src: &mut io::Cursor<&[u8]>) -> Result<Option<Self>, io::Error> {
         let mut reader = src.reader();
 
         let id = rtri!(reader.read_u16::<NetworkEndian>());
@@ -4,8 +4,10 @@
 mod class;
 mod header;
 mod name;
+mod opcode;
 mod qclass;
 mod qtype;
+mod rcode;
 mod rdata;
 mod ttl;
 mod r#type;
@@ -14,10 +16,12 @@ mod r#type;
 pub use class::Class;
 pub use header::Header;
 pub use name::Name;
+pub use opcode::Opcode;
 pub use qclass::QClass;
 pub use qtype::QType;
+pub use rcode::Rcode;
 pub use r#type::Type;
-pub use rdata::RData;
+pub use rdata::{Key, Mx, RData, Sig, Soa, Srv, Tkey, Tsig};
 pub use ttl::Ttl;
 
 /// Module-local macro for converting Err(std::io::ErrorKind::UnexpectedEof) in Result<T, E> into Ok(None) for [`tokio_util::codec::Decoder`]
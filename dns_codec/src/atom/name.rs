@@ -1,35 +1,59 @@
 use std::io::{self, Read, Write};
 
-use byteorder::{NetworkEndian, ReadBytesExt as _, WriteBytesExt};
+use byteorder::{ReadBytesExt as _, WriteBytesExt};
 use bytes::BufMut;
 
-use super::{rotri, rtri};
+use super::rtri;
 
 use tokio_util::bytes::BytesMut;
 
+/// Longest name on the wire, including the length octets (RFC 1035, section 2.3.4).
+const MAX_LENGTH: usize = 255;
+
 // Names are represented as a sequence of labels, where each label consists of a
 /// length octet followed by that number of octets.
 /// The domain name terminates with the zero length octet for the null label of the root.
 /// Note that this field may be an odd number of octets; no padding is used.
 ///
 /// Domain names are subsets of ASCII, consisting of characters between a-z, A-Z, 0-9 and hypens.
-#[derive(Clone, PartialEq, Eq)]
+///
+/// As in DNS itself, names compare and hash case-insensitively;
+/// [`Name::eq_exact`] is available where the exact bytes matter.
+#[derive(Clone)]
 pub struct Name(pub(crate) Vec<u8>);
 
 impl Name {
-    pub(crate) fn decode<'a>(
-        src: &mut io::Cursor<&'a [u8]>,
+    /// Decodes the name at the position of `src`, following compression pointers.
+    ///
+    /// Every pointer has to point before the labels it follows, so that decoding always ends.
+    pub(crate) fn decode(
+        src: &mut io::Cursor<&[u8]>,
     ) -> Result<Option<Self>, io::Error> {
-        let mut label_length = rtri!(src.read_u8());
-        let mut expanded = Vec::with_capacity(label_length.into());
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let mut cursor = src.clone();
+        // Start of the labels being read, which pointers must precede
+        let mut segment = cursor.position();
+        // Where `src` continues, once a pointer has been followed
+        let mut end = None;
+        let mut length = 1;
+        let mut expanded = Vec::new();
         loop {
+            let label_length = rtri!(cursor.read_u8());
             // End of stream
             if label_length == 0 {
                 break;
             }
             // Uncompressed label
             else if label_length & 0b1100_0000 == 0 {
-                let consumed = rtri!(src
+                length += 1 + usize::from(label_length);
+                if length > MAX_LENGTH {
+                    return Err(invalid("Name is longer than 255 bytes"));
+                }
+                if !expanded.is_empty() {
+                    expanded.push(b'.');
+                }
+                let consumed = rtri!(cursor
                     .by_ref()
                     .take(label_length.into())
                     .read_to_end(&mut expanded));
@@ -41,34 +65,30 @@ impl Name {
                 }
             }
             // Compressed label
-            else {
-                let next = rtri!(src.read_u8());
+            else if label_length & 0b1100_0000 == 0b1100_0000 {
+                let next = rtri!(cursor.read_u8());
                 let combined = [label_length & 0b0011_1111, next];
-                let pointer = rtri!(combined.as_ref().read_u16::<NetworkEndian>());
-
-                let mut jmp = src.clone();
-                jmp.set_position(pointer.into());
-
-                // TODO: Safeguard against infinite recursion here
-                let Name(decoded) = rotri!(Name::decode(&mut jmp));
-
-                expanded.extend(decoded);
-                break;
-            }
+                let pointer = u64::from(u16::from_be_bytes(combined));
+                if pointer >= segment {
+                    return Err(invalid("Name compression pointer does not point to an earlier name"));
+                }
 
-            label_length = rtri!(src.read_u8());
-            if label_length != 0 {
-                expanded.push(b'.');
+                end.get_or_insert(cursor.position());
+                cursor.set_position(pointer);
+                segment = pointer;
+            } else {
+                return Err(invalid("Name has a label of unknown type"));
             }
         }
 
+        src.set_position(end.unwrap_or(cursor.position()));
         Ok(Some(Name(expanded)))
     }
 
     pub(crate) fn encode(self, dst: &mut BytesMut) -> Result<(), io::Error> {
         let mut writer = dst.writer();
 
-        for label in self.0.split(|v| *v == b'.') {
+        for label in self.labels() {
             writer.write_u8(label.len() as u8)?;
             writer.write_all(label)?;
         }
@@ -79,9 +99,103 @@ impl Name {
     }
 }
 
+impl Name {
+    /// The root of the domain name space.
+    pub fn root() -> Self {
+        Name(Vec::new())
+    }
+
+    /// The labels of this name, from the leftmost (most specific) to the rightmost.
+    /// The root name has no labels.
+    pub fn labels(&self) -> impl DoubleEndedIterator<Item = &[u8]> {
+        self.0.split(|c| *c == b'.').filter(|label| !label.is_empty())
+    }
+
+    /// The name with the leftmost label removed, or `None` for the root.
+    pub fn parent(&self) -> Option<Name> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let parent = match self.0.iter().position(|c| *c == b'.') {
+            Some(dot) => self.0[dot + 1..].to_vec(),
+            None => Vec::new(),
+        };
+        Some(Name(parent))
+    }
+
+    /// The name with `label` prepended, e.g. `*.example.com` for the label `*` and `example.com`.
+    pub fn child(&self, label: &[u8]) -> Name {
+        let mut child = label.to_vec();
+        if !self.0.is_empty() {
+            child.push(b'.');
+            child.extend_from_slice(&self.0);
+        }
+        Name(child)
+    }
+
+    /// Compares two names byte for byte, i.e. including the case of ASCII letters.
+    pub fn eq_exact(&self, other: &Name) -> bool {
+        self.0 == other.0
+    }
+
+    /// A copy of this name with the case of every ASCII letter chosen at random (DNS 0x20).
+    /// Nameservers that preserve case echo these bits back, making responses harder to forge.
+    #[cfg(feature = "rand")]
+    pub fn randomize_case(&self) -> Name {
+        use rand::Rng as _;
+
+        let mut rng = rand::thread_rng();
+        let randomized = self
+            .0
+            .iter()
+            .map(|c| match rng.gen() {
+                true => c.to_ascii_uppercase(),
+                false => c.to_ascii_lowercase(),
+            })
+            .collect();
+        Name(randomized)
+    }
+
+    /// Whether this name is equal to `zone` or lies beneath it, ignoring ASCII case.
+    /// Every name is a subdomain of the root.
+    pub fn is_subdomain_of(&self, zone: &Name) -> bool {
+        let mut labels = self.labels().rev();
+        zone.labels()
+            .rev()
+            .all(|zone_label| labels.next().is_some_and(|label| label.eq_ignore_ascii_case(zone_label)))
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
+
+impl Eq for Name {}
+
+impl std::hash::Hash for Name {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        for c in &self.0 {
+            state.write_u8(c.to_ascii_lowercase());
+        }
+        state.write_u8(0);
+    }
+}
+
 impl std::fmt::Debug for Name {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_tuple("Name").field(&std::str::from_utf8(&self.0).unwrap()).finish()
+        // Labels may hold any octet, so decoded names are not necessarily UTF-8
+        f.debug_tuple("Name").field(&String::from_utf8_lossy(&self.0)).finish()
+    }
+}
+
+impl std::fmt::Display for Name {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str(".");
+        }
+        f.write_str(&String::from_utf8_lossy(&self.0))
     }
 }
 
@@ -101,6 +215,14 @@ impl std::convert::TryFrom<Vec<u8>> for Name {
     }
 }
 
+impl std::convert::TryFrom<&str> for Name {
+    type Error = io::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.as_bytes().to_vec().try_into()
+    }
+}
+
 // https://www.freesoft.org/CIE/RFC/1035/43.htm
 /*
 #[test]
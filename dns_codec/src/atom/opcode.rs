@@ -0,0 +1,21 @@
+use num_enum::TryFromPrimitive;
+
+/// Kind of query, carried in bits 11 to 14 of the header flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Opcode {
+    /// A standard query
+    QUERY = 0,
+
+    /// An inverse query, obsoleted by RFC 3425
+    IQUERY = 1,
+
+    /// A server status request
+    STATUS = 2,
+
+    /// A change of zone contents, announced by the primary (RFC 1996)
+    NOTIFY = 4,
+
+    /// A dynamic update (RFC 2136)
+    UPDATE = 5,
+}
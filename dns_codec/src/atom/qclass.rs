@@ -24,7 +24,7 @@ pub enum QClass {
 }
 
 impl QClass {
-    pub(crate) fn decode<'a>(src: &mut io::Cursor<&'a [u8]>) -> Result<Option<Self>, io::Error> {
+    pub(crate) fn decode(src: &mut io::Cursor<&[u8]>) -> Result<Option<Self>, io::Error> {
         let decoded = src.read_u16::<NetworkEndian>()?;
         let class = decoded
             .try_into()
@@ -8,7 +8,7 @@ use super::rtri;
 
 /// QTYPE fields appear in the question part of a query.  
 /// QTYPES are a superset of TYPEs, hence all TYPEs are valid QTYPEs.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, TryFromPrimitive)]
 #[repr(u16)]
 pub enum QType {
     /// A host address
@@ -141,6 +141,9 @@ pub enum QType {
 
     DLV = 32769,
 
+    /// A request for an incremental transfer of a zone (RFC 1995)
+    IXFR = 251,
+
     /// A request for a transfer of an entire zone
     AXFR = 252,
 
@@ -155,7 +158,7 @@ pub enum QType {
 }
 
 impl QType {
-    pub(crate) fn decode<'a>(src: &mut io::Cursor<&'a [u8]>) -> Result<Option<Self>, io::Error> {
+    pub(crate) fn decode(src: &mut io::Cursor<&[u8]>) -> Result<Option<Self>, io::Error> {
         let decoded = rtri!(src.read_u16::<NetworkEndian>());
         let class = decoded
             .try_into()
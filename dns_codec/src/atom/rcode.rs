@@ -0,0 +1,39 @@
+use num_enum::TryFromPrimitive;
+
+/// Response code, carried in the lowest four bits of the header flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Rcode {
+    /// No error condition
+    NOERROR = 0,
+
+    /// The name server was unable to interpret the query
+    FORMERR = 1,
+
+    /// The name server was unable to process this query due to a problem with the name server
+    SERVFAIL = 2,
+
+    /// The domain name referenced in the query does not exist
+    NXDOMAIN = 3,
+
+    /// The name server does not support the requested kind of query
+    NOTIMP = 4,
+
+    /// The name server refuses to perform the specified operation for policy reasons
+    REFUSED = 5,
+
+    /// A name that should not exist does exist (RFC 2136)
+    YXDOMAIN = 6,
+
+    /// An RRset that should not exist does exist (RFC 2136)
+    YXRRSET = 7,
+
+    /// An RRset that should exist does not exist (RFC 2136)
+    NXRRSET = 8,
+
+    /// The server is not authoritative for the zone named (RFC 2136)
+    NOTAUTH = 9,
+
+    /// A name is not within the zone named (RFC 2136)
+    NOTZONE = 10,
+}
@@ -1,55 +1,517 @@
 use core::net;
 use std::{
-    io::{self, Read},
+    io::{self, Read, Write as _},
     net::{Ipv4Addr, Ipv6Addr},
 };
 
-use atom::{Class, Type};
-use byteorder::{NetworkEndian, ReadBytesExt};
+use atom::{Class, Name, Type};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt as _};
+use bytes::BufMut as _;
+use tokio_util::bytes::BytesMut;
 
 use crate::atom;
 
-use super::rtri;
+use super::{rotri, rtri};
 
 impl RData {
-    pub(crate) fn decode<'a>(
-        src: &mut io::Cursor<&'a [u8]>,
+    pub(crate) fn decode(
+        src: &mut io::Cursor<&[u8]>,
         length: u16,
         kind: Type,
         class: Class,
     ) -> Result<Option<Self>, io::Error> {
-        let mut available = src.take(length.into());
+        let start = src.position();
+        let end = start + u64::from(length);
 
         let rdata = match (kind, class) {
-            (Type::A, Class::IN) => {
-                let bits = rtri!(available.read_u32::<NetworkEndian>());
+            (_, Class::NONE | Class::STAR) if length == 0 => RData::Empty,
+            (Type::A, Class::IN | Class::NONE) => {
+                let bits = rtri!(src.read_u32::<NetworkEndian>());
                 let address = Ipv4Addr::from_bits(bits);
                 RData::Ipv4(address)
             }
-            (Type::AAAA, Class::IN) => {
-                let bits = rtri!(available.read_u128::<NetworkEndian>());
+            (Type::AAAA, Class::IN | Class::NONE) => {
+                let bits = rtri!(src.read_u128::<NetworkEndian>());
                 let address = Ipv6Addr::from_bits(bits);
                 RData::Ipv6(address)
             }
+            (Type::NS | Type::CNAME | Type::PTR, _) => {
+                let name = rotri!(Name::decode(src));
+                RData::Name(name)
+            }
+            (Type::MX, _) => {
+                let preference = rtri!(src.read_u16::<NetworkEndian>());
+                let exchange = rotri!(Name::decode(src));
+                RData::Mx(Mx {
+                    preference,
+                    exchange,
+                })
+            }
+            (Type::TXT, _) => {
+                let mut strings = Vec::new();
+                while src.position() < end {
+                    let string_length = rtri!(src.read_u8());
+                    let mut string = Vec::with_capacity(string_length.into());
+                    rtri!(src
+                        .by_ref()
+                        .take(string_length.into())
+                        .read_to_end(&mut string));
+                    if string.len() != usize::from(string_length) {
+                        return Ok(None);
+                    }
+                    strings.push(string);
+                }
+                RData::Txt(strings)
+            }
+            (Type::SRV, _) => {
+                let priority = rtri!(src.read_u16::<NetworkEndian>());
+                let weight = rtri!(src.read_u16::<NetworkEndian>());
+                let port = rtri!(src.read_u16::<NetworkEndian>());
+                let target = rotri!(Name::decode(src));
+                RData::Srv(Srv {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                })
+            }
+            (Type::SOA, _) => {
+                let mname = rotri!(Name::decode(src));
+                let rname = rotri!(Name::decode(src));
+                let serial = rtri!(src.read_u32::<NetworkEndian>());
+                let refresh = rtri!(src.read_u32::<NetworkEndian>());
+                let retry = rtri!(src.read_u32::<NetworkEndian>());
+                let expire = rtri!(src.read_u32::<NetworkEndian>());
+                let minimum = rtri!(src.read_u32::<NetworkEndian>());
+                RData::Soa(Soa {
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                })
+            }
+            (Type::TSIG, _) => {
+                let algorithm = rotri!(Name::decode(src));
+                let high = rtri!(src.read_u16::<NetworkEndian>());
+                let low = rtri!(src.read_u32::<NetworkEndian>());
+                let fudge = rtri!(src.read_u16::<NetworkEndian>());
+                let mac_size = rtri!(src.read_u16::<NetworkEndian>());
+                let mac = rotri!(read_bytes(src, mac_size));
+                let original_id = rtri!(src.read_u16::<NetworkEndian>());
+                let error = rtri!(src.read_u16::<NetworkEndian>());
+                let other_length = rtri!(src.read_u16::<NetworkEndian>());
+                let other = rotri!(read_bytes(src, other_length));
+                RData::Tsig(Tsig {
+                    algorithm,
+                    time_signed: u64::from(high) << 32 | u64::from(low),
+                    fudge,
+                    mac,
+                    original_id,
+                    error,
+                    other,
+                })
+            }
+            (Type::KEY, _) => {
+                let flags = rtri!(src.read_u16::<NetworkEndian>());
+                let protocol = rtri!(src.read_u8());
+                let algorithm = rtri!(src.read_u8());
+                let public_key = rotri!(read_rest(src, end));
+                RData::Key(Key {
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key,
+                })
+            }
+            (Type::SIG, _) => {
+                let type_covered = rtri!(src.read_u16::<NetworkEndian>());
+                let algorithm = rtri!(src.read_u8());
+                let labels = rtri!(src.read_u8());
+                let original_ttl = rtri!(src.read_u32::<NetworkEndian>());
+                let expiration = rtri!(src.read_u32::<NetworkEndian>());
+                let inception = rtri!(src.read_u32::<NetworkEndian>());
+                let key_tag = rtri!(src.read_u16::<NetworkEndian>());
+                let signer = rotri!(Name::decode(src));
+                let signature = rotri!(read_rest(src, end));
+                RData::Sig(Sig {
+                    type_covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    expiration,
+                    inception,
+                    key_tag,
+                    signer,
+                    signature,
+                })
+            }
+            (Type::TKEY, _) => {
+                let algorithm = rotri!(Name::decode(src));
+                let inception = rtri!(src.read_u32::<NetworkEndian>());
+                let expiration = rtri!(src.read_u32::<NetworkEndian>());
+                let mode = rtri!(src.read_u16::<NetworkEndian>());
+                let error = rtri!(src.read_u16::<NetworkEndian>());
+                let key_size = rtri!(src.read_u16::<NetworkEndian>());
+                let key = rotri!(read_bytes(src, key_size));
+                let other_length = rtri!(src.read_u16::<NetworkEndian>());
+                let other = rotri!(read_bytes(src, other_length));
+                RData::Tkey(Tkey {
+                    algorithm,
+                    inception,
+                    expiration,
+                    mode,
+                    error,
+                    key,
+                    other,
+                })
+            }
             _ => {
-                unimplemented!("Unsupported kind, class pair: {kind:?}, {class:?})")
-            } /*
-              _ => {
-                  let mut data = Vec::with_capacity(length.into());
-                  let _ = rtri!(available.read_to_end(&mut data));
-                  RData::Otherwise(data)
-              }
-              */
+                let mut data = Vec::with_capacity(length.into());
+                rtri!(src.by_ref().take(length.into()).read_to_end(&mut data));
+                if data.len() != usize::from(length) {
+                    return Ok(None);
+                }
+                RData::Otherwise(data)
+            }
         };
 
+        if src.position() != end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "RDATA of {kind:?} record spans {} bytes instead of the declared {length}",
+                    src.position() - start
+                ),
+            ));
+        }
+
         Ok(Some(rdata))
     }
+
+    /// Writes the RDATA without its length prefix. Names are not compressed.
+    pub(crate) fn encode(self, dst: &mut BytesMut) -> Result<(), io::Error> {
+        match self {
+            RData::Ipv4(address) => dst.writer().write_all(&address.octets())?,
+            RData::Ipv6(address) => dst.writer().write_all(&address.octets())?,
+            RData::Name(name) => name.encode(dst)?,
+            RData::Mx(Mx {
+                preference,
+                exchange,
+            }) => {
+                dst.writer().write_u16::<NetworkEndian>(preference)?;
+                exchange.encode(dst)?;
+            }
+            RData::Txt(strings) => {
+                let mut writer = dst.writer();
+                for string in strings {
+                    let length = u8::try_from(string.len()).map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "TXT character string is longer than 255 bytes",
+                        )
+                    })?;
+                    writer.write_u8(length)?;
+                    writer.write_all(&string)?;
+                }
+            }
+            RData::Srv(Srv {
+                priority,
+                weight,
+                port,
+                target,
+            }) => {
+                let mut writer = dst.writer();
+                writer.write_u16::<NetworkEndian>(priority)?;
+                writer.write_u16::<NetworkEndian>(weight)?;
+                writer.write_u16::<NetworkEndian>(port)?;
+                target.encode(dst)?;
+            }
+            RData::Soa(Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            }) => {
+                mname.encode(dst)?;
+                rname.encode(dst)?;
+                let mut writer = dst.writer();
+                for value in [serial, refresh, retry, expire, minimum] {
+                    writer.write_u32::<NetworkEndian>(value)?;
+                }
+            }
+            RData::Otherwise(data) => dst.writer().write_all(&data)?,
+            RData::Tsig(Tsig {
+                algorithm,
+                time_signed,
+                fudge,
+                mac,
+                original_id,
+                error,
+                other,
+            }) => {
+                algorithm.encode(dst)?;
+                let mut writer = dst.writer();
+                writer.write_u16::<NetworkEndian>((time_signed >> 32) as u16)?;
+                writer.write_u32::<NetworkEndian>(time_signed as u32)?;
+                writer.write_u16::<NetworkEndian>(fudge)?;
+                write_bytes(&mut writer, &mac)?;
+                writer.write_u16::<NetworkEndian>(original_id)?;
+                writer.write_u16::<NetworkEndian>(error)?;
+                write_bytes(&mut writer, &other)?;
+            }
+            RData::Key(Key {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            }) => {
+                let mut writer = dst.writer();
+                writer.write_u16::<NetworkEndian>(flags)?;
+                writer.write_u8(protocol)?;
+                writer.write_u8(algorithm)?;
+                writer.write_all(&public_key)?;
+            }
+            RData::Sig(sig) => {
+                let signature = sig.signature.clone();
+                sig.encode_unsigned(dst)?;
+                dst.writer().write_all(&signature)?;
+            }
+            RData::Tkey(Tkey {
+                algorithm,
+                inception,
+                expiration,
+                mode,
+                error,
+                key,
+                other,
+            }) => {
+                algorithm.encode(dst)?;
+                let mut writer = dst.writer();
+                writer.write_u32::<NetworkEndian>(inception)?;
+                writer.write_u32::<NetworkEndian>(expiration)?;
+                writer.write_u16::<NetworkEndian>(mode)?;
+                writer.write_u16::<NetworkEndian>(error)?;
+                write_bytes(&mut writer, &key)?;
+                write_bytes(&mut writer, &other)?;
+            }
+            RData::Empty => (),
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads `length` bytes, or nothing if fewer are left.
+fn read_bytes(src: &mut io::Cursor<&[u8]>, length: u16) -> Result<Option<Vec<u8>>, io::Error> {
+    let mut data = Vec::with_capacity(length.into());
+    rtri!(src.by_ref().take(length.into()).read_to_end(&mut data));
+    if data.len() != usize::from(length) {
+        return Ok(None);
+    }
+    Ok(Some(data))
 }
 
-#[derive(Debug)]
+/// Reads the remaining bytes up to `end`, or nothing if fewer are left.
+fn read_rest(src: &mut io::Cursor<&[u8]>, end: u64) -> Result<Option<Vec<u8>>, io::Error> {
+    let Some(length) = end.checked_sub(src.position()) else {
+        return Ok(None);
+    };
+    let length = u16::try_from(length).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, "RDATA exceeds 65535 bytes")
+    })?;
+    read_bytes(src, length)
+}
+
+/// Writes `data` preceded by its length.
+fn write_bytes(writer: &mut impl io::Write, data: &[u8]) -> Result<(), io::Error> {
+    let length = u16::try_from(data.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Field exceeds 65535 bytes"))?;
+    writer.write_u16::<NetworkEndian>(length)?;
+    writer.write_all(data)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[repr(u16)]
 pub enum RData {
     Ipv4(net::Ipv4Addr) = 1,
     Ipv6(net::Ipv6Addr) = 26,
+    /// The single domain name of NS, CNAME and PTR records.
     Name(atom::Name),
+    Mx(Mx),
+    /// One or more character strings.
+    Txt(Vec<Vec<u8>>),
+    Srv(Srv),
+    Soa(Soa),
+    Tsig(Tsig),
+    Key(Key),
+    Sig(Sig),
+    Tkey(Tkey),
+    /// Raw RDATA of types that are not (yet) understood.
+    Otherwise(Vec<u8>),
+    /// No RDATA at all, as in the prerequisites and deletions of UPDATE messages (RFC 2136).
+    Empty,
+}
+
+/// A host willing to act as a mail exchange for the owner name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mx {
+    /// Preference given to this RR among others at the same owner. Lower values are preferred.
+    pub preference: u16,
+
+    /// A host willing to act as a mail exchange for the owner name.
+    pub exchange: Name,
+}
+
+/// The location of a service, see RFC 2782.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Srv {
+    /// Lower values are contacted first.
+    pub priority: u16,
+
+    /// Relative weight among targets of the same priority.
+    pub weight: u16,
+
+    pub port: u16,
+
+    pub target: Name,
+}
+
+/// Marks the start of a zone of authority.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Soa {
+    /// The name server that was the original or primary source of data for this zone.
+    pub mname: Name,
+
+    /// The mailbox of the person responsible for this zone.
+    pub rname: Name,
+
+    /// Version number of the original copy of the zone.
+    pub serial: u32,
+
+    /// Interval in seconds before the zone should be refreshed.
+    pub refresh: u32,
+
+    /// Interval in seconds that should elapse before a failed refresh should be retried.
+    pub retry: u32,
+
+    /// Upper limit in seconds on the time interval that can elapse before the zone is no longer authoritative.
+    pub expire: u32,
+
+    /// Minimum TTL that should be exported with any RR from this zone,
+    /// and the TTL of negative responses (RFC 2308).
+    pub minimum: u32,
+}
+
+/// A transaction signature (RFC 8945, section 4.2), authenticating the message it ends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tsig {
+    /// Name of the MAC algorithm, e.g. `hmac-sha256`.
+    pub algorithm: Name,
+
+    /// Seconds since the UNIX epoch when the message was signed; 48 bits on the wire.
+    pub time_signed: u64,
+
+    /// Seconds of difference from `time_signed` permitted.
+    pub fudge: u16,
+
+    pub mac: Vec<u8>,
+
+    /// ID of the message when it was signed, before any forwarder changed it.
+    pub original_id: u16,
+
+    /// Extended RCODE covering TSIG processing, 0 if there was no error.
+    pub error: u16,
+
+    /// Empty, unless `error` is BADTIME: then the server's time, as 48 bits.
+    pub other: Vec<u8>,
+}
+
+/// A public key (RFC 2535, section 3), as SIG(0) signatures are verified with (RFC 2931).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    pub flags: u16,
+
+    /// 3 for keys used with DNS.
+    pub protocol: u8,
+
+    /// DNSSEC algorithm number, e.g. 15 for Ed25519.
+    pub algorithm: u8,
+
+    pub public_key: Vec<u8>,
+}
+
+/// A signature (RFC 2535, section 4); in transaction signatures (SIG(0), RFC 2931)
+/// it ends the message it authenticates, covering no RRset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sig {
+    /// Type of the RRset covered, 0 for SIG(0).
+    pub type_covered: u16,
+
+    /// DNSSEC algorithm number of the signing key.
+    pub algorithm: u8,
+
+    /// Labels of the owner name of the RRset covered, 0 for SIG(0).
+    pub labels: u8,
+
+    pub original_ttl: u32,
+
+    /// Seconds since the UNIX epoch after which the signature is not valid, modulo 2^32.
+    pub expiration: u32,
+
+    /// Seconds since the UNIX epoch before which the signature is not valid, modulo 2^32.
+    pub inception: u32,
+
+    /// Identifies the signing key among those of the signer, see RFC 4034, appendix B.
+    pub key_tag: u16,
+
+    /// Owner of the KEY record to verify the signature with.
+    pub signer: Name,
+
+    pub signature: Vec<u8>,
+}
+
+impl Sig {
+    /// Writes the RDATA up to the signature, which is what the signature covers of it;
+    /// the signer is written in canonical form, lowercase and uncompressed.
+    pub(crate) fn encode_unsigned(self, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let mut writer = dst.writer();
+        writer.write_u16::<NetworkEndian>(self.type_covered)?;
+        writer.write_u8(self.algorithm)?;
+        writer.write_u8(self.labels)?;
+        writer.write_u32::<NetworkEndian>(self.original_ttl)?;
+        writer.write_u32::<NetworkEndian>(self.expiration)?;
+        writer.write_u32::<NetworkEndian>(self.inception)?;
+        writer.write_u16::<NetworkEndian>(self.key_tag)?;
+        Name(self.signer.0.to_ascii_lowercase()).encode(dst)
+    }
+}
+
+/// A key negotiation (RFC 2930, section 2), asking for or establishing a shared secret.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tkey {
+    /// Name of the algorithm the key is for, e.g. `hmac-sha256`.
+    pub algorithm: Name,
+
+    /// Seconds since the UNIX epoch from which the key is valid, modulo 2^32.
+    pub inception: u32,
+
+    /// Seconds since the UNIX epoch until which the key is valid, modulo 2^32.
+    pub expiration: u32,
+
+    /// How the key is established, e.g. 5 for its deletion.
+    pub mode: u16,
+
+    /// Extended RCODE covering TKEY processing, 0 if there was no error.
+    pub error: u16,
+
+    /// Keying material, depending on the mode.
+    pub key: Vec<u8>,
+
+    pub other: Vec<u8>,
 }
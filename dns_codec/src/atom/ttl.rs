@@ -1,6 +1,7 @@
-use std::{cmp::Ordering, io::{self, Read}};
+use std::{cmp::Ordering, io};
 
-use byteorder::{NetworkEndian, ReadBytesExt};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt as _};
+use bytes::BufMut as _;
 
 use super::rtri;
 
@@ -8,7 +9,7 @@ use super::rtri;
 pub struct Ttl(i32);
 
 impl Ttl {
-    pub(crate) fn decode<'a>(src: &mut io::Cursor<&'a [u8]>) -> Result<Option<Self>, io::Error> {
+    pub(crate) fn decode(src: &mut io::Cursor<&[u8]>) -> Result<Option<Self>, io::Error> {
         let ttl = rtri!(src.read_i32::<NetworkEndian>());
         if ttl.is_negative() {
             return Err(io::Error::new(
@@ -19,6 +20,21 @@ impl Ttl {
 
         Ok(Some(Ttl(ttl)))
     }
+
+    pub(crate) fn encode(self, dst: &mut tokio_util::bytes::BytesMut) -> Result<(), io::Error> {
+        let mut writer = dst.writer();
+        writer.write_i32::<NetworkEndian>(self.0)?;
+        Ok(())
+    }
+
+    /// A TTL of `secs` seconds, capped at the largest value RFC 2181 permits.
+    pub fn from_secs(secs: u32) -> Self {
+        Ttl(secs.min(i32::MAX as u32) as i32)
+    }
+
+    pub fn as_secs(self) -> u32 {
+        self.0 as u32
+    }
 }
 
 impl PartialEq<i32> for Ttl {
@@ -1,6 +1,7 @@
 use std::io;
 
-use byteorder::{NetworkEndian, ReadBytesExt as _};
+use byteorder::{NetworkEndian, ReadBytesExt as _, WriteBytesExt as _};
+use bytes::BufMut as _;
 use num_enum::TryFromPrimitive;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
@@ -126,6 +127,9 @@ pub enum Type {
 
     TSIG = 250,
 
+    /// All records of a name; only in the prerequisite and update sections of UPDATE messages (RFC 2136)
+    STAR = 255,
+
     URI = 256,
 
     CAA = 257,
@@ -138,7 +142,7 @@ pub enum Type {
 }
 
 impl Type {
-    pub(crate) fn decode<'a>(src: &mut io::Cursor<&'a [u8]>) -> Result<Option<Self>, io::Error> {
+    pub(crate) fn decode(src: &mut io::Cursor<&[u8]>) -> Result<Option<Self>, io::Error> {
         let decoded = src.read_u16::<NetworkEndian>()?;
         let type_ = decoded
             .try_into()
@@ -146,4 +150,10 @@ impl Type {
 
         Ok(Some(type_))
     }
+
+    pub(crate) fn encode(self, dst: &mut tokio_util::bytes::BytesMut) -> Result<(), io::Error> {
+        let mut writer = dst.writer();
+        writer.write_u16::<NetworkEndian>(self as u16)?;
+        Ok(())
+    }
 }
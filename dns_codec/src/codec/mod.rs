@@ -3,10 +3,10 @@ pub(crate) mod decode;
 mod encode;
 */
 
-mod decode;
+mod stream;
 
-// Entrypoint Codecs; intended for public API usage
-pub struct QueryCodec;
+pub use stream::StreamCodec;
 
-pub struct ResponseCodec;
+// Entrypoint Codec; intended for public API usage
+pub struct MessageCodec;
 
@@ -0,0 +1,174 @@
+use std::io;
+
+use tokio_util::{
+    bytes::{Buf as _, BufMut as _, BytesMut},
+    codec::{Decoder, Encoder},
+};
+
+/// Length of the prefix that precedes every message on stream transports.
+const PREFIX_LENGTH: usize = 2;
+
+/// Length of the header of every message.
+const HEADER_LENGTH: usize = 12;
+
+/// Length of the shortest question and record, owned by the root and without RDATA.
+const MIN_QUESTION_LENGTH: usize = 5;
+const MIN_RECORD_LENGTH: usize = 11;
+
+/// Wraps a message codec for use on stream transports such as TCP,
+/// where every message is preceded by its length as a two byte, network order integer.
+///
+/// Messages are only decoded once they have fully arrived, while lengths too short for the header,
+/// or for the number of entries the header announces, are rejected as soon as they are known.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StreamCodec<C>(pub C);
+
+impl<C> Decoder for StreamCodec<C>
+where
+    C: Decoder<Error = io::Error>,
+{
+    type Item = C::Item;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(prefix) = src.get(..PREFIX_LENGTH) else {
+            return Ok(None);
+        };
+        let length = usize::from(u16::from_be_bytes([prefix[0], prefix[1]]));
+        if length < HEADER_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Message of {length} bytes is too short for a header"),
+            ));
+        }
+        if let Some(header) = src.get(PREFIX_LENGTH..PREFIX_LENGTH + HEADER_LENGTH) {
+            let count = |offset: usize| {
+                usize::from(u16::from_be_bytes([header[offset], header[offset + 1]]))
+            };
+            let required = HEADER_LENGTH
+                + count(4) * MIN_QUESTION_LENGTH
+                + (count(6) + count(8) + count(10)) * MIN_RECORD_LENGTH;
+            if length < required {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Message of {length} bytes is too short for the {required} its header requires"),
+                ));
+            }
+        }
+
+        if src.len() < PREFIX_LENGTH + length {
+            src.reserve(PREFIX_LENGTH + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(PREFIX_LENGTH);
+        let mut message = src.split_to(length);
+
+        // The whole message is available, so running out of bytes means it is malformed
+        let item = self.0.decode(&mut message)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Message is shorter than its contents require",
+            )
+        })?;
+        if !message.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Message is followed by {} bytes beyond its contents",
+                    message.len()
+                ),
+            ));
+        }
+        Ok(Some(item))
+    }
+}
+
+impl<C, T> Encoder<T> for StreamCodec<C>
+where
+    C: Encoder<T, Error = io::Error>,
+{
+    type Error = io::Error;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut message = BytesMut::new();
+        self.0.encode(item, &mut message)?;
+
+        let length = u16::try_from(message.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Message of {} bytes exceeds the maximum length",
+                    message.len()
+                ),
+            )
+        })?;
+
+        dst.reserve(PREFIX_LENGTH + message.len());
+        dst.put_u16(length);
+        dst.extend_from_slice(&message);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio_util::{
+        bytes::BytesMut,
+        codec::{Decoder as _, Encoder as _},
+    };
+
+    #[test]
+    fn length_prefixed_roundtrip() {
+        let query = crate::Message::query("google.com", crate::QType::A)
+            .id(0x8298)
+            .build()
+            .unwrap();
+
+        let mut codec = super::StreamCodec(crate::MessageCodec);
+        let mut encoded = BytesMut::new();
+        codec.encode(query.clone(), &mut encoded).unwrap();
+        codec.encode(query.clone(), &mut encoded).unwrap();
+        assert_eq!(&encoded[..2], &[0, 28]);
+
+        // Nothing is produced until the entire message has arrived
+        let mut src = BytesMut::new();
+        for byte in &encoded[..30] {
+            assert!(codec.decode(&mut src).unwrap().is_none());
+            src.extend_from_slice(&[*byte]);
+        }
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(query.clone()));
+
+        src.extend_from_slice(&encoded[30..]);
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(query));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn impossible_lengths() {
+        let mut codec = super::StreamCodec(crate::MessageCodec);
+
+        // Too short for the header, rejected before the message arrives
+        let mut src = BytesMut::from(&[0, 11][..]);
+        assert!(codec.decode(&mut src).is_err());
+
+        // Too short for the entries announced by the header, rejected as soon as the header arrives
+        let mut src = BytesMut::from(&[0, 20, 0, 1, 0, 0, 0, 2][..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        assert!(codec.decode(&mut src).is_err());
+
+        // Bytes beyond the contents of the message are malformed as well
+        let query = crate::Message::query("example.com", crate::QType::A)
+            .build()
+            .unwrap();
+        let mut message = BytesMut::new();
+        crate::MessageCodec.encode(query, &mut message).unwrap();
+        message.extend_from_slice(&[0]);
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        src.extend_from_slice(&message);
+        assert!(codec.decode(&mut src).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+//! Extension mechanisms for DNS (EDNS, RFC 6891), carried by an OPT pseudo-record
+//! in the additional section of a message.
+
+use std::io;
+
+use byteorder::{NetworkEndian, ReadBytesExt as _};
+use tokio_util::bytes::{BufMut as _, BytesMut};
+
+use crate::{
+    atom::{rotri, rtri},
+    Header, Name, Question,
+};
+
+/// Type of the OPT pseudo-record.
+const OPT: u16 = 41;
+
+/// Code of the edns-tcp-keepalive option (RFC 7828).
+const TCP_KEEPALIVE: u16 = 11;
+
+/// Bit of the DO flag (RFC 3225), in the flags half of the OPT TTL.
+const DNSSEC_OK: u16 = 0x8000;
+
+/// The EDNS information of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    /// Largest UDP payload the sender can reassemble.
+    pub udp_payload_size: u16,
+    /// Upper eight bits of the 12 bit RCODE.
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EdnsOption {
+    /// How long a TCP connection may stay idle, in units of 100 milliseconds (RFC 7828).
+    /// Clients send it without a timeout, to ask the server for its own.
+    TcpKeepalive(Option<u16>),
+    Other {
+        code: u16,
+        data: Vec<u8>,
+    },
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+impl Default for Edns {
+    fn default() -> Self {
+        Edns {
+            udp_payload_size: 1232,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+}
+
+impl Edns {
+    /// The EDNS information of `message`, or `None` if it has no OPT record.
+    ///
+    /// Unlike decoding the whole [`crate::Message`], this skips over the data of all other records.
+    pub fn find(message: &[u8]) -> io::Result<Option<Self>> {
+        fn truncated<T>(item: Option<T>) -> io::Result<T> {
+            item.ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Message is truncated"))
+        }
+
+        let mut cursor = io::Cursor::new(message);
+        let header = truncated(Header::decode(&mut cursor)?)?;
+        if header.arcount == 0 {
+            return Ok(None);
+        }
+        for _ in 0..header.qdcount {
+            truncated(Question::decode(&mut cursor)?)?;
+        }
+
+        let records = usize::from(header.ancount) + usize::from(header.ncount);
+        for index in 0..records + usize::from(header.arcount) {
+            if index >= records && truncated(Edns::is_next(&mut cursor)?)? {
+                return truncated(Edns::decode_record(&mut cursor)?).map(Some);
+            }
+            truncated(Name::decode(&mut cursor)?)?;
+            cursor.set_position(cursor.position() + 8);
+            let length = cursor.read_u16::<NetworkEndian>()?;
+            cursor.set_position(cursor.position() + u64::from(length));
+        }
+        if cursor.position() > message.len() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Message is truncated",
+            ));
+        }
+        Ok(None)
+    }
+
+    /// Whether the record at the position of `src` is an OPT record, leaving the position untouched.
+    pub(crate) fn is_next(src: &mut io::Cursor<&[u8]>) -> io::Result<Option<bool>> {
+        let start = src.position();
+        rotri!(Name::decode(src));
+        let kind = rtri!(src.read_u16::<NetworkEndian>());
+        src.set_position(start);
+        Ok(Some(kind == OPT))
+    }
+
+    /// Decodes the OPT record at the position of `src`.
+    pub(crate) fn decode_record(src: &mut io::Cursor<&[u8]>) -> io::Result<Option<Self>> {
+        let name = rotri!(Name::decode(src));
+        if name != Name::root() {
+            return Err(invalid("OPT record is not owned by the root"));
+        }
+        let _kind = rtri!(src.read_u16::<NetworkEndian>());
+        let class = rtri!(src.read_u16::<NetworkEndian>());
+        let ttl = rtri!(src.read_u32::<NetworkEndian>());
+        let length = usize::from(rtri!(src.read_u16::<NetworkEndian>()));
+
+        let start = src.position() as usize;
+        let Some(rdata) = src.get_ref().get(start..start + length) else {
+            return Ok(None);
+        };
+        src.set_position((start + length) as u64);
+        Edns::decode(class, ttl, rdata).map(Some)
+    }
+
+    fn decode(class: u16, ttl: u32, mut rdata: &[u8]) -> io::Result<Self> {
+        let [extended_rcode, version, flags @ ..] = ttl.to_be_bytes();
+        let mut options = Vec::new();
+        while !rdata.is_empty() {
+            let code = rdata.read_u16::<NetworkEndian>()?;
+            let length = usize::from(rdata.read_u16::<NetworkEndian>()?);
+            if rdata.len() < length {
+                return Err(invalid("EDNS option is longer than the OPT record"));
+            }
+            let (data, rest) = rdata.split_at(length);
+            rdata = rest;
+
+            options.push(match (code, data) {
+                (TCP_KEEPALIVE, []) => EdnsOption::TcpKeepalive(None),
+                (TCP_KEEPALIVE, [high, low]) => {
+                    EdnsOption::TcpKeepalive(Some(u16::from_be_bytes([*high, *low])))
+                }
+                (TCP_KEEPALIVE, _) => return Err(invalid("Malformed edns-tcp-keepalive option")),
+                (code, data) => EdnsOption::Other {
+                    code,
+                    data: data.to_vec(),
+                },
+            });
+        }
+
+        Ok(Edns {
+            udp_payload_size: class,
+            extended_rcode,
+            version,
+            dnssec_ok: u16::from_be_bytes(flags) & DNSSEC_OK != 0,
+            options,
+        })
+    }
+
+    /// Whether the sender asked for the TCP keepalive timeout.
+    pub fn tcp_keepalive(&self) -> Option<Option<u16>> {
+        self.options.iter().find_map(|option| match option {
+            EdnsOption::TcpKeepalive(timeout) => Some(*timeout),
+            EdnsOption::Other { .. } => None,
+        })
+    }
+
+    /// Appends the OPT record to the additional section of the encoded `message`, counting it in the header.
+    pub fn append_to(&self, message: &mut BytesMut) -> io::Result<()> {
+        if message.len() < 12 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Message has no header",
+            ));
+        }
+        let arcount = u16::from_be_bytes([message[10], message[11]])
+            .checked_add(1)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Additional section is already full",
+                )
+            })?;
+        self.encode_record(message)?;
+        message[10..12].copy_from_slice(&arcount.to_be_bytes());
+        Ok(())
+    }
+
+    /// Writes the OPT record, leaving the header alone.
+    pub(crate) fn encode_record(&self, dst: &mut BytesMut) -> io::Result<()> {
+        let mut rdata = BytesMut::new();
+        for option in &self.options {
+            let (code, data) = match option {
+                EdnsOption::TcpKeepalive(None) => (TCP_KEEPALIVE, &[][..]),
+                EdnsOption::TcpKeepalive(Some(timeout)) => {
+                    (TCP_KEEPALIVE, &timeout.to_be_bytes()[..])
+                }
+                EdnsOption::Other { code, data } => (*code, &data[..]),
+            };
+            let length = u16::try_from(data.len()).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "EDNS option exceeds 65535 bytes",
+                )
+            })?;
+            rdata.put_u16(code);
+            rdata.put_u16(length);
+            rdata.extend_from_slice(data);
+        }
+
+        let length = u16::try_from(rdata.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "EDNS options exceed 65535 bytes",
+            )
+        })?;
+        let flags = if self.dnssec_ok { DNSSEC_OK } else { 0 };
+        Name::root().encode(dst)?;
+        dst.put_u16(OPT);
+        dst.put_u16(self.udp_payload_size);
+        dst.put_u8(self.extended_rcode);
+        dst.put_u8(self.version);
+        dst.put_u16(flags);
+        dst.put_u16(length);
+        dst.extend_from_slice(&rdata);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio_util::{bytes::BytesMut, codec::Encoder as _};
+
+    use super::{Edns, EdnsOption};
+
+    #[test]
+    fn find_and_append() {
+        let query = crate::Message::query("example.com", crate::QType::A)
+            .id(7)
+            .build()
+            .unwrap();
+        let mut message = BytesMut::new();
+        crate::MessageCodec.encode(query, &mut message).unwrap();
+        assert_eq!(Edns::find(&message).unwrap(), None);
+
+        let edns = Edns {
+            dnssec_ok: true,
+            options: vec![
+                EdnsOption::TcpKeepalive(Some(1200)),
+                EdnsOption::Other {
+                    code: 10,
+                    data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                },
+            ],
+            ..Default::default()
+        };
+        edns.append_to(&mut message).unwrap();
+        assert_eq!(&message[10..12], &[0, 1]);
+        let found = Edns::find(&message).unwrap().unwrap();
+        assert_eq!(found, edns);
+        assert_eq!(found.tcp_keepalive(), Some(Some(1200)));
+
+        // The timeout is two bytes or nothing
+        let length = message.len();
+        message[length - 16..length - 14].copy_from_slice(&[0, 1]);
+        assert!(Edns::find(&message).is_err());
+    }
+
+    #[test]
+    fn append_rejects_overflow() {
+        // Nothing is appended when the additional section cannot count another record
+        let mut message = BytesMut::from(&[0, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff][..]);
+        let error = Edns::default().append_to(&mut message).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(message.len(), 12);
+
+        // Nor when an option, or the options together, are too long for their length fields
+        let mut message = BytesMut::from(&[0, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0][..]);
+        let long = EdnsOption::Other {
+            code: 10,
+            data: vec![0; 65536],
+        };
+        let edns = Edns {
+            options: vec![long],
+            ..Default::default()
+        };
+        let error = edns.append_to(&mut message).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        let many = EdnsOption::Other {
+            code: 10,
+            data: vec![0; 40000],
+        };
+        let edns = Edns {
+            options: vec![many.clone(), many],
+            ..Default::default()
+        };
+        let error = edns.append_to(&mut message).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(&message[..], &[0, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+}
@@ -1,15 +1,30 @@
 mod atom;
 mod codec;
+mod edns;
+mod message;
+mod message_ref;
 mod molecule;
-mod query;
-mod response;
+#[cfg(feature = "sig0")]
+pub mod sig0;
+#[cfg(any(feature = "tsig", feature = "sig0"))]
+mod signature;
+#[cfg(feature = "tsig")]
+pub mod tkey;
+#[cfg(feature = "tsig")]
+pub mod tsig;
+mod update;
 
 /// Decoding / Encoding
-pub use codec::{QueryCodec, ResponseCodec};
+pub use codec::{MessageCodec, StreamCodec};
 
-pub use atom::{Class, Header, Name, QClass, QType, RData, Ttl, Type};
+pub use atom::{
+    Class, Header, Key, Mx, Name, Opcode, QClass, QType, RData, Rcode, Sig, Soa, Srv, Tkey, Tsig, Ttl,
+    Type,
+};
+pub use edns::{Edns, EdnsOption};
 pub use molecule::{Question, Record};
 
 /// Values
-pub use query::Query;
-pub use response::Response;
+pub use message::{Message, MessageBuilder};
+pub use message_ref::{Labels, MessageRef, NameRef, QuestionRef, Questions, RecordRef, Records};
+pub use update::{Change, Prerequisite, Update, UpdateBuilder};
@@ -0,0 +1,456 @@
+use std::io;
+
+use bytes::Buf as _;
+use tokio_util::bytes::BytesMut;
+
+use crate::{
+    atom::rotri, Edns, Header, MessageCodec, Name, Opcode, QClass, QType, Question, Rcode, Record,
+};
+
+/// A DNS message in either direction: a query, a response, or any other opcode such as
+/// UPDATE or NOTIFY, which reuse the same sections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub header: Header,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    /// Records of the additional section, except for the OPT record carried in [`Message::edns`].
+    pub additionals: Vec<Record>,
+    pub edns: Option<Edns>,
+}
+
+impl Message {
+    /// Starts a query for the records of `kind` at `name`, in class IN and with ID 0.
+    pub fn query<N>(name: N, kind: QType) -> MessageBuilder
+    where
+        N: TryInto<Name>,
+        N::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        MessageBuilder::new(Header {
+            id: 0,
+            flags: 0,
+            qdcount: 0,
+            ancount: 0,
+            ncount: 0,
+            arcount: 0,
+        })
+        .question(name, kind)
+    }
+
+    /// Starts the response to this message, copying its ID, opcode, questions and RD bit.
+    pub fn response(&self) -> MessageBuilder {
+        let mut header = Header {
+            id: self.header.id,
+            flags: 0,
+            qdcount: 0,
+            ancount: 0,
+            ncount: 0,
+            arcount: 0,
+        };
+        header.set_response(true);
+        if let Some(opcode) = self.header.opcode() {
+            header.set_opcode(opcode);
+        }
+        header.set_recursion_desired(self.header.recursion_desired());
+
+        let mut builder = MessageBuilder::new(header);
+        builder.message.questions = self.questions.clone();
+        builder
+    }
+
+    /// The first question, which is the only one of almost every message.
+    pub fn question(&self) -> Option<&Question> {
+        self.questions.first()
+    }
+
+    /// Sets the counts of the header to the lengths of the sections, with ARCOUNT including the OPT record.
+    pub fn set_counts(&mut self) -> io::Result<()> {
+        self.header.qdcount = count(self.questions.len())?;
+        self.header.ancount = count(self.answers.len())?;
+        self.header.ncount = count(self.authorities.len())?;
+        self.header.arcount = count(self.additionals.len() + usize::from(self.edns.is_some()))?;
+        Ok(())
+    }
+}
+
+fn count(length: usize) -> io::Result<u16> {
+    u16::try_from(length).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("Section of {length} entries is too long"))
+    })
+}
+
+/// Builds a [`Message`], see [`Message::query`] and [`Message::response`].
+///
+/// Header counts are left to [`MessageBuilder::build`], which derives them from the sections.
+#[derive(Debug)]
+pub struct MessageBuilder {
+    message: Message,
+    /// The first name that could not be converted, reported by [`MessageBuilder::build`].
+    error: Option<io::Error>,
+}
+
+impl MessageBuilder {
+    fn new(header: Header) -> Self {
+        MessageBuilder {
+            message: Message {
+                header,
+                questions: Vec::new(),
+                answers: Vec::new(),
+                authorities: Vec::new(),
+                additionals: Vec::new(),
+                edns: None,
+            },
+            error: None,
+        }
+    }
+
+    pub fn id(mut self, id: u16) -> Self {
+        self.message.header.id = id;
+        self
+    }
+
+    pub fn opcode(mut self, opcode: Opcode) -> Self {
+        self.message.header.set_opcode(opcode);
+        self
+    }
+
+    pub fn recursion_desired(mut self, desired: bool) -> Self {
+        self.message.header.set_recursion_desired(desired);
+        self
+    }
+
+    pub fn recursion_available(mut self, available: bool) -> Self {
+        self.message.header.set_recursion_available(available);
+        self
+    }
+
+    pub fn authoritative(mut self, authoritative: bool) -> Self {
+        self.message.header.set_authoritative(authoritative);
+        self
+    }
+
+    pub fn rcode(mut self, rcode: Rcode) -> Self {
+        self.message.header.set_rcode(rcode);
+        self
+    }
+
+    /// Asks for the records of `kind` at `name` as well, in class IN.
+    pub fn question<N>(mut self, name: N, kind: QType) -> Self
+    where
+        N: TryInto<Name>,
+        N::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        match name.try_into() {
+            Ok(name) => self.message.questions.push(Question {
+                name,
+                kind,
+                class: QClass::IN,
+            }),
+            Err(e) => {
+                self.error
+                    .get_or_insert_with(|| io::Error::new(io::ErrorKind::InvalidInput, e));
+            }
+        }
+        self
+    }
+
+    pub fn answer(mut self, record: Record) -> Self {
+        self.message.answers.push(record);
+        self
+    }
+
+    pub fn authority(mut self, record: Record) -> Self {
+        self.message.authorities.push(record);
+        self
+    }
+
+    pub fn additional(mut self, record: Record) -> Self {
+        self.message.additionals.push(record);
+        self
+    }
+
+    /// Speaks EDNS, advertising `udp_payload_size` as the largest response the sender can reassemble.
+    pub fn edns(mut self, udp_payload_size: u16) -> Self {
+        self.message.edns = Some(Edns {
+            udp_payload_size,
+            ..Default::default()
+        });
+        self
+    }
+
+    /// Fails with [`io::ErrorKind::InvalidInput`] if a name could not be converted, or a section is too long.
+    pub fn build(self) -> io::Result<Message> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let mut message = self.message;
+        message.set_counts()?;
+        Ok(message)
+    }
+}
+
+impl tokio_util::codec::Decoder for MessageCodec {
+    type Item = crate::Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let underlying: &[u8] = src;
+        let mut cursor = io::Cursor::new(underlying);
+
+        let header = rotri!(Header::decode(&mut cursor));
+
+        let mut questions = Vec::with_capacity(header.qdcount.into());
+        for _ in 0..header.qdcount {
+            let question = rotri!(Question::decode(&mut cursor));
+            questions.push(question);
+        }
+
+        let mut answers = Vec::with_capacity(header.ancount.into());
+        for _ in 0..header.ancount {
+            let answer = rotri!(Record::decode(&mut cursor));
+            answers.push(answer);
+        }
+
+        let mut authorities = Vec::with_capacity(header.ncount.into());
+        for _ in 0..header.ncount {
+            let authority = rotri!(Record::decode(&mut cursor));
+            authorities.push(authority);
+        }
+
+        let mut additionals = Vec::with_capacity(header.arcount.into());
+        let mut edns = None;
+        for _ in 0..header.arcount {
+            if !rotri!(Edns::is_next(&mut cursor)) {
+                let additional = rotri!(Record::decode(&mut cursor));
+                additionals.push(additional);
+            } else if edns.is_none() {
+                edns = Some(rotri!(Edns::decode_record(&mut cursor)));
+            } else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Message has more than one OPT record"));
+            }
+        }
+        src.advance(cursor.position().try_into().unwrap());
+
+        let message = crate::Message {
+            header,
+            questions,
+            answers,
+            authorities,
+            additionals,
+            edns,
+        };
+
+        Ok(Some(message))
+    }
+}
+
+impl tokio_util::codec::Encoder<crate::Message> for MessageCodec {
+    type Error = io::Error;
+
+    /// Writes the header with its counts derived from the sections, whatever they were set to.
+    fn encode(&mut self, mut item: crate::Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        item.set_counts()?;
+        item.header.encode(dst)?;
+
+        for question in item.questions {
+            question.encode(dst)?;
+        }
+        let records = item
+            .answers
+            .into_iter()
+            .chain(item.authorities)
+            .chain(item.additionals);
+        for record in records {
+            record.encode(dst)?;
+        }
+        if let Some(edns) = item.edns {
+            edns.encode_record(dst)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio_util::{
+        bytes::BytesMut,
+        codec::{Decoder as _, Encoder as _},
+    };
+
+    #[test]
+    fn encode_roundtrip() {
+        let name: crate::Name = b"example.com".to_vec().try_into().unwrap();
+        let record = |kind, rdata| crate::Record {
+            name: name.clone(),
+            kind,
+            class: crate::Class::IN,
+            ttl: crate::Ttl::from_secs(3600),
+            length: 0,
+            rdata,
+        };
+
+        let answers = vec![
+            record(crate::Type::A, crate::RData::Ipv4([192, 0, 2, 1].into())),
+            record(
+                crate::Type::MX,
+                crate::RData::Mx(crate::Mx {
+                    preference: 10,
+                    exchange: b"mail.example.com".to_vec().try_into().unwrap(),
+                }),
+            ),
+            record(crate::Type::TXT, crate::RData::Txt(vec![b"v=spf1".to_vec(), b"-all".to_vec()])),
+        ];
+        let authorities = vec![record(
+            crate::Type::SOA,
+            crate::RData::Soa(crate::Soa {
+                mname: b"ns.example.com".to_vec().try_into().unwrap(),
+                rname: crate::Name::root(),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 900,
+                expire: 1209600,
+                minimum: 300,
+            }),
+        )];
+        let response = crate::Message {
+            header: crate::Header {
+                id: 0x1234,
+                flags: 0x8180,
+                qdcount: 1,
+                ancount: answers.len() as u16,
+                ncount: authorities.len() as u16,
+                arcount: 0,
+            },
+            questions: vec![crate::Question {
+                name: name.clone(),
+                kind: crate::QType::STAR,
+                class: crate::QClass::IN,
+            }],
+            answers,
+            authorities,
+            additionals: Vec::new(),
+            edns: None,
+        };
+
+        let mut encoded = BytesMut::new();
+        crate::MessageCodec.encode(response.clone(), &mut encoded).unwrap();
+        let decoded = crate::MessageCodec.decode(&mut encoded).unwrap().unwrap();
+        assert!(encoded.is_empty());
+
+        assert_eq!(decoded.header.flags, response.header.flags);
+        assert_eq!(decoded.questions, response.questions);
+        let rdata = |records: &[crate::Record]| records.iter().map(|r| r.rdata.clone()).collect::<Vec<_>>();
+        assert_eq!(rdata(&decoded.answers), rdata(&response.answers));
+        assert_eq!(rdata(&decoded.authorities), rdata(&response.authorities));
+        assert_eq!(decoded.answers[1].length, 20);
+    }
+
+    #[test]
+    fn edns_roundtrip() {
+        let mut query = crate::Message::query("example.com", crate::QType::A)
+            .id(0x4321)
+            .recursion_desired(true)
+            .additional(crate::Record {
+                name: b"ns.example.com".to_vec().try_into().unwrap(),
+                kind: crate::Type::A,
+                class: crate::Class::IN,
+                ttl: crate::Ttl::from_secs(60),
+                length: 4,
+                rdata: crate::RData::Ipv4([192, 0, 2, 53].into()),
+            })
+            .build()
+            .unwrap();
+        query.edns = Some(crate::Edns {
+            options: vec![crate::EdnsOption::TcpKeepalive(None)],
+            ..Default::default()
+        });
+
+        let mut encoded = BytesMut::new();
+        crate::MessageCodec.encode(query.clone(), &mut encoded).unwrap();
+        assert_eq!(crate::Edns::find(&encoded).unwrap(), query.edns);
+        let decoded = crate::MessageCodec.decode(&mut encoded).unwrap().unwrap();
+        assert_eq!(decoded.header.arcount, 2);
+        assert_eq!(decoded.question(), query.question());
+        assert_eq!(decoded.additionals, query.additionals);
+        assert_eq!(decoded.edns, query.edns);
+
+        // A second OPT record is malformed
+        let mut encoded = BytesMut::new();
+        crate::MessageCodec.encode(query.clone(), &mut encoded).unwrap();
+        query.edns.unwrap().append_to(&mut encoded).unwrap();
+        assert!(crate::MessageCodec.decode(&mut encoded).is_err());
+    }
+
+    #[test]
+    fn builder() {
+        let query = crate::Message::query("example.com", crate::QType::MX)
+            .id(0x0102)
+            .recursion_desired(true)
+            .edns(4096)
+            .build()
+            .unwrap();
+        assert_eq!(query.header.id, 0x0102);
+        assert!(query.header.recursion_desired() && !query.header.is_response());
+        assert_eq!(query.header.opcode(), Some(crate::Opcode::QUERY));
+        assert_eq!((query.header.qdcount, query.header.arcount), (1, 1));
+        assert_eq!(query.edns.as_ref().unwrap().udp_payload_size, 4096);
+
+        let answer = crate::Record {
+            name: query.questions[0].name.clone(),
+            kind: crate::Type::MX,
+            class: crate::Class::IN,
+            ttl: crate::Ttl::from_secs(300),
+            length: 0,
+            rdata: crate::RData::Mx(crate::Mx {
+                preference: 10,
+                exchange: b"mail.example.com".to_vec().try_into().unwrap(),
+            }),
+        };
+        let response = query
+            .response()
+            .recursion_available(true)
+            .answer(answer.clone())
+            .build()
+            .unwrap();
+        assert_eq!(response.header.id, 0x0102);
+        assert!(response.header.is_response() && response.header.recursion_desired());
+        assert_eq!(response.header.rcode(), Some(crate::Rcode::NOERROR));
+        assert_eq!(response.questions, query.questions);
+        assert_eq!(response.answers, vec![answer]);
+        assert_eq!(response.edns, None);
+        assert_eq!((response.header.qdcount, response.header.ancount, response.header.arcount), (1, 1, 0));
+
+        // Counts are derived from the sections when encoding, whatever the header says
+        let mut stale = response.clone();
+        stale.answers.clear();
+        let mut encoded = BytesMut::new();
+        crate::MessageCodec.encode(stale, &mut encoded).unwrap();
+        assert_eq!(&encoded[4..12], &[0, 1, 0, 0, 0, 0, 0, 0]);
+
+        let error = crate::Message::query("exämple.com", crate::QType::A).build().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn compression_loops() {
+        // The question name points to itself
+        let mut looping = BytesMut::from(&b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\xc0\x0c\x00\x01\x00\x01"[..]);
+        let error = crate::MessageCodec.decode(&mut looping).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        // The question points forward, to the answer which points back to it
+        let mut message = b"\x12\x34\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00".to_vec();
+        message.extend(b"\x01a\xc0\x14\x00\x01\x00\x01");
+        message.extend(b"\x01b\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\xc0\x00\x02\x01");
+        let error = crate::MessageCodec.decode(&mut BytesMut::from(&message[..])).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        // Pointers to earlier names are followed
+        message[14..16].copy_from_slice(&[0, 0]);
+        message.remove(15);
+        let decoded = crate::MessageCodec.decode(&mut BytesMut::from(&message[..])).unwrap().unwrap();
+        assert_eq!(decoded.answers[0].name, "b.a".try_into().unwrap());
+    }
+}
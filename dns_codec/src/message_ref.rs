@@ -0,0 +1,608 @@
+//! Borrowed views of an encoded message, for inspecting packets without copying them.
+//!
+//! Sections are walked lazily, names are compared on the wire and RDATA is decoded only when asked for;
+//! [`Message::try_from`] turns a view into the owned representation.
+
+use std::io;
+
+use byteorder::{NetworkEndian, ReadBytesExt as _};
+
+use crate::{
+    Class, Edns, Header, Message, Name, QClass, QType, Question, RData, Record, Ttl, Type,
+};
+
+/// Type of the OPT pseudo-record, see [`Edns`].
+const OPT: u16 = 41;
+
+/// Longest name on the wire, including the length octets (RFC 1035, section 2.3.4).
+const MAX_NAME_LENGTH: usize = 255;
+
+fn truncated<T>(item: Option<T>) -> io::Result<T> {
+    item.ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Message is truncated"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+fn cursor_at(message: &[u8], position: usize) -> io::Cursor<&[u8]> {
+    let mut cursor = io::Cursor::new(message);
+    cursor.set_position(position as u64);
+    cursor
+}
+
+/// Reads the RDLENGTH at the position of `cursor`, returning the offsets of the RDATA and of its end.
+fn rdata_range(cursor: &mut io::Cursor<&[u8]>) -> io::Result<(usize, usize)> {
+    let length = usize::from(cursor.read_u16::<NetworkEndian>()?);
+    let start = cursor.position() as usize;
+    if start + length > cursor.get_ref().len() {
+        return truncated(None);
+    }
+    Ok((start, start + length))
+}
+
+/// A DNS message borrowed from its wire format.
+///
+/// [`MessageRef::parse`] checks the structure of the whole message once, so that its sections can then be
+/// iterated without errors; only decoding RDATA or EDNS can still fail.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageRef<'a> {
+    message: &'a [u8],
+    header: Header,
+    /// Offsets of the answer, authority and additional sections.
+    sections: [usize; 3],
+    /// Offsets of the OPT record and of its end, which the additional section skips over.
+    opt: Option<(usize, usize)>,
+}
+
+impl<'a> MessageRef<'a> {
+    /// Checks that `message` is well formed, without copying any of it. Bytes after the last record are ignored.
+    ///
+    /// Fails with [`io::ErrorKind::UnexpectedEof`] if the message is truncated, and [`io::ErrorKind::InvalidData`]
+    /// for unknown types or classes, more than one OPT record, or compression pointers that do not point
+    /// to an earlier name.
+    pub fn parse(message: &'a [u8]) -> io::Result<Self> {
+        let header = truncated(Header::decode(&mut io::Cursor::new(message))?)?;
+        let mut position = 12;
+
+        for _ in 0..header.qdcount {
+            position = QuestionRef::parse(message, position)?.1;
+        }
+        let answers = position;
+        for _ in 0..header.ancount {
+            position = RecordRef::parse(message, position)?.1;
+        }
+        let authorities = position;
+        for _ in 0..header.ncount {
+            position = RecordRef::parse(message, position)?.1;
+        }
+        let additionals = position;
+
+        let mut opt = None;
+        for _ in 0..header.arcount {
+            let (_, end) = NameRef::parse(message, position)?;
+            let mut cursor = cursor_at(message, end);
+            if cursor.read_u16::<NetworkEndian>()? != OPT {
+                position = RecordRef::parse(message, position)?.1;
+                continue;
+            }
+            if opt.is_some() {
+                return Err(invalid("Message has more than one OPT record"));
+            }
+            cursor.set_position(cursor.position() + 6);
+            let (_, end) = rdata_range(&mut cursor)?;
+            opt = Some((position, end));
+            position = end;
+        }
+
+        Ok(MessageRef {
+            message,
+            header,
+            sections: [answers, authorities, additionals],
+            opt,
+        })
+    }
+
+    pub fn header(&self) -> Header {
+        self.header
+    }
+
+    pub fn questions(&self) -> Questions<'a> {
+        Questions {
+            message: self.message,
+            position: 12,
+            remaining: self.header.qdcount,
+        }
+    }
+
+    /// The first question, which is the only one of almost every message.
+    pub fn question(&self) -> Option<QuestionRef<'a>> {
+        self.questions().next()
+    }
+
+    pub fn answers(&self) -> Records<'a> {
+        self.records(0, self.header.ancount)
+    }
+
+    pub fn authorities(&self) -> Records<'a> {
+        self.records(1, self.header.ncount)
+    }
+
+    /// Records of the additional section, except for the OPT record decoded by [`MessageRef::edns`].
+    pub fn additionals(&self) -> Records<'a> {
+        self.records(2, self.header.arcount - u16::from(self.opt.is_some()))
+    }
+
+    fn records(&self, section: usize, count: u16) -> Records<'a> {
+        Records {
+            message: self.message,
+            position: self.sections[section],
+            remaining: count,
+            opt: self.opt,
+        }
+    }
+
+    /// Decodes the OPT record, if there is one.
+    pub fn edns(&self) -> io::Result<Option<Edns>> {
+        let Some((start, _)) = self.opt else {
+            return Ok(None);
+        };
+        truncated(Edns::decode_record(&mut cursor_at(self.message, start))?).map(Some)
+    }
+}
+
+/// Decodes every section, copying names and RDATA.
+impl TryFrom<MessageRef<'_>> for Message {
+    type Error = io::Error;
+
+    fn try_from(message: MessageRef<'_>) -> Result<Self, Self::Error> {
+        let records = |records: Records<'_>| {
+            records
+                .map(Record::try_from)
+                .collect::<io::Result<Vec<_>>>()
+        };
+
+        Ok(Message {
+            header: message.header,
+            questions: message.questions().map(Question::from).collect(),
+            answers: records(message.answers())?,
+            authorities: records(message.authorities())?,
+            additionals: records(message.additionals())?,
+            edns: message.edns()?,
+        })
+    }
+}
+
+/// Iterator over the questions of a [`MessageRef`].
+#[derive(Debug, Clone)]
+pub struct Questions<'a> {
+    message: &'a [u8],
+    position: usize,
+    remaining: u16,
+}
+
+impl<'a> Iterator for Questions<'a> {
+    type Item = QuestionRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.remaining = self.remaining.checked_sub(1)?;
+        let (question, end) = QuestionRef::parse(self.message, self.position)
+            .expect("questions are checked by MessageRef::parse");
+        self.position = end;
+        Some(question)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining.into(), Some(self.remaining.into()))
+    }
+}
+
+impl ExactSizeIterator for Questions<'_> {}
+
+/// Iterator over the records of a section of a [`MessageRef`].
+#[derive(Debug, Clone)]
+pub struct Records<'a> {
+    message: &'a [u8],
+    position: usize,
+    remaining: u16,
+    opt: Option<(usize, usize)>,
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = RecordRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.remaining = self.remaining.checked_sub(1)?;
+        if let Some((_, end)) = self.opt.filter(|(start, _)| *start == self.position) {
+            self.position = end;
+        }
+        let (record, end) = RecordRef::parse(self.message, self.position)
+            .expect("records are checked by MessageRef::parse");
+        self.position = end;
+        Some(record)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining.into(), Some(self.remaining.into()))
+    }
+}
+
+impl ExactSizeIterator for Records<'_> {}
+
+/// A question borrowed from the wire format of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuestionRef<'a> {
+    pub name: NameRef<'a>,
+    pub kind: QType,
+    pub class: QClass,
+}
+
+impl<'a> QuestionRef<'a> {
+    /// The question at `position`, along with the offset of its end.
+    fn parse(message: &'a [u8], position: usize) -> io::Result<(Self, usize)> {
+        let (name, end) = NameRef::parse(message, position)?;
+        let mut cursor = cursor_at(message, end);
+        let kind = truncated(QType::decode(&mut cursor)?)?;
+        let class = truncated(QClass::decode(&mut cursor)?)?;
+
+        let question = QuestionRef { name, kind, class };
+        Ok((question, cursor.position() as usize))
+    }
+}
+
+impl From<QuestionRef<'_>> for Question {
+    fn from(question: QuestionRef<'_>) -> Self {
+        Question {
+            name: question.name.into(),
+            kind: question.kind,
+            class: question.class,
+        }
+    }
+}
+
+/// A resource record borrowed from the wire format of a message, whose RDATA is decoded on demand.
+#[derive(Debug, Clone, Copy)]
+pub struct RecordRef<'a> {
+    message: &'a [u8],
+    pub name: NameRef<'a>,
+    pub kind: Type,
+    pub class: Class,
+    pub ttl: Ttl,
+    /// Offsets of the RDATA and of its end.
+    rdata: (usize, usize),
+}
+
+impl<'a> RecordRef<'a> {
+    /// The record at `position`, along with the offset of its end.
+    fn parse(message: &'a [u8], position: usize) -> io::Result<(Self, usize)> {
+        let (name, end) = NameRef::parse(message, position)?;
+        let mut cursor = cursor_at(message, end);
+        let kind = truncated(Type::decode(&mut cursor)?)?;
+        let class = truncated(Class::decode(&mut cursor)?)?;
+        let ttl = truncated(Ttl::decode(&mut cursor)?)?;
+        let rdata = rdata_range(&mut cursor)?;
+
+        let record = RecordRef {
+            message,
+            name,
+            kind,
+            class,
+            ttl,
+            rdata,
+        };
+        Ok((record, rdata.1))
+    }
+
+    /// The RDATA as it is on the wire. Names in it may be compressed, pointing elsewhere in the message.
+    pub fn rdata_bytes(&self) -> &'a [u8] {
+        &self.message[self.rdata.0..self.rdata.1]
+    }
+
+    /// Decodes the RDATA, expanding the names it contains.
+    ///
+    /// Names in RDATA are not checked by [`MessageRef::parse`], so this fails on malformed ones,
+    /// including compression pointers that do not point to an earlier name.
+    pub fn rdata(&self) -> io::Result<RData> {
+        let (start, end) = self.rdata;
+        let mut cursor = cursor_at(self.message, start);
+        // The length was checked to fit in RDLENGTH by `RecordRef::parse`
+        let length = (end - start) as u16;
+        truncated(RData::decode(&mut cursor, length, self.kind, self.class)?)
+    }
+}
+
+impl TryFrom<RecordRef<'_>> for Record {
+    type Error = io::Error;
+
+    fn try_from(record: RecordRef<'_>) -> Result<Self, Self::Error> {
+        Ok(Record {
+            name: record.name.into(),
+            kind: record.kind,
+            class: record.class,
+            ttl: record.ttl,
+            length: record.rdata_bytes().len() as u16,
+            rdata: record.rdata()?,
+        })
+    }
+}
+
+/// A domain name borrowed from the wire format of a message, following compression pointers as it is walked.
+///
+/// Like [`Name`], it compares case-insensitively, also against owned names.
+#[derive(Clone, Copy)]
+pub struct NameRef<'a> {
+    message: &'a [u8],
+    start: usize,
+}
+
+impl<'a> NameRef<'a> {
+    /// Checks the name at `start`, returning it along with the offset just past it.
+    ///
+    /// Every compression pointer has to point before the previous one, which rules out loops.
+    fn parse(message: &'a [u8], start: usize) -> io::Result<(Self, usize)> {
+        let mut position = start;
+        let mut limit = start;
+        let mut end = None;
+        let mut length = 1;
+        loop {
+            let label = usize::from(*truncated(message.get(position))?);
+            match label & 0b1100_0000 {
+                0 if label == 0 => break,
+                0 => {
+                    length += 1 + label;
+                    if length > MAX_NAME_LENGTH {
+                        return Err(invalid("Name is longer than 255 bytes"));
+                    }
+                    position += 1 + label;
+                }
+                0b1100_0000 => {
+                    let low = usize::from(*truncated(message.get(position + 1))?);
+                    let pointer = (label & 0b0011_1111) << 8 | low;
+                    if pointer >= limit {
+                        return Err(invalid(
+                            "Name compression pointer does not point to an earlier name",
+                        ));
+                    }
+                    end.get_or_insert(position + 2);
+                    limit = pointer;
+                    position = pointer;
+                }
+                _ => return Err(invalid("Name has a label of unknown type")),
+            }
+        }
+
+        let name = NameRef { message, start };
+        Ok((name, end.unwrap_or(position + 1)))
+    }
+
+    /// The labels of this name, from the leftmost (most specific) to the rightmost.
+    /// The root name has no labels.
+    pub fn labels(&self) -> Labels<'a> {
+        Labels {
+            message: self.message,
+            position: self.start,
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.labels().next().is_none()
+    }
+}
+
+/// Iterator over the labels of a [`NameRef`].
+#[derive(Debug, Clone)]
+pub struct Labels<'a> {
+    message: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Names are checked by `NameRef::parse`, so every offset is within the message
+        loop {
+            let label = usize::from(self.message[self.position]);
+            if label == 0 {
+                return None;
+            }
+            if label & 0b1100_0000 == 0 {
+                let start = self.position + 1;
+                self.position = start + label;
+                return Some(&self.message[start..self.position]);
+            }
+            self.position =
+                (label & 0b0011_1111) << 8 | usize::from(self.message[self.position + 1]);
+        }
+    }
+}
+
+impl PartialEq for NameRef<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        let mut labels = other.labels();
+        self.labels().all(|label| {
+            labels
+                .next()
+                .is_some_and(|other| label.eq_ignore_ascii_case(other))
+        }) && labels.next().is_none()
+    }
+}
+
+impl Eq for NameRef<'_> {}
+
+impl PartialEq<Name> for NameRef<'_> {
+    fn eq(&self, other: &Name) -> bool {
+        let mut labels = self.labels();
+        other.labels().all(|label| {
+            labels
+                .next()
+                .is_some_and(|own| own.eq_ignore_ascii_case(label))
+        }) && labels.next().is_none()
+    }
+}
+
+impl From<NameRef<'_>> for Name {
+    fn from(name: NameRef<'_>) -> Self {
+        let mut expanded = Vec::new();
+        for label in name.labels() {
+            if !expanded.is_empty() {
+                expanded.push(b'.');
+            }
+            expanded.extend_from_slice(label);
+        }
+        Name(expanded)
+    }
+}
+
+impl std::fmt::Display for NameRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_root() {
+            return f.write_str(".");
+        }
+        for (index, label) in self.labels().enumerate() {
+            if index > 0 {
+                f.write_str(".")?;
+            }
+            f.write_str(&String::from_utf8_lossy(label))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for NameRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("NameRef").field(&self.to_string()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use tokio_util::{
+        bytes::BytesMut,
+        codec::{Decoder as _, Encoder as _},
+    };
+
+    use super::MessageRef;
+
+    /// A response for the MX records of example.com, with every other name compressed.
+    fn compressed() -> Vec<u8> {
+        let mut message = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 1];
+        // example.com MX IN, at offset 12
+        message.extend(b"\x07example\x03com\x00\x00\x0f\x00\x01");
+        // example.com MX 10 mail.example.com, at offset 29
+        message.extend(b"\xc0\x0c\x00\x0f\x00\x01\x00\x00\x0e\x10\x00\x09\x00\x0a\x04mail\xc0\x0c");
+        // example.com MX 20 mx2.EXAMPLE.com
+        message.extend(b"\xc0\x0c\x00\x0f\x00\x01\x00\x00\x0e\x10\x00\x13\x00\x14\x03mx2\x07EXAMPLE\x03com\x00");
+        // mail.example.com A 192.0.2.25, owned by the exchange of the first answer at offset 43
+        message.extend(b"\xc0\x2b\x00\x01\x00\x01\x00\x00\x0e\x10\x00\x04\xc0\x00\x02\x19");
+        message
+    }
+
+    #[test]
+    fn borrowed() {
+        let bytes = compressed();
+        let message = MessageRef::parse(&bytes).unwrap();
+        assert_eq!(message.header().id, 0x1234);
+
+        let example: crate::Name = "example.com".try_into().unwrap();
+        let question = message.question().unwrap();
+        assert_eq!(question.name, example);
+        assert_eq!(question.kind, crate::QType::MX);
+
+        let answers: Vec<_> = message.answers().collect();
+        assert_eq!(answers.len(), 2);
+        assert!(answers.iter().all(|answer| answer.name == question.name));
+        assert_eq!(answers[0].rdata_bytes(), b"\x00\x0a\x04mail\xc0\x0c");
+        assert_eq!(
+            answers[1].rdata().unwrap(),
+            crate::RData::Mx(crate::Mx {
+                preference: 20,
+                exchange: "mx2.example.com".try_into().unwrap(),
+            })
+        );
+        assert_eq!(message.authorities().len(), 0);
+
+        let additional = message.additionals().next().unwrap();
+        assert_eq!(additional.name.to_string(), "mail.example.com");
+        assert_eq!(
+            additional.name.labels().collect::<Vec<_>>(),
+            [&b"mail"[..], b"example", b"com"]
+        );
+        assert_eq!(
+            additional.rdata().unwrap(),
+            crate::RData::Ipv4([192, 0, 2, 25].into())
+        );
+        assert_eq!(message.edns().unwrap(), None);
+
+        // The owned message is the same as the one decoded directly
+        let owned = crate::Message::try_from(message).unwrap();
+        let decoded = crate::MessageCodec
+            .decode(&mut BytesMut::from(&bytes[..]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(owned, decoded);
+    }
+
+    #[test]
+    fn edns() {
+        let query = crate::Message::query("example.com", crate::QType::A)
+            .additional(crate::Record {
+                name: "ns.example.com".try_into().unwrap(),
+                kind: crate::Type::A,
+                class: crate::Class::IN,
+                ttl: crate::Ttl::from_secs(60),
+                length: 4,
+                rdata: crate::RData::Ipv4([192, 0, 2, 53].into()),
+            })
+            .edns(4096)
+            .build()
+            .unwrap();
+        let mut bytes = BytesMut::new();
+        crate::MessageCodec
+            .encode(query.clone(), &mut bytes)
+            .unwrap();
+
+        let message = MessageRef::parse(&bytes).unwrap();
+        assert_eq!(message.additionals().len(), 1);
+        assert_eq!(message.edns().unwrap(), query.edns);
+        assert_eq!(crate::Message::try_from(message).unwrap(), query);
+
+        // A second OPT record is malformed
+        query.edns.unwrap().append_to(&mut bytes).unwrap();
+        let error = MessageRef::parse(&bytes).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn malformed() {
+        let bytes = compressed();
+        for length in [5, 20, 40, bytes.len() - 1] {
+            let error = MessageRef::parse(&bytes[..length]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof, "{length}");
+        }
+
+        // Pointers have to point to an earlier name, or they could loop
+        let mut looping = bytes.clone();
+        looping[29..31].copy_from_slice(&[0xc0, 29]);
+        let error = MessageRef::parse(&looping).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // Names in RDATA are only checked when decoded, which fails rather than looping
+        let mut looping = bytes.clone();
+        looping[68..70].copy_from_slice(&[0xc0, 64]);
+        let message = MessageRef::parse(&looping).unwrap();
+        let answer = message.answers().nth(1).unwrap();
+        assert_eq!(
+            answer.rdata().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // The types of records are still checked
+        let mut unknown = bytes;
+        unknown[31..33].copy_from_slice(&[0xff, 0xfe]);
+        let error = MessageRef::parse(&unknown).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
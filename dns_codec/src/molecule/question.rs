@@ -18,7 +18,7 @@ pub struct Question {
 }
 
 impl Question {
-    pub(crate) fn decode<'a>(src: &mut io::Cursor<&'a [u8]>) -> Result<Option<Self>, io::Error> {
+    pub(crate) fn decode(src: &mut io::Cursor<&[u8]>) -> Result<Option<Self>, io::Error> {
         let name = rotri!(Name::decode(src));
         let kind = rotri!(QType::decode(src));
         let class = rotri!(QClass::decode(src));
@@ -1,13 +1,15 @@
 use std::io;
 
-use byteorder::{NetworkEndian, ReadBytesExt};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt as _};
+use bytes::BufMut as _;
+use tokio_util::bytes::BytesMut;
 
 use crate::{
     atom::{rotri, rtri},
     Class, Name, QType, RData, Ttl, Type,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Record {
     /// A domain name to which this resource record pertains.
     pub name: Name,
@@ -32,7 +34,7 @@ pub struct Record {
 }
 
 impl Record {
-    pub(crate) fn decode<'a>(src: &mut io::Cursor<&'a [u8]>) -> Result<Option<Self>, io::Error> {
+    pub(crate) fn decode(src: &mut io::Cursor<&[u8]>) -> Result<Option<Self>, io::Error> {
         let name = rotri!(Name::decode(src));
         log::trace!("{name:?}");
         
@@ -58,6 +60,23 @@ impl Record {
         };
         Ok(Some(record))
     }
+
+    /// Writes the record, deriving RDLENGTH from the encoded [`RData`] rather than trusting [`Record::length`].
+    pub(crate) fn encode(self, dst: &mut BytesMut) -> Result<(), io::Error> {
+        self.name.encode(dst)?;
+        self.kind.encode(dst)?;
+        self.class.encode(dst)?;
+        self.ttl.encode(dst)?;
+
+        let mut rdata = BytesMut::new();
+        self.rdata.encode(&mut rdata)?;
+        let length = u16::try_from(rdata.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "RDATA exceeds 65535 bytes"))?;
+        dst.writer().write_u16::<NetworkEndian>(length)?;
+        dst.extend_from_slice(&rdata);
+
+        Ok(())
+    }
 }
 
 impl PartialEq<QType> for crate::Type {
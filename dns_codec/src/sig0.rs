@@ -0,0 +1,528 @@
+//! Public-key transaction signatures, SIG(0) (RFC 2931): authenticating requests
+//! without sharing a secret, as the verifier only needs the signer's public key.
+//!
+//! Keys are read from the files `dnssec-keygen -T KEY` writes: the public key as a KEY record
+//! in a `.key` file, and the private key in a `.private` file.
+//! Supported are ECDSA P-256 and P-384 (RFC 6605) and Ed25519 (RFC 8080).
+
+use std::{
+    io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::Engine as _;
+use num_enum::TryFromPrimitive;
+use ring::signature::{self, KeyPair as _};
+use tokio_util::bytes::BytesMut;
+
+use crate::{Class, Key, Name, RData, Record, Sig, Ttl, Type};
+
+/// Seconds before and after signing that a signature is valid for, allowing for clock differences.
+pub const VALIDITY: u32 = 300;
+
+/// Flags of KEY records of hosts and users (RFC 2535, section 3.1.2), as `dnssec-keygen -n HOST` sets them.
+const HOST_FLAGS: u16 = 0x0200;
+
+/// Protocol field of KEY records used with DNS.
+const PROTOCOL_DNSSEC: u8 = 3;
+
+/// The signature algorithms supported, by their DNSSEC algorithm numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Algorithm {
+    EcdsaP256Sha256 = 13,
+    EcdsaP384Sha384 = 14,
+    Ed25519 = 15,
+}
+
+impl Algorithm {
+    /// The signing and verification algorithms of ECDSA keys; `None` for Ed25519.
+    fn ecdsa(
+        self,
+    ) -> Option<(
+        &'static signature::EcdsaSigningAlgorithm,
+        &'static signature::EcdsaVerificationAlgorithm,
+    )> {
+        match self {
+            Algorithm::EcdsaP256Sha256 => Some((
+                &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+                &signature::ECDSA_P256_SHA256_FIXED,
+            )),
+            Algorithm::EcdsaP384Sha384 => Some((
+                &signature::ECDSA_P384_SHA384_FIXED_SIGNING,
+                &signature::ECDSA_P384_SHA384_FIXED,
+            )),
+            Algorithm::Ed25519 => None,
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn denied(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, message)
+}
+
+/// Seconds since the UNIX epoch, modulo 2^32 as in SIG records.
+fn seconds(now: SystemTime) -> u32 {
+    now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as u32
+}
+
+/// A public key that signatures of `name` are verified with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey {
+    pub name: Name,
+    pub algorithm: Algorithm,
+    key: Key,
+}
+
+impl PublicKey {
+    /// The key of a KEY `record`.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] for other records, and keys of unsupported algorithms.
+    pub fn from_record(record: &Record) -> io::Result<Self> {
+        let RData::Key(key) = &record.rdata else {
+            return Err(invalid(format!(
+                "{} {:?} is not a KEY record",
+                record.name, record.kind
+            )));
+        };
+        let algorithm = Algorithm::try_from(key.algorithm).map_err(|_| {
+            invalid(format!(
+                "Key of {} uses unsupported algorithm {}",
+                record.name, key.algorithm
+            ))
+        })?;
+        Ok(PublicKey {
+            name: record.name.clone(),
+            algorithm,
+            key: key.clone(),
+        })
+    }
+
+    /// The KEY record to publish this key as.
+    pub fn record(&self) -> Record {
+        Record {
+            name: self.name.clone(),
+            kind: Type::KEY,
+            class: Class::IN,
+            ttl: Ttl::from_secs(3600),
+            length: 0,
+            rdata: RData::Key(self.key.clone()),
+        }
+    }
+
+    /// Reads the KEY record of a `.key` file, in the zone file format:
+    /// `host.example.com. IN KEY 512 3 15 <base64>`, possibly preceded by comments.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let tokens: Vec<&str> = text
+            .lines()
+            .map(|line| line.split(';').next().unwrap_or_default())
+            .flat_map(str::split_whitespace)
+            .filter(|token| !matches!(*token, "(" | ")"))
+            .collect();
+        let Some(kind) = tokens
+            .iter()
+            .position(|token| token.eq_ignore_ascii_case("KEY"))
+        else {
+            return Err(invalid("No KEY record found".to_string()));
+        };
+        let (Some(owner), Some([flags, protocol, algorithm])) =
+            (tokens.first(), tokens.get(kind + 1..kind + 4))
+        else {
+            return Err(invalid("KEY record is incomplete".to_string()));
+        };
+
+        let number = |field: &str| {
+            field
+                .parse()
+                .map_err(|_| invalid(format!("Invalid field of KEY record: {field}")))
+        };
+        let public_key = base64::engine::general_purpose::STANDARD
+            .decode(tokens[kind + 4..].concat())
+            .map_err(|e| invalid(format!("Public key is not valid base64: {e}")))?;
+        let record = Record {
+            name: owner.trim_end_matches('.').as_bytes().to_vec().try_into()?,
+            kind: Type::KEY,
+            class: Class::IN,
+            ttl: Ttl::from_secs(0),
+            length: 0,
+            rdata: RData::Key(Key {
+                flags: number(flags)?,
+                protocol: number(protocol)? as u8,
+                algorithm: number(algorithm)? as u8,
+                public_key,
+            }),
+        };
+        PublicKey::from_record(&record)
+    }
+
+    /// Reads the `.key` file at `path`, see [`PublicKey::parse`].
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        PublicKey::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Identifies this key among others of the same name (RFC 4034, appendix B).
+    pub fn key_tag(&self) -> u16 {
+        let mut rdata = BytesMut::new();
+        RData::Key(self.key.clone())
+            .encode(&mut rdata)
+            .expect("writing to memory does not fail");
+
+        let mut sum: u32 = 0;
+        for (i, byte) in rdata.iter().enumerate() {
+            sum += match i % 2 {
+                0 => u32::from(*byte) << 8,
+                _ => u32::from(*byte),
+            };
+        }
+        sum += (sum >> 16) & 0xffff;
+        sum as u16
+    }
+
+    fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        let public_key = &self.key.public_key;
+        match self.algorithm.ecdsa() {
+            // Uncompressed points, as ring expects them, are prefixed with 4
+            Some((_, algorithm)) => {
+                let point = [&[4], public_key.as_slice()].concat();
+                signature::UnparsedPublicKey::new(algorithm, point)
+                    .verify(data, signature)
+                    .is_ok()
+            }
+            None => signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+                .verify(data, signature)
+                .is_ok(),
+        }
+    }
+}
+
+enum KeyPair {
+    Ecdsa(signature::EcdsaKeyPair),
+    Ed25519(signature::Ed25519KeyPair),
+}
+
+/// A private key to sign requests with, and its public counterpart.
+pub struct SigningKey {
+    public: PublicKey,
+    pair: KeyPair,
+}
+
+impl std::fmt::Debug for SigningKey {
+    // Keep the private key out of logs
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SigningKey {
+    /// Reads a key pair from the contents of its `.key` file, `public`, and of its `.private` file, `private`.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] if the files are malformed or do not belong together.
+    pub fn parse(public: &str, private: &str) -> io::Result<Self> {
+        let public = PublicKey::parse(public)?;
+        let field = |name: &str| {
+            private
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .map(str::trim)
+                .ok_or_else(|| invalid(format!("Private key file lacks the {name} field")))
+        };
+
+        let algorithm = field("Algorithm")?;
+        let number = algorithm.split_whitespace().next().unwrap_or_default();
+        if number.parse() != Ok(public.algorithm as u8) {
+            return Err(invalid(format!(
+                "Private key is for algorithm {algorithm}, public key for {:?}",
+                public.algorithm
+            )));
+        }
+        let private_key = base64::engine::general_purpose::STANDARD
+            .decode(field("PrivateKey")?)
+            .map_err(|e| invalid(format!("Private key is not valid base64: {e}")))?;
+
+        let public_key = &public.key.public_key;
+        let mismatch = |_| {
+            invalid(format!(
+                "Private key does not match the public key of {}",
+                public.name
+            ))
+        };
+        let pair = match public.algorithm.ecdsa() {
+            Some((algorithm, _)) => {
+                let point = [&[4], public_key.as_slice()].concat();
+                let rng = ring::rand::SystemRandom::new();
+                signature::EcdsaKeyPair::from_private_key_and_public_key(
+                    algorithm,
+                    &private_key,
+                    &point,
+                    &rng,
+                )
+                .map(KeyPair::Ecdsa)
+                .map_err(mismatch)?
+            }
+            None => signature::Ed25519KeyPair::from_seed_and_public_key(&private_key, public_key)
+                .map(KeyPair::Ed25519)
+                .map_err(mismatch)?,
+        };
+        Ok(SigningKey { public, pair })
+    }
+
+    /// Reads a key pair from its `.key` and `.private` files, see [`SigningKey::parse`].
+    pub fn load(public: impl AsRef<Path>, private: impl AsRef<Path>) -> io::Result<Self> {
+        SigningKey::parse(
+            &std::fs::read_to_string(public)?,
+            &std::fs::read_to_string(private)?,
+        )
+    }
+
+    /// A key pair of `name` from a PKCS#8 document, as `openssl genpkey` writes them (in DER).
+    pub fn from_pkcs8(name: Name, algorithm: Algorithm, pkcs8: &[u8]) -> io::Result<Self> {
+        let rejected = |e| invalid(format!("Invalid {algorithm:?} key: {e}"));
+        let (pair, public_key) = match algorithm.ecdsa() {
+            Some((signing, _)) => {
+                let rng = ring::rand::SystemRandom::new();
+                let pair =
+                    signature::EcdsaKeyPair::from_pkcs8(signing, pkcs8, &rng).map_err(rejected)?;
+                let public_key = pair.public_key().as_ref()[1..].to_vec();
+                (KeyPair::Ecdsa(pair), public_key)
+            }
+            None => {
+                let pair = signature::Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8)
+                    .map_err(rejected)?;
+                let public_key = pair.public_key().as_ref().to_vec();
+                (KeyPair::Ed25519(pair), public_key)
+            }
+        };
+        let public = PublicKey {
+            name,
+            algorithm,
+            key: Key {
+                flags: HOST_FLAGS,
+                protocol: PROTOCOL_DNSSEC,
+                algorithm: algorithm as u8,
+                public_key,
+            },
+        };
+        Ok(SigningKey { public, pair })
+    }
+
+    /// A new random key pair of `name`.
+    pub fn generate(name: Name, algorithm: Algorithm) -> io::Result<Self> {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = match algorithm.ecdsa() {
+            Some((signing, _)) => signature::EcdsaKeyPair::generate_pkcs8(signing, &rng),
+            None => signature::Ed25519KeyPair::generate_pkcs8(&rng),
+        };
+        let pkcs8 = pkcs8.map_err(|_| io::Error::other("Failed to generate a key pair"))?;
+        SigningKey::from_pkcs8(name, algorithm, pkcs8.as_ref())
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public
+    }
+
+    /// Signs the encoded `message`, appending a SIG record and counting it in the header.
+    pub fn sign(&self, message: &mut BytesMut, now: SystemTime) -> io::Result<()> {
+        let now = seconds(now);
+        let mut sig = Sig {
+            type_covered: 0,
+            algorithm: self.public.algorithm as u8,
+            labels: 0,
+            original_ttl: 0,
+            expiration: now.wrapping_add(VALIDITY),
+            inception: now.wrapping_sub(VALIDITY),
+            key_tag: self.public.key_tag(),
+            signer: self.public.name.clone(),
+            signature: Vec::new(),
+        };
+
+        let data = signed_data(&sig, message);
+        sig.signature = match &self.pair {
+            KeyPair::Ecdsa(pair) => pair
+                .sign(&ring::rand::SystemRandom::new(), &data)
+                .map_err(|_| io::Error::other("ECDSA signing failed"))?
+                .as_ref()
+                .to_vec(),
+            KeyPair::Ed25519(pair) => pair.sign(&data).as_ref().to_vec(),
+        };
+
+        let record = Record {
+            name: Name::root(),
+            kind: Type::SIG,
+            class: Class::STAR,
+            ttl: Ttl::from_secs(0),
+            length: 0,
+            rdata: RData::Sig(sig),
+        };
+        crate::signature::append(message, record)
+    }
+}
+
+/// What a SIG(0) signature covers (RFC 2931, section 3.1): the SIG RDATA up to the signature,
+/// then the message as it was before the SIG record was added.
+fn signed_data(sig: &Sig, unsigned: &[u8]) -> Vec<u8> {
+    let mut data = BytesMut::new();
+    sig.clone()
+        .encode_unsigned(&mut data)
+        .expect("writing to memory does not fail");
+    data.extend_from_slice(unsigned);
+    data.to_vec()
+}
+
+/// Verifies the SIG(0) signature `message` ends with against `keys`, returning the key it was made with.
+///
+/// Gives `None` if `message` is not signed with SIG(0) at all.
+/// Fails with [`io::ErrorKind::PermissionDenied`] if the signer's key is unknown,
+/// the signature does not match, or `now` lies outside of its validity.
+pub fn verify<'k>(
+    message: &[u8],
+    keys: &'k [PublicKey],
+    now: SystemTime,
+) -> io::Result<Option<&'k PublicKey>> {
+    let Some((header, start, record)) = crate::signature::last_record(message)? else {
+        return Ok(None);
+    };
+    let RData::Sig(sig) = record.rdata else {
+        return Ok(None);
+    };
+    if sig.type_covered != 0 || !record.name.0.is_empty() {
+        return Err(invalid(format!(
+            "SIG record of {} at the end of the message is not a SIG(0)",
+            record.name
+        )));
+    }
+
+    let Some(key) = keys.iter().find(|key| {
+        key.name == sig.signer
+            && key.algorithm as u8 == sig.algorithm
+            && key.key_tag() == sig.key_tag
+    }) else {
+        return Err(denied(format!(
+            "No key of {} with tag {} is known",
+            sig.signer, sig.key_tag
+        )));
+    };
+
+    let mut unsigned = message[..start].to_vec();
+    unsigned[10..12].copy_from_slice(&(header.arcount - 1).to_be_bytes());
+    if !key.verify(&signed_data(&sig, &unsigned), &sig.signature) {
+        return Err(denied(format!(
+            "Signature of {} does not match",
+            sig.signer
+        )));
+    }
+
+    // In serial number arithmetic, as the times wrap around in 2106
+    let now = seconds(now);
+    if (now.wrapping_sub(sig.inception) as i32) < 0 || (sig.expiration.wrapping_sub(now) as i32) < 0
+    {
+        return Err(denied(format!(
+            "Signature of {} is not valid at {now}",
+            sig.signer
+        )));
+    }
+    Ok(Some(key))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use ring::signature::KeyPair as _;
+    use tokio_util::{
+        bytes::BytesMut,
+        codec::{Decoder as _, Encoder as _},
+    };
+
+    use super::{Algorithm, PublicKey, SigningKey};
+
+    fn name(name: &str) -> crate::Name {
+        name.as_bytes().to_vec().try_into().unwrap()
+    }
+
+    fn update() -> BytesMut {
+        let update = crate::Update::builder(0x4321, name("example.com"))
+            .delete_name(name("host.example.com"))
+            .build();
+        let mut encoded = BytesMut::new();
+        crate::MessageCodec
+            .encode(update.into(), &mut encoded)
+            .unwrap();
+        encoded
+    }
+
+    /// An Ed25519 key pair of `host.example.com` as `dnssec-keygen` writes them.
+    fn ed25519_files(seed: [u8; 32]) -> (String, String) {
+        let pair = ring::signature::Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
+        let base64 = |bytes: &[u8]| {
+            use base64::Engine as _;
+            base64::engine::general_purpose::STANDARD.encode(bytes)
+        };
+        let public = format!(
+            "; This is a key to sign transactions of host.example.com.\n\
+             host.example.com. IN KEY 512 3 15 {}\n",
+            base64(pair.public_key().as_ref())
+        );
+        let private = format!(
+            "Private-key-format: v1.3\nAlgorithm: 15 (ED25519)\nPrivateKey: {}\n",
+            base64(&seed)
+        );
+        (public, private)
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let now = SystemTime::now();
+        let (public, private) = ed25519_files([7; 32]);
+        let ed25519 = SigningKey::parse(&public, &private).unwrap();
+        assert_eq!(ed25519.public_key(), &PublicKey::parse(&public).unwrap());
+
+        let ecdsa =
+            SigningKey::generate(name("ecdsa.example.com"), Algorithm::EcdsaP256Sha256).unwrap();
+        let p384 =
+            SigningKey::generate(name("ecdsa.example.com"), Algorithm::EcdsaP384Sha384).unwrap();
+        let keys = [
+            ed25519.public_key().clone(),
+            ecdsa.public_key().clone(),
+            p384.public_key().clone(),
+        ];
+
+        for key in [&ed25519, &ecdsa, &p384] {
+            let mut message = update();
+            key.sign(&mut message, now).unwrap();
+            let decoded = crate::MessageCodec
+                .decode(&mut message.clone())
+                .unwrap()
+                .unwrap();
+            assert_eq!(decoded.additionals[0].kind, crate::Type::SIG);
+
+            let signer = super::verify(&message, &keys, now).unwrap().unwrap();
+            assert_eq!(signer, key.public_key());
+
+            // Too late
+            let later = now + Duration::from_secs(2 * u64::from(super::VALIDITY));
+            assert!(super::verify(&message, &keys, later).is_err());
+            // Changed after signing
+            message[3] ^= 0x10;
+            let error = super::verify(&message, &keys, now).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        }
+
+        // Unknown keys, and unsigned messages
+        let mut message = update();
+        ecdsa.sign(&mut message, now).unwrap();
+        assert!(super::verify(&message, &keys[..1], now).is_err());
+        assert!(super::verify(&update(), &keys, now).unwrap().is_none());
+
+        // Private keys must match their public keys
+        let (_, other) = ed25519_files([8; 32]);
+        let error = SigningKey::parse(&public, &other).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,44 @@
+//! What transaction signatures of either kind (TSIG and SIG(0)) have in common:
+//! they are the last record of the message they cover.
+
+use std::io;
+
+use crate::{Header, Question, Record};
+
+/// The last additional record of `message`, the header of `message`,
+/// and the offset at which the record starts; `None` if there are no additional records.
+pub(crate) fn last_record(message: &[u8]) -> io::Result<Option<(Header, usize, Record)>> {
+    fn truncated<T>(item: Option<T>) -> io::Result<T> {
+        item.ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Message is truncated"))
+    }
+
+    let mut cursor = io::Cursor::new(message);
+    let header = truncated(Header::decode(&mut cursor)?)?;
+    if header.arcount == 0 {
+        return Ok(None);
+    }
+    for _ in 0..header.qdcount {
+        truncated(Question::decode(&mut cursor)?)?;
+    }
+    let records = usize::from(header.ancount) + usize::from(header.ncount);
+    for _ in 0..records + usize::from(header.arcount) - 1 {
+        truncated(Record::decode(&mut cursor)?)?;
+    }
+
+    let start = cursor.position() as usize;
+    let record = truncated(Record::decode(&mut cursor)?)?;
+    Ok(Some((header, start, record)))
+}
+
+/// Appends `record` to the additional section of `message`, counting it in the header.
+pub(crate) fn append(message: &mut tokio_util::bytes::BytesMut, record: Record) -> io::Result<()> {
+    if message.len() < 12 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Message has no header",
+        ));
+    }
+    let arcount = u16::from_be_bytes([message[10], message[11]]);
+    message[10..12].copy_from_slice(&(arcount + 1).to_be_bytes());
+    record.encode(message)
+}
@@ -0,0 +1,297 @@
+//! Transaction key negotiation, TKEY (RFC 2930): managing the shared secrets TSIG uses over DNS itself.
+//!
+//! Keys may be assigned by the resolver and deleted again; the other modes are answered with BADMODE.
+//! RFC 2930 has the resolver encrypt an assigned secret to a KEY of the server. Here it is sent as is,
+//! in a request signed with a key the server already knows, so assignments belong on confidential transports only.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    tsig::{Algorithm, Key, Keyring, Session, TsigError},
+    Class, Header, Message, Name, QClass, QType, Question, RData, Rcode, Record, Tkey, Ttl, Type,
+};
+
+/// How a TKEY request establishes or deletes a key (RFC 2930, section 2.5).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u16)]
+pub enum Mode {
+    ServerAssignment = 1,
+    DiffieHellman = 2,
+    GssApi = 3,
+    ResolverAssignment = 4,
+    Deletion = 5,
+}
+
+fn record(name: Name, tkey: Tkey) -> Record {
+    Record {
+        name,
+        kind: Type::TKEY,
+        class: Class::STAR,
+        ttl: Ttl::from_secs(0),
+        length: 0,
+        rdata: RData::Tkey(tkey),
+    }
+}
+
+fn seconds(time: SystemTime) -> u32 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32
+}
+
+/// A request to delete `key` from the server (RFC 2930, section 4.2), to be signed with `key` itself.
+pub fn deletion(id: u16, key: &Key, now: SystemTime) -> Message {
+    let now = seconds(now);
+    let tkey = Tkey {
+        algorithm: key.algorithm.name(),
+        inception: now,
+        expiration: now,
+        mode: Mode::Deletion as u16,
+        error: 0,
+        key: Vec::new(),
+        other: Vec::new(),
+    };
+    request(id, key, tkey)
+}
+
+/// A request to establish `key` with the server from `now` until `expiration` (RFC 2930, section 4.4),
+/// to be signed with another key the server already knows.
+pub fn assignment(id: u16, key: &Key, now: SystemTime, expiration: SystemTime) -> Message {
+    let tkey = Tkey {
+        algorithm: key.algorithm.name(),
+        inception: seconds(now),
+        expiration: seconds(expiration),
+        mode: Mode::ResolverAssignment as u16,
+        error: 0,
+        key: key.secret.clone(),
+        other: Vec::new(),
+    };
+    request(id, key, tkey)
+}
+
+fn request(id: u16, key: &Key, tkey: Tkey) -> Message {
+    Message {
+        header: Header {
+            id,
+            flags: 0,
+            qdcount: 1,
+            ancount: 0,
+            ncount: 0,
+            arcount: 1,
+        },
+        questions: vec![Question {
+            name: key.name.clone(),
+            kind: QType::TKEY,
+            class: QClass::STAR,
+        }],
+        answers: Vec::new(),
+        authorities: Vec::new(),
+        additionals: vec![record(key.name.clone(), tkey)],
+        edns: None,
+    }
+}
+
+/// Processes the TKEY `request`, authenticated by `session` if it was signed, against `keyring` at `now`.
+///
+/// Gives the response to send, which echoes the TKEY record with the outcome in its error field
+/// and without the key data, or FORMERR if `request` carries no TKEY record.
+pub fn respond(
+    request: &Message,
+    session: Option<&Session>,
+    keyring: &mut Keyring,
+    now: SystemTime,
+) -> Message {
+    let mut header = request.header;
+    header.set_response(true);
+    header.set_authoritative(false);
+    header.set_recursion_available(false);
+    header.ancount = 0;
+    header.ncount = 0;
+    header.arcount = 0;
+    let mut response = Message {
+        header,
+        questions: request.questions.clone(),
+        answers: Vec::new(),
+        authorities: Vec::new(),
+        additionals: Vec::new(),
+        edns: None,
+    };
+
+    let tkey = request.additionals.iter().find_map(|r| match &r.rdata {
+        RData::Tkey(tkey) => Some((&r.name, tkey)),
+        _ => None,
+    });
+    let Some((name, tkey)) = tkey else {
+        response.header.set_rcode(Rcode::FORMERR);
+        return response;
+    };
+
+    let error = match Mode::try_from(tkey.mode) {
+        // Only the key itself may delete it
+        Ok(Mode::Deletion) => match session {
+            Some(session) if session.key().name == *name => match keyring.remove(name) {
+                Some(_) => None,
+                None => Some(TsigError::BADNAME),
+            },
+            Some(_) | None => Some(TsigError::BADKEY),
+        },
+        Ok(Mode::ResolverAssignment) => assign(name, tkey, session, keyring, now).err(),
+        _ => Some(TsigError::BADMODE),
+    };
+
+    let answer = Tkey {
+        error: error.map_or(0, |error| error as u16),
+        key: Vec::new(),
+        ..tkey.clone()
+    };
+    response.header.set_rcode(Rcode::NOERROR);
+    response.header.qdcount = response.questions.len() as u16;
+    response.header.ancount = 1;
+    response.answers.push(record(name.clone(), answer));
+    response
+}
+
+/// Adds the key named `name` that `tkey` assigns to `keyring`, if the request was authenticated.
+/// Keys already known are not replaced, as that would let the holder of one key take over another.
+fn assign(
+    name: &Name,
+    tkey: &Tkey,
+    session: Option<&Session>,
+    keyring: &mut Keyring,
+    now: SystemTime,
+) -> Result<(), TsigError> {
+    if session.is_none() || tkey.key.is_empty() {
+        return Err(TsigError::BADKEY);
+    }
+    let algorithm = Algorithm::from_name(&tkey.algorithm).ok_or(TsigError::BADALG)?;
+    if !(tkey.inception..tkey.expiration).contains(&seconds(now)) {
+        return Err(TsigError::BADTIME);
+    }
+    if keyring.get(name).is_some() {
+        return Err(TsigError::BADNAME);
+    }
+
+    let expiration = UNIX_EPOCH + Duration::from_secs(u64::from(tkey.expiration));
+    keyring.insert(Key::new(name.clone(), algorithm, tkey.key.clone()).expiring(expiration));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use tokio_util::codec::Encoder as _;
+
+    use crate::tsig::{Algorithm, Key, Keyring, Session, TsigError};
+
+    fn key(name: &str) -> Key {
+        Key::new(
+            name.as_bytes().to_vec().try_into().unwrap(),
+            Algorithm::HmacSha256,
+            b"secret".to_vec(),
+        )
+    }
+
+    fn error(response: &crate::Message) -> u16 {
+        match &response.answers[0].rdata {
+            crate::RData::Tkey(tkey) => tkey.error,
+            rdata => panic!("not a TKEY: {rdata:?}"),
+        }
+    }
+
+    #[test]
+    fn deletion() {
+        let mut keyring = Keyring::new();
+        keyring.insert(key("a.example.com"));
+        keyring.insert(key("b.example.com"));
+        let request = super::deletion(7, &key("a.example.com"), SystemTime::now());
+
+        // Unsigned, or signed with another key
+        let now = SystemTime::now();
+        let response = super::respond(&request, None, &mut keyring, now);
+        assert_eq!(error(&response), TsigError::BADKEY as u16);
+        let other = Session::new(key("b.example.com"));
+        let response = super::respond(&request, Some(&other), &mut keyring, now);
+        assert_eq!(error(&response), TsigError::BADKEY as u16);
+
+        let session = Session::new(key("a.example.com"));
+        let response = super::respond(&request, Some(&session), &mut keyring, now);
+        assert_eq!(response.header.id, 7);
+        assert_eq!(error(&response), 0);
+        assert!(keyring.get(&key("a.example.com").name).is_none());
+        let response = super::respond(&request, Some(&session), &mut keyring, now);
+        assert_eq!(error(&response), TsigError::BADNAME as u16);
+
+        // Establishing keys is not supported
+        let mut request = request;
+        if let crate::RData::Tkey(tkey) = &mut request.additionals[0].rdata {
+            tkey.mode = super::Mode::DiffieHellman as u16;
+        }
+        let response = super::respond(&request, Some(&session), &mut keyring, now);
+        assert_eq!(error(&response), TsigError::BADMODE as u16);
+    }
+
+    #[test]
+    fn resolver_assignment() {
+        let mut keyring = Keyring::new();
+        keyring.insert(key("a.example.com"));
+        let session = Session::new(key("a.example.com"));
+        let now = SystemTime::now();
+        let expiration = now + Duration::from_secs(3600);
+        let assigned = Key::new(
+            b"new.example.com".to_vec().try_into().unwrap(),
+            Algorithm::HmacSha256,
+            b"assigned".to_vec(),
+        );
+        let request = super::assignment(9, &assigned, now, expiration);
+
+        // Only requests signed with a known key may assign one
+        let response = super::respond(&request, None, &mut keyring, now);
+        assert_eq!(error(&response), TsigError::BADKEY as u16);
+        let response = super::respond(&request, Some(&session), &mut keyring, now);
+        assert_eq!(error(&response), 0);
+        match &response.answers[0].rdata {
+            crate::RData::Tkey(tkey) => assert!(tkey.key.is_empty()),
+            rdata => panic!("not a TKEY: {rdata:?}"),
+        }
+
+        // Requests signed with the new key are accepted until it expires
+        let mut signed = tokio_util::bytes::BytesMut::new();
+        crate::MessageCodec
+            .encode(
+                crate::Message::query(b"example.com".to_vec(), crate::QType::A)
+                    .build()
+                    .unwrap(),
+                &mut signed,
+            )
+            .unwrap();
+        Session::new(assigned.clone())
+            .sign(&mut signed, now)
+            .unwrap();
+        assert!(crate::tsig::verify_request(&signed, &keyring, now)
+            .unwrap()
+            .is_some());
+        let later = expiration + Duration::from_secs(1);
+        let rejection = crate::tsig::verify_request(&signed, &keyring, later).unwrap_err();
+        assert_eq!(rejection.error, TsigError::BADKEY);
+
+        // Keys that are already known are not replaced
+        let response = super::respond(&request, Some(&session), &mut keyring, now);
+        assert_eq!(error(&response), TsigError::BADNAME as u16);
+        let takeover = super::assignment(10, &key("a.example.com"), now, expiration);
+        let response = super::respond(&takeover, Some(&session), &mut keyring, now);
+        assert_eq!(error(&response), TsigError::BADNAME as u16);
+
+        // Nor are keys accepted outside of their validity
+        let assigned = Key::new(
+            b"late.example.com".to_vec().try_into().unwrap(),
+            Algorithm::HmacSha256,
+            b"late".to_vec(),
+        );
+        let request = super::assignment(11, &assigned, now, expiration);
+        let response = super::respond(&request, Some(&session), &mut keyring, later);
+        assert_eq!(error(&response), TsigError::BADTIME as u16);
+    }
+}
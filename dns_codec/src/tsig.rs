@@ -0,0 +1,625 @@
+//! Transaction signatures (RFC 8945): authenticating messages with a secret shared by both ends.
+//!
+//! Signing and verification work on encoded messages, as the MAC covers their exact bytes.
+//! A [`Session`] covers one exchange: a request and the one or more responses to it,
+//! each of which is signed over the MAC of the message before it.
+
+use std::{
+    collections::HashMap,
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::BufMut as _;
+use hmac::Hmac;
+use num_enum::TryFromPrimitive;
+use tokio_util::bytes::BytesMut;
+
+use crate::{Class, Name, RData, Record, Tsig, Ttl, Type};
+
+/// Seconds of clock difference tolerated between signer and verifier, as RFC 8945 recommends.
+pub const FUDGE: u16 = 300;
+
+/// Unsigned messages accepted in a row in the middle of a stream of responses (RFC 8945, section 5.3.1).
+const MAX_UNSIGNED: usize = 99;
+
+/// The MAC algorithms supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Algorithm {
+    HmacSha256,
+    HmacSha384,
+    HmacSha512,
+}
+
+impl Algorithm {
+    /// The name identifying the algorithm in TSIG records.
+    pub fn name(self) -> Name {
+        let name: &[u8] = match self {
+            Algorithm::HmacSha256 => b"hmac-sha256",
+            Algorithm::HmacSha384 => b"hmac-sha384",
+            Algorithm::HmacSha512 => b"hmac-sha512",
+        };
+        Name(name.to_vec())
+    }
+
+    pub fn from_name(name: &Name) -> Option<Self> {
+        [
+            Algorithm::HmacSha256,
+            Algorithm::HmacSha384,
+            Algorithm::HmacSha512,
+        ]
+        .into_iter()
+        .find(|algorithm| algorithm.name() == *name)
+    }
+
+    fn mac(self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+        fn mac<M: hmac::Mac + hmac::digest::KeyInit>(secret: &[u8], data: &[u8]) -> Vec<u8> {
+            let mut mac =
+                <M as hmac::Mac>::new_from_slice(secret).expect("HMAC takes keys of any length");
+            mac.update(data);
+            mac.finalize().into_bytes().to_vec()
+        }
+        match self {
+            Algorithm::HmacSha256 => mac::<Hmac<sha2::Sha256>>(secret, data),
+            Algorithm::HmacSha384 => mac::<Hmac<sha2::Sha384>>(secret, data),
+            Algorithm::HmacSha512 => mac::<Hmac<sha2::Sha512>>(secret, data),
+        }
+    }
+
+    /// Whether `mac` is the MAC of `data`, compared in constant time.
+    fn verify(self, secret: &[u8], data: &[u8], mac: &[u8]) -> bool {
+        fn verify<M: hmac::Mac + hmac::digest::KeyInit>(
+            secret: &[u8],
+            data: &[u8],
+            expected: &[u8],
+        ) -> bool {
+            let mut mac =
+                <M as hmac::Mac>::new_from_slice(secret).expect("HMAC takes keys of any length");
+            mac.update(data);
+            mac.verify_slice(expected).is_ok()
+        }
+        match self {
+            Algorithm::HmacSha256 => verify::<Hmac<sha2::Sha256>>(secret, data, mac),
+            Algorithm::HmacSha384 => verify::<Hmac<sha2::Sha384>>(secret, data, mac),
+            Algorithm::HmacSha512 => verify::<Hmac<sha2::Sha512>>(secret, data, mac),
+        }
+    }
+}
+
+/// A secret shared with another party, known to both by its name.
+#[derive(Clone)]
+pub struct Key {
+    pub name: Name,
+    pub algorithm: Algorithm,
+    pub(crate) secret: Vec<u8>,
+    /// Seconds since the UNIX epoch after which signatures made with this key are no longer accepted.
+    expiration: Option<u64>,
+}
+
+impl Key {
+    pub fn new(name: Name, algorithm: Algorithm, secret: Vec<u8>) -> Self {
+        Key {
+            name,
+            algorithm,
+            secret,
+            expiration: None,
+        }
+    }
+
+    /// Stops accepting signatures made with this key after `expiration`, as agreed on with TKEY.
+    pub fn expiring(mut self, expiration: SystemTime) -> Self {
+        self.expiration = Some(seconds(expiration));
+        self
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expiration
+            .is_some_and(|expiration| seconds(now) > expiration)
+    }
+}
+
+impl std::fmt::Debug for Key {
+    // Keep the secret out of logs
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Key")
+            .field("name", &self.name)
+            .field("algorithm", &self.algorithm)
+            .field("expiration", &self.expiration)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The keys a server accepts signatures from, by name.
+#[derive(Debug, Clone, Default)]
+pub struct Keyring {
+    keys: HashMap<Name, Key>,
+}
+
+impl Keyring {
+    pub fn new() -> Self {
+        Keyring::default()
+    }
+
+    /// Adds `key`, returning the key previously known by the same name, if any.
+    pub fn insert(&mut self, key: Key) -> Option<Key> {
+        self.keys.insert(key.name.clone(), key)
+    }
+
+    pub fn get(&self, name: &Name) -> Option<&Key> {
+        self.keys.get(name)
+    }
+
+    pub fn remove(&mut self, name: &Name) -> Option<Key> {
+        self.keys.remove(name)
+    }
+}
+
+/// Why a signature was not accepted, as carried in the error field of TSIG records;
+/// TKEY records (RFC 2930) share these codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u16)]
+pub enum TsigError {
+    /// The MAC does not match, or the message is not signed where it must be
+    BADSIG = 16,
+
+    /// The key or algorithm is not known
+    BADKEY = 17,
+
+    /// The message was signed too long before or after the time of verification
+    BADTIME = 18,
+
+    /// The TKEY mode is not supported
+    BADMODE = 19,
+
+    /// The TKEY names a key that is not known
+    BADNAME = 20,
+
+    /// The TKEY names an algorithm that is not supported
+    BADALG = 21,
+
+    /// The MAC is truncated further than permitted
+    BADTRUNC = 22,
+}
+
+impl From<TsigError> for io::Error {
+    fn from(error: TsigError) -> Self {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("TSIG verification failed with {error:?}"),
+        )
+    }
+}
+
+/// Seconds since the UNIX epoch, as carried in TSIG records.
+fn seconds(now: SystemTime) -> u64 {
+    now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// A message split into its TSIG record and the bytes the MAC covers.
+struct Signed {
+    /// The message as it was before signing: with its original ID, and without the TSIG record.
+    unsigned: Vec<u8>,
+    key_name: Name,
+    tsig: Tsig,
+}
+
+/// Splits the TSIG record off the end of `message`, if it has one.
+fn split(message: &[u8]) -> io::Result<Option<Signed>> {
+    let Some((header, start, record)) = crate::signature::last_record(message)? else {
+        return Ok(None);
+    };
+    let RData::Tsig(tsig) = record.rdata else {
+        return Ok(None);
+    };
+
+    let mut unsigned = message[..start].to_vec();
+    unsigned[..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+    unsigned[10..12].copy_from_slice(&(header.arcount - 1).to_be_bytes());
+    Ok(Some(Signed {
+        unsigned,
+        key_name: record.name,
+        tsig,
+    }))
+}
+
+/// Writes `name` in the canonical form MACs cover: lowercase and uncompressed.
+fn put_name(dst: &mut BytesMut, name: &Name) {
+    let canonical = Name(name.0.to_ascii_lowercase());
+    canonical
+        .encode(dst)
+        .expect("writing to memory does not fail");
+}
+
+fn put_time(dst: &mut BytesMut, time: u64) {
+    dst.put_u16((time >> 32) as u16);
+    dst.put_u32(time as u32);
+}
+
+/// The signing state of one exchange: a request, and the one or more responses to it.
+#[derive(Debug, Clone)]
+pub struct Session {
+    key: Key,
+    /// MAC of the last signed message of the exchange.
+    previous: Option<Vec<u8>>,
+    /// Messages signed or verified so far.
+    count: usize,
+    /// Messages received without a signature since the last signed one.
+    unsigned: Vec<u8>,
+    unsigned_count: usize,
+}
+
+impl Session {
+    /// A new exchange, starting with a request signed by `key`.
+    pub fn new(key: Key) -> Self {
+        Session {
+            key,
+            previous: None,
+            count: 0,
+            unsigned: Vec::new(),
+            unsigned_count: 0,
+        }
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// Whether every message verified so far was covered by a signature;
+    /// the last message of a stream must be signed.
+    pub fn is_complete(&self) -> bool {
+        self.unsigned_count == 0
+    }
+
+    /// The bytes the MAC of the next message covers, given `unsigned`, that message as it was before signing.
+    fn digest(&self, unsigned: &[u8], key_name: &Name, tsig: &Tsig) -> Vec<u8> {
+        let mut digest = BytesMut::new();
+        if let Some(previous) = &self.previous {
+            digest.put_u16(previous.len() as u16);
+            digest.extend_from_slice(previous);
+        }
+        digest.extend_from_slice(&self.unsigned);
+        digest.extend_from_slice(unsigned);
+
+        // Later messages of a stream only cover the timers (RFC 8945, section 5.3.1)
+        if self.count < 2 {
+            put_name(&mut digest, key_name);
+            digest.put_u16(Class::STAR as u16);
+            digest.put_u32(0);
+            put_name(&mut digest, &tsig.algorithm);
+            put_time(&mut digest, tsig.time_signed);
+            digest.put_u16(tsig.fudge);
+            digest.put_u16(tsig.error);
+            digest.put_u16(tsig.other.len() as u16);
+            digest.extend_from_slice(&tsig.other);
+        } else {
+            put_time(&mut digest, tsig.time_signed);
+            digest.put_u16(tsig.fudge);
+        }
+        digest.to_vec()
+    }
+
+    /// Signs `message`, the next of this exchange, appending a TSIG record and counting it in the header.
+    pub fn sign(&mut self, message: &mut BytesMut, now: SystemTime) -> io::Result<()> {
+        self.sign_with_error(message, now, None)
+    }
+
+    fn sign_with_error(
+        &mut self,
+        message: &mut BytesMut,
+        now: SystemTime,
+        error: Option<TsigError>,
+    ) -> io::Result<()> {
+        if message.len() < 12 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Message has no header",
+            ));
+        }
+        let time_signed = seconds(now);
+        let mut tsig = Tsig {
+            algorithm: self.key.algorithm.name(),
+            time_signed,
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: u16::from_be_bytes([message[0], message[1]]),
+            error: error.map_or(0, |error| error as u16),
+            other: Vec::new(),
+        };
+        if error == Some(TsigError::BADTIME) {
+            let mut other = BytesMut::new();
+            put_time(&mut other, time_signed);
+            tsig.other = other.to_vec();
+        }
+
+        let digest = self.digest(message, &self.key.name, &tsig);
+        tsig.mac = self.key.algorithm.mac(&self.key.secret, &digest);
+        self.previous = Some(tsig.mac.clone());
+        self.count += 1;
+
+        append(message, self.key.name.clone(), tsig)
+    }
+
+    /// Verifies `message`, the next of this exchange, against the TSIG record it ends with.
+    ///
+    /// Messages after the first response may come without a signature,
+    /// as long as one of the following messages covers them.
+    pub fn verify(&mut self, message: &[u8], now: SystemTime) -> Result<(), TsigError> {
+        let signed = split(message).map_err(|_| TsigError::BADSIG)?;
+        let Some(signed) = signed else {
+            if self.count < 2 || self.unsigned_count == MAX_UNSIGNED {
+                return Err(TsigError::BADSIG);
+            }
+            self.unsigned.extend_from_slice(message);
+            self.unsigned_count += 1;
+            return Ok(());
+        };
+
+        let error = TsigError::try_from(signed.tsig.error).ok();
+        // The server could not sign these, so they come without a MAC (RFC 8945, section 5.3.2)
+        if let Some(error @ (TsigError::BADSIG | TsigError::BADKEY)) = error {
+            return Err(error);
+        }
+        if signed.key_name != self.key.name
+            || Algorithm::from_name(&signed.tsig.algorithm) != Some(self.key.algorithm)
+        {
+            return Err(TsigError::BADKEY);
+        }
+        // Any other error is only taken from the server once the MAC shows that it sent it
+        match self.check(&signed, now) {
+            Err(TsigError::BADSIG) => Err(TsigError::BADSIG),
+            checked => error.map_or(checked, Err),
+        }
+    }
+
+    /// Checks the MAC of `signed`, then its time (RFC 8945, section 5.2).
+    fn check(&mut self, signed: &Signed, now: SystemTime) -> Result<(), TsigError> {
+        let digest = self.digest(&signed.unsigned, &signed.key_name, &signed.tsig);
+        if !self
+            .key
+            .algorithm
+            .verify(&self.key.secret, &digest, &signed.tsig.mac)
+        {
+            return Err(TsigError::BADSIG);
+        }
+
+        self.previous = Some(signed.tsig.mac.clone());
+        self.count += 1;
+        self.unsigned.clear();
+        self.unsigned_count = 0;
+
+        if seconds(now).abs_diff(signed.tsig.time_signed) > u64::from(signed.tsig.fudge) {
+            return Err(TsigError::BADTIME);
+        }
+        Ok(())
+    }
+}
+
+/// Appends a TSIG record to `message` and counts it in the header.
+fn append(message: &mut BytesMut, key_name: Name, tsig: Tsig) -> io::Result<()> {
+    let record = Record {
+        name: key_name,
+        kind: Type::TSIG,
+        class: Class::STAR,
+        ttl: Ttl::from_secs(0),
+        length: 0,
+        rdata: RData::Tsig(tsig),
+    };
+    crate::signature::append(message, record)
+}
+
+/// A signed request that was not accepted, and how to tell the client.
+#[derive(Debug)]
+pub struct Rejection {
+    pub error: TsigError,
+    key_name: Name,
+    algorithm: Name,
+    /// Set if the request was authentic, in which case the response is signed as well.
+    session: Option<Box<Session>>,
+}
+
+impl Rejection {
+    /// Appends the TSIG record telling the client why its request was rejected to `response`,
+    /// which should carry the NOTAUTH response code (RFC 8945, section 5.2).
+    pub fn sign(self, response: &mut BytesMut, now: SystemTime) -> io::Result<()> {
+        if response.len() < 12 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Message has no header",
+            ));
+        }
+        if let Some(mut session) = self.session {
+            return session.sign_with_error(response, now, Some(self.error));
+        }
+
+        let tsig = Tsig {
+            algorithm: self.algorithm,
+            time_signed: seconds(now),
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: u16::from_be_bytes([response[0], response[1]]),
+            error: self.error as u16,
+            other: Vec::new(),
+        };
+        append(response, self.key_name, tsig)
+    }
+}
+
+/// Verifies the signature of `request` against the keys of `keyring`, as a server does.
+///
+/// Gives the session to sign the responses with if the request is signed and authentic,
+/// and `None` if it is not signed at all.
+pub fn verify_request(
+    request: &[u8],
+    keyring: &Keyring,
+    now: SystemTime,
+) -> Result<Option<Session>, Rejection> {
+    let signed = match split(request) {
+        Ok(Some(signed)) => signed,
+        Ok(None) | Err(_) => return Ok(None),
+    };
+    let reject = |error, session| Rejection {
+        error,
+        key_name: signed.key_name.clone(),
+        algorithm: signed.tsig.algorithm.clone(),
+        session,
+    };
+
+    let key = keyring
+        .get(&signed.key_name)
+        .filter(|key| Algorithm::from_name(&signed.tsig.algorithm) == Some(key.algorithm))
+        .filter(|key| !key.is_expired(now));
+    let Some(key) = key else {
+        return Err(reject(TsigError::BADKEY, None));
+    };
+
+    let mut session = Session::new(key.clone());
+    match session.check(&signed, now) {
+        Ok(()) => Ok(Some(session)),
+        Err(TsigError::BADTIME) => Err(reject(TsigError::BADTIME, Some(Box::new(session)))),
+        Err(error) => Err(reject(error, None)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use tokio_util::{
+        bytes::BytesMut,
+        codec::{Decoder as _, Encoder as _},
+    };
+
+    use super::{Algorithm, Key, Keyring, Session, TsigError};
+
+    fn name(name: &str) -> crate::Name {
+        name.as_bytes().to_vec().try_into().unwrap()
+    }
+
+    fn key(algorithm: Algorithm) -> Key {
+        Key::new(
+            name("transfer.example.com"),
+            algorithm,
+            b"not so secret".to_vec(),
+        )
+    }
+
+    fn message(id: u16, response: bool) -> BytesMut {
+        let mut query = crate::Message::query("example.com", crate::QType::AXFR)
+            .id(id)
+            .build()
+            .unwrap();
+        query.header.set_response(response);
+        let mut encoded = BytesMut::new();
+        crate::MessageCodec.encode(query, &mut encoded).unwrap();
+        encoded
+    }
+
+    #[test]
+    fn exchange() {
+        let now = SystemTime::now();
+        for algorithm in [
+            Algorithm::HmacSha256,
+            Algorithm::HmacSha384,
+            Algorithm::HmacSha512,
+        ] {
+            let mut keyring = Keyring::new();
+            keyring.insert(key(algorithm));
+
+            let mut client = Session::new(key(algorithm));
+            let mut request = message(0x1234, false);
+            client.sign(&mut request, now).unwrap();
+
+            // The TSIG record decodes as the last additional record
+            let decoded = crate::MessageCodec
+                .decode(&mut request.clone())
+                .unwrap()
+                .unwrap();
+            assert_eq!(decoded.header.arcount, 1);
+            assert_eq!(decoded.additionals[0].kind, crate::Type::TSIG);
+
+            let mut server = super::verify_request(&request, &keyring, now)
+                .unwrap()
+                .unwrap();
+
+            // A stream of responses, with an unsigned one in the middle
+            let mut responses = Vec::new();
+            for signed in [true, true, false, true] {
+                let mut response = message(0x1234, true);
+                match signed {
+                    true => server.sign(&mut response, now).unwrap(),
+                    // Covered by the next signature instead
+                    false => server.unsigned.extend_from_slice(&response),
+                }
+                responses.push(response);
+            }
+            for response in &responses {
+                client.verify(response, now).unwrap();
+            }
+            assert!(client.is_complete());
+        }
+    }
+
+    #[test]
+    fn rejections() {
+        let now = SystemTime::now();
+        let mut keyring = Keyring::new();
+        keyring.insert(key(Algorithm::HmacSha256));
+
+        // Unsigned requests are for the server to judge
+        let request = message(1, false);
+        assert!(super::verify_request(&request, &keyring, now)
+            .unwrap()
+            .is_none());
+
+        // Unknown keys and algorithms
+        let mut request = message(1, false);
+        Session::new(key(Algorithm::HmacSha512))
+            .sign(&mut request, now)
+            .unwrap();
+        let rejection = super::verify_request(&request, &keyring, now).unwrap_err();
+        assert_eq!(rejection.error, TsigError::BADKEY);
+
+        // Changed after signing
+        let mut client = Session::new(key(Algorithm::HmacSha256));
+        let mut request = message(1, false);
+        client.sign(&mut request, now).unwrap();
+        request[2] |= 0x01;
+        let rejection = super::verify_request(&request, &keyring, now).unwrap_err();
+        assert_eq!(rejection.error, TsigError::BADSIG);
+
+        // Signed too long ago; the client learns of it from a signed response
+        request[2] &= !0x01;
+        let later = now + Duration::from_secs(u64::from(super::FUDGE) + 1);
+        let rejection = super::verify_request(&request, &keyring, later).unwrap_err();
+        assert_eq!(rejection.error, TsigError::BADTIME);
+        let mut response = message(1, true);
+        rejection.sign(&mut response, later).unwrap();
+        assert_eq!(client.verify(&response, later), Err(TsigError::BADTIME));
+
+        // Errors other than BADSIG and BADKEY must be signed, or anyone could claim them
+        let mut client = Session::new(key(Algorithm::HmacSha256));
+        let mut request = message(3, false);
+        client.sign(&mut request, now).unwrap();
+        let forged = |error| super::Rejection {
+            error,
+            key_name: name("transfer.example.com"),
+            algorithm: Algorithm::HmacSha256.name(),
+            session: None,
+        };
+        let mut response = message(3, true);
+        forged(TsigError::BADTIME).sign(&mut response, now).unwrap();
+        assert_eq!(client.verify(&response, now), Err(TsigError::BADSIG));
+        let mut response = message(3, true);
+        forged(TsigError::BADKEY).sign(&mut response, now).unwrap();
+        assert_eq!(client.verify(&response, now), Err(TsigError::BADKEY));
+
+        // Responses must be signed
+        let mut client = Session::new(key(Algorithm::HmacSha256));
+        let mut request = message(2, false);
+        client.sign(&mut request, now).unwrap();
+        assert_eq!(
+            client.verify(&message(2, true), now),
+            Err(TsigError::BADSIG)
+        );
+    }
+}
@@ -0,0 +1,345 @@
+use std::io;
+
+use crate::{
+    Class, Header, Message, Name, Opcode, QClass, QType, Question, RData, Record, Ttl, Type,
+};
+
+/// A dynamic update (RFC 2136): changes to a single zone, applied only if all prerequisites hold.
+///
+/// On the wire, the sections of a [`Message`] are reused: the question names the zone,
+/// and prerequisites, updates and additional records follow as answers, authorities and additionals.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Update {
+    pub header: Header,
+    /// The zone to be updated, asking for its SOA.
+    pub zone: Question,
+    pub prerequisites: Vec<Prerequisite>,
+    pub updates: Vec<Change>,
+    pub additionals: Vec<Record>,
+}
+
+/// A condition on the zone that must hold for an update to be applied (RFC 2136, section 2.4).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Prerequisite {
+    /// The name owns at least one record.
+    NameInUse(Name),
+    /// The name owns no records at all.
+    NameNotInUse(Name),
+    /// The name owns records of this type, whatever their data.
+    RrsetExists(Name, Type),
+    /// The name owns no records of this type.
+    RrsetDoesNotExist(Name, Type),
+    /// Part of an RRset that must exist exactly as given;
+    /// all records of the same name and type together make up the RRset.
+    Rrset(Record),
+}
+
+/// A change to the zone (RFC 2136, section 2.5).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// Adds the record to its RRset.
+    Add(Record),
+    /// Deletes the record, compared regardless of its TTL.
+    Delete(Record),
+    /// Deletes every record of this type owned by the name.
+    DeleteRrset(Name, Type),
+    /// Deletes every record owned by the name.
+    DeleteName(Name),
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A record with the given meaning but no data, as prerequisites and deletions use.
+fn marker(name: Name, kind: Type, class: Class) -> Record {
+    Record {
+        name,
+        kind,
+        class,
+        ttl: Ttl::from_secs(0),
+        length: 0,
+        rdata: RData::Empty,
+    }
+}
+
+impl Prerequisite {
+    /// Reads the meaning of `record` from its class, which is either NONE, ANY or that of the zone.
+    fn decode(record: Record, zone: Class) -> io::Result<Self> {
+        if record.ttl.as_secs() != 0 {
+            return Err(invalid(format!(
+                "Prerequisite on {} has a non-zero TTL",
+                record.name
+            )));
+        }
+
+        let empty = record.rdata == RData::Empty;
+        let prerequisite = match (record.class, record.kind) {
+            (Class::STAR, Type::STAR) if empty => Prerequisite::NameInUse(record.name),
+            (Class::STAR, kind) if empty => Prerequisite::RrsetExists(record.name, kind),
+            (Class::NONE, Type::STAR) if empty => Prerequisite::NameNotInUse(record.name),
+            (Class::NONE, kind) if empty => Prerequisite::RrsetDoesNotExist(record.name, kind),
+            (class, kind) if class == zone && kind != Type::STAR => Prerequisite::Rrset(record),
+            (class, kind) => {
+                return Err(invalid(format!(
+                    "Invalid prerequisite on {}: {kind:?} in class {class:?}",
+                    record.name
+                )))
+            }
+        };
+        Ok(prerequisite)
+    }
+
+    fn encode(self) -> Record {
+        match self {
+            Prerequisite::NameInUse(name) => marker(name, Type::STAR, Class::STAR),
+            Prerequisite::NameNotInUse(name) => marker(name, Type::STAR, Class::NONE),
+            Prerequisite::RrsetExists(name, kind) => marker(name, kind, Class::STAR),
+            Prerequisite::RrsetDoesNotExist(name, kind) => marker(name, kind, Class::NONE),
+            Prerequisite::Rrset(record) => Record {
+                ttl: Ttl::from_secs(0),
+                ..record
+            },
+        }
+    }
+}
+
+impl Change {
+    /// Reads the meaning of `record` from its class, which is either NONE, ANY or that of the zone.
+    /// Deleted records are given the class of the zone.
+    fn decode(record: Record, zone: Class) -> io::Result<Self> {
+        let empty = record.rdata == RData::Empty;
+        let change = match (record.class, record.kind) {
+            (class, kind) if class == zone && kind != Type::STAR => Change::Add(record),
+            (Class::STAR, Type::STAR) if empty => Change::DeleteName(record.name),
+            (Class::STAR, kind) if empty => Change::DeleteRrset(record.name, kind),
+            (Class::NONE, kind) if kind != Type::STAR && !empty => Change::Delete(Record {
+                class: zone,
+                ..record
+            }),
+            (class, kind) => {
+                return Err(invalid(format!(
+                    "Invalid update of {}: {kind:?} in class {class:?}",
+                    record.name
+                )))
+            }
+        };
+        Ok(change)
+    }
+
+    fn encode(self) -> Record {
+        match self {
+            Change::Add(record) => record,
+            Change::Delete(record) => Record {
+                class: Class::NONE,
+                ttl: Ttl::from_secs(0),
+                ..record
+            },
+            Change::DeleteRrset(name, kind) => marker(name, kind, Class::STAR),
+            Change::DeleteName(name) => marker(name, Type::STAR, Class::STAR),
+        }
+    }
+}
+
+impl Update {
+    /// Starts an update of the zone at `zone`, in class IN.
+    pub fn builder(id: u16, zone: Name) -> UpdateBuilder {
+        UpdateBuilder {
+            update: Update {
+                header: Header {
+                    id,
+                    flags: 0,
+                    qdcount: 1,
+                    ancount: 0,
+                    ncount: 0,
+                    arcount: 0,
+                },
+                zone: Question {
+                    name: zone,
+                    kind: QType::SOA,
+                    class: QClass::IN,
+                },
+                prerequisites: Vec::new(),
+                updates: Vec::new(),
+                additionals: Vec::new(),
+            },
+        }
+    }
+}
+
+impl TryFrom<Message> for Update {
+    type Error = io::Error;
+
+    /// Fails with [`io::ErrorKind::InvalidData`] unless `message` is an UPDATE of a single zone,
+    /// with prerequisites and updates that make sense.
+    fn try_from(message: Message) -> io::Result<Self> {
+        if message.header.opcode() != Some(Opcode::UPDATE) {
+            return Err(invalid("Message is not an UPDATE".to_string()));
+        }
+        let [zone] = <[Question; 1]>::try_from(message.questions)
+            .map_err(|_| invalid("UPDATE must name exactly one zone".to_string()))?;
+        if zone.kind != QType::SOA {
+            return Err(invalid(format!(
+                "UPDATE of {} asks for {:?}",
+                zone.name, zone.kind
+            )));
+        }
+        let class = Class::try_from(zone.class as u16)
+            .ok()
+            .filter(|class| *class != Class::STAR)
+            .ok_or_else(|| invalid(format!("UPDATE of {} in class {:?}", zone.name, zone.class)))?;
+
+        let prerequisites = message
+            .answers
+            .into_iter()
+            .map(|record| Prerequisite::decode(record, class))
+            .collect::<io::Result<_>>()?;
+        let updates = message
+            .authorities
+            .into_iter()
+            .map(|record| Change::decode(record, class))
+            .collect::<io::Result<_>>()?;
+
+        Ok(Update {
+            header: message.header,
+            zone,
+            prerequisites,
+            updates,
+            additionals: message.additionals,
+        })
+    }
+}
+
+impl From<Update> for Message {
+    /// Lays `update` out as a message, with the counts of the header matching its sections.
+    fn from(update: Update) -> Self {
+        let mut header = update.header;
+        header.set_opcode(Opcode::UPDATE);
+        header.qdcount = 1;
+        header.ancount = update.prerequisites.len() as u16;
+        header.ncount = update.updates.len() as u16;
+        header.arcount = update.additionals.len() as u16;
+
+        Message {
+            header,
+            questions: vec![update.zone],
+            answers: update
+                .prerequisites
+                .into_iter()
+                .map(Prerequisite::encode)
+                .collect(),
+            authorities: update.updates.into_iter().map(Change::encode).collect(),
+            additionals: update.additionals,
+            edns: None,
+        }
+    }
+}
+
+/// Builds an [`Update`], see [`Update::builder`].
+#[derive(Debug, Clone)]
+pub struct UpdateBuilder {
+    update: Update,
+}
+
+impl UpdateBuilder {
+    /// Applies the update only if `prerequisite` holds.
+    pub fn require(mut self, prerequisite: Prerequisite) -> Self {
+        self.update.prerequisites.push(prerequisite);
+        self
+    }
+
+    pub fn add_record(mut self, record: Record) -> Self {
+        self.update.updates.push(Change::Add(record));
+        self
+    }
+
+    pub fn delete_record(mut self, record: Record) -> Self {
+        self.update.updates.push(Change::Delete(record));
+        self
+    }
+
+    pub fn delete_rrset(mut self, name: Name, kind: Type) -> Self {
+        self.update.updates.push(Change::DeleteRrset(name, kind));
+        self
+    }
+
+    pub fn delete_name(mut self, name: Name) -> Self {
+        self.update.updates.push(Change::DeleteName(name));
+        self
+    }
+
+    pub fn additional(mut self, record: Record) -> Self {
+        self.update.additionals.push(record);
+        self
+    }
+
+    pub fn build(self) -> Update {
+        self.update
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio_util::{
+        bytes::BytesMut,
+        codec::{Decoder as _, Encoder as _},
+    };
+
+    use crate::{Change, Prerequisite, Update};
+
+    fn name(name: &str) -> crate::Name {
+        name.as_bytes().to_vec().try_into().unwrap()
+    }
+
+    #[test]
+    fn wire_roundtrip() {
+        let host = name("host.example.com");
+        let record = crate::Record {
+            name: host.clone(),
+            kind: crate::Type::A,
+            class: crate::Class::IN,
+            ttl: crate::Ttl::from_secs(300),
+            length: 4,
+            rdata: crate::RData::Ipv4([192, 0, 2, 7].into()),
+        };
+        let update = Update::builder(0x1234, name("example.com"))
+            .require(Prerequisite::NameNotInUse(host.clone()))
+            .require(Prerequisite::RrsetExists(
+                name("example.com"),
+                crate::Type::NS,
+            ))
+            .delete_name(name("old.example.com"))
+            .delete_rrset(host.clone(), crate::Type::AAAA)
+            .delete_record(crate::Record {
+                ttl: crate::Ttl::from_secs(0),
+                ..record.clone()
+            })
+            .add_record(record)
+            .build();
+
+        let mut datagram = BytesMut::new();
+        crate::MessageCodec
+            .encode(update.clone().into(), &mut datagram)
+            .unwrap();
+        let message = crate::MessageCodec.decode(&mut datagram).unwrap().unwrap();
+        assert_eq!(message.header.opcode(), Some(crate::Opcode::UPDATE));
+        assert_eq!(message.authorities[2].class, crate::Class::NONE);
+
+        let mut decoded = Update::try_from(message).unwrap();
+        decoded.header = update.header;
+        assert_eq!(decoded, update);
+        assert!(matches!(decoded.updates[3], Change::Add(_)));
+    }
+
+    #[test]
+    fn rejects_nonsense() {
+        let mut message: crate::Message = Update::builder(1, name("example.com"))
+            .delete_name(name("host.example.com"))
+            .build()
+            .into();
+        // Deleting all records of all types in no class is not a thing
+        message.authorities[0].class = crate::Class::NONE;
+        let error = Update::try_from(message).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,140 @@
+//! Positive answers, kept for as long as the smallest TTL among their records allows.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Number of answers kept by default.
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+#[derive(Debug)]
+struct Entry {
+    records: Vec<dns_codec::Record>,
+    inserted: Instant,
+    expires: Instant,
+}
+
+#[derive(Debug)]
+pub(crate) struct Cache {
+    entries: HashMap<(dns_codec::Name, dns_codec::QType), Entry>,
+    capacity: usize,
+}
+
+impl Cache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Cache {
+            entries: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// The cached records of type `kind` for `name`, with their TTLs reduced by the time they have been cached for.
+    pub(crate) fn get(
+        &self,
+        name: &dns_codec::Name,
+        kind: dns_codec::QType,
+        now: Instant,
+    ) -> Option<Vec<dns_codec::Record>> {
+        let entry = self.entries.get(&(name.clone(), kind))?;
+        if entry.expires <= now {
+            return None;
+        }
+
+        let elapsed = now.saturating_duration_since(entry.inserted).as_secs() as u32;
+        let records = entry
+            .records
+            .iter()
+            .map(|record| dns_codec::Record {
+                ttl: dns_codec::Ttl::from_secs(record.ttl.as_secs().saturating_sub(elapsed)),
+                ..record.clone()
+            })
+            .collect();
+        Some(records)
+    }
+
+    /// Caches `records` as the answer for `name` and `kind`.
+    /// Answers without records, or with a record that must not be cached (TTL 0), are ignored.
+    pub(crate) fn insert(
+        &mut self,
+        name: dns_codec::Name,
+        kind: dns_codec::QType,
+        records: &[dns_codec::Record],
+        now: Instant,
+    ) {
+        let Some(ttl) = records.iter().map(|record| record.ttl.as_secs()).min() else {
+            return;
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        if self.entries.len() >= self.capacity {
+            self.entries.retain(|_, entry| entry.expires > now);
+            if self.entries.len() >= self.capacity {
+                log::debug!("cache: full, not caching {name} {kind:?}");
+                return;
+            }
+        }
+
+        self.entries.insert(
+            (name, kind),
+            Entry {
+                records: records.to_vec(),
+                inserted: now,
+                expires: now + Duration::from_secs(ttl.into()),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    fn record(ttl: u32) -> dns_codec::Record {
+        dns_codec::Record {
+            name: b"example.com".to_vec().try_into().unwrap(),
+            kind: dns_codec::Type::A,
+            class: dns_codec::Class::IN,
+            ttl: dns_codec::Ttl::from_secs(ttl),
+            length: 4,
+            rdata: dns_codec::RData::Ipv4([192, 0, 2, 1].into()),
+        }
+    }
+
+    #[test]
+    fn expires_with_smallest_ttl() {
+        let name: dns_codec::Name = b"example.com".to_vec().try_into().unwrap();
+        let upper: dns_codec::Name = b"EXAMPLE.com".to_vec().try_into().unwrap();
+        let mut cache = super::Cache::new(2);
+        let now = Instant::now();
+
+        cache.insert(
+            name.clone(),
+            dns_codec::QType::A,
+            &[record(300), record(60)],
+            now,
+        );
+        let records = cache
+            .get(&upper, dns_codec::QType::A, now + Duration::from_secs(10))
+            .unwrap();
+        let ttls: Vec<_> = records.iter().map(|r| r.ttl.as_secs()).collect();
+        assert_eq!(ttls, [290, 50]);
+
+        assert!(cache.get(&name, dns_codec::QType::AAAA, now).is_none());
+        assert!(cache
+            .get(&name, dns_codec::QType::A, now + Duration::from_secs(60))
+            .is_none());
+
+        // Records that must not be cached
+        cache.insert(name.clone(), dns_codec::QType::MX, &[record(0)], now);
+        assert!(cache.get(&name, dns_codec::QType::MX, now).is_none());
+
+        // Expired entries make room for new ones once full
+        let later = now + Duration::from_secs(120);
+        cache.insert(name.clone(), dns_codec::QType::TXT, &[record(300)], now);
+        cache.insert(name.clone(), dns_codec::QType::SRV, &[record(300)], later);
+        assert!(cache.get(&name, dns_codec::QType::SRV, later).is_some());
+    }
+}
@@ -0,0 +1,105 @@
+use std::{collections::HashMap, fs, io, net::IpAddr, path::Path};
+
+/// The contents of a hosts(5) file, consulted before any query is sent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hosts {
+    /// Addresses by lowercase name, in file order.
+    addresses: HashMap<String, Vec<IpAddr>>,
+
+    /// Names by address, the canonical name first.
+    names: HashMap<IpAddr, Vec<String>>,
+}
+
+impl Hosts {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Ok(Hosts::parse(&contents))
+    }
+
+    /// Parses hosts contents leniently, skipping lines that do not start with an address.
+    pub fn parse(contents: &str) -> Self {
+        let mut hosts = Hosts::default();
+
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+
+            let Some(address) = words.next() else {
+                continue;
+            };
+            let Ok(address) = address.parse::<IpAddr>() else {
+                log::warn!("hosts: ignoring malformed {line:?}");
+                continue;
+            };
+
+            for name in words {
+                let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+                let addresses = hosts.addresses.entry(name.clone()).or_default();
+                if !addresses.contains(&address) {
+                    addresses.push(address);
+                }
+
+                let names = hosts.names.entry(address).or_default();
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+
+        hosts
+    }
+
+    /// Addresses of `name` for an A (IPv4) or AAAA (IPv6) query, or `None` if the name is not listed.
+    /// Other query types are never answered from the hosts file.
+    pub fn lookup(&self, name: &str, kind: dns_codec::QType) -> Option<Vec<IpAddr>> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let addresses = self.addresses.get(&name)?;
+
+        let addresses = match kind {
+            dns_codec::QType::A => addresses.iter().filter(|a| a.is_ipv4()).copied().collect(),
+            dns_codec::QType::AAAA => addresses.iter().filter(|a| a.is_ipv6()).copied().collect(),
+            _ => return None,
+        };
+        Some(addresses)
+    }
+
+    /// Names of `address` for a PTR query, the canonical name first.
+    pub fn reverse(&self, address: IpAddr) -> Option<&[String]> {
+        self.names.get(&address).map(Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    #[test]
+    fn lookup_and_reverse() {
+        let hosts = super::Hosts::parse(
+            "127.0.0.1\tlocalhost\n\
+             ::1 localhost ip6-localhost # loopback\n\
+             192.0.2.10 Host.Example.com host\n\
+             garbage line\n",
+        );
+
+        let v4: IpAddr = "127.0.0.1".parse().unwrap();
+        let v6: IpAddr = "::1".parse().unwrap();
+        assert_eq!(
+            hosts.lookup("localhost", dns_codec::QType::A),
+            Some(vec![v4])
+        );
+        assert_eq!(
+            hosts.lookup("LOCALHOST.", dns_codec::QType::AAAA),
+            Some(vec![v6])
+        );
+        assert_eq!(hosts.lookup("localhost", dns_codec::QType::MX), None);
+        assert_eq!(hosts.lookup("example.com", dns_codec::QType::A), None);
+
+        let address: IpAddr = "192.0.2.10".parse().unwrap();
+        assert_eq!(
+            hosts.reverse(address),
+            Some(&["host.example.com".to_owned(), "host".to_owned()][..])
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Resolver configuration, as read from the system's `resolv.conf` and `hosts` files.
+
+mod hosts;
+mod resolv_conf;
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+};
+
+pub use hosts::Hosts;
+pub use resolv_conf::ResolvConf;
+
+pub const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+pub const HOSTS_PATH: &str = "/etc/hosts";
+
+/// IPv4 addresses of a.root-servers.net through m.root-servers.net, as listed in the IANA root hints file.
+const ROOT_SERVERS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+/// The root servers, for [`Config::root_hints`].
+pub fn root_servers() -> Vec<SocketAddr> {
+    ROOT_SERVERS
+        .iter()
+        .map(|address| SocketAddr::new((*address).into(), 53))
+        .collect()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub resolv_conf: ResolvConf,
+    pub hosts: Hosts,
+    /// Upstreams to query over TLS instead of the nameservers of `resolv_conf`.
+    pub tls: Option<crate::tls::TlsConfig>,
+    /// Upstreams to query over HTTPS instead of the nameservers of `resolv_conf`.
+    /// Ignored if `tls` is set.
+    pub https: Option<crate::https::HttpsConfig>,
+    /// Upstreams to query over QUIC instead of the nameservers of `resolv_conf`.
+    /// Ignored if `tls` or `https` is set.
+    pub quic: Option<crate::quic::QuicConfig>,
+    /// Root servers to resolve names from iteratively, following referrals down to the authoritative
+    /// nameservers, instead of asking upstreams to recurse. Takes precedence over all upstreams.
+    ///
+    /// Nameservers learned from referrals are queried over IPv4, on the port of the first root server.
+    pub root_hints: Option<Vec<SocketAddr>>,
+}
+
+impl Config {
+    /// Loads the configuration from arbitrary paths, e.g. for testing.
+    pub fn load(resolv_conf: impl AsRef<Path>, hosts: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Config {
+            resolv_conf: ResolvConf::load(resolv_conf)?,
+            hosts: Hosts::load(hosts)?,
+            tls: None,
+            https: None,
+            quic: None,
+            root_hints: None,
+        })
+    }
+
+    /// Loads the configuration of this system.
+    /// Missing files are treated as empty, as glibc does.
+    pub fn system() -> io::Result<Self> {
+        fn or_default<T: Default>(loaded: io::Result<T>) -> io::Result<T> {
+            match loaded {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+                loaded => loaded,
+            }
+        }
+
+        Ok(Config {
+            resolv_conf: or_default(ResolvConf::load(RESOLV_CONF_PATH))?,
+            hosts: or_default(Hosts::load(HOSTS_PATH))?,
+            tls: None,
+            https: None,
+            quic: None,
+            root_hints: None,
+        })
+    }
+
+    /// Whether upstreams are configured, rather than `resolv_conf` defaulting to the local host.
+    pub fn has_upstreams(&self) -> bool {
+        !self.resolv_conf.nameservers.is_empty()
+            || self.tls.is_some()
+            || self.https.is_some()
+            || self.quic.is_some()
+    }
+
+    /// Configuration of the sans-io state machine,
+    /// querying the TLS, HTTPS or QUIC upstreams if there are any.
+    ///
+    /// Iterating from [`Config::root_hints`] queries no upstreams, and leaves the timeouts and attempts
+    /// of resolv.conf, which are meant for its nameservers, at the sans-io defaults.
+    pub fn sans_io_config(&self) -> dns_sans_io::Config {
+        let config = self.resolv_conf.sans_io_config();
+        if self.root_hints.is_some() {
+            return dns_sans_io::Config {
+                edns: config.edns,
+                ..Default::default()
+            };
+        }
+        let (upstreams, upstream_transport) = match (&self.tls, &self.https, &self.quic) {
+            (Some(tls), _, _) => (tls.addresses(), dns_sans_io::Transport::Tls),
+            (None, Some(https), _) => (https.addresses(), dns_sans_io::Transport::Https),
+            (None, None, Some(quic)) => (quic.addresses(), dns_sans_io::Transport::Quic),
+            (None, None, None) => return config,
+        };
+        dns_sans_io::Config {
+            max_attempts: self.resolv_conf.attempts * upstreams.len() as u32,
+            upstreams,
+            upstream_transport,
+            ..config
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    fs, io,
+    net::{self, IpAddr, Ipv4Addr},
+    path::Path,
+    time::Duration,
+};
+
+/// Limits imposed by glibc, see resolv.conf(5).
+const MAX_NAMESERVERS: usize = 3;
+const MAX_SEARCH: usize = 6;
+const MAX_NDOTS: u8 = 15;
+const MAX_TIMEOUT: u64 = 30;
+const MAX_ATTEMPTS: u32 = 5;
+
+/// The contents of a resolv.conf(5) file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvConf {
+    /// Recursive resolvers to query, in order of preference.
+    pub nameservers: Vec<net::SocketAddr>,
+
+    /// Domains appended to names with fewer than `ndots` dots, in order.
+    pub search: Vec<String>,
+
+    /// Number of dots a name must contain to be tried as-is before the search list.
+    pub ndots: u8,
+
+    /// How long to wait for a nameserver before retrying.
+    pub timeout: Duration,
+
+    /// How often every nameserver is tried before giving up.
+    pub attempts: u32,
+
+    /// Spread queries across nameservers instead of always starting with the first.
+    pub rotate: bool,
+
+    /// Advertise EDNS0 support in queries.
+    pub edns0: bool,
+
+    /// Send A and AAAA queries one after the other rather than in parallel.
+    pub single_request: bool,
+}
+
+impl Default for ResolvConf {
+    fn default() -> Self {
+        ResolvConf {
+            nameservers: Vec::new(),
+            search: Vec::new(),
+            ndots: 1,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            rotate: false,
+            edns0: false,
+            single_request: false,
+        }
+    }
+}
+
+impl ResolvConf {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Ok(ResolvConf::parse(&contents))
+    }
+
+    /// Parses resolv.conf contents leniently, ignoring unknown keywords and malformed values as glibc does.
+    pub fn parse(contents: &str) -> Self {
+        let mut conf = ResolvConf::default();
+
+        for line in contents.lines() {
+            let line = line.split(['#', ';']).next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+
+            match words.next() {
+                Some("nameserver") => {
+                    let Some(address) = words.next().and_then(|w| w.parse::<IpAddr>().ok()) else {
+                        log::warn!("resolv.conf: ignoring malformed {line:?}");
+                        continue;
+                    };
+                    if conf.nameservers.len() < MAX_NAMESERVERS {
+                        conf.nameservers.push(net::SocketAddr::new(address, 53));
+                    }
+                }
+                // `domain` and `search` override each other; the last one wins
+                Some("domain") => {
+                    conf.search = words.next().map(normalize).into_iter().collect();
+                }
+                Some("search") => {
+                    conf.search = words.take(MAX_SEARCH).map(normalize).collect();
+                }
+                Some("options") => {
+                    for option in words {
+                        conf.apply_option(option);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        conf
+    }
+
+    fn apply_option(&mut self, option: &str) {
+        let (key, value) = match option.split_once(':') {
+            Some((key, value)) => (key, Some(value)),
+            None => (option, None),
+        };
+
+        match (key, value.and_then(|v| v.parse::<u64>().ok())) {
+            ("ndots", Some(ndots)) => self.ndots = ndots.min(MAX_NDOTS.into()) as u8,
+            ("timeout", Some(timeout)) => {
+                self.timeout = Duration::from_secs(timeout.clamp(1, MAX_TIMEOUT))
+            }
+            ("attempts", Some(attempts)) => {
+                self.attempts = (attempts as u32).clamp(1, MAX_ATTEMPTS)
+            }
+            ("rotate", None) => self.rotate = true,
+            ("edns0", None) => self.edns0 = true,
+            ("single-request", None) => self.single_request = true,
+            _ => log::debug!("resolv.conf: ignoring option {option:?}"),
+        }
+    }
+
+    /// Nameservers to query; the local host if none are configured.
+    pub fn nameservers(&self) -> Vec<net::SocketAddr> {
+        if self.nameservers.is_empty() {
+            vec![net::SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 53)]
+        } else {
+            self.nameservers.clone()
+        }
+    }
+
+    /// The fully qualified names to try for `name`, in order, following glibc's search list rules.
+    ///
+    /// Names ending in a dot are absolute and never expanded. Other names are tried as-is first
+    /// if they contain at least `ndots` dots, and after every search domain otherwise.
+    pub fn candidates(&self, name: &str) -> Vec<String> {
+        if let Some(absolute) = name.strip_suffix('.') {
+            return vec![absolute.to_owned()];
+        }
+
+        let expanded = self.search.iter().map(|domain| format!("{name}.{domain}"));
+
+        let dots = name.matches('.').count();
+        if dots >= self.ndots.into() {
+            std::iter::once(name.to_owned()).chain(expanded).collect()
+        } else {
+            expanded.chain(std::iter::once(name.to_owned())).collect()
+        }
+    }
+
+    /// Stub resolver configuration forwarding to the configured nameservers.
+    pub fn sans_io_config(&self) -> dns_sans_io::Config {
+        let upstreams = self.nameservers();
+        dns_sans_io::Config {
+            initial_timeout: self.timeout,
+            max_timeout: self.timeout,
+            max_attempts: self.attempts * upstreams.len() as u32,
+            rotate: self.rotate,
+            edns: self
+                .edns0
+                .then(|| dns_codec::Edns::default().udp_payload_size),
+            upstreams,
+            ..Default::default()
+        }
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net, time::Duration};
+
+    #[test]
+    fn load_and_expand() {
+        let path = std::env::temp_dir().join(format!("resolv.conf.{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# generated\n\
+             nameserver 192.0.2.53\n\
+             nameserver 2001:db8::53 ; secondary\n\
+             nameserver not-an-address\n\
+             domain ignored.example\n\
+             search corp.example. example.com\n\
+             options ndots:2 timeout:3 attempts:9 rotate edns0 single-request unknown\n",
+        )
+        .unwrap();
+        let conf = super::ResolvConf::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let nameservers: Vec<net::SocketAddr> = vec![
+            "192.0.2.53:53".parse().unwrap(),
+            "[2001:db8::53]:53".parse().unwrap(),
+        ];
+        assert_eq!(conf.nameservers, nameservers);
+        assert_eq!(conf.search, ["corp.example", "example.com"]);
+        assert_eq!(conf.ndots, 2);
+        assert_eq!(conf.timeout, Duration::from_secs(3));
+        assert_eq!(conf.attempts, 5);
+        assert!(conf.rotate && conf.edns0 && conf.single_request);
+        assert_eq!(conf.sans_io_config().edns, Some(1232));
+        assert_eq!(super::ResolvConf::default().sans_io_config().edns, None);
+
+        assert_eq!(
+            conf.candidates("www"),
+            ["www.corp.example", "www.example.com", "www"]
+        );
+        assert_eq!(
+            conf.candidates("www.example.org"),
+            [
+                "www.example.org",
+                "www.example.org.corp.example",
+                "www.example.org.example.com"
+            ]
+        );
+        assert_eq!(conf.candidates("www."), ["www"]);
+    }
+}
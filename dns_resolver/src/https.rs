@@ -0,0 +1,486 @@
+//! DNS over HTTPS (RFC 8484) to upstream resolvers.
+//!
+//! Each upstream gets at most one HTTP/2 connection at a time, opened on its first query and reused,
+//! with every query sent as a request of its own over it.
+
+use std::{collections::HashMap, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use base64::Engine as _;
+use http_body_util::{BodyExt as _, Full};
+use hyper::{client::conn::http2::SendRequest, header, Request, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rustls::{pki_types::ServerName, RootCertStore};
+use tokio::{net::TcpStream, sync::mpsc};
+use tokio_rustls::TlsConnector;
+use tokio_util::{
+    bytes::{Bytes, BytesMut},
+    codec::{Decoder as _, Encoder as _},
+};
+
+use crate::{io::Received, tls::TlsUpstream};
+
+/// Path DNS over HTTPS is commonly served at, and the one [`crate::server::Server`] serves it at.
+pub const DOH_PATH: &str = "/dns-query";
+
+/// Media type of DNS messages in requests and responses.
+pub(crate) const MEDIA_TYPE: &str = "application/dns-message";
+
+/// A connection without requests for this long is closed once its last response has arrived.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How queries are put into requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Method {
+    /// The query is the body of the request.
+    #[default]
+    Post,
+    /// The query is base64url-encoded into the `dns` parameter of the URI, which makes responses cacheable.
+    Get,
+}
+
+/// A resolver reached over HTTPS.
+#[derive(Debug, Clone)]
+pub struct HttpsUpstream {
+    /// Where to connect, and how to authenticate the server.
+    /// The auth name is also the authority requests are made to; without it, the address is.
+    pub tls: TlsUpstream,
+    /// Path of the URI template, e.g. [`DOH_PATH`].
+    pub path: String,
+    pub method: Method,
+}
+
+impl HttpsUpstream {
+    /// An upstream at `https://{auth_name}{path}`, connected to at `address`,
+    /// and authenticated by its certificate being valid for `auth_name`.
+    pub fn new(address: SocketAddr, auth_name: impl Into<String>, path: impl Into<String>) -> Self {
+        HttpsUpstream {
+            tls: TlsUpstream::new(address, auth_name),
+            path: path.into(),
+            method: Method::default(),
+        }
+    }
+
+    pub fn with_method(mut self, method: Method) -> Self {
+        self.method = method;
+        self
+    }
+
+    /// The URI of requests for the encoded `query`, which is only part of it for GET.
+    fn uri(&self, query: &[u8]) -> String {
+        let address = self.tls.address;
+        let host = match &self.tls.auth_name {
+            Some(name) => name.clone(),
+            None if address.is_ipv6() => format!("[{}]", address.ip()),
+            None => address.ip().to_string(),
+        };
+        let authority = match address.port() {
+            443 => host,
+            port => format!("{host}:{port}"),
+        };
+        match self.method {
+            Method::Post => format!("https://{authority}{}", self.path),
+            Method::Get => {
+                let dns = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(query);
+                format!("https://{authority}{}?dns={dns}", self.path)
+            }
+        }
+    }
+
+    fn request(&self, query: Bytes) -> io::Result<Request<Full<Bytes>>> {
+        let request = Request::builder()
+            .uri(self.uri(&query))
+            .header(header::ACCEPT, MEDIA_TYPE);
+        let request = match self.method {
+            Method::Post => request
+                .method(hyper::Method::POST)
+                .header(header::CONTENT_TYPE, MEDIA_TYPE)
+                .body(Full::new(query)),
+            Method::Get => request.method(hyper::Method::GET).body(Full::default()),
+        };
+        request.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+}
+
+/// Upstreams queried over HTTPS instead of those of `resolv.conf`.
+#[derive(Debug, Clone)]
+pub struct HttpsConfig {
+    pub upstreams: Vec<HttpsUpstream>,
+    roots: Arc<RootCertStore>,
+}
+
+impl HttpsConfig {
+    /// Trusts the Mozilla root certificates.
+    pub fn new(upstreams: Vec<HttpsUpstream>) -> Self {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        HttpsConfig::with_roots(upstreams, roots)
+    }
+
+    pub fn with_roots(upstreams: Vec<HttpsUpstream>, roots: RootCertStore) -> Self {
+        HttpsConfig {
+            upstreams,
+            roots: Arc::new(roots),
+        }
+    }
+
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.upstreams
+            .iter()
+            .map(|upstream| upstream.tls.address)
+            .collect()
+    }
+}
+
+/// The lifetime HTTP caches may keep a response for: the smallest TTL of its answers and authorities
+/// (RFC 8484, section 5.1), or zero if it has none.
+pub(crate) fn max_age(response: &dns_codec::Message) -> u32 {
+    response
+        .answers
+        .iter()
+        .chain(&response.authorities)
+        .map(|record| record.ttl.as_secs())
+        .min()
+        .unwrap_or(0)
+}
+
+/// The `max-age` directive of a Cache-Control header.
+fn cache_control_max_age(headers: &header::HeaderMap) -> Option<u32> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|directive| directive.trim().strip_prefix("max-age="))
+        .and_then(|secs| secs.parse().ok())
+}
+
+/// Shortens the TTLs of `response` to the freshness it has left as an HTTP response,
+/// as a cache it passed through may have kept it for a while (RFC 8484, section 5.1).
+fn honour_max_age(response: &mut dns_codec::Message, headers: &header::HeaderMap) {
+    let Some(max_age) = cache_control_max_age(headers) else {
+        return;
+    };
+    let age = headers
+        .get(header::AGE)
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+        .unwrap_or(0);
+    let remaining = max_age.saturating_sub(age);
+
+    let records = response.answers.iter_mut().chain(&mut response.authorities);
+    for record in records.chain(&mut response.additionals) {
+        if record.ttl.as_secs() > remaining {
+            record.ttl = dns_codec::Ttl::from_secs(remaining);
+        }
+    }
+}
+
+/// An upstream with everything needed to connect to it.
+struct Upstream {
+    upstream: HttpsUpstream,
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+/// The open connections to the upstreams of an [`HttpsConfig`].
+pub(crate) struct Connections {
+    upstreams: HashMap<SocketAddr, Arc<Upstream>>,
+    /// Queries waiting to be sent over each open connection.
+    open: HashMap<SocketAddr, mpsc::UnboundedSender<dns_codec::Message>>,
+}
+
+impl Connections {
+    pub(crate) fn new(config: &HttpsConfig) -> io::Result<Self> {
+        let upstreams = config
+            .upstreams
+            .iter()
+            .map(|upstream| {
+                let (connector, server_name) =
+                    crate::tls::connector(&upstream.tls, &config.roots, b"h2")?;
+                let upstream = Upstream {
+                    upstream: upstream.clone(),
+                    connector,
+                    server_name,
+                };
+                Ok((upstream.upstream.tls.address, Arc::new(upstream)))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Connections {
+            upstreams,
+            open: HashMap::new(),
+        })
+    }
+
+    /// Sends `query` over the connection to `target`, opening one if there is none.
+    /// Responses, and errors of requests and of the connection, are passed to `received`.
+    pub(crate) fn send(
+        &mut self,
+        target: SocketAddr,
+        query: dns_codec::Message,
+        received: &mpsc::UnboundedSender<Received>,
+    ) -> io::Result<()> {
+        // A connection that has been closed since drops its end of the queue
+        let query = match self.open.get(&target) {
+            Some(open) => match open.send(query) {
+                Ok(()) => return Ok(()),
+                Err(mpsc::error::SendError(query)) => query,
+            },
+            None => query,
+        };
+
+        let upstream = self.upstreams.get(&target).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{target} is not an HTTPS upstream"),
+            )
+        })?;
+        let (sender, queries) = mpsc::unbounded_channel();
+        let _ = sender.send(query);
+        self.open.insert(target, sender);
+
+        let upstream = upstream.clone();
+        let received = received.clone();
+        tokio::spawn(async move {
+            if let Err(e) = multiplex(upstream, queries, &received).await {
+                let _ = received.send(Err(e));
+            }
+        });
+        Ok(())
+    }
+}
+
+fn other(e: hyper::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Sends each of `queries` as a request of its own over a single connection to `upstream`,
+/// without waiting for responses in between. Stops taking queries once none have come
+/// for [`IDLE_TIMEOUT`], and closes the connection once the outstanding responses have arrived.
+///
+/// A query queued just as the connection closes is lost; the sans-io state machine retransmits it.
+async fn multiplex(
+    upstream: Arc<Upstream>,
+    mut queries: mpsc::UnboundedReceiver<dns_codec::Message>,
+    received: &mpsc::UnboundedSender<Received>,
+) -> io::Result<()> {
+    let target = upstream.upstream.tls.address;
+    let stream = TcpStream::connect(target).await?;
+    let stream = upstream
+        .connector
+        .connect(upstream.server_name.clone(), stream)
+        .await?;
+    let (sender, connection) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .map_err(other)?;
+    log::debug!("https: connected to {target}");
+    tokio::pin!(connection);
+
+    loop {
+        tokio::select! {
+            query = queries.recv() => {
+                let Some(query) = query else { break };
+                let sender = sender.clone();
+                let upstream = upstream.clone();
+                let received = received.clone();
+                tokio::spawn(async move {
+                    let _ = received.send(exchange(sender, &upstream.upstream, query).await);
+                });
+            }
+            result = &mut connection => {
+                log::debug!("https: {target} closed the connection");
+                return result.map_err(other);
+            }
+            _ = tokio::time::sleep(IDLE_TIMEOUT) => {
+                log::debug!("https: closing idle connection to {target}");
+                break;
+            }
+        }
+    }
+
+    // The connection finishes once the requests still in flight have dropped their senders
+    drop(sender);
+    connection.await.map_err(other)
+}
+
+/// Sends `query` as a request to `upstream`, and decodes the response to it.
+///
+/// The query is sent with ID 0 to make responses to GET requests cacheable (RFC 8484, section 4.1),
+/// so the response is given the ID of the query back.
+async fn exchange(
+    mut sender: SendRequest<Full<Bytes>>,
+    upstream: &HttpsUpstream,
+    mut query: dns_codec::Message,
+) -> Received {
+    let target = upstream.tls.address;
+    let id = query.header.id;
+    query.header.id = 0;
+    let mut message = BytesMut::new();
+    dns_codec::MessageCodec.encode(query, &mut message)?;
+
+    let response = sender
+        .send_request(upstream.request(message.freeze())?)
+        .await
+        .map_err(other)?;
+    let status = response.status();
+    if status != StatusCode::OK {
+        return Err(io::Error::other(format!(
+            "{target} responded with status {status}"
+        )));
+    }
+    let media_type = response.headers().get(header::CONTENT_TYPE);
+    if media_type.is_none_or(|media_type| media_type != MEDIA_TYPE) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{target} responded with {media_type:?} instead of {MEDIA_TYPE}"),
+        ));
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = body.collect().await.map_err(other)?.to_bytes();
+    let mut response = dns_codec::MessageCodec
+        .decode(&mut BytesMut::from(&body[..]))?
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Response of {target} is shorter than its contents require"),
+            )
+        })?;
+    response.header.id = id;
+    honour_max_age(&mut response, &parts.headers);
+    Ok((response, target))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use base64::Engine as _;
+    use http_body_util::{BodyExt as _, Full};
+    use hyper::{body::Incoming, header, Request, Response};
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use tokio::{net::TcpListener, sync::mpsc};
+    use tokio_util::bytes::Bytes;
+
+    use super::{HttpsConfig, HttpsUpstream, Method};
+
+    /// Requests received by [`listener`]: their method and the ID of the query they carried.
+    type Requests = Arc<Mutex<Vec<(hyper::Method, u16)>>>;
+
+    /// An HTTP/2 listener answering with [`crate::resolver::test::respond`],
+    /// with the answers cached for 20 of their 60 seconds already.
+    /// Also counts the connections accepted.
+    async fn listener() -> (SocketAddr, HttpsConfig, Requests, Arc<AtomicUsize>) {
+        let (mut config, certificate) = crate::tls::test::server_config();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = tcp.local_addr().unwrap();
+        let requests = Requests::default();
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let (log, counter) = (requests.clone(), accepted.clone());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = tcp.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let (acceptor, log) = (acceptor.clone(), log.clone());
+                tokio::spawn(async move {
+                    let stream = acceptor.accept(stream).await.unwrap();
+                    let service = hyper::service::service_fn(move |request: Request<Incoming>| {
+                        let log = log.clone();
+                        async move {
+                            let method = request.method().clone();
+                            let query = match method {
+                                hyper::Method::GET => {
+                                    let dns = request.uri().query().unwrap().strip_prefix("dns=");
+                                    base64::engine::general_purpose::URL_SAFE_NO_PAD
+                                        .decode(dns.unwrap())
+                                        .unwrap()
+                                }
+                                _ => request.collect().await.unwrap().to_bytes().to_vec(),
+                            };
+                            log.lock()
+                                .unwrap()
+                                .push((method, u16::from_be_bytes([query[0], query[1]])));
+
+                            let response = Response::builder()
+                                .header(header::CONTENT_TYPE, super::MEDIA_TYPE)
+                                .header(header::CACHE_CONTROL, "max-age=60")
+                                .header(header::AGE, "20")
+                                .body(Full::new(Bytes::from(crate::resolver::test::respond(
+                                    &query,
+                                ))));
+                            Ok::<_, Infallible>(response.unwrap())
+                        }
+                    });
+                    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                        .unwrap();
+                });
+            }
+        });
+
+        let upstream = HttpsUpstream::new(address, "dns.example", super::DOH_PATH);
+        let config = HttpsConfig::with_roots(vec![upstream], crate::tls::test::roots(&certificate));
+        (address, config, requests, accepted)
+    }
+
+    #[tokio::test]
+    async fn get_and_post() {
+        let (address, mut config, requests, accepted) = listener().await;
+
+        for method in [Method::Post, Method::Get] {
+            config.upstreams[0].method = method;
+            let mut connections = super::Connections::new(&config).unwrap();
+            let (sender, mut receiver) = mpsc::unbounded_channel();
+            for id in [1, 2, 3] {
+                connections
+                    .send(address, crate::tls::test::query(id), &sender)
+                    .unwrap();
+            }
+
+            let mut ids = Vec::new();
+            for _ in 0..3 {
+                let (response, source) = receiver.recv().await.unwrap().unwrap();
+                assert_eq!(source, address);
+                assert_eq!(response.answers[0].ttl.as_secs(), 40);
+                ids.push(response.header.id);
+            }
+            ids.sort();
+            assert_eq!(ids, [1, 2, 3]);
+        }
+
+        // Three requests each over a connection per method, all with ID 0
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        let requests = requests.lock().unwrap();
+        let methods: Vec<_> = requests.iter().map(|(method, _)| method.as_str()).collect();
+        assert_eq!(methods, ["POST", "POST", "POST", "GET", "GET", "GET"]);
+        assert!(requests.iter().all(|(_, id)| *id == 0));
+    }
+
+    #[tokio::test]
+    async fn lookups() {
+        let (_, https, _, accepted) = listener().await;
+        let config = crate::config::Config {
+            https: Some(https),
+            ..Default::default()
+        };
+
+        let resolver = crate::Resolver::new(config).await.unwrap();
+        let addresses = resolver.lookup_ip("example.com").await.unwrap();
+        assert_eq!(addresses.len(), 2);
+        let exchanges = resolver.lookup_mx("example.com").await.unwrap();
+        assert_eq!(exchanges.len(), 2);
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+}
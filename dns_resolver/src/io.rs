@@ -1,70 +1,282 @@
-use std::{
-    net::{self, SocketAddr},
-    task::ready,
+use std::net::{self, SocketAddr};
+
+use futures::{SinkExt as _, StreamExt as _};
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    sync::mpsc,
+};
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder as _, Encoder as _, FramedRead, FramedWrite},
 };
 
-use tokio::net::{TcpStream, UdpSocket};
+/// Largest datagram accepted from a nameserver.
+const MAX_DATAGRAM: usize = 65535;
 
-pub struct Config {
-    udp: SocketAddr,
-    tcp: SocketAddr,
-}
+pub(crate) type Received = std::io::Result<(dns_codec::Message, SocketAddr)>;
 
+/// Carries out [`dns_sans_io::Transmit`]s over the transport they ask for,
+/// and funnels all responses into a single stream.
+///
+/// Queries over UDP share one socket, unless a dedicated source port was requested;
+/// queries over TCP each use their own connection to the target,
+/// while queries over TLS, HTTPS and QUIC share one connection per upstream.
 pub struct DnsSockets {
     udp: UdpSocket,
-    tcp: TcpStream,
+    tls: Option<crate::tls::Connections>,
+    https: Option<crate::https::Connections>,
+    quic: Option<crate::quic::Connections>,
+    sender: mpsc::UnboundedSender<Received>,
+    receiver: mpsc::UnboundedReceiver<Received>,
 }
 
 impl DnsSockets {
-    pub async fn bind(config: Config) -> Result<Self, std::io::Error> {
-        DnsSockets {
-            udp: UdpSocket::bind(config.udp).await?,
-            tcp: TcpStream::connect(config.tcp).await?,
-        }
+    pub async fn bind(udp: SocketAddr) -> Result<Self, std::io::Error> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Ok(DnsSockets {
+            udp: UdpSocket::bind(udp).await?,
+            tls: None,
+            https: None,
+            quic: None,
+            sender,
+            receiver,
+        })
     }
-}
 
-impl tokio::io::AsyncRead for DnsSockets {
-    fn poll_read(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        let (read, _) = self.tcp.split();
+    /// Also sends queries over TLS, to the upstreams of `config`.
+    pub fn with_tls(mut self, config: &crate::tls::TlsConfig) -> std::io::Result<Self> {
+        self.tls = Some(crate::tls::Connections::new(config)?);
+        Ok(self)
+    }
+
+    /// Also sends queries over HTTPS, to the upstreams of `config`.
+    pub fn with_https(mut self, config: &crate::https::HttpsConfig) -> std::io::Result<Self> {
+        self.https = Some(crate::https::Connections::new(config)?);
+        Ok(self)
+    }
+
+    /// Also sends queries over QUIC, to the upstreams of `config`.
+    pub fn with_quic(mut self, config: &crate::quic::QuicConfig) -> std::io::Result<Self> {
+        self.quic = Some(crate::quic::Connections::new(config)?);
+        Ok(self)
+    }
+
+    pub async fn send(&mut self, transmit: dns_sans_io::Transmit) -> std::io::Result<()> {
+        let dns_sans_io::Transmit {
+            target,
+            transport,
+            query,
+            source_port,
+            deadline,
+        } = transmit;
+
+        match (transport, source_port) {
+            (dns_sans_io::Transport::Udp, None) => {
+                let mut datagram = BytesMut::new();
+                dns_codec::MessageCodec.encode(query, &mut datagram)?;
+                self.udp.send_to(&datagram, target).await?;
+            }
+            (dns_sans_io::Transport::Udp, Some(port)) => {
+                let sender = self.sender.clone();
+                tokio::spawn(async move {
+                    // Past the deadline the response would be ignored, so the socket is released
+                    match tokio::time::timeout_at(deadline.into(), query_udp(target, port, query))
+                        .await
+                    {
+                        Ok(received) => {
+                            let _ = sender.send(received);
+                        }
+                        Err(_) => log::debug!("udp: no response from {target} before the deadline"),
+                    }
+                });
+            }
+            (dns_sans_io::Transport::Tcp, _) => {
+                let sender = self.sender.clone();
+                tokio::spawn(async move {
+                    let _ = sender.send(query_tcp(target, query, deadline).await);
+                });
+            }
+            (dns_sans_io::Transport::Tls, _) => match &mut self.tls {
+                Some(tls) => tls.send(target, query, &self.sender)?,
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "No TLS upstreams are configured",
+                    ))
+                }
+            },
+            (dns_sans_io::Transport::Https, _) => match &mut self.https {
+                Some(https) => https.send(target, query, &self.sender)?,
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "No HTTPS upstreams are configured",
+                    ))
+                }
+            },
+            (dns_sans_io::Transport::Quic, _) => match &mut self.quic {
+                Some(quic) => quic.send(target, query, &self.sender)?,
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "No QUIC upstreams are configured",
+                    ))
+                }
+            },
+        }
+
+        Ok(())
+    }
 
-        let status = [self.udp.poll_recv(cx, buf), read.poll_read(cx, buf)]
-            .into_iter()
-            .find(|poll| poll.is_ready());
+    pub async fn recv(&mut self) -> Received {
+        let mut datagram = vec![0; MAX_DATAGRAM];
 
-        status.unwrap_or(std::task::Poll::Pending)
+        tokio::select! {
+            received = self.udp.recv_from(&mut datagram) => {
+                let (length, source) = received?;
+                let response = decode_datagram(&datagram[..length])?;
+                Ok((response, source))
+            }
+            Some(received) = self.receiver.recv() => received,
+        }
     }
 }
 
-impl tokio::io::AsyncWrite for DnsSockets {
-    fn poll_write(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &[u8],
-    ) -> std::task::Poll<Result<usize, std::io::Error>> {
-        if buf.len() <= 512 {
-            self.udp.poll_send(cx, buf)
-        } else {
-            let (_, write) = self.tcp.split();
-            write.poll_write(cx, buf)
+fn decode_datagram(datagram: &[u8]) -> std::io::Result<dns_codec::Message> {
+    let mut datagram = BytesMut::from(datagram);
+    dns_codec::MessageCodec
+        .decode(&mut datagram)?
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Datagram is shorter than its contents require",
+            )
+        })
+}
+
+/// Sends `query` from a socket bound to `port`, and waits for the datagram that answers it.
+/// Falls back to an ephemeral port picked by the OS if `port` is unavailable.
+async fn query_udp(target: SocketAddr, port: u16, query: dns_codec::Message) -> Received {
+    let unspecified: net::IpAddr = match target {
+        SocketAddr::V4(_) => net::Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => net::Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = match UdpSocket::bind((unspecified, port)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::debug!("source port {port} unavailable ({e}), using an ephemeral port");
+            UdpSocket::bind((unspecified, 0)).await?
         }
+    };
+    socket.connect(target).await?;
+
+    let mut datagram = BytesMut::new();
+    dns_codec::MessageCodec.encode(query, &mut datagram)?;
+    socket.send(&datagram).await?;
+
+    let mut datagram = vec![0; MAX_DATAGRAM];
+    let length = socket.recv(&mut datagram).await?;
+    Ok((decode_datagram(&datagram[..length])?, target))
+}
+
+/// Sends `query` over a fresh TCP connection, and waits for the response on it until `deadline`.
+async fn query_tcp(
+    target: SocketAddr,
+    query: dns_codec::Message,
+    deadline: std::time::Instant,
+) -> Received {
+    let timed_out = |_| {
+        std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("{target} did not respond before the deadline"),
+        )
+    };
+    let deadline = tokio::time::Instant::from(deadline);
+
+    let stream = tokio::time::timeout_at(deadline, TcpStream::connect(target))
+        .await
+        .map_err(timed_out)??;
+    let (read, write) = stream.into_split();
+
+    let mut sink = FramedWrite::new(write, dns_codec::StreamCodec(dns_codec::MessageCodec));
+    let mut stream = FramedRead::new(read, dns_codec::StreamCodec(dns_codec::MessageCodec));
+
+    sink.send(query).await?;
+    match tokio::time::timeout_at(deadline, stream.next())
+        .await
+        .map_err(timed_out)?
+    {
+        Some(response) => Ok((response?, target)),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("{target} closed the connection without responding"),
+        )),
     }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use tokio::{
+        io::AsyncReadExt as _,
+        net::{TcpListener, UdpSocket},
+    };
 
-    fn poll_flush(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
-        todo!()
+    fn query() -> dns_codec::Message {
+        dns_codec::Message::query(b"example.com".to_vec(), dns_codec::QType::A)
+            .build()
+            .unwrap()
     }
 
-    fn poll_shutdown(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
-        std::task::Poll::Ready(Ok(()))
+    #[tokio::test]
+    async fn deadlines() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // A port that is free once its probe is dropped
+        let port = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let mut sockets = super::DnsSockets::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        sockets
+            .send(dns_sans_io::Transmit {
+                target: silent.local_addr().unwrap(),
+                transport: dns_sans_io::Transport::Udp,
+                query: query(),
+                source_port: Some(port),
+                deadline: Instant::now() + Duration::from_millis(50),
+            })
+            .await
+            .unwrap();
+        let mut datagram = [0; 512];
+        silent.recv_from(&mut datagram).await.unwrap();
+
+        // The dedicated socket is closed once nobody waits for the response any more
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        UdpSocket::bind(("0.0.0.0", port)).await.unwrap();
+
+        // A TCP connection is closed at the deadline too, failing the query
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        sockets
+            .send(dns_sans_io::Transmit {
+                target: listener.local_addr().unwrap(),
+                transport: dns_sans_io::Transport::Tcp,
+                query: query(),
+                source_port: None,
+                deadline: Instant::now() + Duration::from_millis(50),
+            })
+            .await
+            .unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let error = sockets.recv().await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert!(!received.is_empty());
     }
 }
@@ -0,0 +1,483 @@
+//! Iterative resolution, for a [`crate::Resolver`] configured with root hints instead of upstreams.
+//!
+//! Lookups start at the root servers and follow the referrals that the sans-io state machine reports as
+//! [`dns_sans_io::Outcome::NamespaceIp`] and [`dns_sans_io::Outcome::NamespaceNames`] down to the
+//! nameservers authoritative for the name. Nameservers referred to without glue have their addresses
+//! looked up the same way, and aliases are followed to their target.
+
+use std::{io, net::SocketAddr};
+
+use crate::resolver::{self, Resolution};
+
+/// Most queries a single lookup may send, so that referral loops and alias chains come to an end.
+const MAX_QUERIES: usize = 48;
+
+/// Most lookups of nameserver addresses nested within one another, as when the nameservers of a zone
+/// came without glue, and so did those of the zone the nameservers are in.
+const MAX_DEPTH: usize = 4;
+
+/// A name being resolved: the lookup itself, or the address of a nameserver it was referred to.
+struct Frame {
+    name: dns_codec::Name,
+    kind: dns_codec::QType,
+    /// Zone the `nameservers` are authoritative for.
+    zone: dns_codec::Name,
+    nameservers: Vec<SocketAddr>,
+    /// CNAME records followed to `name`, which precede its answers.
+    aliases: Vec<dns_codec::Record>,
+    /// Zone `name` was referred to without glue, and the nameservers of it whose addresses are still to be tried.
+    delegation: Option<(dns_codec::Name, Vec<dns_codec::Name>)>,
+}
+
+impl Frame {
+    fn new(name: dns_codec::Name, kind: dns_codec::QType, root_hints: &[SocketAddr]) -> Self {
+        Frame {
+            name,
+            kind,
+            zone: dns_codec::Name::root(),
+            nameservers: root_hints.to_vec(),
+            aliases: Vec::new(),
+            delegation: None,
+        }
+    }
+}
+
+/// A lookup in progress, waiting for the response to its latest query.
+pub(crate) struct Iteration {
+    root_hints: Vec<SocketAddr>,
+    /// The lookup, followed by the nameserver addresses looked up on its behalf, innermost last.
+    frames: Vec<Frame>,
+    /// Queries sent so far, across all frames.
+    queries: usize,
+}
+
+pub(crate) enum Step {
+    /// The query with this ID was enqueued; its response continues the iteration.
+    Query(u16, Iteration),
+    /// The lookup is over.
+    Done(Resolution),
+}
+
+impl Iteration {
+    /// Starts resolving `kind` for `name` at the `root_hints`.
+    pub(crate) fn start(
+        sans_io: &mut dns_sans_io::DnsSansIo,
+        root_hints: &[SocketAddr],
+        name: dns_codec::Name,
+        kind: dns_codec::QType,
+    ) -> Step {
+        let iteration = Iteration {
+            root_hints: root_hints.to_vec(),
+            frames: vec![Frame::new(name, kind, root_hints)],
+            queries: 0,
+        };
+        iteration.query(sans_io)
+    }
+
+    /// Continues with the `response` to the latest query.
+    pub(crate) fn step(
+        mut self,
+        sans_io: &mut dns_sans_io::DnsSansIo,
+        response: dns_sans_io::Response,
+    ) -> Step {
+        let depth = self.frames.len();
+        let port = self.port();
+        let frame = self
+            .frames
+            .last_mut()
+            .expect("iterations end with their last frame");
+        let event = format!("0x{:04x}", response.id);
+
+        match (&response.outcome, &response.message) {
+            (
+                dns_sans_io::Outcome::NamespaceIp(_) | dns_sans_io::Outcome::NamespaceNames(_),
+                Some(message),
+            ) => {
+                let Some((zone, mut nameservers)) = delegation(&frame.name, &frame.zone, message)
+                else {
+                    let error = io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{} referred {} to no zone beneath {}",
+                            response.source, frame.name, frame.zone
+                        ),
+                    );
+                    return self.fail(sans_io, error);
+                };
+                let glue = glue(&nameservers, message, port);
+                log::debug!(target: &event, "iterate: {} referred {} to {zone}, with {} addresses", response.source, frame.name, glue.len());
+
+                if !glue.is_empty() {
+                    frame.zone = zone;
+                    frame.nameservers = glue;
+                    return self.query(sans_io);
+                }
+                if depth == MAX_DEPTH {
+                    let error = io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Nameservers of {zone} cannot be reached without glue"),
+                    );
+                    return self.fail(sans_io, error);
+                }
+                let nameserver = nameservers.pop().expect("delegations name nameservers");
+                frame.delegation = Some((zone, nameservers));
+                self.frames.push(Frame::new(
+                    nameserver,
+                    dns_codec::QType::A,
+                    &self.root_hints,
+                ));
+                self.query(sans_io)
+            }
+            (dns_sans_io::Outcome::Unresolved, Some(message)) => {
+                let Some((aliases, target)) = aliases(&frame.name, message) else {
+                    return self.finish(sans_io, resolver::resolution(response));
+                };
+                log::debug!(target: &event, "iterate: {} is an alias of {target}", frame.name);
+
+                // Only the nameservers of the zone the target is in know about it
+                if !target.is_subdomain_of(&frame.zone) {
+                    frame.zone = dns_codec::Name::root();
+                    frame.nameservers = self.root_hints.clone();
+                }
+                frame.aliases.extend(aliases);
+                frame.name = target;
+                self.query(sans_io)
+            }
+            _ => self.finish(sans_io, resolver::resolution(response)),
+        }
+    }
+
+    /// Port that nameservers learned from referrals are queried on.
+    fn port(&self) -> u16 {
+        self.root_hints.first().map_or(53, SocketAddr::port)
+    }
+
+    /// Enqueues the query of the innermost frame.
+    fn query(mut self, sans_io: &mut dns_sans_io::DnsSansIo) -> Step {
+        if self.queries == MAX_QUERIES {
+            return Step::Done(Resolution {
+                answer: Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Lookup was not resolved within {MAX_QUERIES} queries"),
+                )),
+                response: None,
+            });
+        }
+
+        let frame = self
+            .frames
+            .last()
+            .expect("iterations end with their last frame");
+        let resource = match resolver::resource(&frame.name) {
+            Ok(resource) => resource,
+            Err(e) => return self.fail(sans_io, e),
+        };
+        let id = sans_io.enqueue_query_in_zone(
+            frame.zone.clone(),
+            &frame.nameservers,
+            frame.kind,
+            resource,
+        );
+        self.queries += 1;
+        Step::Query(id, self)
+    }
+
+    /// Ends the innermost frame with `resolution`, handing a nameserver's addresses to the frame that needs them.
+    fn finish(mut self, sans_io: &mut dns_sans_io::DnsSansIo, mut resolution: Resolution) -> Step {
+        if let [frame] = &mut self.frames[..] {
+            let aliases = std::mem::take(&mut frame.aliases);
+            if let Some(message) = &mut resolution.response {
+                message.answers.splice(..0, aliases.iter().cloned());
+            }
+            if let Ok(records) = &mut resolution.answer {
+                records.splice(..0, aliases);
+            }
+            return Step::Done(resolution);
+        }
+
+        let port = self.port();
+        let addresses: Vec<_> = match resolution.answer {
+            Ok(records) => records
+                .iter()
+                .filter_map(|record| match record.rdata {
+                    dns_codec::RData::Ipv4(address) => Some(SocketAddr::new(address.into(), port)),
+                    _ => None,
+                })
+                .collect(),
+            Err(e) => return self.fail(sans_io, e),
+        };
+        if addresses.is_empty() {
+            let error = io::Error::new(io::ErrorKind::NotFound, "Nameserver has no IPv4 addresses");
+            return self.fail(sans_io, error);
+        }
+
+        self.frames.pop();
+        let parent = self
+            .frames
+            .last_mut()
+            .expect("nameserver lookups have a parent");
+        let (zone, _) = parent
+            .delegation
+            .take()
+            .expect("nameserver lookups have a delegation");
+        parent.zone = zone;
+        parent.nameservers = addresses;
+        self.query(sans_io)
+    }
+
+    /// Gives up on the innermost frame, trying the next nameserver of the delegation that needed it, if any.
+    fn fail(mut self, sans_io: &mut dns_sans_io::DnsSansIo, error: io::Error) -> Step {
+        self.frames.pop();
+        while let Some(parent) = self.frames.last_mut() {
+            let next = parent
+                .delegation
+                .as_mut()
+                .and_then(|(_, nameservers)| nameservers.pop());
+            if let Some(nameserver) = next {
+                self.frames.push(Frame::new(
+                    nameserver,
+                    dns_codec::QType::A,
+                    &self.root_hints,
+                ));
+                return self.query(sans_io);
+            }
+            self.frames.pop();
+        }
+
+        Step::Done(Resolution {
+            answer: Err(error),
+            response: None,
+        })
+    }
+}
+
+/// The zone beneath `zone` that `message` refers `name` to, and the names of its nameservers.
+fn delegation(
+    name: &dns_codec::Name,
+    zone: &dns_codec::Name,
+    message: &dns_codec::Message,
+) -> Option<(dns_codec::Name, Vec<dns_codec::Name>)> {
+    // Scrubbing left only authority records within `zone` and owned by ancestors of `name`
+    let mut delegated: Option<&dns_codec::Name> = None;
+    let mut nameservers = Vec::new();
+    for record in &message.authorities {
+        let (dns_codec::Type::NS, dns_codec::RData::Name(nameserver)) =
+            (record.kind, &record.rdata)
+        else {
+            continue;
+        };
+        if record.name == *zone || !name.is_subdomain_of(&record.name) {
+            continue;
+        }
+        if delegated.is_some_and(|delegated| *delegated != record.name) {
+            continue;
+        }
+        delegated = Some(&record.name);
+        nameservers.push(nameserver.clone());
+    }
+    Some((delegated?.clone(), nameservers))
+}
+
+/// IPv4 addresses of `nameservers` among the additional records of `message`.
+fn glue(
+    nameservers: &[dns_codec::Name],
+    message: &dns_codec::Message,
+    port: u16,
+) -> Vec<SocketAddr> {
+    message
+        .additionals
+        .iter()
+        .filter(|record| nameservers.contains(&record.name))
+        .filter_map(|record| match record.rdata {
+            dns_codec::RData::Ipv4(address) => Some(SocketAddr::new(address.into(), port)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The CNAME records of `message` leading away from `name`, and the name the last of them points to.
+fn aliases(
+    name: &dns_codec::Name,
+    message: &dns_codec::Message,
+) -> Option<(Vec<dns_codec::Record>, dns_codec::Name)> {
+    let mut aliases = Vec::new();
+    let mut target = name;
+    // Every record is followed at most once, should the aliases form a loop
+    while aliases.len() < message.answers.len() {
+        let alias = message
+            .answers
+            .iter()
+            .find(|record| record.kind == dns_codec::Type::CNAME && record.name == *target);
+        let Some(
+            alias @ dns_codec::Record {
+                rdata: dns_codec::RData::Name(next),
+                ..
+            },
+        ) = alias
+        else {
+            break;
+        };
+        aliases.push(alias.clone());
+        target = next;
+    }
+    let target = target.clone();
+    (!aliases.is_empty()).then_some((aliases, target))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use tokio::net::UdpSocket;
+    use tokio_util::{
+        bytes::BytesMut,
+        codec::{Decoder as _, Encoder as _},
+    };
+
+    fn name(name: &str) -> dns_codec::Name {
+        name.try_into().unwrap()
+    }
+
+    fn record(owner: &str, kind: dns_codec::Type, rdata: dns_codec::RData) -> dns_codec::Record {
+        dns_codec::Record {
+            name: name(owner),
+            kind,
+            class: dns_codec::Class::IN,
+            ttl: dns_codec::Ttl::from_secs(3600),
+            length: 0,
+            rdata,
+        }
+    }
+
+    /// Spawns a nameserver on `address` answering every query with `respond`, returning the address it is bound to.
+    async fn nameserver(
+        address: SocketAddr,
+        respond: fn(&dns_codec::Message) -> dns_codec::MessageBuilder,
+    ) -> SocketAddr {
+        let socket = UdpSocket::bind(address).await.unwrap();
+        let address = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            loop {
+                let (length, source) = socket.recv_from(&mut buffer).await.unwrap();
+                let query = dns_codec::MessageCodec
+                    .decode(&mut BytesMut::from(&buffer[..length]))
+                    .unwrap()
+                    .unwrap();
+                let mut response = BytesMut::new();
+                let message = respond(&query).build().unwrap();
+                dns_codec::MessageCodec
+                    .encode(message, &mut response)
+                    .unwrap();
+                socket.send_to(&response, source).await.unwrap();
+            }
+        });
+
+        address
+    }
+
+    /// The root zone, delegating `example` to ns.example with glue, and `test` to the same nameserver without.
+    fn root(query: &dns_codec::Message) -> dns_codec::MessageBuilder {
+        let ns = |zone| {
+            record(
+                zone,
+                dns_codec::Type::NS,
+                dns_codec::RData::Name(name("ns.example")),
+            )
+        };
+        let response = query.response();
+        match query.questions[0].name.labels().last() {
+            None => response.authoritative(true).answer(record(
+                "",
+                dns_codec::Type::NS,
+                dns_codec::RData::Name(name("a.root-servers.net")),
+            )),
+            Some(b"example") => response.authority(ns("example")).additional(record(
+                "ns.example",
+                dns_codec::Type::A,
+                dns_codec::RData::Ipv4([127, 0, 0, 2].into()),
+            )),
+            Some(_) => response.authority(ns("test")),
+        }
+    }
+
+    /// ns.example, authoritative for `example` and `test`.
+    /// www.example is an alias of host.test, and loop.example is referred back to `example`.
+    fn authoritative(query: &dns_codec::Message) -> dns_codec::MessageBuilder {
+        let response = query.response().authoritative(true);
+        let owner = query.questions[0].name.to_string();
+        let address = |address: [u8; 4]| {
+            record(
+                &owner,
+                dns_codec::Type::A,
+                dns_codec::RData::Ipv4(address.into()),
+            )
+        };
+        match owner.as_str() {
+            "www.example" => response.answer(record(
+                "www.example",
+                dns_codec::Type::CNAME,
+                dns_codec::RData::Name(name("host.test")),
+            )),
+            "host.test" => response.answer(address([192, 0, 2, 1])),
+            "ns.example" => response.answer(address([127, 0, 0, 2])),
+            "loop.example" => response.authoritative(false).authority(record(
+                "example",
+                dns_codec::Type::NS,
+                dns_codec::RData::Name(name("ns.example")),
+            )),
+            _ => response.rcode(dns_codec::Rcode::NXDOMAIN),
+        }
+    }
+
+    #[tokio::test]
+    async fn referrals() {
+        let root = nameserver("127.0.0.1:0".parse().unwrap(), root).await;
+        let ns = SocketAddr::new([127, 0, 0, 2].into(), root.port());
+        nameserver(ns, authoritative).await;
+
+        let config = crate::config::Config {
+            root_hints: Some(vec![root]),
+            ..Default::default()
+        };
+        let resolver = crate::Resolver::new(config).await.unwrap();
+
+        // Referred to example with glue, then aliased into test, whose nameserver comes without glue
+        let resolution = resolver
+            .forward(name("www.example"), dns_codec::QType::A)
+            .await
+            .unwrap();
+        let answers = resolution.answer.unwrap();
+        let kinds: Vec<_> = answers.iter().map(|record| record.kind).collect();
+        assert_eq!(kinds, [dns_codec::Type::CNAME, dns_codec::Type::A]);
+        assert_eq!(answers[1].name, name("host.test"));
+        assert_eq!(resolution.response.unwrap().answers, answers);
+
+        // The root answers for itself
+        let resolution = resolver
+            .forward(dns_codec::Name::root(), dns_codec::QType::NS)
+            .await
+            .unwrap();
+        assert_eq!(resolution.answer.unwrap().len(), 1);
+
+        // Negative answers are kept for relaying
+        let resolution = resolver
+            .forward(name("missing.example"), dns_codec::QType::A)
+            .await
+            .unwrap();
+        let error = resolution.answer.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+        let response = resolution.response.unwrap();
+        assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::NXDOMAIN));
+
+        // A referral that does not lead any closer is a failure
+        let resolution = resolver
+            .forward(name("loop.example"), dns_codec::QType::A)
+            .await
+            .unwrap();
+        let error = resolution.answer.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(resolution.response.is_none());
+    }
+}
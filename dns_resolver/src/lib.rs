@@ -0,0 +1,11 @@
+mod cache;
+pub mod config;
+pub mod https;
+mod io;
+mod iterate;
+pub mod quic;
+mod resolver;
+pub mod server;
+pub mod tls;
+
+pub use resolver::{reverse_name, Resolver};
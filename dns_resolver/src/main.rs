@@ -1,58 +1,33 @@
-use dns_codec;
-use dns_sans_io;
+//! Recursive DNS server, forwarding to the nameservers of the system's resolv.conf if it lists any,
+//! none of which may be this server itself, and iterating from the root servers otherwise.
+//!
+//! Usage: `dns_resolver [LISTEN_ADDRESS] [ALLOWED_SUBNET...]`, listening on 0.0.0.0:53 by default
+//! and answering only the local host unless subnets allowed to query are given.
 
-use log;
-
-use futures::{SinkExt, StreamExt};
-use tokio::net::UdpSocket;
-use tokio_util::udp::UdpFramed;
-
-// mod io;
+use std::net::SocketAddr;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    let udpsocket = UdpSocket::bind("0.0.0.0:53").await.unwrap();
-    let mut sink = UdpFramed::new(&udpsocket, dns_codec::QueryCodec);
-    let mut stream = UdpFramed::new(&udpsocket, dns_codec::ResponseCodec);
-
-    let id = 0x8296;
-
-    let mut sans_io = dns_sans_io::DnsSansIo::new();
-    let resource = "google.com".to_owned();
-
-    sans_io.enqueue_query(
-        "1.1.1.1:53".parse().unwrap(),
-        id,
-        dns_codec::QType::AAAA,
-        resource.clone().into_bytes(),
-    );
-    let response = loop {
-        if let Some(transmit) = sans_io.poll_query() {
-            let dns_sans_io::Transmit { query, target } = transmit;
-            sink.send((query, target)).await.unwrap()
-        }
-
-        tokio::select! {
-            message = stream.next() => {
-                match message {
-                    Some(Ok((response, source))) => {
-                        let response = sans_io.handle_response(source, response).unwrap();
-                        match response.outcome {
-                            dns_sans_io::Outcome::Resolved(records) => { break records; },
-                            dns_sans_io::Outcome::NamespaceIp(records) => panic!("namespace ips"),
-                            dns_sans_io::Outcome::NamespaceNames(_) => panic!("namespace names"),
-                            dns_sans_io::Outcome::Unresolved => panic!("{resource} is unknown!"),
-                        }
-                    },
-                    Some(Err(e)) => { log::error!("{e}"); return; },
-                    None => { log::error!("closed?"); return; }
-                };
-
-            }
-        }
+    let mut args = std::env::args().skip(1);
+    let address: SocketAddr = args
+        .next()
+        .map(|address| address.parse().expect("invalid listen address"))
+        .unwrap_or_else(|| "0.0.0.0:53".parse().unwrap());
+    let allowed: Vec<dns_resolver::server::Subnet> = args
+        .map(|subnet| subnet.parse().expect("invalid allowed subnet"))
+        .collect();
+    let acl = match allowed.is_empty() {
+        true => dns_resolver::server::Acl::default(),
+        false => dns_resolver::server::Acl::new(allowed),
     };
 
-    println!("{response:#?}");
+    let mut config = dns_resolver::config::Config::system().unwrap();
+    if !config.has_upstreams() {
+        config.root_hints = Some(dns_resolver::config::root_servers());
+    }
+    let resolver = dns_resolver::Resolver::new(config).await.unwrap();
+    let server = dns_resolver::server::Server::new(resolver, acl);
+    server.serve(address).await.unwrap();
 }
@@ -0,0 +1,393 @@
+//! DNS over QUIC (RFC 9250) to upstream resolvers.
+//!
+//! Each upstream gets at most one connection at a time, opened on its first query and reused,
+//! with every query sent on a bidirectional stream of its own. Connections are resumed with
+//! 0-RTT where the server allows it, so the first query after a reconnect goes out right away.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{sync::mpsc, task::JoinSet};
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder as _, Encoder as _},
+};
+
+use crate::io::Received;
+
+/// Port DNS over QUIC is served on.
+pub const DOQ_PORT: u16 = 853;
+
+/// Error code closing a connection that is no longer needed (RFC 9250, section 4.3).
+const DOQ_NO_ERROR: u32 = 0;
+
+/// A connection without queries or responses for this long is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest response accepted, including its length prefix.
+const MAX_RESPONSE: usize = 2 + 65535;
+
+/// Upstreams queried over QUIC, authenticated just like those queried over TLS.
+pub type QuicConfig = crate::tls::TlsConfig;
+
+/// An upstream with everything needed to connect to it.
+struct Upstream {
+    address: SocketAddr,
+    config: quinn::ClientConfig,
+    server_name: String,
+}
+
+/// The open connections to the upstreams of a [`QuicConfig`].
+pub(crate) struct Connections {
+    upstreams: HashMap<SocketAddr, Arc<Upstream>>,
+    /// Queries waiting to be sent over each open connection.
+    open: HashMap<SocketAddr, mpsc::UnboundedSender<dns_codec::Message>>,
+}
+
+impl Connections {
+    pub(crate) fn new(config: &QuicConfig) -> io::Result<Self> {
+        let upstreams = config
+            .upstreams
+            .iter()
+            .map(|upstream| {
+                let (mut tls, server_name) =
+                    crate::tls::client_config(upstream, config.roots(), b"doq")?;
+                // Session tickets are kept by the configuration, so that reconnects can resume
+                tls.enable_early_data = true;
+                let quic = quinn::crypto::rustls::QuicClientConfig::try_from(Arc::new(tls))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                let upstream = Upstream {
+                    address: upstream.address,
+                    config: quinn::ClientConfig::new(Arc::new(quic)),
+                    server_name: server_name.to_str().into_owned(),
+                };
+                Ok((upstream.address, Arc::new(upstream)))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Connections {
+            upstreams,
+            open: HashMap::new(),
+        })
+    }
+
+    /// Sends `query` over the connection to `target`, opening one if there is none.
+    /// Responses, and errors of streams and of the connection, are passed to `received`.
+    pub(crate) fn send(
+        &mut self,
+        target: SocketAddr,
+        query: dns_codec::Message,
+        received: &mpsc::UnboundedSender<Received>,
+    ) -> io::Result<()> {
+        // A connection that has been closed since drops its end of the queue
+        let query = match self.open.get(&target) {
+            Some(open) => match open.send(query) {
+                Ok(()) => return Ok(()),
+                Err(mpsc::error::SendError(query)) => query,
+            },
+            None => query,
+        };
+
+        let upstream = self.upstreams.get(&target).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{target} is not a QUIC upstream"),
+            )
+        })?;
+        let (sender, queries) = mpsc::unbounded_channel();
+        let _ = sender.send(query);
+        self.open.insert(target, sender);
+
+        let upstream = upstream.clone();
+        let received = received.clone();
+        tokio::spawn(async move {
+            if let Err(e) = multiplex(upstream, queries, &received).await {
+                let _ = received.send(Err(e));
+            }
+        });
+        Ok(())
+    }
+}
+
+fn other(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Connects to `upstream`, in 0-RTT if a session can be resumed and `first` may be sent early.
+async fn connect(
+    upstream: &Upstream,
+    first: &dns_codec::Message,
+) -> io::Result<(quinn::Endpoint, quinn::Connection)> {
+    let unspecified: SocketAddr = match upstream.address {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let endpoint = quinn::Endpoint::client(unspecified)?;
+    let connecting = endpoint
+        .connect_with(
+            upstream.config.clone(),
+            upstream.address,
+            &upstream.server_name,
+        )
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    // Early data can be replayed by an attacker, which only queries are safe against (RFC 9250, section 4.5)
+    if first.header.opcode() != Some(dns_codec::Opcode::QUERY) {
+        return Ok((endpoint, connecting.await?));
+    }
+    let connection = match connecting.into_0rtt() {
+        Ok((connection, _)) => {
+            log::debug!(
+                "quic: resuming the connection to {} in 0-RTT",
+                upstream.address
+            );
+            connection
+        }
+        Err(connecting) => connecting.await?,
+    };
+    Ok((endpoint, connection))
+}
+
+/// Sends each of `queries` on a stream of its own over a single connection to `upstream`,
+/// without waiting for responses in between. Stops taking queries once nothing has happened
+/// for [`IDLE_TIMEOUT`], and closes the connection once the outstanding responses have arrived.
+///
+/// A query queued just as the connection closes is lost; the sans-io state machine retransmits it.
+async fn multiplex(
+    upstream: Arc<Upstream>,
+    mut queries: mpsc::UnboundedReceiver<dns_codec::Message>,
+    received: &mpsc::UnboundedSender<Received>,
+) -> io::Result<()> {
+    let target = upstream.address;
+    let Some(first) = queries.recv().await else {
+        return Ok(());
+    };
+    let (endpoint, connection) = connect(&upstream, &first).await?;
+    log::debug!("quic: connected to {target}");
+
+    let mut exchanges = JoinSet::new();
+    let mut query = Some(first);
+    loop {
+        if let Some(query) = query.take() {
+            let (connection, received) = (connection.clone(), received.clone());
+            exchanges.spawn(async move {
+                let _ = received.send(exchange(&connection, target, query).await);
+            });
+        }
+
+        tokio::select! {
+            next = queries.recv() => match next {
+                Some(next) => query = Some(next),
+                None => break,
+            },
+            Some(_) = exchanges.join_next() => (),
+            error = connection.closed() => {
+                log::debug!("quic: connection to {target} closed: {error}");
+                break;
+            }
+            _ = tokio::time::sleep(IDLE_TIMEOUT) => {
+                log::debug!("quic: closing idle connection to {target}");
+                break;
+            }
+        }
+    }
+
+    while exchanges.join_next().await.is_some() {}
+    connection.close(DOQ_NO_ERROR.into(), b"");
+    endpoint.wait_idle().await;
+    Ok(())
+}
+
+/// Why a stream failed.
+enum Failure {
+    /// The query was sent as early data, which the server did not accept.
+    ZeroRttRejected,
+    Io(io::Error),
+}
+
+impl<E: Into<io::Error>> From<E> for Failure {
+    fn from(e: E) -> Self {
+        Failure::Io(e.into())
+    }
+}
+
+/// Sends the length-prefixed `message` on a new stream, and reads the response from it.
+async fn request(connection: &quinn::Connection, message: &[u8]) -> Result<Vec<u8>, Failure> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    match send.write_all(message).await {
+        Err(quinn::WriteError::ZeroRttRejected) => return Err(Failure::ZeroRttRejected),
+        written => written?,
+    }
+    // The client indicates the end of the query by finishing the stream (RFC 9250, section 4.2)
+    send.finish().map_err(other)?;
+    match recv.read_to_end(MAX_RESPONSE).await {
+        Err(quinn::ReadToEndError::Read(quinn::ReadError::ZeroRttRejected)) => {
+            Err(Failure::ZeroRttRejected)
+        }
+        Err(e) => Err(Failure::Io(other(e))),
+        Ok(response) => Ok(response),
+    }
+}
+
+/// Sends `query` on a stream of its own over `connection`, and decodes the response on it.
+///
+/// The query is sent with ID 0 as streams already tell responses apart (RFC 9250, section 4.2.1),
+/// so the response is given the ID of the query back.
+async fn exchange(
+    connection: &quinn::Connection,
+    target: SocketAddr,
+    mut query: dns_codec::Message,
+) -> Received {
+    let id = query.header.id;
+    query.header.id = 0;
+    let mut message = BytesMut::new();
+    dns_codec::StreamCodec(dns_codec::MessageCodec).encode(query, &mut message)?;
+
+    // Streams opened in 0-RTT are lost if the server rejects it, and are repeated once the handshake completed
+    let response = match request(connection, &message).await {
+        Err(Failure::ZeroRttRejected) => {
+            log::debug!("quic: {target} rejected 0-RTT, repeating the query");
+            request(connection, &message).await
+        }
+        response => response,
+    };
+    let response = match response {
+        Ok(response) => response,
+        Err(Failure::Io(e)) => return Err(e),
+        Err(Failure::ZeroRttRejected) => {
+            return Err(other(format!("{target} rejected 0-RTT twice")))
+        }
+    };
+
+    let mut response = dns_codec::StreamCodec(dns_codec::MessageCodec)
+        .decode(&mut BytesMut::from(&response[..]))?
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{target} finished the stream before the response was complete"),
+            )
+        })?;
+    response.header.id = id;
+    Ok((response, target))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use tokio::sync::mpsc;
+
+    use crate::tls::TlsUpstream;
+
+    /// Streams received by [`listener`]: whether each came as 0-RTT, and the ID of its query.
+    type Streams = Arc<Mutex<Vec<(bool, u16)>>>;
+
+    /// A QUIC listener answering with [`crate::resolver::test::respond`], accepting 0-RTT.
+    /// Closes every connection after three streams.
+    async fn listener() -> (SocketAddr, super::QuicConfig, Streams) {
+        let (mut tls, certificate) = crate::tls::test::server_config();
+        tls.alpn_protocols = vec![b"doq".to_vec()];
+        tls.max_early_data_size = u32::MAX;
+        let quic = quinn::crypto::rustls::QuicServerConfig::try_from(tls).unwrap();
+        let config = quinn::ServerConfig::with_crypto(Arc::new(quic));
+        let endpoint = quinn::Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = endpoint.local_addr().unwrap();
+
+        let streams = Streams::default();
+        let log = streams.clone();
+        tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                let log = log.clone();
+                tokio::spawn(async move {
+                    let (connection, _) = incoming.accept().unwrap().into_0rtt().unwrap();
+                    for _ in 0..3 {
+                        let Ok((mut send, mut recv)) = connection.accept_bi().await else {
+                            return;
+                        };
+                        let early = recv.is_0rtt();
+                        let query = recv.read_to_end(super::MAX_RESPONSE).await.unwrap();
+                        let query = &query[2..];
+                        log.lock()
+                            .unwrap()
+                            .push((early, u16::from_be_bytes([query[0], query[1]])));
+
+                        let response = crate::resolver::test::respond(query);
+                        send.write_all(&(response.len() as u16).to_be_bytes())
+                            .await
+                            .unwrap();
+                        send.write_all(&response).await.unwrap();
+                        send.finish().unwrap();
+                        send.stopped().await.unwrap();
+                    }
+                    connection.close(super::DOQ_NO_ERROR.into(), b"");
+                });
+            }
+        });
+
+        let upstream = TlsUpstream::new(address, "dns.example");
+        let config =
+            super::QuicConfig::with_roots(vec![upstream], crate::tls::test::roots(&certificate));
+        (address, config, streams)
+    }
+
+    #[tokio::test]
+    async fn streams_and_resumption() {
+        let (address, config, streams) = listener().await;
+        let mut connections = super::Connections::new(&config).unwrap();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+
+        for id in [1, 2, 3] {
+            connections
+                .send(address, crate::tls::test::query(id), &sender)
+                .unwrap();
+        }
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let (response, source) = receiver.recv().await.unwrap().unwrap();
+            assert_eq!(source, address);
+            assert_eq!(response.answers.len(), 1);
+            ids.push(response.header.id);
+        }
+        ids.sort();
+        assert_eq!(ids, [1, 2, 3]);
+
+        // Once the server has closed the connection, the next one resumes the session in 0-RTT
+        while !connections.open[&address].is_closed() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        connections
+            .send(address, crate::tls::test::query(4), &sender)
+            .unwrap();
+        let (response, _) = receiver.recv().await.unwrap().unwrap();
+        assert_eq!(response.header.id, 4);
+
+        let streams = streams.lock().unwrap();
+        assert_eq!(streams.len(), 4);
+        assert!(streams.iter().all(|(_, id)| *id == 0));
+        assert!(streams[..3].iter().all(|(early, _)| !early));
+        assert!(streams[3].0);
+    }
+
+    #[tokio::test]
+    async fn lookups() {
+        let (_, quic, _) = listener().await;
+        let config = crate::config::Config {
+            quic: Some(quic),
+            ..Default::default()
+        };
+
+        let resolver = crate::Resolver::new(config).await.unwrap();
+        let addresses = resolver.lookup_ip("example.com").await.unwrap();
+        assert_eq!(addresses.len(), 2);
+        let exchanges = resolver.lookup_mx("example.com").await.unwrap();
+        assert_eq!(exchanges.len(), 2);
+    }
+}
@@ -0,0 +1,549 @@
+//! Asynchronous resolver on top of [`dns_sans_io::DnsSansIo`].
+//!
+//! A single background task owns the sans-io state machine, the sockets it sends over and the cache;
+//! lookups are handed to it over a channel and answered from the cache,
+//! or once the response with their ID arrives. Lookups iterating from the root hints
+//! send one query at a time, and wait for the response to each under its ID.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    config,
+    iterate::{Iteration, Step},
+};
+
+type Answer = io::Result<Vec<dns_codec::Record>>;
+
+/// What a query resolved to, as relayed by a [`crate::server::Server`].
+#[derive(Debug)]
+pub(crate) struct Resolution {
+    /// The answer section: the records asked for, preceded by the CNAME chain leading to them, if any.
+    pub(crate) answer: Answer,
+    /// The response the answer was taken from; `None` for answers from the cache,
+    /// and if no nameserver gave an acceptable response.
+    pub(crate) response: Option<dns_codec::Message>,
+}
+
+struct Lookup {
+    kind: dns_codec::QType,
+    name: dns_codec::Name,
+    respond: oneshot::Sender<Resolution>,
+}
+
+/// Resolves names through the recursive resolvers of its [`config::Config`],
+/// or iteratively from its root hints, answering from the hosts file where possible.
+///
+/// Cloning is cheap; all clones share the same sockets and background task.
+#[derive(Clone)]
+pub struct Resolver {
+    config: Arc<config::Config>,
+    lookups: mpsc::UnboundedSender<Lookup>,
+}
+
+impl Resolver {
+    /// Binds a socket for talking to the configured nameservers and spawns the task driving it.
+    /// Connections to TLS, HTTPS and QUIC upstreams are opened on their first query.
+    /// Must be called from within a tokio runtime.
+    pub async fn new(config: config::Config) -> io::Result<Self> {
+        let sans_io = dns_sans_io::DnsSansIo::with_config(config.sans_io_config());
+
+        let first = match &config.root_hints {
+            Some(root_hints) => root_hints.first().copied(),
+            None => config.resolv_conf.nameservers().first().copied(),
+        };
+        let unspecified: IpAddr = match first {
+            Some(SocketAddr::V6(_)) => Ipv6Addr::UNSPECIFIED.into(),
+            _ => Ipv4Addr::UNSPECIFIED.into(),
+        };
+        let mut sockets = crate::io::DnsSockets::bind(SocketAddr::new(unspecified, 0)).await?;
+        if let Some(tls) = &config.tls {
+            sockets = sockets.with_tls(tls)?;
+        } else if let Some(https) = &config.https {
+            sockets = sockets.with_https(https)?;
+        } else if let Some(quic) = &config.quic {
+            sockets = sockets.with_quic(quic)?;
+        }
+
+        let cache = crate::cache::Cache::new(crate::cache::DEFAULT_CAPACITY);
+        let (lookups, receiver) = mpsc::unbounded_channel();
+        let root_hints = config.root_hints.clone();
+        tokio::spawn(drive(sans_io, sockets, cache, root_hints, receiver));
+
+        Ok(Resolver {
+            config: Arc::new(config),
+            lookups,
+        })
+    }
+
+    pub(crate) fn config(&self) -> &config::Config {
+        &self.config
+    }
+
+    /// A resolver using the system's resolv.conf and hosts files.
+    pub async fn system() -> io::Result<Self> {
+        Resolver::new(config::Config::system()?).await
+    }
+
+    /// Records of type `kind` for `name`, trying every candidate of the search list in turn.
+    ///
+    /// Fails with [`io::ErrorKind::NotFound`] if none of the candidates have such records.
+    pub async fn lookup(&self, name: &str, kind: dns_codec::QType) -> Answer {
+        let mut last_error = None;
+        for candidate in self.config.resolv_conf.candidates(name) {
+            match self.query(candidate, kind).await {
+                Ok(records) => return Ok(records),
+                // Only a negative answer means the next candidate is worth a try
+                Err(e) if e.kind() == io::ErrorKind::NotFound => last_error = Some(e),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{name} has no candidates"))
+        }))
+    }
+
+    /// Records of type `kind` for the absolute `name`, bypassing the search list and hosts file.
+    pub(crate) async fn query(&self, name: String, kind: dns_codec::QType) -> Answer {
+        let name = name.into_bytes().try_into()?;
+        let records = self.forward(name, kind).await?.answer?;
+        Ok(records
+            .into_iter()
+            .filter(|record| kind == dns_codec::QType::STAR || record.kind == kind)
+            .collect())
+    }
+
+    /// Resolves `kind` for the absolute `name` like [`Resolver::query`], keeping the whole response.
+    pub(crate) async fn forward(
+        &self,
+        name: dns_codec::Name,
+        kind: dns_codec::QType,
+    ) -> io::Result<Resolution> {
+        let (respond, resolution) = oneshot::channel();
+        self.lookups
+            .send(Lookup {
+                kind,
+                name,
+                respond,
+            })
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Resolver task has stopped"))?;
+
+        resolution
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Resolver task has stopped"))
+    }
+
+    /// IPv6 and IPv4 addresses of `name`, in that order, from the hosts file or from A and AAAA queries.
+    /// Both queries are sent at once, unless `single-request` is configured.
+    pub async fn lookup_ip(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+        let hosts = &self.config.hosts;
+        let v6 = hosts.lookup(name, dns_codec::QType::AAAA);
+        let v4 = hosts.lookup(name, dns_codec::QType::A);
+        if let (Some(v6), Some(v4)) = (v6, v4) {
+            return Ok(v6.into_iter().chain(v4).collect());
+        }
+
+        let (v6, v4) = if self.config.resolv_conf.single_request {
+            let v6 = self.lookup(name, dns_codec::QType::AAAA).await;
+            let v4 = self.lookup(name, dns_codec::QType::A).await;
+            (v6, v4)
+        } else {
+            tokio::join!(
+                self.lookup(name, dns_codec::QType::AAAA),
+                self.lookup(name, dns_codec::QType::A)
+            )
+        };
+
+        let addresses = match (v6, v4) {
+            (Err(e), Err(_)) => return Err(e),
+            (v6, v4) => v6.into_iter().chain(v4).flatten(),
+        };
+        Ok(addresses
+            .filter_map(|record| match record.rdata {
+                dns_codec::RData::Ipv4(address) => Some(address.into()),
+                dns_codec::RData::Ipv6(address) => Some(address.into()),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Mail exchanges of `name`, most preferred first.
+    pub async fn lookup_mx(&self, name: &str) -> io::Result<Vec<dns_codec::Mx>> {
+        let records = self.lookup(name, dns_codec::QType::MX).await?;
+        let mut exchanges: Vec<_> = records
+            .into_iter()
+            .filter_map(|record| match record.rdata {
+                dns_codec::RData::Mx(mx) => Some(mx),
+                _ => None,
+            })
+            .collect();
+        exchanges.sort_by_key(|mx| mx.preference);
+        Ok(exchanges)
+    }
+
+    /// Text of every TXT record of `name`, with the character strings of each record concatenated.
+    pub async fn lookup_txt(&self, name: &str) -> io::Result<Vec<Vec<u8>>> {
+        let records = self.lookup(name, dns_codec::QType::TXT).await?;
+        Ok(records
+            .into_iter()
+            .filter_map(|record| match record.rdata {
+                dns_codec::RData::Txt(strings) => Some(strings.concat()),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Locations of the service `name` (e.g. `_imap._tcp.example.com`), ordered by priority
+    /// and, within the same priority, by descending weight.
+    pub async fn lookup_srv(&self, name: &str) -> io::Result<Vec<dns_codec::Srv>> {
+        let records = self.lookup(name, dns_codec::QType::SRV).await?;
+        let mut services: Vec<_> = records
+            .into_iter()
+            .filter_map(|record| match record.rdata {
+                dns_codec::RData::Srv(srv) => Some(srv),
+                _ => None,
+            })
+            .collect();
+        services.sort_by_key(|srv| (srv.priority, std::cmp::Reverse(srv.weight)));
+        Ok(services)
+    }
+
+    /// The start of authority of the zone `name`.
+    pub async fn lookup_soa(&self, name: &str) -> io::Result<dns_codec::Soa> {
+        let records = self.lookup(name, dns_codec::QType::SOA).await?;
+        records
+            .into_iter()
+            .find_map(|record| match record.rdata {
+                dns_codec::RData::Soa(soa) => Some(soa),
+                _ => None,
+            })
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{name} has no SOA")))
+    }
+
+    /// Names of `address`, from the hosts file or from a PTR query for its in-addr.arpa / ip6.arpa name.
+    pub async fn reverse_lookup(&self, address: IpAddr) -> io::Result<Vec<dns_codec::Name>> {
+        if let Some(names) = self.config.hosts.reverse(address) {
+            return names
+                .iter()
+                .map(|name| name.clone().into_bytes().try_into())
+                .collect();
+        }
+
+        // The reverse name is absolute, so must not be expanded by the search list
+        let records = self
+            .query(reverse_name(address), dns_codec::QType::PTR)
+            .await?;
+        Ok(records
+            .into_iter()
+            .filter_map(|record| match record.rdata {
+                dns_codec::RData::Name(name) => Some(name),
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+/// The name PTR records of `address` are found under,
+/// e.g. `1.2.0.192.in-addr.arpa` for `192.0.2.1`.
+pub fn reverse_name(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(address) => {
+            let [a, b, c, d] = address.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+        }
+        IpAddr::V6(address) => {
+            let mut name = String::with_capacity(72);
+            for octet in address.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", octet & 0x0f, octet >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+/// Runs the sans-io state machine until every [`Resolver`] handle has been dropped.
+/// Lookups iterate from `root_hints` if there are any, and are forwarded to the upstreams otherwise.
+async fn drive(
+    mut sans_io: dns_sans_io::DnsSansIo,
+    mut sockets: crate::io::DnsSockets,
+    mut cache: crate::cache::Cache,
+    root_hints: Option<Vec<SocketAddr>>,
+    mut lookups: mpsc::UnboundedReceiver<Lookup>,
+) {
+    type Key = (dns_codec::Name, dns_codec::QType);
+    type Waiting = (Key, Option<Iteration>, oneshot::Sender<Resolution>);
+    let mut waiting: HashMap<u16, Waiting> = HashMap::new();
+
+    loop {
+        while let Some(transmit) = sans_io.poll_query(Instant::now()) {
+            // The query remains in flight, so it is retransmitted once it times out
+            if let Err(e) = sockets.send(transmit).await {
+                log::warn!("failed to send query: {e}");
+            }
+        }
+
+        let timeout = async {
+            match sans_io.poll_timeout() {
+                Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let responses = tokio::select! {
+            lookup = lookups.recv() => {
+                let Some(Lookup { kind, name, respond }) = lookup else {
+                    break;
+                };
+                if let Some(records) = cache.get(&name, kind, Instant::now()) {
+                    let _ = respond.send(Resolution { answer: Ok(records), response: None });
+                    continue;
+                }
+                let (id, iteration) = match &root_hints {
+                    Some(root_hints) => {
+                        match Iteration::start(&mut sans_io, root_hints, name.clone(), kind) {
+                            Step::Query(id, iteration) => (id, Some(iteration)),
+                            Step::Done(resolution) => { let _ = respond.send(resolution); continue; }
+                        }
+                    }
+                    None => match resource(&name) {
+                        Ok(resource) => (sans_io.enqueue_recursive_query(kind, resource), None),
+                        Err(e) => {
+                            let _ = respond.send(Resolution { answer: Err(e), response: None });
+                            continue;
+                        }
+                    },
+                };
+                waiting.insert(id, ((name, kind), iteration, respond));
+                continue;
+            }
+            received = sockets.recv() => {
+                match received {
+                    Ok((response, source)) => {
+                        match sans_io.handle_response(Instant::now(), source, response) {
+                            Ok(responses) => responses,
+                            Err(e) => { log::debug!("{e}"); continue; }
+                        }
+                    }
+                    Err(e) => { log::warn!("{e}"); continue; }
+                }
+            }
+            _ = timeout => {
+                sans_io.handle_timeout(Instant::now())
+            }
+        };
+
+        for response in responses {
+            let Some(((name, kind), iteration, respond)) = waiting.remove(&response.id) else {
+                continue;
+            };
+            let resolution = match iteration {
+                Some(iteration) => match iteration.step(&mut sans_io, response) {
+                    Step::Query(id, iteration) => {
+                        waiting.insert(id, ((name, kind), Some(iteration), respond));
+                        continue;
+                    }
+                    Step::Done(resolution) => resolution,
+                },
+                None => resolution(response),
+            };
+            if let Ok(records) = &resolution.answer {
+                cache.insert(name, kind, records, Instant::now());
+            }
+            let _ = respond.send(resolution);
+        }
+    }
+}
+
+/// `name` as the sans-io state machine takes it, failing for names it cannot build queries for.
+pub(crate) fn resource(name: &dns_codec::Name) -> io::Result<Vec<u8>> {
+    // The root has no labels, and so is the empty name
+    let resource = name.labels().collect::<Vec<_>>().join(&b'.');
+    // Decoded names may hold any octet, while queries are only built for ASCII ones
+    dns_codec::Name::try_from(resource.clone())?;
+    Ok(resource)
+}
+
+/// What the sans-io `response` resolved its query to.
+pub(crate) fn resolution(response: dns_sans_io::Response) -> Resolution {
+    let answer = match (response.outcome, &response.message) {
+        // The whole answer section, so that the CNAME chain is kept
+        (dns_sans_io::Outcome::Resolved(_), Some(message)) => Ok(message.answers.clone()),
+        (outcome, _) => answer(outcome),
+    };
+    Resolution {
+        answer,
+        response: response.message,
+    }
+}
+
+fn answer(outcome: dns_sans_io::Outcome) -> Answer {
+    match outcome {
+        dns_sans_io::Outcome::Resolved(records) => Ok(records),
+        dns_sans_io::Outcome::Unresolved => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "No records of the requested type exist",
+        )),
+        dns_sans_io::Outcome::TimedOut => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "No nameserver responded",
+        )),
+        dns_sans_io::Outcome::NoNameservers => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No nameserver to send the query to",
+        )),
+        dns_sans_io::Outcome::NamespaceIp(_) | dns_sans_io::Outcome::NamespaceNames(_) => {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Recursive nameserver responded with a referral",
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::net::{IpAddr, SocketAddr};
+
+    use tokio::net::UdpSocket;
+
+    /// Answers every query like a recursive resolver with fixed records for example.com,
+    /// echoing the question and pointing the answers' owner at it.
+    /// Names starting with `www` are aliases of example.com, and names starting with `missing` do not exist.
+    pub(crate) async fn upstream() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            loop {
+                let (length, source) = socket.recv_from(&mut buffer).await.unwrap();
+                socket
+                    .send_to(&respond(&buffer[..length]), source)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        address
+    }
+
+    /// The response of [`upstream`] to `query`.
+    pub(crate) fn respond(query: &[u8]) -> Vec<u8> {
+        const EXAMPLE: &[u8] = b"\x07example\x03com\x00";
+        let length = query.len();
+        let qtype = u16::from_be_bytes([query[length - 4], query[length - 3]]);
+        let qname = &query[12..length - 4];
+
+        let rdata: Vec<Vec<u8>> = match qtype {
+            1 => vec![vec![192, 0, 2, 1]],
+            28 => vec![[0x20, 0x01, 0x0d, 0xb8]
+                .into_iter()
+                .chain([0; 11])
+                .chain([1])
+                .collect()],
+            15 => vec![
+                [0, 20].into_iter().chain(*b"\x05mail2\xc0\x0c").collect(),
+                [0, 10].into_iter().chain(*b"\x05mail1\xc0\x0c").collect(),
+            ],
+            _ => vec![],
+        };
+        // Owner, type and RDATA of every answer
+        let mut answers: Vec<(&[u8], u16, Vec<u8>)> = Vec::new();
+        if qname.starts_with(b"\x03www") {
+            answers.push((b"\xc0\x0c", 5, EXAMPLE.to_vec()));
+            answers.extend(rdata.into_iter().map(|rdata| (EXAMPLE, qtype, rdata)));
+        } else if !qname.starts_with(b"\x07missing") {
+            answers.extend(
+                rdata
+                    .into_iter()
+                    .map(|rdata| (&b"\xc0\x0c"[..], qtype, rdata)),
+            );
+        }
+        // Negative answers carry the SOA of the zone
+        let soa: Vec<u8> = b"\x02ns\xc0\x0c\x0ahostmaster\xc0\x0c"
+            .iter()
+            .copied()
+            .chain([
+                0, 0, 0, 1, 0, 0, 0x1c, 0x20, 0, 0, 0x0e, 0x10, 0, 0x12, 0x75, 0, 0, 0, 1, 0x2c,
+            ])
+            .collect();
+        let authorities: Vec<(&[u8], u16, Vec<u8>)> = match answers.is_empty() {
+            true => vec![(EXAMPLE, 6, soa)],
+            false => vec![],
+        };
+
+        let mut response = query.to_vec();
+        response[2] = 0x81;
+        response[3] = if qname.starts_with(b"\x07missing") {
+            0x83
+        } else {
+            0x80
+        };
+        response[7] = answers.len() as u8;
+        response[9] = authorities.len() as u8;
+        for (owner, kind, rdata) in answers.into_iter().chain(authorities) {
+            response.extend_from_slice(owner);
+            response.extend_from_slice(&kind.to_be_bytes());
+            response.extend_from_slice(b"\0\x01\0\0\x0e\x10");
+            response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            response.extend_from_slice(&rdata);
+        }
+        response
+    }
+
+    #[tokio::test]
+    async fn lookups() {
+        let mut config = crate::config::Config::default();
+        config.resolv_conf.nameservers = vec![upstream().await];
+        config.resolv_conf.search = vec!["example.com".to_owned()];
+        config.hosts = crate::config::Hosts::parse("192.0.2.99 gateway.local\n");
+
+        let resolver = super::Resolver::new(config).await.unwrap();
+
+        let addresses = resolver.lookup_ip("example.com").await.unwrap();
+        let expected: Vec<IpAddr> =
+            vec!["2001:db8::1".parse().unwrap(), "192.0.2.1".parse().unwrap()];
+        assert_eq!(addresses, expected);
+
+        let exchanges = resolver.lookup_mx("example.com").await.unwrap();
+        let exchanges: Vec<_> = exchanges
+            .iter()
+            .map(|mx| (mx.preference, mx.exchange.to_string()))
+            .collect();
+        assert_eq!(
+            exchanges,
+            [
+                (10, "mail1.example.com".to_owned()),
+                (20, "mail2.example.com".to_owned())
+            ]
+        );
+
+        let error = resolver.lookup_txt("example.com").await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+
+        let gateway: IpAddr = "192.0.2.99".parse().unwrap();
+        let names = resolver.reverse_lookup(gateway).await.unwrap();
+        assert_eq!(names[0].to_string(), "gateway.local");
+    }
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(
+            super::reverse_name("192.0.2.1".parse().unwrap()),
+            "1.2.0.192.in-addr.arpa"
+        );
+        assert_eq!(
+            super::reverse_name("2001:db8::567:89ab".parse().unwrap()),
+            "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+    }
+}
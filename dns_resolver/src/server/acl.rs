@@ -0,0 +1,147 @@
+//! Access control: which clients a server is willing to recurse for.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+/// A block of addresses sharing a prefix, e.g. `192.0.2.0/24` or `2001:db8::/32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Subnet {
+    /// Fails with [`io::ErrorKind::InvalidInput`] if `prefix` exceeds the length of `address`.
+    pub fn new(address: IpAddr, prefix: u8) -> io::Result<Self> {
+        let bits = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix > bits {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Prefix /{prefix} is longer than {bits} bits"),
+            ));
+        }
+        Ok(Subnet { address, prefix })
+    }
+
+    /// Whether `address` lies within this subnet.
+    /// IPv4-mapped IPv6 addresses, as seen on dual-stack sockets, match IPv4 subnets.
+    pub fn contains(&self, address: IpAddr) -> bool {
+        fn masked(bits: u128, prefix: u8, width: u8) -> u128 {
+            match prefix {
+                0 => 0,
+                prefix => bits >> (width - prefix),
+            }
+        }
+
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(subnet), IpAddr::V4(address)) => {
+                masked(subnet.to_bits().into(), self.prefix, 32)
+                    == masked(address.to_bits().into(), self.prefix, 32)
+            }
+            (IpAddr::V6(subnet), IpAddr::V6(address)) => {
+                masked(subnet.to_bits(), self.prefix, 128)
+                    == masked(address.to_bits(), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = io::Error;
+
+    /// Parses CIDR notation; a bare address is a subnet of just that host.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid subnet {s:?}: {e}"),
+            )
+        };
+
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address: IpAddr = address.parse().map_err(|e| invalid(&e))?;
+        let prefix = match (prefix, address) {
+            (Some(prefix), _) => prefix.parse().map_err(|e| invalid(&e))?,
+            (None, IpAddr::V4(_)) => 32,
+            (None, IpAddr::V6(_)) => 128,
+        };
+        Subnet::new(address, prefix)
+    }
+}
+
+/// Clients allowed to recurse, by subnet. Everyone else is answered with REFUSED.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    allowed: Vec<Subnet>,
+}
+
+impl Acl {
+    pub fn new(allowed: Vec<Subnet>) -> Self {
+        Acl { allowed }
+    }
+
+    /// Allows every client. Only suitable where the network itself restricts access,
+    /// as an open resolver is easily abused for amplification attacks.
+    pub fn allow_all() -> Self {
+        Acl::new(vec![
+            Subnet::new(Ipv4Addr::UNSPECIFIED.into(), 0).unwrap(),
+            Subnet::new(Ipv6Addr::UNSPECIFIED.into(), 0).unwrap(),
+        ])
+    }
+
+    pub fn allows(&self, client: IpAddr) -> bool {
+        self.allowed.iter().any(|subnet| subnet.contains(client))
+    }
+}
+
+impl Default for Acl {
+    /// Only the local host, like BIND's default `allow-recursion`.
+    fn default() -> Self {
+        Acl::new(vec![
+            Subnet::new(Ipv4Addr::LOCALHOST.into(), 8).unwrap(),
+            Subnet::new(Ipv6Addr::LOCALHOST.into(), 128).unwrap(),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use super::{Acl, Subnet};
+
+    #[test]
+    fn subnets() {
+        let acl = Acl::new(vec![
+            "192.0.2.0/24".parse().unwrap(),
+            "2001:db8::/32".parse().unwrap(),
+            "198.51.100.7".parse().unwrap(),
+        ]);
+        let allows = |address: &str| acl.allows(address.parse::<IpAddr>().unwrap());
+
+        assert!(allows("192.0.2.200"));
+        assert!(allows("::ffff:192.0.2.1"));
+        assert!(allows("2001:db8:1::1"));
+        assert!(allows("198.51.100.7"));
+        assert!(!allows("192.0.3.1"));
+        assert!(!allows("198.51.100.8"));
+        assert!(!allows("2001:db9::1"));
+
+        assert!(Acl::default().allows("127.0.0.53".parse().unwrap()));
+        assert!(!Acl::default().allows("192.0.2.1".parse().unwrap()));
+        assert!(Acl::allow_all().allows("2001:db9::1".parse().unwrap()));
+
+        assert!("192.0.2.0/33".parse::<Subnet>().is_err());
+        assert!("example.com/8".parse::<Subnet>().is_err());
+    }
+}
@@ -0,0 +1,100 @@
+//! Answering DNS over HTTPS requests (RFC 8484).
+
+use std::{convert::Infallible, net::IpAddr};
+
+use base64::Engine as _;
+use http_body_util::{BodyExt as _, Full, Limited};
+use hyper::{body::Incoming, header, Method, Request, Response, StatusCode};
+use tokio_util::{
+    bytes::{Bytes, BytesMut},
+    codec::{Decoder as _, Encoder as _},
+};
+
+use crate::https::{DOH_PATH, MEDIA_TYPE};
+
+/// Largest query accepted, in either encoding.
+const MAX_QUERY: usize = 65535;
+
+fn status(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::default());
+    *response.status_mut() = status;
+    response
+}
+
+/// The query carried by `request`, or the status to reject it with.
+async fn query(request: Request<Incoming>) -> Result<dns_codec::Message, StatusCode> {
+    if request.uri().path() != DOH_PATH {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let message = match *request.method() {
+        Method::GET => {
+            let dns = request
+                .uri()
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .find_map(|parameter| parameter.strip_prefix("dns="))
+                .ok_or(StatusCode::BAD_REQUEST)?;
+            if dns.len() > MAX_QUERY * 4 / 3 + 4 {
+                return Err(StatusCode::URI_TOO_LONG);
+            }
+            let message = base64::engine::general_purpose::URL_SAFE_NO_PAD
+                .decode(dns)
+                .map_err(|_| StatusCode::BAD_REQUEST)?;
+            Bytes::from(message)
+        }
+        Method::POST => {
+            let media_type = request.headers().get(header::CONTENT_TYPE);
+            if media_type.is_none_or(|media_type| media_type != MEDIA_TYPE) {
+                return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            Limited::new(request.into_body(), MAX_QUERY)
+                .collect()
+                .await
+                .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?
+                .to_bytes()
+        }
+        _ => return Err(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    match dns_codec::MessageCodec.decode(&mut BytesMut::from(&message[..])) {
+        Ok(Some(query)) => Ok(query),
+        Ok(None) | Err(_) => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+impl super::Server {
+    /// The response to `request` from `client`: the answer to the query it carries,
+    /// cacheable for as long as the smallest TTL in it, or an error status.
+    pub(super) async fn answer_https(
+        &self,
+        client: IpAddr,
+        request: Request<Incoming>,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let query = match query(request).await {
+            Ok(query) => query,
+            Err(rejection) => {
+                log::debug!("https: rejecting request from {client} with {rejection}");
+                return Ok(status(rejection));
+            }
+        };
+        let Some(answer) = self.answer(client, query).await else {
+            return Ok(status(StatusCode::BAD_REQUEST));
+        };
+
+        let max_age = crate::https::max_age(&answer);
+        let mut message = BytesMut::new();
+        if let Err(e) = dns_codec::MessageCodec.encode(answer, &mut message) {
+            log::warn!("https: failed to encode the answer for {client}: {e}");
+            return Ok(status(StatusCode::INTERNAL_SERVER_ERROR));
+        }
+
+        let response = Response::builder()
+            .header(header::CONTENT_TYPE, MEDIA_TYPE)
+            .header(header::CACHE_CONTROL, format!("max-age={max_age}"))
+            .body(Full::new(message.freeze()))
+            .expect("response is valid");
+        Ok(response)
+    }
+}
@@ -0,0 +1,633 @@
+//! Recursive DNS server, answering clients over UDP, TCP and HTTPS with what a [`Resolver`] finds,
+//! iterating from its root hints or forwarding queries to its upstreams.
+
+mod acl;
+mod https;
+mod tcp;
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use tokio::net::{TcpListener, UdpSocket};
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder as _, Encoder as _},
+};
+
+pub use acl::{Acl, Subnet};
+pub use tcp::TcpLimits;
+
+use crate::{resolver::Resolution, Resolver};
+
+/// Largest response sent over UDP to clients without EDNS; longer ones are truncated
+/// so that the client retries over TCP (RFC 1035, section 4.2.1).
+const UDP_RESPONSE: usize = 512;
+
+/// Largest response sent over UDP to EDNS clients, however large a payload they can reassemble.
+/// The same as the payload size advertised in the OPT record of responses.
+const MAX_UDP_RESPONSE: usize = 1232;
+
+/// Largest datagram accepted from a client.
+const MAX_DATAGRAM: usize = 65535;
+
+/// Answers queries of clients permitted by its [`Acl`], resolving them through a [`Resolver`] and its cache.
+///
+/// Cloning is cheap; all clones share the same resolver.
+#[derive(Clone)]
+pub struct Server {
+    resolver: Resolver,
+    acl: Arc<Acl>,
+    tcp: TcpLimits,
+    connections: Arc<tcp::Connections>,
+}
+
+impl Server {
+    pub fn new(resolver: Resolver, acl: Acl) -> Self {
+        Server {
+            resolver,
+            acl: Arc::new(acl),
+            tcp: TcpLimits::default(),
+            connections: Arc::default(),
+        }
+    }
+
+    /// Replaces the default limits on TCP connections.
+    pub fn with_tcp_limits(mut self, limits: TcpLimits) -> Self {
+        self.tcp = limits;
+        self
+    }
+
+    /// Binds `address` over both UDP and TCP, and serves clients until either listener fails.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if neither root hints nor upstreams are configured,
+    /// or one of the upstreams is `address` itself, as queries would be forwarded back to this server.
+    pub async fn serve(self, address: SocketAddr) -> io::Result<()> {
+        let config = self.resolver.config();
+        if config.root_hints.is_none() && !config.has_upstreams() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Neither root hints to iterate from nor upstreams to forward to are configured",
+            ));
+        }
+        let serves = |upstream: &SocketAddr| {
+            upstream.port() == address.port()
+                && (upstream.ip() == address.ip()
+                    || address.ip().is_unspecified() && upstream.ip().is_loopback())
+        };
+        if let Some(upstream) = config.sans_io_config().upstreams.iter().find(|u| serves(u)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Upstream {upstream} is this server itself"),
+            ));
+        }
+
+        let udp = UdpSocket::bind(address).await?;
+        let tcp = TcpListener::bind(address).await?;
+        log::info!("serving on {address}");
+
+        tokio::try_join!(self.clone().serve_udp(udp), self.serve_tcp(tcp))?;
+        Ok(())
+    }
+
+    /// Answers every datagram arriving on `socket`, each in its own task.
+    pub async fn serve_udp(self, socket: UdpSocket) -> io::Result<()> {
+        let socket = Arc::new(socket);
+        let mut datagram = vec![0; MAX_DATAGRAM];
+
+        loop {
+            let (length, client) = socket.recv_from(&mut datagram).await?;
+            let query =
+                match dns_codec::MessageCodec.decode(&mut BytesMut::from(&datagram[..length])) {
+                    Ok(Some(query)) => query,
+                    Ok(None) | Err(_) => {
+                        log::debug!("udp: dropping malformed query from {client}");
+                        continue;
+                    }
+                };
+
+            let limit = udp_limit(&query);
+            let server = self.clone();
+            let socket = socket.clone();
+            tokio::spawn(async move {
+                let Some(response) = server.answer(client.ip(), query).await else {
+                    return;
+                };
+                let result = async {
+                    let datagram = encode_datagram(response, limit)?;
+                    socket.send_to(&datagram, client).await
+                };
+                if let Err(e) = result.await {
+                    log::warn!("udp: failed to answer {client}: {e}");
+                }
+            });
+        }
+    }
+
+    /// Accepts connections on `listener`, each served in its own task.
+    pub async fn serve_tcp(self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, client) = listener.accept().await?;
+
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_connection(stream, client).await {
+                    log::debug!("tcp: connection to {client} failed: {e}");
+                }
+            });
+        }
+    }
+
+    /// Accepts TLS connections on `listener`, and answers DNS over HTTPS requests on them
+    /// over HTTP/2 or HTTP/1.1, whichever the client negotiates.
+    /// Queries are expected at [`crate::https::DOH_PATH`].
+    pub async fn serve_https(
+        self,
+        listener: TcpListener,
+        mut tls: rustls::ServerConfig,
+    ) -> io::Result<()> {
+        tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls));
+
+        loop {
+            let (stream, client) = listener.accept().await?;
+
+            let server = self.clone();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::debug!("https: handshake with {client} failed: {e}");
+                        return;
+                    }
+                };
+                let service = hyper::service::service_fn(|request| {
+                    let server = server.clone();
+                    async move { server.answer_https(client.ip(), request).await }
+                });
+                let builder = hyper_util::server::conn::auto::Builder::new(
+                    hyper_util::rt::TokioExecutor::new(),
+                );
+                if let Err(e) = builder
+                    .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+                    .await
+                {
+                    log::debug!("https: connection to {client} failed: {e}");
+                }
+            });
+        }
+    }
+
+    /// The response to `query` from `client`, or `None` if it is not a query at all.
+    pub async fn answer(
+        &self,
+        client: IpAddr,
+        query: dns_codec::Message,
+    ) -> Option<dns_codec::Message> {
+        let event = format!("0x{:04x}", query.header.id);
+        if query.header.is_response() {
+            log::debug!(target: &event, "server: ignoring response from {client}");
+            return None;
+        }
+
+        let mut response = query.response().recursion_available(true);
+        // A client speaking EDNS gets an OPT record back (RFC 6891, section 7)
+        if query.edns.is_some() {
+            response = response.edns(MAX_UDP_RESPONSE as u16);
+        }
+        let mut response = response
+            .build()
+            .expect("the questions of a query fit the response");
+
+        let questions = &query.questions;
+        let rcode = match &questions[..] {
+            _ if query.header.opcode() != Some(dns_codec::Opcode::QUERY) => {
+                dns_codec::Rcode::NOTIMP
+            }
+            [_] if !self.acl.allows(client) => {
+                log::info!(target: &event, "server: refusing to recurse for {client}");
+                dns_codec::Rcode::REFUSED
+            }
+            [question] if question.class != dns_codec::QClass::IN => dns_codec::Rcode::NOTIMP,
+            [question] => {
+                log::debug!(target: &event, "server: {client} asks for {} {:?}", question.name, question.kind);
+                let resolution = self
+                    .resolver
+                    .forward(question.name.clone(), question.kind)
+                    .await;
+                match resolution {
+                    // Relayed as the nameserver answered, including NXDOMAIN and the SOA of negative answers
+                    Ok(Resolution {
+                        response: Some(resolved),
+                        ..
+                    }) => {
+                        response.answers = resolved.answers;
+                        response.authorities = resolved.authorities;
+                        resolved
+                            .header
+                            .rcode()
+                            .unwrap_or(dns_codec::Rcode::SERVFAIL)
+                    }
+                    Ok(Resolution {
+                        answer: Ok(records),
+                        response: None,
+                    }) => {
+                        response.answers = records;
+                        dns_codec::Rcode::NOERROR
+                    }
+                    Ok(Resolution {
+                        answer: Err(e),
+                        response: None,
+                    })
+                    | Err(e) => {
+                        log::info!(target: &event, "server: failed to resolve {}: {e}", question.name);
+                        dns_codec::Rcode::SERVFAIL
+                    }
+                }
+            }
+            // Answers to several questions could not be told apart
+            _ => {
+                log::debug!(target: &event, "server: {client} asks {} questions", questions.len());
+                dns_codec::Rcode::FORMERR
+            }
+        };
+        response.header.set_rcode(rcode);
+        if let Err(e) = response.set_counts() {
+            log::warn!(target: &event, "server: answer does not fit a message: {e}");
+            response.answers.clear();
+            response.authorities.clear();
+            response.header.set_rcode(dns_codec::Rcode::SERVFAIL);
+            response
+                .set_counts()
+                .expect("the questions of a query fit the response");
+        }
+
+        Some(response)
+    }
+}
+
+/// Largest response to `query` sent over UDP: the payload size the client advertises over EDNS,
+/// but no less than 512 octets (RFC 6891, section 6.2.5) nor more than [`MAX_UDP_RESPONSE`].
+fn udp_limit(query: &dns_codec::Message) -> usize {
+    query.edns.as_ref().map_or(UDP_RESPONSE, |edns| {
+        usize::from(edns.udp_payload_size).clamp(UDP_RESPONSE, MAX_UDP_RESPONSE)
+    })
+}
+
+/// Encodes `response` for UDP, dropping its records and setting TC if it is longer than `limit`.
+/// Encoding derives the header counts from what is left.
+fn encode_datagram(mut response: dns_codec::Message, limit: usize) -> io::Result<BytesMut> {
+    let mut datagram = BytesMut::new();
+    dns_codec::MessageCodec.encode(response.clone(), &mut datagram)?;
+    if datagram.len() <= limit {
+        return Ok(datagram);
+    }
+
+    response.answers.clear();
+    response.authorities.clear();
+    response.additionals.clear();
+    response.header.set_truncated(true);
+
+    datagram.clear();
+    dns_codec::MessageCodec.encode(response, &mut datagram)?;
+    Ok(datagram)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use base64::Engine as _;
+    use futures::{SinkExt as _, StreamExt as _};
+    use http_body_util::BodyExt as _;
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
+    use tokio_util::{
+        bytes::{Bytes, BytesMut},
+        codec::{Decoder as _, Encoder as _, FramedRead, FramedWrite},
+    };
+
+    fn query(id: u16, name: &[u8], kind: dns_codec::QType) -> dns_codec::Message {
+        dns_codec::Message::query(name.to_vec(), kind)
+            .id(id)
+            .recursion_desired(true)
+            .build()
+            .unwrap()
+    }
+
+    /// Spawns a server forwarding to a fake upstream, returning its UDP and TCP addresses.
+    async fn server(acl: super::Acl) -> (SocketAddr, SocketAddr) {
+        let mut config = crate::config::Config::default();
+        config.resolv_conf.nameservers = vec![crate::resolver::test::upstream().await];
+        let resolver = crate::Resolver::new(config).await.unwrap();
+        let server = super::Server::new(resolver, acl);
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addresses = (udp.local_addr().unwrap(), tcp.local_addr().unwrap());
+        tokio::spawn(server.clone().serve_udp(udp));
+        tokio::spawn(server.serve_tcp(tcp));
+        addresses
+    }
+
+    async fn ask_udp(server: SocketAddr, query: dns_codec::Message) -> dns_codec::Message {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut datagram = BytesMut::new();
+        dns_codec::MessageCodec
+            .encode(query, &mut datagram)
+            .unwrap();
+        client.send_to(&datagram, server).await.unwrap();
+
+        let mut buffer = vec![0; 512];
+        let length = client.recv(&mut buffer).await.unwrap();
+        dns_codec::MessageCodec
+            .decode(&mut BytesMut::from(&buffer[..length]))
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn recursion() {
+        let (udp, tcp) = server(super::Acl::default()).await;
+
+        let response = ask_udp(udp, query(0x1234, b"example.com", dns_codec::QType::A)).await;
+        assert_eq!(response.header.id, 0x1234);
+        assert!(response.header.is_response() && response.header.recursion_available());
+        assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::NOERROR));
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(
+            response.answers[0].rdata,
+            dns_codec::RData::Ipv4([192, 0, 2, 1].into())
+        );
+
+        // Several queries over the same connection
+        let (read, write) = TcpStream::connect(tcp).await.unwrap().into_split();
+        let mut queries = FramedWrite::new(write, dns_codec::StreamCodec(dns_codec::MessageCodec));
+        let mut responses = FramedRead::new(read, dns_codec::StreamCodec(dns_codec::MessageCodec));
+
+        queries
+            .send(query(1, b"example.com", dns_codec::QType::MX))
+            .await
+            .unwrap();
+        let response = responses.next().await.unwrap().unwrap();
+        assert_eq!((response.header.id, response.answers.len()), (1, 2));
+
+        queries
+            .send(query(2, b"example.com", dns_codec::QType::TXT))
+            .await
+            .unwrap();
+        let response = responses.next().await.unwrap().unwrap();
+        assert_eq!(response.header.id, 2);
+        assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::NOERROR));
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities.len(), 1);
+
+        // The upstream rcode and authority section are relayed
+        let response = ask_udp(udp, query(3, b"missing.example.com", dns_codec::QType::A)).await;
+        assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::NXDOMAIN));
+        assert!(response.answers.is_empty());
+        assert!(matches!(
+            response.authorities[..],
+            [dns_codec::Record {
+                kind: dns_codec::Type::SOA,
+                ..
+            }]
+        ));
+
+        // Queries for the root, which has no labels, are forwarded too
+        let response = ask_udp(udp, query(5, b"", dns_codec::QType::NS)).await;
+        assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::NOERROR));
+        assert!(response.questions[0].name.labels().next().is_none());
+
+        // Names that cannot be queried for are a failure, not the end of the resolver
+        let mut message = BytesMut::new();
+        let ascii = query(6, b"example.com", dns_codec::QType::A);
+        dns_codec::MessageCodec.encode(ascii, &mut message).unwrap();
+        message[13] = 0xff;
+        let binary = dns_codec::MessageCodec
+            .decode(&mut message)
+            .unwrap()
+            .unwrap();
+        let response = ask_udp(udp, binary).await;
+        assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::SERVFAIL));
+
+        // So is the CNAME chain leading to the answer
+        let response = ask_udp(udp, query(4, b"www.example.com", dns_codec::QType::A)).await;
+        assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::NOERROR));
+        let kinds: Vec<_> = response.answers.iter().map(|record| record.kind).collect();
+        assert_eq!(kinds, [dns_codec::Type::CNAME, dns_codec::Type::A]);
+    }
+
+    #[tokio::test]
+    async fn upstreams() {
+        // Nothing to forward to, nor to iterate from
+        let resolver = crate::Resolver::new(crate::config::Config::default())
+            .await
+            .unwrap();
+        let server = super::Server::new(resolver, super::Acl::default());
+        let error = server
+            .serve("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+        // Iterating needs no upstreams, so the server keeps serving
+        let config = crate::config::Config {
+            root_hints: Some(crate::config::root_servers()),
+            ..Default::default()
+        };
+        let resolver = crate::Resolver::new(config).await.unwrap();
+        let server = super::Server::new(resolver, super::Acl::default());
+        let serving = server.serve("127.0.0.1:0".parse().unwrap());
+        assert!(tokio::time::timeout(Duration::from_millis(100), serving)
+            .await
+            .is_err());
+
+        // Forwarding to itself
+        let mut config = crate::config::Config::default();
+        config.resolv_conf.nameservers = vec!["127.0.0.1:5353".parse().unwrap()];
+        let resolver = crate::Resolver::new(config).await.unwrap();
+        let server = super::Server::new(resolver, super::Acl::default());
+        let error = server
+            .serve("0.0.0.0:5353".parse().unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    /// Sends `request` to the DNS over HTTPS listener at `server`, trusting `certificate`.
+    async fn ask_https(
+        server: SocketAddr,
+        certificate: &rustls::pki_types::CertificateDer<'static>,
+        request: hyper::Request<http_body_util::Full<Bytes>>,
+    ) -> hyper::Response<Bytes> {
+        let upstream = crate::tls::TlsUpstream::new(server, "dns.example");
+        let roots = Arc::new(crate::tls::test::roots(certificate));
+        let (connector, server_name) = crate::tls::connector(&upstream, &roots, b"h2").unwrap();
+        let stream = TcpStream::connect(server).await.unwrap();
+        let stream = connector.connect(server_name, stream).await.unwrap();
+        let (mut sender, connection) = hyper::client::conn::http2::handshake(
+            hyper_util::rt::TokioExecutor::new(),
+            hyper_util::rt::TokioIo::new(stream),
+        )
+        .await
+        .unwrap();
+        tokio::spawn(connection);
+
+        let response = sender.send_request(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+        hyper::Response::from_parts(parts, body)
+    }
+
+    #[tokio::test]
+    async fn https() {
+        let mut config = crate::config::Config::default();
+        config.resolv_conf.nameservers = vec![crate::resolver::test::upstream().await];
+        let resolver = crate::Resolver::new(config).await.unwrap();
+        let server = super::Server::new(resolver, super::Acl::default());
+        let (tls, certificate) = crate::tls::test::server_config();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(server.serve_https(listener, tls));
+
+        let mut message = BytesMut::new();
+        dns_codec::MessageCodec
+            .encode(query(0, b"example.com", dns_codec::QType::A), &mut message)
+            .unwrap();
+        let dns = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&message);
+        let uri = |path: &str| format!("https://dns.example:{}{path}", address.port());
+
+        // GET, cacheable for as long as the TTL of the answer
+        let request = hyper::Request::get(uri(&format!("/dns-query?dns={dns}")))
+            .body(Default::default())
+            .unwrap();
+        let response = ask_https(address, &certificate, request).await;
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+        assert_eq!(
+            response.headers()[hyper::header::CONTENT_TYPE],
+            "application/dns-message"
+        );
+        assert_eq!(
+            response.headers()[hyper::header::CACHE_CONTROL],
+            "max-age=3600"
+        );
+        let answer = dns_codec::MessageCodec
+            .decode(&mut BytesMut::from(&response.body()[..]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(answer.header.id, 0);
+        assert_eq!(
+            answer.answers[0].rdata,
+            dns_codec::RData::Ipv4([192, 0, 2, 1].into())
+        );
+
+        // POST, and requests that are not DNS over HTTPS
+        let post = |content_type: &str, path: &str| {
+            hyper::Request::post(uri(path))
+                .header(hyper::header::CONTENT_TYPE, content_type)
+                .body(http_body_util::Full::new(message.clone().freeze()))
+                .unwrap()
+        };
+        let response = ask_https(
+            address,
+            &certificate,
+            post("application/dns-message", "/dns-query"),
+        )
+        .await;
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+        let response = ask_https(address, &certificate, post("text/plain", "/dns-query")).await;
+        assert_eq!(response.status(), hyper::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let response = ask_https(address, &certificate, post("application/dns-message", "/")).await;
+        assert_eq!(response.status(), hyper::StatusCode::NOT_FOUND);
+        let request = hyper::Request::get(uri("/dns-query?dns=*"))
+            .body(Default::default())
+            .unwrap();
+        let response = ask_https(address, &certificate, request).await;
+        assert_eq!(response.status(), hyper::StatusCode::BAD_REQUEST);
+
+        // A resolver querying the listener as its upstream
+        let upstream =
+            crate::https::HttpsUpstream::new(address, "dns.example", crate::https::DOH_PATH)
+                .with_method(crate::https::Method::Get);
+        let config = crate::config::Config {
+            https: Some(crate::https::HttpsConfig::with_roots(
+                vec![upstream],
+                crate::tls::test::roots(&certificate),
+            )),
+            ..Default::default()
+        };
+        let resolver = crate::Resolver::new(config).await.unwrap();
+        let exchanges = resolver.lookup_mx("example.com").await.unwrap();
+        assert_eq!(exchanges.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn messages() {
+        let (udp, _) = server(super::Acl::default()).await;
+
+        let mut asking = query(1, b"example.com", dns_codec::QType::A);
+        asking.edns = Some(dns_codec::Edns::default());
+        let response = ask_udp(udp, asking).await;
+        assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::NOERROR));
+        assert_eq!(response.edns, Some(dns_codec::Edns::default()));
+        assert_eq!(response.header.arcount, 1);
+
+        let mut asking = query(2, b"example.com", dns_codec::QType::A);
+        asking.questions.push(asking.questions[0].clone());
+        let response = ask_udp(udp, asking).await;
+        assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::FORMERR));
+        assert!(response.answers.is_empty() && response.edns.is_none());
+    }
+
+    #[test]
+    fn datagram_sizes() {
+        let mut asking = query(1, b"example.com", dns_codec::QType::A);
+        assert_eq!(super::udp_limit(&asking), 512);
+        for (advertised, limit) in [(256, 512), (1000, 1000), (4096, 1232)] {
+            asking.edns = Some(dns_codec::Edns {
+                udp_payload_size: advertised,
+                ..dns_codec::Edns::default()
+            });
+            assert_eq!(super::udp_limit(&asking), limit);
+        }
+
+        // Some 800 octets of answers
+        let mut response = asking.clone();
+        response.header.set_response(true);
+        response.answers = (0..30)
+            .map(|i| dns_codec::Record {
+                name: b"example.com".to_vec().try_into().unwrap(),
+                kind: dns_codec::Type::A,
+                class: dns_codec::Class::IN,
+                ttl: dns_codec::Ttl::from_secs(3600),
+                length: 4,
+                rdata: dns_codec::RData::Ipv4([192, 0, 2, i].into()),
+            })
+            .collect();
+
+        let decode = |datagram: BytesMut| {
+            dns_codec::MessageCodec
+                .decode(&mut datagram.clone())
+                .unwrap()
+                .unwrap()
+        };
+        let fitting = decode(super::encode_datagram(response.clone(), 1232).unwrap());
+        assert!(!fitting.header.is_truncated());
+        assert_eq!(fitting.answers.len(), 30);
+        let truncated = decode(super::encode_datagram(response, 512).unwrap());
+        assert!(truncated.header.is_truncated() && truncated.answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn access_control() {
+        let (udp, _) = server(super::Acl::new(vec!["192.0.2.0/24".parse().unwrap()])).await;
+
+        let response = ask_udp(udp, query(7, b"example.com", dns_codec::QType::A)).await;
+        assert_eq!(response.header.rcode(), Some(dns_codec::Rcode::REFUSED));
+        assert!(response.answers.is_empty());
+        assert_eq!(response.questions.len(), 1);
+    }
+}
@@ -0,0 +1,357 @@
+//! Answering DNS over TCP (RFC 7766): many queries per connection, answered as they complete
+//! rather than in order, with idle timeouts and a limit on the connections of every client.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::Duration,
+};
+
+use futures::{stream::FuturesUnordered, SinkExt as _, StreamExt as _};
+use tokio::net::TcpStream;
+use tokio_util::{
+    bytes::{Bytes, BytesMut},
+    codec::{BytesCodec, Decoder as _, Encoder as _, FramedRead, FramedWrite},
+};
+
+/// Limits on the TCP connections of clients.
+#[derive(Debug, Clone)]
+pub struct TcpLimits {
+    /// How long a connection may stay open with no query pending, which is also the timeout
+    /// sent to clients asking for it through EDNS (RFC 7828).
+    pub idle_timeout: Duration,
+    /// Connections a client may have open at once; further ones are closed as soon as they are accepted.
+    pub connections_per_client: usize,
+    /// Queries of a connection answered at once; no more are read until one of them is answered.
+    pub queries_per_connection: usize,
+}
+
+impl Default for TcpLimits {
+    fn default() -> Self {
+        TcpLimits {
+            idle_timeout: Duration::from_secs(10),
+            connections_per_client: 8,
+            queries_per_connection: 32,
+        }
+    }
+}
+
+/// Number of connections open from every client.
+#[derive(Debug, Default)]
+pub(super) struct Connections(Mutex<HashMap<IpAddr, usize>>);
+
+/// A connection of `client`, counted until it is dropped.
+struct Slot<'a> {
+    connections: &'a Connections,
+    client: IpAddr,
+}
+
+impl Connections {
+    /// Counts a new connection of `client`, unless it already has `limit` open.
+    fn open(&self, client: IpAddr, limit: usize) -> Option<Slot<'_>> {
+        let mut open = self.0.lock().unwrap();
+        let count = open.entry(client).or_default();
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(Slot {
+            connections: self,
+            client,
+        })
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut open = self.connections.0.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.client);
+            }
+        }
+    }
+}
+
+impl super::Server {
+    /// Answers the queries arriving on `stream` concurrently, sending every response as soon as it is ready,
+    /// until the client closes it or it stays idle for too long.
+    pub(super) async fn serve_connection(
+        &self,
+        stream: TcpStream,
+        client: SocketAddr,
+    ) -> io::Result<()> {
+        let Some(_slot) = self
+            .connections
+            .open(client.ip(), self.tcp.connections_per_client)
+        else {
+            log::debug!("tcp: closing connection from {client}, which has too many open");
+            return Ok(());
+        };
+
+        let (read, write) = stream.into_split();
+        let mut queries = FramedRead::new(read, dns_codec::StreamCodec(BytesCodec::new()));
+        let mut responses = FramedWrite::new(write, dns_codec::StreamCodec(BytesCodec::new()));
+        let mut pending = FuturesUnordered::new();
+        let mut reading = true;
+
+        loop {
+            if !reading && pending.is_empty() {
+                break;
+            }
+
+            let readable = reading && pending.len() < self.tcp.queries_per_connection;
+            tokio::select! {
+                message = queries.next(), if readable => match message {
+                    // A malformed query is dropped alone, as the framing still delimits the ones after it
+                    Some(message) => match dns_codec::MessageCodec.decode(&mut message?) {
+                        Ok(Some(query)) => pending.push(self.answer_stream(client.ip(), query)),
+                        Ok(None) | Err(_) => {
+                            log::debug!("tcp: dropping malformed query from {client}");
+                        }
+                    },
+                    // Queries already read are still answered once the client is done sending
+                    None => reading = false,
+                },
+                Some(response) = pending.next() => {
+                    if let Some(response) = response? {
+                        responses.send(response).await?;
+                    }
+                }
+                () = tokio::time::sleep(self.tcp.idle_timeout), if reading && pending.is_empty() => {
+                    log::debug!("tcp: closing idle connection from {client}");
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The encoded answer to `query`, telling the idle timeout if the query asked for it.
+    async fn answer_stream(
+        &self,
+        client: IpAddr,
+        query: dns_codec::Message,
+    ) -> io::Result<Option<Bytes>> {
+        let keepalive = query
+            .edns
+            .as_ref()
+            .is_some_and(|edns| edns.tcp_keepalive().is_some());
+        let Some(mut response) = self.answer(client, query).await else {
+            return Ok(None);
+        };
+        if let Some(edns) = response.edns.as_mut().filter(|_| keepalive) {
+            let timeout = self.tcp.idle_timeout.as_millis() / 100;
+            let timeout = u16::try_from(timeout).unwrap_or(u16::MAX);
+            edns.options
+                .push(dns_codec::EdnsOption::TcpKeepalive(Some(timeout)));
+        }
+
+        let mut message = BytesMut::new();
+        dns_codec::MessageCodec.encode(response, &mut message)?;
+        Ok(Some(message.freeze()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use futures::{SinkExt as _, StreamExt as _};
+    use tokio::{
+        io::AsyncWriteExt as _,
+        net::{TcpListener, TcpStream, UdpSocket},
+    };
+    use tokio_util::{
+        bytes::{Bytes, BytesMut},
+        codec::{BytesCodec, Decoder as _, Encoder as _, Framed},
+    };
+
+    use super::TcpLimits;
+
+    type Connection = Framed<TcpStream, dns_codec::StreamCodec<BytesCodec>>;
+
+    /// Answers like [`crate::resolver::test::upstream`], but only after a while for names starting with "slow".
+    async fn upstream() -> SocketAddr {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let address = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            loop {
+                let (length, source) = socket.recv_from(&mut buffer).await.unwrap();
+                let query = buffer[..length].to_vec();
+                let socket = socket.clone();
+                tokio::spawn(async move {
+                    if query[12..].starts_with(b"\x04slow") {
+                        tokio::time::sleep(Duration::from_millis(300)).await;
+                    }
+                    let response = crate::resolver::test::respond(&query);
+                    socket.send_to(&response, source).await.unwrap();
+                });
+            }
+        });
+
+        address
+    }
+
+    async fn server(limits: TcpLimits) -> SocketAddr {
+        let mut config = crate::config::Config::default();
+        config.resolv_conf.nameservers = vec![upstream().await];
+        let resolver = crate::Resolver::new(config).await.unwrap();
+        let server = super::super::Server::new(resolver, super::super::Acl::default())
+            .with_tcp_limits(limits);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(server.serve_tcp(listener));
+        address
+    }
+
+    async fn connect(server: SocketAddr) -> Connection {
+        let stream = TcpStream::connect(server).await.unwrap();
+        Framed::new(stream, dns_codec::StreamCodec(BytesCodec::new()))
+    }
+
+    fn query(id: u16, name: &[u8], edns: Option<dns_codec::Edns>) -> Bytes {
+        let mut query = dns_codec::Message::query(name.to_vec(), dns_codec::QType::A)
+            .id(id)
+            .recursion_desired(true)
+            .build()
+            .unwrap();
+        query.edns = edns;
+
+        let mut message = BytesMut::new();
+        dns_codec::MessageCodec.encode(query, &mut message).unwrap();
+        message.freeze()
+    }
+
+    async fn receive(connection: &mut Connection) -> Option<BytesMut> {
+        tokio::time::timeout(Duration::from_secs(2), connection.next())
+            .await
+            .expect("server neither answered nor closed the connection")
+            .transpose()
+            .unwrap()
+    }
+
+    fn id(message: &[u8]) -> u16 {
+        u16::from_be_bytes([message[0], message[1]])
+    }
+
+    #[tokio::test]
+    async fn out_of_order() {
+        let server = server(TcpLimits::default()).await;
+        let mut connection = connect(server).await;
+
+        connection
+            .send(query(1, b"slow.example.com", None))
+            .await
+            .unwrap();
+        connection
+            .send(query(2, b"example.com", None))
+            .await
+            .unwrap();
+
+        // The second query is answered first, while the first one is still being resolved
+        let response = receive(&mut connection).await.unwrap();
+        assert_eq!(id(&response), 2);
+        let response = receive(&mut connection).await.unwrap();
+        assert_eq!(id(&response), 1);
+        let response = dns_codec::MessageCodec
+            .decode(&mut response.clone())
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.answers.len(), 1);
+
+        // Pending queries are still answered after the client stops sending
+        connection
+            .send(query(3, b"slow.example.com", None))
+            .await
+            .unwrap();
+        connection.get_mut().shutdown().await.unwrap();
+        assert_eq!(id(&receive(&mut connection).await.unwrap()), 3);
+        assert!(receive(&mut connection).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn malformed() {
+        let server = server(TcpLimits::default()).await;
+        let mut connection = connect(server).await;
+
+        // Only the query whose label overruns the message goes unanswered, the connection keeps serving the rest
+        let overrun = b"\0\x01\x01\0\0\x01\0\0\0\0\0\0\x05ab\0\0";
+        connection.send(Bytes::from_static(overrun)).await.unwrap();
+        connection
+            .send(query(2, b"example.com", None))
+            .await
+            .unwrap();
+        assert_eq!(id(&receive(&mut connection).await.unwrap()), 2);
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let server = server(TcpLimits {
+            idle_timeout: Duration::from_millis(200),
+            connections_per_client: 1,
+            ..Default::default()
+        })
+        .await;
+
+        let mut first = connect(server).await;
+        first.send(query(1, b"example.com", None)).await.unwrap();
+        assert_eq!(id(&receive(&mut first).await.unwrap()), 1);
+
+        // A second connection is one too many
+        let mut second = connect(server).await;
+        assert!(receive(&mut second).await.is_none());
+
+        // The first one is closed once idle, making room for another
+        assert!(receive(&mut first).await.is_none());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut third = connect(server).await;
+        third.send(query(3, b"example.com", None)).await.unwrap();
+        assert_eq!(id(&receive(&mut third).await.unwrap()), 3);
+    }
+
+    #[tokio::test]
+    async fn keepalive() {
+        let server = server(TcpLimits {
+            idle_timeout: Duration::from_secs(12),
+            ..Default::default()
+        })
+        .await;
+        let mut connection = connect(server).await;
+
+        connection
+            .send(query(1, b"example.com", None))
+            .await
+            .unwrap();
+        let response = receive(&mut connection).await.unwrap();
+        assert_eq!(dns_codec::Edns::find(&response).unwrap(), None);
+
+        let edns = dns_codec::Edns {
+            options: vec![dns_codec::EdnsOption::TcpKeepalive(None)],
+            ..Default::default()
+        };
+        connection
+            .send(query(2, b"example.com", Some(edns)))
+            .await
+            .unwrap();
+        let response = receive(&mut connection).await.unwrap();
+        assert_eq!(id(&response), 2);
+        let edns = dns_codec::Edns::find(&response).unwrap().unwrap();
+        assert_eq!(edns.tcp_keepalive(), Some(Some(120)));
+
+        // Without the option, the timeout is not sent
+        connection
+            .send(query(3, b"example.com", Some(dns_codec::Edns::default())))
+            .await
+            .unwrap();
+        let response = receive(&mut connection).await.unwrap();
+        let edns = dns_codec::Edns::find(&response).unwrap().unwrap();
+        assert_eq!(edns.tcp_keepalive(), None);
+    }
+}
@@ -0,0 +1,554 @@
+//! DNS over TLS (RFC 7858) to upstream resolvers.
+//!
+//! Each upstream gets at most one connection at a time, opened on its first query and reused,
+//! with queries pipelined over it and responses matched up by the sans-io state machine.
+
+use std::{collections::HashMap, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use futures::{SinkExt as _, StreamExt as _};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::WebPkiSupportedAlgorithms,
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    server::ParsedCertificate,
+    DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use tokio::{net::TcpStream, sync::mpsc};
+use tokio_rustls::TlsConnector;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::io::Received;
+
+/// Port DNS over TLS is served on.
+pub const DOT_PORT: u16 = 853;
+
+/// A connection without queries or responses for this long is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A resolver reached over TLS, and how to authenticate it (RFC 8310, section 8).
+#[derive(Debug, Clone)]
+pub struct TlsUpstream {
+    pub address: SocketAddr,
+    /// Name the certificate must be valid for, chained to the roots of the [`TlsConfig`].
+    /// Also sent as SNI; without it, the address is.
+    pub auth_name: Option<String>,
+    /// SHA-256 digests of public keys, one of which a certificate of the chain must have,
+    /// see [`spki_pin`]. Without an auth name, the chain is not validated and only the
+    /// end-entity certificate is checked.
+    pub spki_pins: Vec<[u8; 32]>,
+}
+
+impl TlsUpstream {
+    /// An upstream at `address`, authenticated by its certificate being valid for `auth_name`.
+    pub fn new(address: SocketAddr, auth_name: impl Into<String>) -> Self {
+        TlsUpstream {
+            address,
+            auth_name: Some(auth_name.into()),
+            spki_pins: Vec::new(),
+        }
+    }
+
+    /// Also requires one of the certificates to have the public key pinned with `pin`.
+    pub fn with_pin(mut self, pin: [u8; 32]) -> Self {
+        self.spki_pins.push(pin);
+        self
+    }
+}
+
+/// Upstreams queried over TLS instead of those of `resolv.conf`.
+/// Also describes upstreams queried over QUIC, see [`crate::quic::QuicConfig`].
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub upstreams: Vec<TlsUpstream>,
+    roots: Arc<RootCertStore>,
+}
+
+impl TlsConfig {
+    /// Trusts the Mozilla root certificates.
+    pub fn new(upstreams: Vec<TlsUpstream>) -> Self {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        TlsConfig::with_roots(upstreams, roots)
+    }
+
+    pub fn with_roots(upstreams: Vec<TlsUpstream>, roots: RootCertStore) -> Self {
+        TlsConfig {
+            upstreams,
+            roots: Arc::new(roots),
+        }
+    }
+
+    pub(crate) fn roots(&self) -> &Arc<RootCertStore> {
+        &self.roots
+    }
+
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.upstreams
+            .iter()
+            .map(|upstream| upstream.address)
+            .collect()
+    }
+}
+
+/// The pin of the public key of `certificate`: the SHA-256 digest of its DER-encoded
+/// SubjectPublicKeyInfo (RFC 7858, section 4.2).
+pub fn spki_pin(certificate: &CertificateDer<'_>) -> io::Result<[u8; 32]> {
+    let parsed = ParsedCertificate::try_from(certificate)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, &parsed.subject_public_key_info());
+    Ok(digest
+        .as_ref()
+        .try_into()
+        .expect("SHA-256 digests are 32 bytes"))
+}
+
+/// Checks certificates against the auth name and pins of a single upstream.
+#[derive(Debug)]
+struct Verifier {
+    /// Chain and name validation, if there is an auth name.
+    webpki: Option<Arc<rustls::client::WebPkiServerVerifier>>,
+    pins: Vec<[u8; 32]>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+        if !self.pins.is_empty() {
+            // Intermediates are only known to belong to the chain once it has been validated;
+            // otherwise anyone could send the pinned certificate along with their own
+            let intermediates = match self.webpki {
+                Some(_) => intermediates,
+                None => &[],
+            };
+            let pinned = std::iter::once(end_entity)
+                .chain(intermediates)
+                .filter_map(|certificate| spki_pin(certificate).ok())
+                .any(|pin| self.pins.contains(&pin));
+            if !pinned {
+                return Err(rustls::CertificateError::ApplicationVerificationFailure.into());
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, certificate, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, certificate, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// A client configuration for `upstream` offering the `alpn` protocol, and the name to present to it.
+///
+/// Fails with [`io::ErrorKind::InvalidInput`] if `upstream` has neither an auth name nor pins,
+/// as it could not be authenticated at all.
+pub(crate) fn client_config(
+    upstream: &TlsUpstream,
+    roots: &Arc<RootCertStore>,
+    alpn: &[u8],
+) -> io::Result<(rustls::ClientConfig, ServerName<'static>)> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    if upstream.auth_name.is_none() && upstream.spki_pins.is_empty() {
+        return Err(invalid(format!(
+            "TLS upstream {} has neither an auth name nor pins",
+            upstream.address
+        )));
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let (webpki, server_name) = match &upstream.auth_name {
+        Some(name) => {
+            let verifier = rustls::client::WebPkiServerVerifier::builder_with_provider(
+                roots.clone(),
+                provider.clone(),
+            )
+            .build()
+            .map_err(|e| invalid(e.to_string()))?;
+            let server_name = ServerName::try_from(name.clone())
+                .map_err(|e| invalid(format!("Invalid auth name {name:?}: {e}")))?;
+            (Some(verifier), server_name)
+        }
+        None => (None, ServerName::IpAddress(upstream.address.ip().into())),
+    };
+    let verifier = Verifier {
+        webpki,
+        pins: upstream.spki_pins.clone(),
+        algorithms: provider.signature_verification_algorithms,
+    };
+
+    let mut config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(e.to_string()))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    config.alpn_protocols = vec![alpn.to_vec()];
+
+    Ok((config, server_name))
+}
+
+/// A connector for `upstream` offering the `alpn` protocol, and the name to present to it,
+/// see [`client_config`].
+pub(crate) fn connector(
+    upstream: &TlsUpstream,
+    roots: &Arc<RootCertStore>,
+    alpn: &[u8],
+) -> io::Result<(TlsConnector, ServerName<'static>)> {
+    let (config, server_name) = client_config(upstream, roots, alpn)?;
+    Ok((TlsConnector::from(Arc::new(config)), server_name))
+}
+
+/// The open connections to the upstreams of a [`TlsConfig`].
+pub(crate) struct Connections {
+    connectors: HashMap<SocketAddr, (TlsConnector, ServerName<'static>)>,
+    /// Queries waiting to be written to each open connection.
+    open: HashMap<SocketAddr, mpsc::UnboundedSender<dns_codec::Message>>,
+}
+
+impl Connections {
+    pub(crate) fn new(config: &TlsConfig) -> io::Result<Self> {
+        let connectors = config
+            .upstreams
+            .iter()
+            .map(|upstream| {
+                Ok((
+                    upstream.address,
+                    connector(upstream, &config.roots, b"dot")?,
+                ))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Connections {
+            connectors,
+            open: HashMap::new(),
+        })
+    }
+
+    /// Sends `query` over the connection to `target`, opening one if there is none.
+    /// Responses, and the error that ends a connection, are passed to `received`.
+    pub(crate) fn send(
+        &mut self,
+        target: SocketAddr,
+        query: dns_codec::Message,
+        received: &mpsc::UnboundedSender<Received>,
+    ) -> io::Result<()> {
+        // A connection that has been closed since drops its end of the queue
+        let query = match self.open.get(&target) {
+            Some(open) => match open.send(query) {
+                Ok(()) => return Ok(()),
+                Err(mpsc::error::SendError(query)) => query,
+            },
+            None => query,
+        };
+
+        let (connector, server_name) = self.connectors.get(&target).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{target} is not a TLS upstream"),
+            )
+        })?;
+        let (sender, queries) = mpsc::unbounded_channel();
+        let _ = sender.send(query);
+        self.open.insert(target, sender);
+
+        let connection = connect(target, connector.clone(), server_name.clone());
+        let received = received.clone();
+        tokio::spawn(async move {
+            if let Err(e) = pipeline(target, connection, queries, &received).await {
+                let _ = received.send(Err(e));
+            }
+        });
+        Ok(())
+    }
+}
+
+async fn connect(
+    target: SocketAddr,
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+) -> io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    let stream = TcpStream::connect(target).await?;
+    connector.connect(server_name, stream).await
+}
+
+/// Writes `queries` to the connection as they come, without waiting for responses in between,
+/// until the connection has been idle for [`IDLE_TIMEOUT`] or is closed by `target`.
+///
+/// A query queued just as the connection closes is lost; the sans-io state machine retransmits it.
+async fn pipeline(
+    target: SocketAddr,
+    connection: impl std::future::Future<
+        Output = io::Result<tokio_rustls::client::TlsStream<TcpStream>>,
+    >,
+    mut queries: mpsc::UnboundedReceiver<dns_codec::Message>,
+    received: &mpsc::UnboundedSender<Received>,
+) -> io::Result<()> {
+    let (read, write) = tokio::io::split(connection.await?);
+    let mut sink = FramedWrite::new(write, dns_codec::StreamCodec(dns_codec::MessageCodec));
+    let mut responses = FramedRead::new(read, dns_codec::StreamCodec(dns_codec::MessageCodec));
+    log::debug!("tls: connected to {target}");
+
+    loop {
+        tokio::select! {
+            query = queries.recv() => match query {
+                Some(query) => sink.send(query).await?,
+                None => return Ok(()),
+            },
+            response = responses.next() => match response {
+                Some(response) => {
+                    let _ = received.send(Ok((response?, target)));
+                }
+                None => {
+                    log::debug!("tls: {target} closed the connection");
+                    return Ok(());
+                }
+            },
+            _ = tokio::time::sleep(IDLE_TIMEOUT) => {
+                log::debug!("tls: closing idle connection to {target}");
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::{TlsConfig, TlsUpstream};
+
+    /// A self-signed certificate for dns.example, and a server configuration presenting it.
+    pub(crate) fn server_config() -> (rustls::ServerConfig, CertificateDer<'static>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["dns.example".to_owned()]).unwrap();
+        let certificate = certified.cert.der().clone();
+        let key =
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate.clone()], key)
+            .unwrap();
+        (config, certificate)
+    }
+
+    /// A TLS listener presenting the certificate of [`server_config`],
+    /// which answers with [`crate::resolver::test::respond`] once `batch` queries have arrived,
+    /// last one first. Also counts the connections accepted.
+    async fn listener(batch: usize) -> (SocketAddr, CertificateDer<'static>, Arc<AtomicUsize>) {
+        let (config, certificate) = server_config();
+        let (address, accepted) = serve(config, batch).await;
+        (address, certificate, accepted)
+    }
+
+    /// Like [`listener`], presenting the certificates of `config`.
+    async fn serve(config: rustls::ServerConfig, batch: usize) -> (SocketAddr, Arc<AtomicUsize>) {
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = tcp.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = tcp.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let mut pending = Vec::new();
+                    while let Ok(length) = stream.read_u16().await {
+                        let mut query = vec![0; length.into()];
+                        stream.read_exact(&mut query).await.unwrap();
+                        pending.push(crate::resolver::test::respond(&query));
+                        if pending.len() < batch {
+                            continue;
+                        }
+                        for response in pending.drain(..).rev() {
+                            stream.write_u16(response.len() as u16).await.unwrap();
+                            stream.write_all(&response).await.unwrap();
+                        }
+                    }
+                });
+            }
+        });
+
+        (address, accepted)
+    }
+
+    pub(crate) fn roots(certificate: &CertificateDer<'static>) -> rustls::RootCertStore {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(certificate.clone()).unwrap();
+        roots
+    }
+
+    pub(crate) fn query(id: u16) -> dns_codec::Message {
+        dns_codec::Message::query("example.com", dns_codec::QType::A)
+            .id(id)
+            .recursion_desired(true)
+            .build()
+            .unwrap()
+    }
+
+    /// Sends a query with each of `ids` to the single upstream of `config`,
+    /// and returns the IDs of the responses in the order they arrived.
+    async fn exchange(config: &TlsConfig, ids: &[u16]) -> std::io::Result<Vec<u16>> {
+        let target = config.upstreams[0].address;
+        let mut connections = super::Connections::new(config)?;
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        for id in ids {
+            connections.send(target, query(*id), &sender)?;
+        }
+
+        let mut responses = Vec::new();
+        while responses.len() < ids.len() {
+            let (response, source) = receiver.recv().await.unwrap()?;
+            assert_eq!(source, target);
+            responses.push(response.header.id);
+        }
+        Ok(responses)
+    }
+
+    #[tokio::test]
+    async fn pipelining() {
+        let (address, certificate, accepted) = listener(3).await;
+        let config = TlsConfig::with_roots(
+            vec![TlsUpstream::new(address, "dns.example")],
+            roots(&certificate),
+        );
+
+        // All three are written before the first response is, over a single connection
+        let responses = exchange(&config, &[1, 2, 3]).await.unwrap();
+        assert_eq!(responses, [3, 2, 1]);
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn authentication() {
+        let (address, certificate, _) = listener(1).await;
+        let pin = super::spki_pin(&certificate).unwrap();
+
+        // Pinned, without a name or trusted roots
+        let pinned = TlsUpstream {
+            address,
+            auth_name: None,
+            spki_pins: vec![pin],
+        };
+        let config = TlsConfig::with_roots(vec![pinned.clone()], rustls::RootCertStore::empty());
+        assert_eq!(exchange(&config, &[1]).await.unwrap(), [1]);
+
+        let mismatched = TlsUpstream {
+            spki_pins: vec![[0; 32]],
+            ..pinned.clone()
+        };
+        let config = TlsConfig::with_roots(vec![mismatched], rustls::RootCertStore::empty());
+        assert!(exchange(&config, &[1]).await.is_err());
+
+        // The auth name must match the certificate, and the pin must match too if given
+        let named = TlsUpstream::new(address, "other.example");
+        let config = TlsConfig::with_roots(vec![named], roots(&certificate));
+        assert!(exchange(&config, &[1]).await.is_err());
+
+        let named = TlsUpstream::new(address, "dns.example").with_pin([0; 32]);
+        let config = TlsConfig::with_roots(vec![named], roots(&certificate));
+        assert!(exchange(&config, &[1]).await.is_err());
+
+        // Without a validated chain, a pinned certificate sent along with another key proves nothing
+        let (_, pinned_certificate) = server_config();
+        let certified = rcgen::generate_simple_self_signed(vec!["dns.example".to_owned()]).unwrap();
+        let key =
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+        let chain = vec![certified.cert.der().clone(), pinned_certificate.clone()];
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let impostor = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .unwrap();
+        let (impostor, _) = serve(impostor, 1).await;
+        let spoofed = TlsUpstream {
+            address: impostor,
+            auth_name: None,
+            spki_pins: vec![super::spki_pin(&pinned_certificate).unwrap()],
+        };
+        let config = TlsConfig::with_roots(vec![spoofed], rustls::RootCertStore::empty());
+        assert!(exchange(&config, &[1]).await.is_err());
+
+        let unauthenticated = TlsUpstream {
+            spki_pins: vec![],
+            ..pinned
+        };
+        let config = TlsConfig::with_roots(vec![unauthenticated], roots(&certificate));
+        let error = exchange(&config, &[1]).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn lookups() {
+        let (address, certificate, accepted) = listener(1).await;
+        let config = crate::config::Config {
+            tls: Some(TlsConfig::with_roots(
+                vec![TlsUpstream::new(address, "dns.example")],
+                roots(&certificate),
+            )),
+            ..Default::default()
+        };
+
+        let resolver = crate::Resolver::new(config).await.unwrap();
+        let addresses = resolver.lookup_ip("example.com").await.unwrap();
+        assert_eq!(addresses.len(), 2);
+        let exchanges = resolver.lookup_mx("example.com").await.unwrap();
+        assert_eq!(exchanges.len(), 2);
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+}
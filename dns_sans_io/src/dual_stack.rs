@@ -0,0 +1,95 @@
+//! Dual-stack lookups as recommended by Happy Eyeballs (RFC 8305): AAAA and A queries for the same name
+//! are sent together, and their answers merged into a single [`Outcome`](crate::Outcome).
+
+use core::net;
+use std::time::{Duration, Instant};
+
+use crate::Outcome;
+
+#[derive(Debug)]
+pub(crate) struct DualStack {
+    /// ID of the AAAA query, which doubles as the ID of the lookup.
+    pub(crate) v6_id: u16,
+    pub(crate) v4_id: u16,
+    pub(crate) v6: Option<Outcome>,
+    pub(crate) v4: Option<Outcome>,
+    /// Set once the first positive answer arrived; the other family is not waited for beyond it.
+    pub(crate) deadline: Option<Instant>,
+    /// Nameserver that answered most recently.
+    pub(crate) source: Option<net::SocketAddr>,
+}
+
+impl DualStack {
+    pub(crate) fn new(v6_id: u16, v4_id: u16) -> Self {
+        DualStack {
+            v6_id,
+            v4_id,
+            v6: None,
+            v4: None,
+            deadline: None,
+            source: None,
+        }
+    }
+
+    /// The ID of the member query that has not been answered yet, if any.
+    pub(crate) fn outstanding(&self) -> Option<u16> {
+        match (&self.v6, &self.v4) {
+            (None, _) => Some(self.v6_id),
+            (_, None) => Some(self.v4_id),
+            _ => None,
+        }
+    }
+
+    /// Records the outcome of one of the member queries.
+    /// Returns whether the lookup is complete, i.e. both families have been answered.
+    pub(crate) fn record(
+        &mut self,
+        response: crate::Response,
+        now: Instant,
+        delay: Duration,
+    ) -> bool {
+        let crate::Response {
+            id,
+            source,
+            outcome,
+            ..
+        } = response;
+
+        if matches!(outcome, Outcome::Resolved(_)) && self.deadline.is_none() {
+            self.deadline = Some(now + delay);
+        }
+        self.source = Some(source);
+
+        if id == self.v6_id {
+            self.v6 = Some(outcome);
+        } else {
+            self.v4 = Some(outcome);
+        }
+
+        self.v6.is_some() && self.v4.is_some()
+    }
+
+    /// Merges whatever has arrived, interleaving the families starting with IPv6 (RFC 8305, section 4).
+    pub(crate) fn merge(self) -> Outcome {
+        let (v6, v4) = match (self.v6, self.v4) {
+            (Some(Outcome::Resolved(v6)), Some(Outcome::Resolved(v4))) => (v6, v4),
+            (Some(Outcome::Resolved(v6)), _) => (v6, Vec::new()),
+            (_, Some(Outcome::Resolved(v4))) => (Vec::new(), v4),
+            // Neither family resolved; a timeout is the more telling failure
+            (Some(Outcome::TimedOut), _) | (_, Some(Outcome::TimedOut)) => {
+                return Outcome::TimedOut
+            }
+            _ => return Outcome::Unresolved,
+        };
+
+        let mut merged = Vec::with_capacity(v6.len() + v4.len());
+        let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+        loop {
+            match (v6.next(), v4.next()) {
+                (None, None) => break,
+                (v6, v4) => merged.extend(v6.into_iter().chain(v4)),
+            }
+        }
+        Outcome::Resolved(merged)
+    }
+}
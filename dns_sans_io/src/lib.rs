@@ -2,41 +2,190 @@ use core::net;
 use std::{
     collections::{HashMap, VecDeque},
     io,
+    time::{Duration, Instant},
 };
 
-use log;
+use rand::Rng as _;
+
+mod dual_stack;
+mod matching;
+mod nameservers;
 
 #[derive(Debug)]
 struct Enqueued {
-    target: net::SocketAddr,
-    query: dns_codec::Query,
+    /// Nameservers for the queried resource, any of which may be picked as the target.
+    candidates: Vec<net::SocketAddr>,
+    /// Nameservers this query has already been sent to, in order.
+    tried: Vec<net::SocketAddr>,
+    /// Zone the candidates are authoritative for; records outside of it are discarded.
+    zone: dns_codec::Name,
+    transport: Transport,
+    id: u16,
+    recursive: bool,
+    question: dns_codec::Question,
 }
 
 #[derive(Debug)]
+struct Transmitted {
+    target: net::SocketAddr,
+    sent: Instant,
+    deadline: Instant,
+    /// Question as it was sent, i.e. possibly with randomized case.
+    question: dns_codec::Question,
+    /// Whether the case of the question name was randomized.
+    randomized_case: bool,
+    /// Whether a response was rejected only for not echoing the randomized case.
+    case_mismatch: bool,
+    enqueued: Enqueued,
+}
+
+#[derive(Debug, Clone)]
 pub enum Outcome {
     Resolved(Vec<dns_codec::Record>),
     NamespaceIp(Vec<dns_codec::Record>),
     NamespaceNames(Vec<dns_codec::Record>),
     Unresolved,
+    /// No nameserver answered within any of the attempts.
+    TimedOut,
+    /// There was no nameserver to send the query to, e.g. because no upstreams are configured.
+    /// The source and target of the [`Response`] are unspecified.
+    NoNameservers,
+}
+
+/// Transport a query should be sent over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// A single datagram.
+    Udp,
+    /// A stream carrying the query with a two byte length prefix, see [`dns_codec::StreamCodec`].
+    /// Used once a response over UDP turned out to be truncated.
+    Tcp,
+    /// A TLS stream carrying the same framing as [`Transport::Tcp`] (DNS over TLS, RFC 7858).
+    /// Used for upstreams only, see [`Config::upstream_transport`].
+    Tls,
+    /// An HTTP request carrying the query as `application/dns-message` (DNS over HTTPS, RFC 8484).
+    /// Used for upstreams only, see [`Config::upstream_transport`].
+    Https,
+    /// A QUIC stream of its own carrying the same framing as [`Transport::Tcp`] (DNS over QUIC, RFC 9250).
+    /// Used for upstreams only, see [`Config::upstream_transport`].
+    Quic,
 }
 
 #[derive(Debug)]
 pub struct Transmit {
     pub target: net::SocketAddr,
-    pub query: dns_codec::Query,
+    pub transport: Transport,
+    pub query: dns_codec::Message,
+    /// Local port the IO layer should send this query from, see [`Config::randomize_source_port`].
+    /// `None` leaves the choice to the IO layer.
+    pub source_port: Option<u16>,
+    /// When this attempt times out; the IO layer need not wait for a response any longer.
+    pub deadline: Instant,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Response {
+    pub id: u16,
     pub source: net::SocketAddr,
     pub target: net::SocketAddr,
     pub outcome: Outcome,
+    /// The response the outcome was drawn from, with out-of-zone records scrubbed.
+    /// `None` if no nameserver gave an acceptable one, and for dual-stack lookups.
+    pub message: Option<dns_codec::Message>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Retransmission timeout of the first attempt; doubled on every subsequent attempt.
+    pub initial_timeout: Duration,
+
+    /// Upper bound for the retransmission timeout.
+    pub max_timeout: Duration,
+
+    /// Number of transmissions, across all candidate nameservers, before a query is given up on.
+    pub max_attempts: u32,
+
+    /// Number of consecutive timeouts, SERVFAIL or REFUSED answers after which a nameserver is lame.
+    pub lame_threshold: u32,
+
+    /// How long a lame nameserver is avoided for.
+    pub lame_duration: Duration,
+
+    /// Pick a random, unprivileged source port for every transmission, so that forging a response
+    /// requires guessing the port in addition to the query ID.
+    pub randomize_source_port: bool,
+
+    /// Require the question of a response to echo the letter case of the query exactly,
+    /// instead of comparing names case-insensitively.
+    pub require_exact_case: bool,
+
+    /// Randomize the case of the query name (DNS 0x20) and require responses to echo it exactly.
+    /// Nameservers that do not preserve case are detected, and queried without 0x20 from then on.
+    pub randomize_case: bool,
+
+    /// Number of consecutive queries a nameserver must leave unanswered except for responses
+    /// that do not echo the 0x20 case before it is queried without 0x20.
+    /// Such responses are always rejected, so a forged one cannot turn 0x20 off by itself.
+    pub case_mismatch_threshold: u32,
+
+    /// Advertise EDNS (RFC 6891) in queries, with this as the largest UDP payload the IO layer can reassemble.
+    /// `None` sends plain DNS queries.
+    pub edns: Option<u16>,
+
+    /// Recursive resolvers that [`DnsSansIo::enqueue_recursive_query`] forwards queries to, in order of preference.
+    pub upstreams: Vec<net::SocketAddr>,
+
+    /// Spread recursive queries across all upstreams instead of always starting with the first.
+    pub rotate: bool,
+
+    /// Transport recursive queries are sent to the upstreams over.
+    /// Responses over streams are never truncated, so this is kept across retransmissions.
+    pub upstream_transport: Transport,
+
+    /// How long a dual-stack lookup waits for the second address family once the first has resolved
+    /// (Resolution Delay, RFC 8305).
+    pub resolution_delay: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            initial_timeout: Duration::from_millis(800),
+            max_timeout: Duration::from_secs(8),
+            max_attempts: 4,
+            lame_threshold: 3,
+            lame_duration: Duration::from_secs(600),
+            randomize_source_port: false,
+            require_exact_case: false,
+            randomize_case: false,
+            case_mismatch_threshold: 3,
+            edns: None,
+            upstreams: Vec::new(),
+            rotate: false,
+            upstream_transport: Transport::Udp,
+            resolution_delay: Duration::from_millis(50),
+        }
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct DnsSansIo {
+    config: Config,
     enqueued: VecDeque<Enqueued>,
-    transmitted: HashMap<u16, (net::SocketAddr, dns_codec::QType)>,
+    transmitted: HashMap<u16, Transmitted>,
+    nameservers: nameservers::Nameservers,
+    /// Index of the upstream the next recursive query starts with, if rotating.
+    rotation: usize,
+    /// Dual-stack lookups by the ID of their AAAA query.
+    dual_stack: HashMap<u16, dual_stack::DualStack>,
+    /// IDs of the AAAA and A queries belonging to dual-stack lookups, mapped to the lookup's ID.
+    dual_stack_members: HashMap<u16, u16>,
+    /// IDs handed out to callers waiting on each enqueued or transmitted query, by the ID the query is sent with.
+    /// Callers asking the same question while it is outstanding share its transmission.
+    waiting: HashMap<u16, Vec<u16>>,
+    /// Responses to queries that had no nameserver to be sent to, by when that was found out.
+    /// Returned by the next call to [`DnsSansIo::handle_timeout`].
+    unsendable: Vec<(Instant, Response)>,
 }
 
 impl DnsSansIo {
@@ -45,88 +194,440 @@ impl DnsSansIo {
             ..Default::default()
         }
     }
+
+    pub fn with_config(config: Config) -> Self {
+        DnsSansIo {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Smoothed round trip time of `nameserver`, if it has answered before.
+    pub fn srtt(&self, nameserver: &net::SocketAddr) -> Option<Duration> {
+        self.nameservers.srtt(nameserver)
+    }
+
+    /// Whether `nameserver` has recently failed too often to be preferred.
+    pub fn is_lame(&self, nameserver: &net::SocketAddr, now: Instant) -> bool {
+        self.nameservers.is_lame(nameserver, now)
+    }
 }
 
 impl DnsSansIo {
-    /// Build and enqueue DNS query
+    /// Build and enqueue DNS query, returning the ID it was assigned.
     /// TODO: Build portion
+    ///
+    /// The query is sent to whichever of `nameservers` is expected to answer fastest,
+    /// and retransmitted to the others if it goes unanswered.
+    ///
+    /// If the same question is already outstanding, no new query is sent;
+    /// the returned ID is answered along with the outstanding one instead.
     pub fn enqueue_query(
         &mut self,
-        nameserver: net::SocketAddr,
-        id: u16,
+        nameservers: &[net::SocketAddr],
         type_: dns_codec::QType,
         resource: Vec<u8>,
-    ) {
-        let event = format!("0x{:04x}", id);
-        log::info!(target: &event, "enqueue: outgoing query for {} to {}", std::str::from_utf8(&resource).unwrap(), nameserver);
+    ) -> u16 {
+        self.enqueue_query_in_zone(dns_codec::Name::root(), nameservers, type_, resource)
+    }
 
-        let query = dns_codec::Query {
-            header: dns_codec::Header {
-                id,
-                flags: 0,
-                qdcount: 1,
-                ancount: 0,
-                ncount: 0,
-                arcount: 0,
-            },
-            question: dns_codec::Question {
-                name: resource.try_into().unwrap(),
-                kind: type_,
-                class: dns_codec::QClass::IN,
-            },
+    /// Like [`DnsSansIo::enqueue_query`], for `nameservers` that are known to be authoritative for `zone`.
+    /// Authority and additional records outside of `zone` are discarded from their responses.
+    pub fn enqueue_query_in_zone(
+        &mut self,
+        zone: dns_codec::Name,
+        nameservers: &[net::SocketAddr],
+        type_: dns_codec::QType,
+        resource: Vec<u8>,
+    ) -> u16 {
+        self.enqueue(zone, nameservers.to_vec(), false, type_, resource)
+    }
+
+    /// Stub resolver mode: asks one of the configured [`Config::upstreams`] to resolve the query
+    /// recursively, failing over to the others in order if it does not answer.
+    ///
+    /// Upstreams that are lame or do not offer recursion are skipped,
+    /// and with [`Config::rotate`] each query starts at the next upstream.
+    pub fn enqueue_recursive_query(&mut self, type_: dns_codec::QType, resource: Vec<u8>) -> u16 {
+        let mut upstreams = self.config.upstreams.clone();
+        if self.config.rotate && !upstreams.is_empty() {
+            let start = self.rotation % upstreams.len();
+            upstreams.rotate_left(start);
+            self.rotation = self.rotation.wrapping_add(1);
+        }
+
+        self.enqueue(dns_codec::Name::root(), upstreams, true, type_, resource)
+    }
+
+    /// Dual-stack lookup of `resource` through the configured [`Config::upstreams`], see [`DnsSansIo::enqueue_recursive_query`].
+    ///
+    /// AAAA and A queries are sent at once, and answered by a single [`Response`] carrying the returned ID.
+    /// Its records are ordered as RFC 8305 recommends, alternating between IPv6 and IPv4 starting with IPv6.
+    /// Once either family has resolved, the other is waited for no longer than [`Config::resolution_delay`].
+    pub fn enqueue_dual_stack_query(&mut self, resource: Vec<u8>) -> u16 {
+        let v6_id = self.enqueue_recursive_query(dns_codec::QType::AAAA, resource.clone());
+        let v4_id = self.enqueue_recursive_query(dns_codec::QType::A, resource);
+
+        self.dual_stack_members.insert(v6_id, v6_id);
+        self.dual_stack_members.insert(v4_id, v6_id);
+        self.dual_stack
+            .insert(v6_id, dual_stack::DualStack::new(v6_id, v4_id));
+
+        v6_id
+    }
+
+    fn enqueue(
+        &mut self,
+        zone: dns_codec::Name,
+        candidates: Vec<net::SocketAddr>,
+        recursive: bool,
+        type_: dns_codec::QType,
+        resource: Vec<u8>,
+    ) -> u16 {
+        let id = self.allocate_id();
+
+        let event = format!("0x{:04x}", id);
+        let question = dns_codec::Question {
+            name: resource.clone().try_into().unwrap(),
+            kind: type_,
+            class: dns_codec::QClass::IN,
         };
+        if let Some(outstanding) = self.outstanding(&zone, &candidates, recursive, &question) {
+            log::info!(target: &event, "enqueue: joining outstanding query 0x{outstanding:04x} for {}", question.name);
+            self.waiting.get_mut(&outstanding).unwrap().push(id);
+            return id;
+        }
+        log::info!(target: &event, "enqueue: outgoing query for {} to {:?}", std::str::from_utf8(&resource).unwrap(), candidates);
+
+        self.waiting.insert(id, vec![id]);
 
         self.enqueued.push_back(Enqueued {
-            target: nameserver,
-            query,
+            candidates,
+            tried: Vec::new(),
+            zone,
+            transport: match recursive {
+                true => self.config.upstream_transport,
+                false => Transport::Udp,
+            },
+            id,
+            recursive,
+            question,
         });
 
-        /*
-        match self.enqueued.entry(query.header.id) {
-            Entry::Occupied(dst) => {
-                log::warn!(target: &event, "enqueue: failed because ID is already in use for {dst:?}");
+        id
+    }
+
+    /// ID of the enqueued or transmitted query asking `question` of the same nameservers, if any.
+    /// Names are compared case-insensitively, so 0x20 randomization does not defeat coalescing,
+    /// and nameservers regardless of their order, so neither does [`Config::rotate`].
+    fn outstanding(
+        &self,
+        zone: &dns_codec::Name,
+        candidates: &[net::SocketAddr],
+        recursive: bool,
+        question: &dns_codec::Question,
+    ) -> Option<u16> {
+        let same_nameservers = |other: &[net::SocketAddr]| {
+            other.len() == candidates.len() && other.iter().all(|c| candidates.contains(c))
+        };
+        let transmitted = self.transmitted.values().map(|t| &t.enqueued);
+        self.enqueued
+            .iter()
+            .chain(transmitted)
+            .find(|e| {
+                e.question == *question
+                    && e.recursive == recursive
+                    && e.zone == *zone
+                    && same_nameservers(&e.candidates)
+            })
+            .map(|e| e.id)
+    }
+
+    /// Draws random IDs until one is found that is neither enqueued, in flight nor handed out to a caller.
+    fn allocate_id(&self) -> u16 {
+        loop {
+            let id = dns_codec::Header::random_id();
+
+            let in_use = self.transmitted.contains_key(&id)
+                || self.enqueued.iter().any(|e| e.id == id)
+                || self.dual_stack.contains_key(&id)
+                || self.waiting.values().flatten().any(|caller| *caller == id);
+            if !in_use {
+                break id;
             }
-            Entry::Vacant(empty) => {
-                empty.insert(Enqueued {
-                    target: nameserver,
-                    query,
-                });
-                log::debug!(target: &event, "enqueue: success");
+            log::trace!("allocate: 0x{id:04x} is already in use");
+        }
+    }
+
+    /// Queries without any nameserver to send them to are skipped, and answered with
+    /// [`Outcome::NoNameservers`] by the next call to [`DnsSansIo::handle_timeout`].
+    pub fn poll_query(&mut self, now: Instant) -> Option<Transmit> {
+        let (mut enqueued, target, event) = loop {
+            let enqueued = self.enqueued.pop_front()?;
+            let event = format!("0x{:04x}", enqueued.id);
+
+            let selected = if enqueued.recursive {
+                self.nameservers
+                    .select_in_order(&enqueued.candidates, &enqueued.tried, now)
+            } else {
+                self.nameservers
+                    .select(&enqueued.candidates, &enqueued.tried, now)
+            };
+            if let Some(target) = selected {
+                break (enqueued, target, event);
             }
+
+            log::warn!(target: &event, "poll: failing query without nameservers");
+            let unspecified = net::SocketAddr::from((net::Ipv4Addr::UNSPECIFIED, 0));
+            let response = Response {
+                id: enqueued.id,
+                source: unspecified,
+                target: unspecified,
+                outcome: Outcome::NoNameservers,
+                message: None,
+            };
+            self.unsendable.push((now, response));
         };
-        */
+
+        // Exponential backoff, capped by the configured maximum
+        let attempt = enqueued.tried.len() as u32;
+        let timeout = self
+            .config
+            .initial_timeout
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.config.max_timeout);
+        enqueued.tried.push(target);
+
+        let mut question = enqueued.question.clone();
+        let randomized_case =
+            self.config.randomize_case && self.nameservers.preserves_case(&target);
+        if randomized_case {
+            question.name = question.name.randomize_case();
+        }
+        let transport = enqueued.transport;
+        log::debug!(target: &event, "poll: query {target} over {transport:?} for {:?} (attempt {}, timeout {timeout:?})", question.name, attempt + 1);
+
+        let mut query = dns_codec::Message::query(question.name.clone(), question.kind)
+            .id(enqueued.id)
+            .recursion_desired(enqueued.recursive);
+        if let Some(udp_payload_size) = self.config.edns {
+            query = query.edns(udp_payload_size);
+        }
+        let query = query.build().expect("a single question always fits");
+        self.transmitted.insert(
+            enqueued.id,
+            Transmitted {
+                target,
+                sent: now,
+                deadline: now + timeout,
+                question,
+                randomized_case,
+                case_mismatch: false,
+                enqueued,
+            },
+        );
+
+        let source_port = (self.config.randomize_source_port && transport == Transport::Udp)
+            .then(|| rand::thread_rng().gen_range(1024..=u16::MAX));
+
+        Some(Transmit {
+            target,
+            transport,
+            query,
+            source_port,
+            deadline: now + timeout,
+        })
     }
 
-    pub fn poll_query(&mut self) -> Option<Transmit> {
-        let Some(Enqueued { target, query }) = self.enqueued.pop_front() else {
-            return None;
+    /// The earliest point in time at which [`DnsSansIo::handle_timeout`] must be called.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let retransmissions = self.transmitted.values().map(|t| t.deadline);
+        let resolution_delays = self.dual_stack.values().filter_map(|d| d.deadline);
+        let unsendable = self.unsendable.iter().map(|(since, _)| *since);
+        retransmissions
+            .chain(resolution_delays)
+            .chain(unsendable)
+            .min()
+    }
+
+    /// Retransmits every query whose deadline has passed to the next best nameserver.
+    /// Queries that have exhausted their attempts are given up on and reported as [`Outcome::TimedOut`],
+    /// along with those that could not be sent at all.
+    pub fn handle_timeout(&mut self, now: Instant) -> Vec<Response> {
+        let expired: Vec<u16> = self
+            .transmitted
+            .iter()
+            .filter(|(_, transmitted)| transmitted.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        let mut responses = Vec::new();
+        for (_, response) in std::mem::take(&mut self.unsendable) {
+            for response in self.fan_out(response) {
+                responses.extend(self.complete(now, response));
+            }
+        }
+        for id in expired {
+            let transmitted = self.transmitted.remove(&id).unwrap();
+
+            let event = format!("0x{:04x}", id);
+            log::info!(target: &event, "timeout: no response from {}", transmitted.target);
+
+            // The server did answer, just not in the case it was asked in
+            if transmitted.case_mismatch {
+                self.nameservers
+                    .record_case_mismatch(transmitted.target, &self.config);
+            } else {
+                self.nameservers
+                    .record_failure(transmitted.target, now, &self.config);
+            }
+            if let Some(response) = self.retry(transmitted, Outcome::TimedOut) {
+                for response in self.fan_out(response) {
+                    responses.extend(self.complete(now, response));
+                }
+            }
+        }
+
+        // Dual-stack lookups whose second family took longer than the resolution delay
+        let delayed: Vec<u16> = self
+            .dual_stack
+            .values()
+            .filter(|lookup| lookup.deadline.is_some_and(|deadline| deadline <= now))
+            .map(|lookup| lookup.v6_id)
+            .collect();
+        for id in delayed {
+            responses.push(self.finish_dual_stack(id));
+        }
+
+        responses
+    }
+
+    /// Copies the final response of a query to every caller waiting on it, see [`DnsSansIo::enqueue_query`].
+    fn fan_out(&mut self, response: Response) -> Vec<Response> {
+        let callers = self.waiting.remove(&response.id).unwrap_or_default();
+        callers
+            .into_iter()
+            .map(|id| Response {
+                id,
+                ..response.clone()
+            })
+            .collect()
+    }
+
+    /// Stops waiting on behalf of the caller that was handed out `id`,
+    /// cancelling the query it waited on unless other callers still do.
+    fn abandon(&mut self, id: u16) {
+        let Some((&query_id, callers)) = self
+            .waiting
+            .iter_mut()
+            .find(|(_, callers)| callers.contains(&id))
+        else {
+            return;
         };
-        /*
-        let  = match self.enqueued.pop_front() {
-            Some(enqueued) => enqueued,
-            None => { log::warn!(target: &query.header.id, "poll: failed because ID is unknown"); return None; },
+        callers.retain(|caller| *caller != id);
+        if !callers.is_empty() {
+            return;
+        }
+
+        self.waiting.remove(&query_id);
+        self.enqueued.retain(|e| e.id != query_id);
+        self.transmitted.remove(&query_id);
+    }
+
+    /// Routes the final response of a query that is part of a dual-stack lookup into it,
+    /// producing the lookup's response once it is complete.
+    fn complete(&mut self, now: Instant, response: Response) -> Option<Response> {
+        let Some(&lookup_id) = self.dual_stack_members.get(&response.id) else {
+            return Some(response);
         };
-        */
+        let lookup = self.dual_stack.get_mut(&lookup_id).unwrap();
+
+        if !lookup.record(response, now, self.config.resolution_delay) {
+            return None;
+        }
+        Some(self.finish_dual_stack(lookup_id))
+    }
+
+    /// Merges whatever a dual-stack lookup has received, abandoning its outstanding member query.
+    /// Only called once at least one member query has been answered.
+    fn finish_dual_stack(&mut self, id: u16) -> Response {
+        let lookup = self.dual_stack.remove(&id).unwrap();
+        self.dual_stack_members.remove(&lookup.v6_id);
+        self.dual_stack_members.remove(&lookup.v4_id);
+
+        if let Some(outstanding) = lookup.outstanding() {
+            let event = format!("0x{:04x}", id);
+            log::debug!(target: &event, "dual-stack: not waiting for 0x{outstanding:04x} any longer");
 
-        let event = format!("0x{:04x}", query.header.id);
+            self.abandon(outstanding);
+        }
+
+        let source = lookup.source.unwrap();
+        Response {
+            id,
+            source,
+            target: source,
+            outcome: lookup.merge(),
+            message: None,
+        }
+    }
 
-        self.transmitted
-            .insert(query.header.id, (target, query.question.kind));
-        log::debug!(target: &event, "poll: query {target} for {:?}", query.question.name);
+    /// Re-enqueues a failed query, or gives up on it with `outcome` once all attempts are exhausted.
+    fn retry(&mut self, transmitted: Transmitted, outcome: Outcome) -> Option<Response> {
+        let Transmitted {
+            target, enqueued, ..
+        } = transmitted;
+        let id = enqueued.id;
+
+        if enqueued.tried.len() as u32 >= self.config.max_attempts {
+            let event = format!("0x{:04x}", id);
+            log::warn!(target: &event, "retry: giving up after {} attempts", enqueued.tried.len());
+
+            return Some(Response {
+                id,
+                source: target,
+                target,
+                outcome,
+                message: None,
+            });
+        }
 
-        Some(Transmit { target, query })
+        self.enqueued.push_front(enqueued);
+        None
     }
 
+    /// Returns a response for every caller waiting on the answered query.
+    ///
+    /// Returns no responses if the nameserver failed to answer and the query was enqueued for retransmission,
+    /// or for callers whose query is part of a dual-stack lookup that is still waiting for its other half.
     pub fn handle_response(
         &mut self,
+        now: Instant,
         nameserver: net::SocketAddr,
-        response: dns_codec::Response,
-    ) -> io::Result<Response> {
+        response: dns_codec::Message,
+    ) -> io::Result<Vec<Response>> {
+        let Some(response) = self.receive(now, nameserver, response)? else {
+            return Ok(Vec::new());
+        };
+
+        let mut responses = Vec::new();
+        for response in self.fan_out(response) {
+            responses.extend(self.complete(now, response));
+        }
+        Ok(responses)
+    }
+
+    fn receive(
+        &mut self,
+        now: Instant,
+        nameserver: net::SocketAddr,
+        mut response: dns_codec::Message,
+    ) -> io::Result<Option<Response>> {
         // We must decode the header
         let header = response.header;
         let event = format!("0x{:04x}", header.id);
 
-        let Some((target, interest)) = self.transmitted.remove(&header.id) else {
+        let Some(transmitted) = self.transmitted.get(&header.id) else {
             log::warn!(target: &event, "response: unknown id {}", header.id);
             return Err(io::Error::new(
                 io::ErrorKind::NotFound,
@@ -134,13 +635,80 @@ impl DnsSansIo {
             ));
         };
 
+        // Leave the query in flight if the response is rejected, as it may have been forged
+        let require_exact_case = self.config.require_exact_case || transmitted.randomized_case;
+        if let Err(e) = matching::verify(
+            &transmitted.question,
+            transmitted.target,
+            nameserver,
+            &response,
+            require_exact_case,
+        ) {
+            let case_mismatch = transmitted.randomized_case
+                && matching::verify(
+                    &transmitted.question,
+                    transmitted.target,
+                    nameserver,
+                    &response,
+                    false,
+                )
+                .is_ok();
+            if case_mismatch {
+                // Counted against the server only if no correctly cased response follows
+                log::info!(target: &event, "response: {nameserver} did not echo 0x20 case");
+                self.transmitted.get_mut(&header.id).unwrap().case_mismatch = true;
+            } else {
+                log::warn!(target: &event, "response: rejected; {e}");
+            }
+            return Err(e);
+        }
+
+        let transmitted = self.transmitted.remove(&header.id).unwrap();
+        let target = transmitted.target;
+        if transmitted.randomized_case {
+            self.nameservers.record_case_preserved(target);
+        }
+        let interest = transmitted.enqueued.question.kind;
+
+        if let Some(rcode @ (dns_codec::Rcode::SERVFAIL | dns_codec::Rcode::REFUSED)) =
+            header.rcode()
+        {
+            log::info!(target: &event, "response: {nameserver} answered {rcode:?}");
+            self.nameservers.record_failure(target, now, &self.config);
+            return Ok(self.retry(transmitted, Outcome::Unresolved));
+        }
+
+        // An upstream that does not recurse is of no use to a stub resolver
+        let recursive = transmitted.enqueued.recursive;
+        if recursive && !header.recursion_available() {
+            log::info!(target: &event, "response: {nameserver} does not offer recursion");
+            self.nameservers.record_failure(target, now, &self.config);
+            return Ok(self.retry(transmitted, Outcome::Unresolved));
+        }
+        self.nameservers
+            .record_rtt(target, now.saturating_duration_since(transmitted.sent));
+
+        if header.is_truncated() && transmitted.enqueued.transport == Transport::Udp {
+            log::info!(target: &event, "response: truncated by {nameserver}, retrying over TCP");
+
+            let mut enqueued = transmitted.enqueued;
+            enqueued.transport = Transport::Tcp;
+            enqueued.tried.retain(|tried| *tried != target);
+            self.enqueued.push_front(enqueued);
+            return Ok(None);
+        }
+
+        let Enqueued { zone, question, .. } = &transmitted.enqueued;
+        matching::scrub(&event, question, zone, &mut response);
+
         let mut outcome = Outcome::Unresolved;
 
         if matches!(outcome, Outcome::Unresolved) {
             let records: Vec<_> = response
                 .answers
-                .into_iter()
+                .iter()
                 .filter(|r| r.kind == interest)
+                .cloned()
                 .collect();
             if !records.is_empty() {
                 log::info!(target: &event, "response: resolved!");
@@ -151,8 +719,9 @@ impl DnsSansIo {
         if matches!(outcome, Outcome::Unresolved) {
             let records: Vec<_> = response
                 .additionals
-                .into_iter()
+                .iter()
                 .filter(|r| r.kind == interest)
+                .cloned()
                 .collect();
             if !records.is_empty() {
                 log::info!(target: &event, "response: received namespace ips!");
@@ -163,8 +732,9 @@ impl DnsSansIo {
         if matches!(outcome, Outcome::Unresolved) {
             let records: Vec<_> = response
                 .authorities
-                .into_iter()
+                .iter()
                 .filter(|r| r.kind == dns_codec::Type::NS)
+                .cloned()
                 .collect();
             if !records.is_empty() {
                 log::info!(target: &event, "response: received namespace names!");
@@ -172,11 +742,13 @@ impl DnsSansIo {
             }
         }
 
-        Ok(Response {
+        Ok(Some(Response {
+            id: header.id,
             source: nameserver,
             target,
             outcome,
-        })
+            message: Some(response),
+        }))
     }
 
     /*
@@ -220,9 +792,13 @@ impl DnsSansIo {
     */
 }
 
+
 #[cfg(test)]
 mod test {
-    use std::net;
+    use std::{
+        net,
+        time::{Duration, Instant},
+    };
 
     use tokio_util::{bytes::BytesMut, codec::Decoder};
 
@@ -230,16 +806,12 @@ mod test {
     fn resolve_ip() {
         let nameserver: net::SocketAddr = "8.8.8.8:53".parse().unwrap();
         let mut resolver = crate::DnsSansIo::default();
+        let now = Instant::now();
 
         // I want to know about google.com
-        resolver.enqueue_query(
-            nameserver,
-            0x8298,
-            dns_codec::QType::A,
-            b"google.com".to_vec(),
-        );
+        let id = resolver.enqueue_query(&[nameserver], dns_codec::QType::A, b"google.com".to_vec());
 
-        let crate::Transmit { target, query: _ } = resolver.poll_query().unwrap();
+        let crate::Transmit { target, .. } = resolver.poll_query(now).unwrap();
 
         // UDP Send....
 
@@ -247,16 +819,464 @@ mod test {
         let origin = target;
 
         let mut bytes = BytesMut::new();
-        bytes.extend_from_slice(b"\x82\x98\x80\x80\0\x01\0\x01\0\0\0\0\x06google\x03com\0\0\x01\0\x01\xc0\x0c\0\x01\0\x01\0\0\0\xc2\0\x04\xac\xd9\x10\xae");
+        bytes.extend_from_slice(&id.to_be_bytes());
+        bytes.extend_from_slice(b"\x80\x80\0\x01\0\x01\0\0\0\0\x06google\x03com\0\0\x01\0\x01\xc0\x0c\0\x01\0\x01\0\0\0\xc2\0\x04\xac\xd9\x10\xae");
 
-        let mut codec = dns_codec::ResponseCodec;
+        let mut codec = dns_codec::MessageCodec;
         let response = codec.decode(&mut bytes).unwrap().unwrap();
 
-        let super::Response {
-            source,
-            target,
-            outcome,
-        } = resolver.handle_response(origin, response).unwrap();
+        let super::Response { outcome, .. } = resolver
+            .handle_response(now + Duration::from_millis(20), origin, response)
+            .unwrap()
+            .pop()
+            .unwrap();
         dbg!(outcome);
+
+        assert_eq!(resolver.srtt(&nameserver), Some(Duration::from_millis(20)));
+    }
+
+    #[test_log::test]
+    fn failover_with_backoff() {
+        let first: net::SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let second: net::SocketAddr = "192.0.2.2:53".parse().unwrap();
+
+        let config = crate::Config {
+            max_attempts: 3,
+            lame_threshold: 2,
+            ..Default::default()
+        };
+        let initial_timeout = config.initial_timeout;
+        let mut resolver = crate::DnsSansIo::with_config(config);
+        let mut now = Instant::now();
+
+        resolver.enqueue_query(&[first, second], dns_codec::QType::A, b"example.com".to_vec());
+
+        // Every attempt goes to a server that has not been tried yet, with a doubled timeout
+        let mut targets = Vec::new();
+        let mut timeouts = Vec::new();
+        for _ in 0..3 {
+            let transmit = resolver.poll_query(now).unwrap();
+            targets.push(transmit.target);
+
+            let deadline = resolver.poll_timeout().unwrap();
+            timeouts.push(deadline - now);
+            now = deadline;
+
+            let responses = resolver.handle_timeout(now);
+            assert_eq!(responses.is_empty(), targets.len() < 3);
+        }
+
+        assert_ne!(targets[0], targets[1]);
+        assert_eq!(
+            timeouts,
+            [initial_timeout, initial_timeout * 2, initial_timeout * 4]
+        );
+        assert!(resolver.poll_query(now).is_none());
+        assert!(resolver.poll_timeout().is_none());
+        assert!(resolver.is_lame(&targets[0], now));
+    }
+
+    /// Encodes a response to an A query for `name` with the given additional A records.
+    fn referral(id: u16, name: &[u8], additionals: &[(&[u8], [u8; 4])]) -> BytesMut {
+        fn encode_name(bytes: &mut BytesMut, name: &[u8]) {
+            for label in name.split(|c| *c == b'.') {
+                bytes.extend_from_slice(&[label.len() as u8]);
+                bytes.extend_from_slice(label);
+            }
+            bytes.extend_from_slice(&[0]);
+        }
+
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&id.to_be_bytes());
+        bytes.extend_from_slice(b"\x80\x00\0\x01\0\0\0\0");
+        bytes.extend_from_slice(&(additionals.len() as u16).to_be_bytes());
+
+        encode_name(&mut bytes, name);
+        bytes.extend_from_slice(b"\0\x01\0\x01");
+
+        for (owner, address) in additionals {
+            encode_name(&mut bytes, owner);
+            bytes.extend_from_slice(b"\0\x01\0\x01\0\0\x0e\x10\0\x04");
+            bytes.extend_from_slice(address);
+        }
+        bytes
+    }
+
+    /// Encodes a recursive response for `name` answering `kind` with every one of `rdatas`.
+    fn answer(id: u16, name: &[u8], kind: dns_codec::QType, rdatas: &[&[u8]]) -> dns_codec::Message {
+        let mut bytes = referral(id, name, &[]);
+        bytes[3] = 0x80;
+        bytes[7] = rdatas.len() as u8;
+        bytes[name.len() + 15..name.len() + 16].copy_from_slice(&[kind as u8]);
+
+        for rdata in rdatas {
+            bytes.extend_from_slice(b"\xc0\x0c\0");
+            bytes.extend_from_slice(&[kind as u8]);
+            bytes.extend_from_slice(b"\0\x01\0\0\x0e\x10\0");
+            bytes.extend_from_slice(&[rdata.len() as u8]);
+            bytes.extend_from_slice(rdata);
+        }
+        dns_codec::MessageCodec.decode(&mut bytes).unwrap().unwrap()
+    }
+
+    #[test_log::test]
+    fn dual_stack() {
+        let upstream: net::SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let mut resolver = crate::DnsSansIo::with_config(crate::Config {
+            upstreams: vec![upstream],
+            ..Default::default()
+        });
+        let delay = crate::Config::default().resolution_delay;
+        let now = Instant::now();
+
+        // Both families arrive in time and are interleaved, IPv6 first
+        let id = resolver.enqueue_dual_stack_query(b"example.com".to_vec());
+        let v6 = resolver.poll_query(now).unwrap().query;
+        let v4 = resolver.poll_query(now).unwrap().query;
+        assert_eq!(v6.header.id, id);
+        assert_eq!(v6.questions[0].kind, dns_codec::QType::AAAA);
+        assert_eq!(v4.questions[0].kind, dns_codec::QType::A);
+
+        let addresses: &[&[u8]] = &[&[192, 0, 2, 10], &[192, 0, 2, 11]];
+        let response = answer(v4.header.id, b"example.com", dns_codec::QType::A, addresses);
+        assert!(resolver.handle_response(now, upstream, response).unwrap().is_empty());
+        assert_eq!(resolver.poll_timeout(), Some(now + delay));
+
+        let addresses: &[&[u8]] = &[&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]];
+        let response = answer(v6.header.id, b"example.com", dns_codec::QType::AAAA, addresses);
+        let response = resolver.handle_response(now, upstream, response).unwrap().pop().unwrap();
+        assert_eq!(response.id, id);
+        let crate::Outcome::Resolved(records) = response.outcome else {
+            panic!("expected addresses, got {:?}", response.outcome);
+        };
+        let kinds: Vec<_> = records.iter().map(|r| r.kind).collect();
+        assert_eq!(kinds, [dns_codec::Type::AAAA, dns_codec::Type::A, dns_codec::Type::A]);
+
+        // IPv6 is slow, so IPv4 is returned on its own after the resolution delay
+        let id = resolver.enqueue_dual_stack_query(b"example.com".to_vec());
+        let _v6 = resolver.poll_query(now).unwrap().query;
+        let v4 = resolver.poll_query(now).unwrap().query;
+
+        let addresses: &[&[u8]] = &[&[192, 0, 2, 10]];
+        let response = answer(v4.header.id, b"example.com", dns_codec::QType::A, addresses);
+        assert!(resolver.handle_response(now, upstream, response).unwrap().is_empty());
+
+        let responses = resolver.handle_timeout(now + delay);
+        let [response] = responses.as_slice() else {
+            panic!("expected a single response, got {responses:?}");
+        };
+        assert_eq!(response.id, id);
+        assert!(matches!(&response.outcome, crate::Outcome::Resolved(records) if records.len() == 1));
+        assert!(resolver.poll_timeout().is_none());
+    }
+
+    #[test_log::test]
+    fn coalescing() {
+        let upstream: net::SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let mut resolver = crate::DnsSansIo::with_config(crate::Config {
+            upstreams: vec![upstream],
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        // Callers asking the same question share a single transmission, regardless of case
+        let first = resolver.enqueue_recursive_query(dns_codec::QType::A, b"example.com".to_vec());
+        let transmit = resolver.poll_query(now).unwrap();
+        let second = resolver.enqueue_recursive_query(dns_codec::QType::A, b"EXAMPLE.com".to_vec());
+        let other = resolver.enqueue_recursive_query(dns_codec::QType::AAAA, b"example.com".to_vec());
+        assert_ne!(first, second);
+        assert_eq!(resolver.poll_query(now).unwrap().query.header.id, other);
+        assert!(resolver.poll_query(now).is_none());
+
+        let addresses: &[&[u8]] = &[&[192, 0, 2, 10]];
+        let response = answer(transmit.query.header.id, b"example.com", dns_codec::QType::A, addresses);
+        let mut ids: Vec<_> = resolver
+            .handle_response(now, upstream, response)
+            .unwrap()
+            .into_iter()
+            .map(|response| {
+                assert!(matches!(response.outcome, crate::Outcome::Resolved(_)));
+                response.id
+            })
+            .collect();
+        ids.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(ids, expected);
+
+        // Once answered, the question is asked anew
+        let third = resolver.enqueue_recursive_query(dns_codec::QType::A, b"example.com".to_vec());
+        assert_eq!(resolver.poll_query(now).unwrap().query.header.id, third);
+    }
+
+    #[test_log::test]
+    fn coalescing_per_nameservers() {
+        let root: net::SocketAddr = "198.41.0.4:53".parse().unwrap();
+        let com: net::SocketAddr = "192.5.6.30:53".parse().unwrap();
+        let mut resolver = crate::DnsSansIo::default();
+        let now = Instant::now();
+
+        // The same question of different nameservers is sent to each of them
+        let first = resolver.enqueue_query(&[root], dns_codec::QType::A, b"example.com".to_vec());
+        let second = resolver.enqueue_query(&[com], dns_codec::QType::A, b"example.com".to_vec());
+        let transmit = resolver.poll_query(now).unwrap();
+        assert_eq!((transmit.query.header.id, transmit.target), (first, root));
+        let transmit = resolver.poll_query(now).unwrap();
+        assert_eq!((transmit.query.header.id, transmit.target), (second, com));
+
+        // Only the same nameservers, in whatever order, share a transmission
+        resolver.enqueue_query(&[root, com], dns_codec::QType::A, b"example.org".to_vec());
+        resolver.enqueue_query(&[com, root], dns_codec::QType::A, b"example.org".to_vec());
+        assert!(resolver.poll_query(now).is_some());
+        assert!(resolver.poll_query(now).is_none());
+    }
+
+    #[test_log::test]
+    fn strict_matching() {
+        let nameserver: net::SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let spoofer: net::SocketAddr = "192.0.2.1:5353".parse().unwrap();
+        let mut resolver = crate::DnsSansIo::default();
+        let now = Instant::now();
+
+        let id = resolver.enqueue_query_in_zone(
+            b"example.com".to_vec().try_into().unwrap(),
+            &[nameserver],
+            dns_codec::QType::A,
+            b"www.example.com".to_vec(),
+        );
+        resolver.poll_query(now).unwrap();
+
+        let mut codec = dns_codec::MessageCodec;
+        let glue: &[(&[u8], [u8; 4])] = &[
+            (b"NS1.Example.COM", [192, 0, 2, 53]),
+            (b"ns.attacker.org", [203, 0, 113, 66]),
+            (b"ns.\xff.org", [203, 0, 113, 67]),
+        ];
+
+        // Wrong source port
+        let forged = codec.decode(&mut referral(id, b"www.example.com", glue)).unwrap().unwrap();
+        assert!(resolver.handle_response(now, spoofer, forged).is_err());
+
+        // Question does not echo the query
+        let forged = codec.decode(&mut referral(id, b"www.attacker.org", glue)).unwrap().unwrap();
+        assert!(resolver.handle_response(now, nameserver, forged).is_err());
+
+        // Labels that are not UTF-8 are rejected like any other mismatch, and logged without panicking
+        let forged = codec.decode(&mut referral(id, b"www.ex\xffmple.com", glue)).unwrap().unwrap();
+        assert!(format!("{:?}", forged.questions[0].name).contains('\u{fffd}'));
+        assert!(resolver.handle_response(now, nameserver, forged).is_err());
+
+        // Question is echoed with different case, and the out-of-bailiwick glue is dropped
+        let genuine = codec.decode(&mut referral(id, b"WWW.example.com", glue)).unwrap().unwrap();
+        let response = resolver.handle_response(now, nameserver, genuine).unwrap().pop().unwrap();
+        let crate::Outcome::NamespaceIp(records) = response.outcome else {
+            panic!("expected glue, got {:?}", response.outcome);
+        };
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].name, b"ns1.example.com".to_vec().try_into().unwrap());
+    }
+
+    #[test_log::test]
+    fn case_randomization() {
+        let nameserver: net::SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let mut resolver = crate::DnsSansIo::with_config(crate::Config {
+            randomize_case: true,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        // Long enough that randomization virtually never produces the original name
+        let resource = b"abcdefghijklmnopqrstuvwxyz.example.com";
+        let id = resolver.enqueue_query(&[nameserver], dns_codec::QType::A, resource.to_vec());
+
+        let mut codec = dns_codec::MessageCodec;
+        let lowercase: dns_codec::Name = resource.to_vec().try_into().unwrap();
+
+        let transmit = resolver.poll_query(now).unwrap();
+        let sent = transmit.query.questions[0].name.clone();
+        assert_eq!(sent, lowercase);
+        assert!(!sent.eq_exact(&lowercase));
+
+        // A response in the wrong case is rejected, and leaves the query in flight
+        let response = codec.decode(&mut referral(id, resource, &[])).unwrap().unwrap();
+        assert!(resolver.handle_response(now, nameserver, response).is_err());
+
+        // Until the server echoes the case, as a forged response cannot
+        let echoed = sent.labels().collect::<Vec<_>>().join(&b'.');
+        let response = codec.decode(&mut referral(id, &echoed, &[])).unwrap().unwrap();
+        assert!(!resolver.handle_response(now, nameserver, response).unwrap().is_empty());
+
+        // A server that answers every query in lowercase only is queried without 0x20 in the end
+        let id = resolver.enqueue_query(&[nameserver], dns_codec::QType::A, resource.to_vec());
+        let mut now = now;
+        let threshold = resolver.config.case_mismatch_threshold;
+        for _ in 0..threshold {
+            let transmit = resolver.poll_query(now).unwrap();
+            assert!(!transmit.query.questions[0].name.eq_exact(&lowercase));
+
+            let response = codec.decode(&mut referral(id, resource, &[])).unwrap().unwrap();
+            assert!(resolver.handle_response(now, nameserver, response).is_err());
+            now = resolver.poll_timeout().unwrap();
+            assert!(resolver.handle_timeout(now).is_empty());
+        }
+
+        let transmit = resolver.poll_query(now).unwrap();
+        assert!(transmit.query.questions[0].name.eq_exact(&lowercase));
+
+        let response = codec.decode(&mut referral(id, resource, &[])).unwrap().unwrap();
+        assert!(!resolver.handle_response(now, nameserver, response).unwrap().is_empty());
+    }
+
+    #[test_log::test]
+    fn truncation_falls_back_to_tcp() {
+        let nameserver: net::SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let mut resolver = crate::DnsSansIo::default();
+        let now = Instant::now();
+
+        let id = resolver.enqueue_query(&[nameserver], dns_codec::QType::A, b"example.com".to_vec());
+        let transmit = resolver.poll_query(now).unwrap();
+        assert_eq!(transmit.transport, crate::Transport::Udp);
+
+        let mut bytes = referral(id, b"example.com", &[]);
+        bytes[2] |= 0x02;
+        let response = dns_codec::MessageCodec.decode(&mut bytes).unwrap().unwrap();
+        assert!(resolver.handle_response(now, nameserver, response).unwrap().is_empty());
+
+        let transmit = resolver.poll_query(now).unwrap();
+        assert_eq!(transmit.target, nameserver);
+        assert_eq!(transmit.transport, crate::Transport::Tcp);
+        assert_eq!(transmit.query.header.id, id);
+    }
+
+    #[test_log::test]
+    fn edns() {
+        let nameserver: net::SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let now = Instant::now();
+
+        let mut resolver = crate::DnsSansIo::default();
+        resolver.enqueue_query(&[nameserver], dns_codec::QType::A, b"example.com".to_vec());
+        assert!(resolver.poll_query(now).unwrap().query.edns.is_none());
+
+        let mut resolver = crate::DnsSansIo::with_config(crate::Config {
+            edns: Some(1232),
+            ..Default::default()
+        });
+        resolver.enqueue_query(&[nameserver], dns_codec::QType::A, b"example.com".to_vec());
+        let edns = resolver.poll_query(now).unwrap().query.edns.unwrap();
+        assert_eq!(edns.udp_payload_size, 1232);
+    }
+
+    #[test_log::test]
+    fn upstream_transport() {
+        let first: net::SocketAddr = "192.0.2.1:853".parse().unwrap();
+        let second: net::SocketAddr = "192.0.2.2:853".parse().unwrap();
+        let mut resolver = crate::DnsSansIo::with_config(crate::Config {
+            upstreams: vec![first, second],
+            upstream_transport: crate::Transport::Tls,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        resolver.enqueue_recursive_query(dns_codec::QType::A, b"example.com".to_vec());
+        let transmit = resolver.poll_query(now).unwrap();
+        assert_eq!((transmit.target, transmit.transport), (first, crate::Transport::Tls));
+
+        // Retransmissions stay on the configured transport
+        let timeout = resolver.poll_timeout().unwrap();
+        assert!(resolver.handle_timeout(timeout).is_empty());
+        let transmit = resolver.poll_query(timeout).unwrap();
+        assert_eq!(transmit.transport, crate::Transport::Tls);
+
+        // Queries to authoritative nameservers are unaffected
+        resolver.enqueue_query(&[first], dns_codec::QType::A, b"example.net".to_vec());
+        let transmit = resolver.poll_query(timeout).unwrap();
+        assert_eq!(transmit.transport, crate::Transport::Udp);
+    }
+
+    #[test_log::test]
+    fn stub_rotation_and_failover() {
+        let first: net::SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let second: net::SocketAddr = "192.0.2.2:53".parse().unwrap();
+        let third: net::SocketAddr = "192.0.2.3:53".parse().unwrap();
+
+        let mut resolver = crate::DnsSansIo::with_config(crate::Config {
+            upstreams: vec![first, second, third],
+            rotate: true,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        // Successive queries start at successive upstreams, and ask for recursion
+        let mut targets = Vec::new();
+        for i in 0..4 {
+            let name = format!("host{i}.example.com").into_bytes();
+            resolver.enqueue_recursive_query(dns_codec::QType::A, name);
+            let transmit = resolver.poll_query(now).unwrap();
+            assert!(transmit.query.header.recursion_desired());
+            targets.push(transmit.target);
+        }
+        assert_eq!(targets, [first, second, third, first]);
+
+        // Upstreams that do not offer recursion are failed over from
+        let mut resolver = crate::DnsSansIo::with_config(crate::Config {
+            upstreams: vec![first, second],
+            ..Default::default()
+        });
+        let id = resolver.enqueue_recursive_query(dns_codec::QType::A, b"example.com".to_vec());
+        assert_eq!(resolver.poll_query(now).unwrap().target, first);
+
+        let response = dns_codec::MessageCodec
+            .decode(&mut referral(id, b"example.com", &[]))
+            .unwrap()
+            .unwrap();
+        assert!(resolver.handle_response(now, first, response).unwrap().is_empty());
+        assert_eq!(resolver.poll_query(now).unwrap().target, second);
+
+        let mut bytes = referral(id, b"example.com", &[]);
+        bytes[3] |= 0x80;
+        let response = dns_codec::MessageCodec.decode(&mut bytes).unwrap().unwrap();
+        assert!(!resolver.handle_response(now, second, response).unwrap().is_empty());
+    }
+
+    #[test_log::test]
+    fn without_nameservers() {
+        let upstream: net::SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let mut resolver = crate::DnsSansIo::default();
+        let now = Instant::now();
+
+        // Without upstreams, the query is answered right away and the queue is drained past it
+        let failed = resolver.enqueue_recursive_query(dns_codec::QType::A, b"example.com".to_vec());
+        let sent = resolver.enqueue_query(&[upstream], dns_codec::QType::A, b"example.com".to_vec());
+        assert_eq!(resolver.poll_query(now).unwrap().query.header.id, sent);
+        assert_eq!(resolver.poll_timeout(), Some(now));
+
+        let responses = resolver.handle_timeout(now);
+        let [response] = responses.as_slice() else {
+            panic!("expected a single response, got {responses:?}");
+        };
+        assert_eq!(response.id, failed);
+        assert!(matches!(response.outcome, crate::Outcome::NoNameservers));
+        assert!(resolver.poll_timeout().unwrap() > now);
+    }
+
+    #[test_log::test]
+    fn unique_ids_and_ports() {
+        let nameserver: net::SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let mut resolver = crate::DnsSansIo::with_config(crate::Config {
+            randomize_source_port: true,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        let mut ids = std::collections::HashSet::new();
+        for i in 0..4096 {
+            let name = format!("host{i}.example.com").into_bytes();
+            let id = resolver.enqueue_query(&[nameserver], dns_codec::QType::A, name);
+            assert!(ids.insert(id), "0x{id:04x} was handed out twice");
+
+            let transmit = resolver.poll_query(now).unwrap();
+            assert_eq!(transmit.query.header.id, id);
+            assert!(transmit.source_port.is_some_and(|port| port >= 1024));
+        }
     }
 }
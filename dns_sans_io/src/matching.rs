@@ -0,0 +1,82 @@
+//! Checks that decide whether a response may be attributed to an outstanding query at all,
+//! and which of its records may be trusted.
+
+use core::net;
+use std::io;
+
+/// Rejects responses that do not originate from the queried nameserver,
+/// are not flagged as responses, or do not echo the question that was asked.
+pub(crate) fn verify(
+    asked: &dns_codec::Question,
+    target: net::SocketAddr,
+    source: net::SocketAddr,
+    response: &dns_codec::Message,
+    require_exact_case: bool,
+) -> io::Result<()> {
+    if source != target {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Response arrived from {source}, but the query was sent to {target}"),
+        ));
+    }
+
+    if !response.header.is_response() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Message does not have the QR bit set",
+        ));
+    }
+
+    let [question] = response.questions.as_slice() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Response carries {} questions instead of echoing the query",
+                response.questions.len()
+            ),
+        ));
+    };
+
+    let name_matches = if require_exact_case {
+        question.name.eq_exact(&asked.name)
+    } else {
+        question.name == asked.name
+    };
+    if !name_matches || question.kind != asked.kind || question.class != asked.class {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Response question {question:?} does not match query {asked:?}"),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Discards records from the authority and additional sections that the queried nameserver
+/// has no authority over, i.e. which lie outside of `zone`.
+///
+/// Authority records must additionally be owned by the queried name or one of its ancestors,
+/// as anything else cannot be a referral or negative answer for the query.
+pub(crate) fn scrub(
+    event: &str,
+    question: &dns_codec::Question,
+    zone: &dns_codec::Name,
+    response: &mut dns_codec::Message,
+) {
+    response.authorities.retain(|record| {
+        let in_bailiwick =
+            record.name.is_subdomain_of(zone) && question.name.is_subdomain_of(&record.name);
+        if !in_bailiwick {
+            log::warn!(target: event, "scrub: discarding out-of-bailiwick authority {:?}", record.name);
+        }
+        in_bailiwick
+    });
+
+    response.additionals.retain(|record| {
+        let in_bailiwick = record.name.is_subdomain_of(zone);
+        if !in_bailiwick {
+            log::warn!(target: event, "scrub: discarding out-of-bailiwick additional {:?}", record.name);
+        }
+        in_bailiwick
+    });
+}
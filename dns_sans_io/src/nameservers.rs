@@ -0,0 +1,145 @@
+use core::net;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Weight (in tenths) of the previous SRTT when folding in a new sample, as used by BIND.
+const SRTT_WEIGHT: u32 = 7;
+
+/// Servers that were not selected have their SRTT decayed slightly, so that they are eventually retried.
+const SRTT_DECAY_PERCENT: u32 = 98;
+
+/// Upper bound for penalised SRTTs.
+const SRTT_CEILING: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Stats {
+    /// Smoothed round trip time; zero for servers that have never been queried.
+    srtt: Duration,
+
+    /// Consecutive timeouts, SERVFAIL or REFUSED answers.
+    failures: u32,
+
+    /// Point in time until which this server is considered lame.
+    lame_until: Option<Instant>,
+
+    /// Consecutive queries that only drew responses not echoing the letter case of the question.
+    case_mismatches: u32,
+
+    /// Whether this server was found to not echo the letter case of questions.
+    ignores_case: bool,
+}
+
+/// Per-nameserver bookkeeping used to prefer fast servers and to avoid lame ones.
+#[derive(Debug, Default)]
+pub(crate) struct Nameservers {
+    stats: HashMap<net::SocketAddr, Stats>,
+}
+
+impl Nameservers {
+    /// Selects the most promising server out of `candidates`.
+    ///
+    /// Servers that have not been tried for this query are preferred over ones that have,
+    /// and servers that are not lame are preferred over lame ones. Ties are broken by SRTT.
+    pub(crate) fn select(
+        &mut self,
+        candidates: &[net::SocketAddr],
+        tried: &[net::SocketAddr],
+        now: Instant,
+    ) -> Option<net::SocketAddr> {
+        let selected = candidates.iter().copied().min_by_key(|candidate| {
+            let stats = self.stats.get(candidate).copied().unwrap_or_default();
+            let lame = stats.lame_until.is_some_and(|until| until > now);
+            (tried.contains(candidate), lame, stats.srtt)
+        })?;
+
+        for candidate in candidates.iter().filter(|c| **c != selected) {
+            if let Some(stats) = self.stats.get_mut(candidate) {
+                stats.srtt = stats.srtt * SRTT_DECAY_PERCENT / 100;
+            }
+        }
+
+        Some(selected)
+    }
+
+    /// Selects the first candidate in the given order that has neither been tried for this query nor is lame,
+    /// as a stub resolver does with its configured upstreams.
+    pub(crate) fn select_in_order(
+        &self,
+        candidates: &[net::SocketAddr],
+        tried: &[net::SocketAddr],
+        now: Instant,
+    ) -> Option<net::SocketAddr> {
+        candidates
+            .iter()
+            .copied()
+            .min_by_key(|candidate| (tried.contains(candidate), self.is_lame(candidate, now)))
+    }
+
+    /// Folds a successful round trip into the server's SRTT and clears its failure count.
+    pub(crate) fn record_rtt(&mut self, server: net::SocketAddr, rtt: Duration) {
+        let stats = self.stats.entry(server).or_default();
+        stats.srtt = if stats.srtt.is_zero() {
+            rtt
+        } else {
+            (stats.srtt * SRTT_WEIGHT + rtt * (10 - SRTT_WEIGHT)) / 10
+        };
+        stats.failures = 0;
+        stats.lame_until = None;
+    }
+
+    /// Penalises a server that timed out or answered with SERVFAIL / REFUSED,
+    /// marking it lame once it has failed `config.lame_threshold` times in a row.
+    pub(crate) fn record_failure(
+        &mut self,
+        server: net::SocketAddr,
+        now: Instant,
+        config: &crate::Config,
+    ) {
+        let stats = self.stats.entry(server).or_default();
+        stats.srtt = (stats.srtt.max(config.initial_timeout) * 2).min(SRTT_CEILING);
+        stats.failures += 1;
+
+        if stats.failures >= config.lame_threshold {
+            log::info!("nameserver {server} is lame for {:?}", config.lame_duration);
+            stats.lame_until = Some(now + config.lame_duration);
+        }
+    }
+
+    /// Counts a query that timed out after `server` only sent responses with the wrong letter case,
+    /// and stops sending it 0x20 randomized names once it has done so `config.case_mismatch_threshold` times in a row.
+    pub(crate) fn record_case_mismatch(&mut self, server: net::SocketAddr, config: &crate::Config) {
+        let stats = self.stats.entry(server).or_default();
+        stats.case_mismatches += 1;
+
+        if stats.case_mismatches >= config.case_mismatch_threshold && !stats.ignores_case {
+            log::info!("nameserver {server} does not preserve case, disabling 0x20");
+            stats.ignores_case = true;
+        }
+    }
+
+    /// Clears the case mismatch count of a server that echoed a 0x20 randomized name verbatim.
+    pub(crate) fn record_case_preserved(&mut self, server: net::SocketAddr) {
+        if let Some(stats) = self.stats.get_mut(&server) {
+            stats.case_mismatches = 0;
+        }
+    }
+
+    pub(crate) fn preserves_case(&self, server: &net::SocketAddr) -> bool {
+        self.stats
+            .get(server)
+            .is_none_or(|stats| !stats.ignores_case)
+    }
+
+    pub(crate) fn srtt(&self, server: &net::SocketAddr) -> Option<Duration> {
+        self.stats.get(server).map(|stats| stats.srtt)
+    }
+
+    pub(crate) fn is_lame(&self, server: &net::SocketAddr, now: Instant) -> bool {
+        self.stats
+            .get(server)
+            .and_then(|stats| stats.lame_until)
+            .is_some_and(|until| until > now)
+    }
+}